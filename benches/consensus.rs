@@ -0,0 +1,85 @@
+//! Benchmarks for consensus processing and guard selection.
+//!
+//! ```text
+//! cargo bench --bench consensus
+//! ```
+//!
+//! Every benchmark runs against the same synthetic 7000-relay consensus from
+//! [`fixtures::consensus`], so numbers are comparable between commits.
+
+mod fixtures;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use vanguards_rs::{
+    consensus_update, parse_network_statuses, BwWeightedGenerator, Config, ExcludeNodes,
+    FlagsRestriction, NodeRestrictionList, Position, VanguardState,
+};
+
+fn bench_parse_network_statuses(c: &mut Criterion) {
+    let fixture = fixtures::consensus(fixtures::RELAY_COUNT);
+
+    c.bench_function("parse_network_statuses", |b| {
+        b.iter(|| parse_network_statuses(black_box(&fixture.ns_all)).unwrap())
+    });
+}
+
+fn bench_consensus_update(c: &mut Criterion) {
+    let fixture = fixtures::consensus(fixtures::RELAY_COUNT);
+    let routers = parse_network_statuses(&fixture.ns_all).unwrap();
+    let config = Config::default();
+    let exclude = ExcludeNodes::new();
+
+    c.bench_function("consensus_update", |b| {
+        b.iter_batched(
+            || {
+                let mut state = VanguardState::new("bench.state");
+                state.enable_vanguards = true;
+                state
+            },
+            |mut state| {
+                consensus_update(&mut state, &routers, &fixture.weights, &exclude, &config)
+                    .unwrap();
+                state
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_replenish_layers(c: &mut Criterion) {
+    let fixture = fixtures::consensus(fixtures::RELAY_COUNT);
+    let routers = parse_network_statuses(&fixture.ns_all).unwrap();
+    let restrictions = NodeRestrictionList::new(vec![Box::new(FlagsRestriction::new(
+        vec![
+            "Fast".to_string(),
+            "Stable".to_string(),
+            "Valid".to_string(),
+        ],
+        vec!["Authority".to_string()],
+    ))]);
+    let generator =
+        BwWeightedGenerator::new(routers, restrictions, fixture.weights, Position::Middle).unwrap();
+    let config = Config::default();
+    let exclude = ExcludeNodes::new();
+
+    c.bench_function("replenish_layers", |b| {
+        b.iter_batched(
+            || VanguardState::new("bench.state"),
+            |mut state| {
+                state
+                    .replenish_layers(&generator, &exclude, &config.vanguards)
+                    .unwrap();
+                state
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_parse_network_statuses,
+    bench_consensus_update,
+    bench_replenish_layers
+);
+criterion_main!(benches);
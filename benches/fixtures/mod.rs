@@ -0,0 +1,152 @@
+//! Synthetic consensus fixtures for the benchmarks.
+//!
+//! The generated consensus is deterministic and roughly shaped like the live
+//! network: about 7000 relays, a long-tailed bandwidth distribution and flag
+//! frequencies close to what the directory authorities hand out. It uses the
+//! `r`/`s`/`w` layout of a `GETINFO ns/all` reply.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Relay count of a typical consensus.
+pub const RELAY_COUNT: usize = 7000;
+
+/// Seed for the fixture generator, so every run benchmarks the same data.
+const SEED: u64 = 0x7661_6e67;
+
+/// Fraction of relays carrying each optional flag.
+const FLAG_ODDS: [(&str, f64); 7] = [
+    ("Exit", 0.20),
+    ("Fast", 0.92),
+    ("Guard", 0.40),
+    ("HSDir", 0.55),
+    ("Stable", 0.82),
+    ("V2Dir", 0.90),
+    ("BadExit", 0.005),
+];
+
+/// A consensus rendered as text plus its bandwidth weights.
+pub struct Fixture {
+    /// Body of a `GETINFO ns/all` reply.
+    pub ns_all: String,
+    /// Bandwidth weights from the consensus footer.
+    pub weights: HashMap<String, i64>,
+}
+
+/// Generates a fixture with `count` relays.
+pub fn consensus(count: usize) -> Fixture {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut ns_all = String::with_capacity(count * 160);
+
+    for i in 0..count {
+        let identity: [u8; 20] = rng.gen();
+        let address = format!(
+            "{}.{}.{}.{}",
+            rng.gen_range(1..224),
+            rng.gen::<u8>(),
+            rng.gen::<u8>(),
+            rng.gen_range(1..255)
+        );
+        writeln!(
+            ns_all,
+            "r relay{} {} 2024-01-01 {:02}:{:02}:00 {} 9001 0",
+            i,
+            base64_unpadded(&identity),
+            rng.gen_range(0..24),
+            rng.gen_range(0..60),
+            address
+        )
+        .unwrap();
+
+        let mut flags = vec!["Running", "Valid"];
+        flags.extend(
+            FLAG_ODDS
+                .iter()
+                .filter(|(_, odds)| rng.gen_bool(*odds))
+                .map(|(flag, _)| *flag),
+        );
+        flags.sort_unstable();
+        writeln!(ns_all, "s {}", flags.join(" ")).unwrap();
+
+        // Pareto-ish: most relays are slow, a few carry most of the traffic
+        let bandwidth = (20.0 / rng.gen_range(0.0005f64..1.0).powf(1.4)) as u64;
+        if rng.gen_bool(0.95) {
+            let measured = bandwidth * rng.gen_range(60..140) / 100;
+            writeln!(ns_all, "w Bandwidth={} Measured={}", bandwidth, measured).unwrap();
+        } else {
+            writeln!(ns_all, "w Bandwidth={} Unmeasured=1", bandwidth).unwrap();
+        }
+    }
+
+    Fixture {
+        ns_all,
+        weights: weights(),
+    }
+}
+
+/// Bandwidth weights in the range the authorities currently publish.
+fn weights() -> HashMap<String, i64> {
+    [
+        ("Wbd", 0),
+        ("Wbe", 0),
+        ("Wbg", 4131),
+        ("Wbm", 10000),
+        ("Wdb", 10000),
+        ("Web", 10000),
+        ("Wed", 10000),
+        ("Wee", 10000),
+        ("Weg", 10000),
+        ("Wem", 10000),
+        ("Wgb", 10000),
+        ("Wgd", 0),
+        ("Wgg", 5869),
+        ("Wgm", 5869),
+        ("Wmb", 10000),
+        ("Wmd", 0),
+        ("Wme", 0),
+        ("Wmg", 4131),
+        ("Wmm", 10000),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect()
+}
+
+/// Encodes `bytes` as base64 without `=` padding, as consensuses do.
+fn base64_unpadded(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+/// Hex fingerprint matching an identity encoded by [`base64_unpadded`].
+#[allow(dead_code)]
+pub fn identity_hex(b64: &str) -> Option<String> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    let mut hex = String::new();
+    for c in b64.bytes() {
+        bits = bits << 6 | ALPHABET.iter().position(|&a| a == c)? as u32;
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            write!(hex, "{:02X}", (bits >> nbits) & 0xff).unwrap();
+        }
+    }
+    Some(hex)
+}
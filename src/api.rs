@@ -53,7 +53,7 @@
 //! #[tokio::main]
 //! async fn main() -> vanguards_rs::Result<()> {
 //!     let config = Config::default();
-//!     let mut vanguards = Vanguards::from_config(config).await?;
+//!     let vanguards = Vanguards::from_config(config).await?;
 //!     vanguards.run().await
 //! }
 //! ```
@@ -71,7 +71,7 @@
 //!     config.state_file = PathBuf::from("/var/lib/tor/vanguards.state");
 //!     config.loglevel = LogLevel::Debug;
 //!     
-//!     let mut vanguards = Vanguards::from_config(config).await?;
+//!     let vanguards = Vanguards::from_config(config).await?;
 //!     vanguards.run().await
 //! }
 //! ```
@@ -112,11 +112,16 @@
 //! - [`control::run_main`] - Main event loop
 //! - [Python vanguards](https://github.com/mikeperry-tor/vanguards) - Original implementation
 
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use stem_rs::controller::Controller;
+use tokio::sync::{Mutex, Notify};
 use zeroize::Zeroize;
 
 use crate::config::Config;
-use crate::control::{self, AppState};
+use crate::control::{self, AppState, AttackEvent, RunHooks, WouldCloseTally};
 use crate::error::Result;
 use crate::logger::plog;
 use crate::vanguards::VanguardState;
@@ -218,6 +223,94 @@ impl std::fmt::Debug for SecurePassword {
     }
 }
 
+/// A snapshot of the protection state, returned by [`Vanguards::stats`].
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use vanguards_rs::{Config, Vanguards};
+///
+/// # async fn example() -> vanguards_rs::Result<()> {
+/// let vanguards = Vanguards::from_config(Config::default()).await?;
+/// let stats = vanguards.stats().await;
+/// println!("{} circuits tracked", stats.tracked_circuits);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VanguardsStats {
+    /// Current layer2 guard count.
+    pub layer2_guards: usize,
+    /// Current layer3 guard count.
+    pub layer3_guards: usize,
+    /// Circuits bandguards is tracking.
+    pub tracked_circuits: usize,
+    /// Open connections to entry guards.
+    pub live_guard_connections: usize,
+    /// Circuits destroyed while carrying traffic.
+    pub circs_destroyed: u64,
+    /// Circuits closed after a detection, by attack name.
+    pub circuits_closed: BTreeMap<&'static str, u64>,
+    /// Rendezvous point overuse detections.
+    pub rend_overuse: u64,
+    /// Circuits each detector decided to close, whether or not
+    /// `close_circuits` let it.
+    pub would_close: WouldCloseTally,
+}
+
+/// Stops a running [`Vanguards`] from another task.
+///
+/// Obtained from [`Vanguards::shutdown_handle`]. Calling
+/// [`shutdown`](Self::shutdown) has the same effect as CTRL+C: the event
+/// loop exits, the state file is saved, and [`Vanguards::run`] returns
+/// `Ok(())`. Clones share one flag, and a handle that has fired stays
+/// fired, so a later `run` returns at once.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use vanguards_rs::{Config, Vanguards};
+///
+/// #[tokio::main]
+/// async fn main() -> vanguards_rs::Result<()> {
+///     let vanguards = Vanguards::from_config(Config::default())
+///         .await?
+///         .handle_ctrl_c(false);
+///     let handle = vanguards.shutdown_handle();
+///     tokio::spawn(async move {
+///         tokio::time::sleep(Duration::from_secs(3600)).await;
+///         handle.shutdown();
+///     });
+///     vanguards.run().await
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    /// Set once shutdown is requested.
+    requested: Arc<AtomicBool>,
+    /// Wakes the event loop when shutdown is requested.
+    notify: Arc<Notify>,
+}
+
+impl ShutdownHandle {
+    /// Asks the event loop to exit after saving the state file.
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    /// Returns `true` once [`shutdown`](Self::shutdown) has been called.
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Waits until [`shutdown`](Self::shutdown) is called.
+    pub(crate) async fn wait(&self) {
+        self.notify.notified().await;
+    }
+}
+
 /// Main vanguards manager combining all protection components.
 ///
 /// This struct provides a high-level interface for running vanguards protection
@@ -288,7 +381,7 @@ impl std::fmt::Debug for SecurePassword {
 /// async fn main() -> vanguards_rs::Result<()> {
 ///     // Create with default configuration
 ///     let config = Config::default();
-///     let mut vanguards = Vanguards::from_config(config).await?;
+///     let vanguards = Vanguards::from_config(config).await?;
 ///     
 ///     // Run the protection loop
 ///     vanguards.run().await
@@ -317,6 +410,32 @@ impl std::fmt::Debug for SecurePassword {
 /// }
 /// ```
 ///
+/// ## Reading Stats While Running
+///
+/// [`run`](Vanguards::run) borrows the manager shared, so
+/// [`stats`](Vanguards::stats) can be polled alongside it:
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use vanguards_rs::{Config, Vanguards};
+///
+/// #[tokio::main]
+/// async fn main() -> vanguards_rs::Result<()> {
+///     let vanguards = Vanguards::from_config(Config::default()).await?;
+///     let report = async {
+///         loop {
+///             tokio::time::sleep(Duration::from_secs(60)).await;
+///             let stats = vanguards.stats().await;
+///             println!("{} circuits tracked", stats.tracked_circuits);
+///         }
+///     };
+///     tokio::select! {
+///         result = vanguards.run() => result,
+///         _ = report => Ok(()),
+///     }
+/// }
+/// ```
+///
 /// # Security Considerations
 ///
 /// - Passwords are cleared from memory after authentication
@@ -335,6 +454,15 @@ pub struct Vanguards {
     state: AppState,
     /// Secure password wrapper (cleared on drop).
     _password: Option<SecurePassword>,
+    /// Latest stats, refreshed by the event loop while [`run`](Self::run) is going.
+    stats: Arc<Mutex<VanguardsStats>>,
+    /// Stops [`run`](Self::run) when fired.
+    shutdown: ShutdownHandle,
+    /// Whether [`run`](Self::run) stops on CTRL+C.
+    handle_ctrl_c: bool,
+    /// Called by [`run`](Self::run) for each detection, if set with
+    /// [`on_attack`](Self::on_attack).
+    attack_callback: Option<Arc<dyn Fn(AttackEvent) + Send + Sync>>,
 }
 
 impl Vanguards {
@@ -370,7 +498,7 @@ impl Vanguards {
     /// ```
     pub fn new(_controller: Controller, config: Config) -> Result<Self> {
         let state_path = &config.state_file;
-        let vanguard_state = match VanguardState::read_from_file(state_path) {
+        let vanguard_state = match VanguardState::read_with_backups(state_path) {
             Ok(mut state) => {
                 plog(
                     LogLevel::Info,
@@ -380,7 +508,7 @@ impl Vanguards {
                         state.layer3.len()
                     ),
                 );
-                state.enable_vanguards = config.enable_vanguards;
+                state.apply_config(&config);
                 state
             }
             Err(_) => {
@@ -389,16 +517,21 @@ impl Vanguards {
                     &format!("Creating new vanguard state at: {}", state_path.display()),
                 );
                 let mut state = VanguardState::new(&state_path.to_string_lossy());
-                state.enable_vanguards = config.enable_vanguards;
+                state.apply_config(&config);
                 state
             }
         };
 
         let app_state = AppState::new(vanguard_state, config);
+        let stats = Arc::new(Mutex::new(app_state.vanguards_stats()));
 
         Ok(Self {
             state: app_state,
             _password: None,
+            stats,
+            shutdown: ShutdownHandle::default(),
+            handle_ctrl_c: true,
+            attack_callback: None,
         })
     }
 
@@ -431,11 +564,10 @@ impl Vanguards {
     /// }
     /// ```
     pub async fn from_config(config: Config) -> Result<Self> {
-        // Wrap password in secure container
-        let secure_password = config.control_pass.clone().map(SecurePassword::new);
+        let secure_password = control::configured_password(&config)?;
 
         let state_path = &config.state_file;
-        let vanguard_state = match VanguardState::read_from_file(state_path) {
+        let vanguard_state = match VanguardState::read_with_backups(state_path) {
             Ok(mut state) => {
                 plog(
                     LogLevel::Info,
@@ -445,7 +577,7 @@ impl Vanguards {
                         state.layer3.len()
                     ),
                 );
-                state.enable_vanguards = config.enable_vanguards;
+                state.apply_config(&config);
                 state
             }
             Err(_) => {
@@ -454,16 +586,21 @@ impl Vanguards {
                     &format!("Creating new vanguard state at: {}", state_path.display()),
                 );
                 let mut state = VanguardState::new(&state_path.to_string_lossy());
-                state.enable_vanguards = config.enable_vanguards;
+                state.apply_config(&config);
                 state
             }
         };
 
         let app_state = AppState::new(vanguard_state, config);
+        let stats = Arc::new(Mutex::new(app_state.vanguards_stats()));
 
         Ok(Self {
             state: app_state,
             _password: secure_password,
+            stats,
+            shutdown: ShutdownHandle::default(),
+            handle_ctrl_c: true,
+            attack_callback: None,
         })
     }
 
@@ -473,6 +610,11 @@ impl Vanguards {
     /// components, and processes events until the connection is closed or
     /// an error occurs.
     ///
+    /// The loop keeps [`stats`](Self::stats) current as it handles events,
+    /// and exits cleanly on CTRL+C (unless turned off with
+    /// [`handle_ctrl_c`](Self::handle_ctrl_c)) or when the
+    /// [`shutdown_handle`](Self::shutdown_handle) fires.
+    ///
     /// # Errors
     ///
     /// Returns an error if the protection loop fails.
@@ -485,12 +627,99 @@ impl Vanguards {
     /// #[tokio::main]
     /// async fn main() -> vanguards_rs::Result<()> {
     ///     let config = Config::default();
-    ///     let mut vanguards = Vanguards::from_config(config).await?;
+    ///     let vanguards = Vanguards::from_config(config).await?;
     ///     vanguards.run().await
     /// }
     /// ```
-    pub async fn run(&mut self) -> Result<()> {
-        control::run_main(self.state.config.clone()).await
+    pub async fn run(&self) -> Result<()> {
+        let hooks = RunHooks {
+            stats: Some(self.stats.clone()),
+            shutdown: Some(self.shutdown.clone()),
+            ctrl_c: self.handle_ctrl_c,
+            on_attack: self.attack_callback.clone(),
+        };
+        control::run_main_with(self.state.config.clone(), hooks).await
+    }
+
+    /// Returns a snapshot of guard counts, tracked circuits and attack tallies.
+    ///
+    /// Before [`run`](Self::run) starts this reflects the loaded state file;
+    /// afterwards it is refreshed after each detection or guard or circuit
+    /// change and at least once a minute, and keeps the last values once
+    /// `run` returns.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use vanguards_rs::{Config, Vanguards};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> vanguards_rs::Result<()> {
+    ///     let vanguards = Vanguards::from_config(Config::default()).await?;
+    ///     let stats = vanguards.stats().await;
+    ///     println!("Layer2 guards: {}", stats.layer2_guards);
+    ///     println!("Closed circuits: {:?}", stats.circuits_closed);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn stats(&self) -> VanguardsStats {
+        self.stats.lock().await.clone()
+    }
+
+    /// Returns a handle that stops [`run`](Self::run) from another task.
+    ///
+    /// See [`ShutdownHandle`] for an example.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Sets whether [`run`](Self::run) installs a CTRL+C handler.
+    ///
+    /// On by default. Applications that handle signals themselves should
+    /// turn it off and stop the loop through
+    /// [`shutdown_handle`](Self::shutdown_handle) instead.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use vanguards_rs::{Config, Vanguards};
+    ///
+    /// # async fn example() -> vanguards_rs::Result<()> {
+    /// let vanguards = Vanguards::from_config(Config::default())
+    ///     .await?
+    ///     .handle_ctrl_c(false);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn handle_ctrl_c(mut self, enabled: bool) -> Self {
+        self.handle_ctrl_c = enabled;
+        self
+    }
+
+    /// Registers a callback [`run`](Self::run) calls for each detection.
+    ///
+    /// See [`AppState::on_attack`]; the callback runs on the event loop, so
+    /// it should hand slow work off to another task or thread.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use vanguards_rs::{AttackEvent, Config, Vanguards};
+    ///
+    /// # async fn example() -> vanguards_rs::Result<()> {
+    /// let vanguards = Vanguards::from_config(Config::default())
+    ///     .await?
+    ///     .on_attack(|event| {
+    ///         if let AttackEvent::DroppedCells { circ_id, .. } = event {
+    ///             eprintln!("dropped cells on circuit {}", circ_id);
+    ///         }
+    ///     });
+    /// vanguards.run().await
+    /// # }
+    /// ```
+    pub fn on_attack(mut self, callback: impl Fn(AttackEvent) + Send + Sync + 'static) -> Self {
+        self.attack_callback = Some(Arc::new(callback));
+        self
     }
 
     /// Returns a reference to the current vanguard state.
@@ -515,6 +744,44 @@ impl Vanguards {
         &self.state.vanguard_state
     }
 
+    /// Expires a single layer2 or layer3 guard and saves the state file.
+    ///
+    /// The guard is replaced the next time the layers are replenished, which
+    /// happens on the next consensus once [`run`](Self::run) is going. A
+    /// running daemon can be asked to do the same immediately over IPC with
+    /// `expire <fingerprint>`.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the guard was found and removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::State`](crate::Error::State) if the state file cannot be written.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use vanguards_rs::{Config, Vanguards};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> vanguards_rs::Result<()> {
+    ///     let mut vanguards = Vanguards::from_config(Config::default()).await?;
+    ///     if !vanguards.expire_guard("AABBCCDD00112233445566778899AABBCCDDEEFF")? {
+    ///         println!("Not a current guard");
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn expire_guard(&mut self, fingerprint: &str) -> Result<bool> {
+        let vanguard_state = &mut self.state.vanguard_state;
+        if !vanguard_state.expire_guard(fingerprint) {
+            return Ok(false);
+        }
+        vanguard_state.write_to_file(std::path::Path::new(&vanguard_state.state_file))?;
+        Ok(true)
+    }
+
     /// Returns a reference to the current configuration.
     ///
     /// # Example
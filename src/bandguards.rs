@@ -86,6 +86,7 @@
 //! │  Excessive Bandwidth  │ bytes > max_megabytes   │ Close circuit         │
 //! │  HSDIR Abuse          │ hsdir bytes > limit     │ Close circuit         │
 //! │  Intro Abuse          │ intro bytes > limit     │ Close circuit         │
+//! │  HSDIR Flood          │ hsdir circs/min > limit │ Log warning           │
 //! │  Old Circuits         │ age > max_age_hours     │ Close circuit         │
 //! │  Guard Conn Kill      │ conn close + circ fail  │ Log warning           │
 //! │  Network Disconnect   │ no conns for N secs     │ Log warning           │
@@ -106,6 +107,19 @@
 //!   RELAY_PAYLOAD_SIZE = 498 bytes (509 - 11 byte header)
 //! ```
 //!
+//! # Attack Records
+//!
+//! A circuit is flagged when an attack is detected on it and unflagged once
+//! we close it. If Tor closes a circuit while it is still flagged, because
+//! circuit closing is paused or our close failed, the detection is kept in
+//! [`BandwidthStats::attack_records`] as "closed by Tor before action"
+//! rather than vanishing with the circuit.
+//!
+//! Each record carries the circuit's service attribution: the onion address
+//! from the CIRC event's `REND_QUERY`, or failing that its SOCKS username.
+//! With several services on one Tor, [`BandwidthStats::attack_summary`]
+//! groups the records by it so a detection can be traced to its service.
+//!
 //! # Tor Bug Workarounds
 //!
 //! This module includes workarounds for known Tor bugs that can cause
@@ -167,9 +181,13 @@
 //! - [Python vanguards bandguards](https://github.com/mikeperry-tor/vanguards) - Original implementation
 //! - [Tor Bug Tracker](https://gitlab.torproject.org/tpo/core/tor/-/issues) - Bug references
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
 
-use crate::config::BandguardsConfig;
+use crate::config::{BandguardsConfig, LogLevel};
+use crate::logger::plog;
 
 /// Cell payload size in bytes.
 pub const CELL_PAYLOAD_SIZE: u64 = 509;
@@ -192,6 +210,22 @@ const BYTES_PER_MB: u64 = 1024 * BYTES_PER_KB;
 /// Maximum lag between guard connection close and circuit destroy events.
 pub const MAX_CIRC_DESTROY_LAG_SECS: u64 = 2;
 
+/// Number of entries kept in [`BandwidthStats::attack_records`].
+pub const MAX_ATTACK_RECORDS: usize = 100;
+
+/// Most kill times kept per guard for [`BandwidthStats::check_guard_kills`].
+const MAX_KILL_TIMES: usize = 1000;
+
+/// Window over which HSDIR circuit launches are counted, in seconds.
+pub const HSDIR_RATE_WINDOW_SECS: f64 = 60.0;
+
+/// Shortest span a circuit's throughput is measured over, in seconds.
+///
+/// Tor sends CIRC_BW about once a second, but events that queued up behind
+/// a slow handler arrive together; dividing by the few milliseconds between
+/// them would report a rate many times the real one.
+const MIN_RATE_INTERVAL_SECS: f64 = 1.0;
+
 /// Per-circuit bandwidth statistics for attack detection.
 ///
 /// Tracks all bandwidth-related information for a single circuit,
@@ -295,10 +329,27 @@ pub struct BwCircuitStat {
     pub overhead_read_bytes: u64,
     /// Overhead sent bytes (protocol overhead).
     pub overhead_sent_bytes: u64,
+    /// Arrival time of the CIRC_BW event that started the current
+    /// throughput measurement.
+    pub last_bw_at: Option<f64>,
+    /// Bytes read and sent since [`last_bw_at`](Self::last_bw_at).
+    pub rate_window_bytes: u64,
+    /// Unix timestamp of the last CIRC or CIRC_BW event for this circuit,
+    /// starting at [`created_at`](Self::created_at).
+    pub last_update: f64,
+    /// Throughput over the last measurement of at least a second, in bytes
+    /// per second.
+    pub bytes_per_sec: f64,
     /// Guard fingerprint for this circuit.
     pub guard_fp: Option<String>,
     /// Timestamp when the circuit may have been destroyed due to guard closure.
     pub possibly_destroyed_at: Option<f64>,
+    /// Attack detected on this circuit that we have not yet acted on.
+    pub flagged: Option<&'static str>,
+    /// Whether the circuit was already reported as over `circ_max_age_hours`.
+    pub age_reported: bool,
+    /// Service the circuit belongs to, set by [`BandwidthStats::tag_service`].
+    pub service: Option<String>,
 }
 
 impl BwCircuitStat {
@@ -309,6 +360,10 @@ impl BwCircuitStat {
     /// * `circ_id` - The circuit ID
     /// * `is_hs` - Whether this is a hidden service circuit
     pub fn new(circ_id: String, is_hs: bool) -> Self {
+        let created_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
         Self {
             circ_id,
             is_hs,
@@ -322,18 +377,22 @@ impl BwCircuitStat {
             old_hs_state: None,
             in_use: false,
             built: false,
-            created_at: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs_f64(),
+            created_at,
             read_bytes: 0,
             sent_bytes: 0,
             delivered_read_bytes: 0,
             delivered_sent_bytes: 0,
             overhead_read_bytes: 0,
             overhead_sent_bytes: 0,
+            last_bw_at: None,
+            rate_window_bytes: 0,
+            last_update: created_at,
+            bytes_per_sec: 0.0,
             guard_fp: None,
             possibly_destroyed_at: None,
+            flagged: None,
+            age_reported: false,
+            service: None,
         }
     }
 
@@ -363,6 +422,29 @@ impl BwCircuitStat {
         cells_received as i64 - cells_delivered as i64
     }
 
+    /// Calculates the number of dropped sent cells.
+    ///
+    /// The written-direction counterpart of [`dropped_read_cells`]: cells
+    /// we sent that Tor did not account for as delivered or overhead.
+    ///
+    /// [`dropped_read_cells`]: BwCircuitStat::dropped_read_cells
+    ///
+    /// # Formula
+    ///
+    /// ```text
+    /// dropped = sent_bytes / CELL_PAYLOAD_SIZE - (delivered_sent + overhead_sent) / RELAY_PAYLOAD_SIZE
+    /// ```
+    ///
+    /// # Returns
+    ///
+    /// The number of dropped cells. Can be negative due to timing issues.
+    pub fn dropped_sent_cells(&self) -> i64 {
+        let cells_sent = self.sent_bytes / CELL_PAYLOAD_SIZE;
+        let cells_delivered =
+            (self.delivered_sent_bytes + self.overhead_sent_bytes) / RELAY_PAYLOAD_SIZE;
+        cells_sent as i64 - cells_delivered as i64
+    }
+
     /// Returns the circuit age in seconds.
     pub fn age_secs(&self) -> f64 {
         let now = std::time::SystemTime::now()
@@ -380,7 +462,8 @@ impl BwCircuitStat {
 
 /// Per-guard connection statistics.
 ///
-/// Tracks connection state and closure information for a single guard relay.
+/// Tracks connection state and closure information for a single guard relay,
+/// and the bandwidth of circuits attributed to it.
 #[derive(Debug, Clone)]
 pub struct BwGuardStat {
     /// Guard fingerprint.
@@ -393,8 +476,14 @@ pub struct BwGuardStat {
     pub killed_conn_pending: bool,
     /// Total connections made to this guard.
     pub conns_made: u32,
+    /// When recent connections were killed with live circuits, oldest first.
+    pub kill_times: VecDeque<f64>,
     /// Close reasons and their counts.
     pub close_reasons: HashMap<String, u32>,
+    /// Bytes read on circuits through this guard.
+    pub total_read_bytes: u64,
+    /// Bytes sent on circuits through this guard.
+    pub total_sent_bytes: u64,
 }
 
 impl BwGuardStat {
@@ -410,10 +499,18 @@ impl BwGuardStat {
             killed_conn_at: 0.0,
             killed_conn_pending: false,
             conns_made: 0,
+            kill_times: VecDeque::new(),
             close_reasons: HashMap::new(),
+            total_read_bytes: 0,
+            total_sent_bytes: 0,
         }
     }
 
+    /// Returns the total bytes (read + sent) through this guard.
+    pub fn total_bytes(&self) -> u64 {
+        self.total_read_bytes + self.total_sent_bytes
+    }
+
     /// Records a close reason.
     pub fn record_close_reason(&mut self, reason: &str) {
         *self.close_reasons.entry(reason.to_string()).or_insert(0) += 1;
@@ -503,6 +600,8 @@ pub struct BandwidthStats {
     pub guards: HashMap<String, BwGuardStat>,
     /// Total circuits destroyed.
     pub circs_destroyed_total: u64,
+    /// Circuits dropped by [`prune_stale`](Self::prune_stale).
+    pub circs_pruned_total: u64,
     /// Timestamp when all connections were lost (None if connected).
     pub no_conns_since: Option<f64>,
     /// Timestamp when circuits started failing (None if working).
@@ -515,6 +614,25 @@ pub struct BandwidthStats {
     pub disconnected_circs: bool,
     /// Whether we're currently disconnected (no connections).
     pub disconnected_conns: bool,
+    /// Most recent detections and how they ended, oldest first.
+    pub attack_records: VecDeque<AttackRecord>,
+    /// Circuits we closed after a detection, by attack name. Unlike
+    /// [`attack_records`](Self::attack_records) this is never trimmed.
+    pub closed_by_kind: BTreeMap<&'static str, u64>,
+    /// Launch times of HSDIR circuits within the last [`HSDIR_RATE_WINDOW_SECS`].
+    pub hsdir_launches: VecDeque<f64>,
+    /// Whether the current HSDIR burst has already been reported.
+    pub hsdir_rate_alerted: bool,
+    /// Unix timestamp of the last circuit limit sweep.
+    pub last_limit_sweep: Option<f64>,
+    /// Whether `GUARD_WAIT` marks a circuit built, like `BUILT` does.
+    ///
+    /// Mirrors [`BandguardsConfig::treat_guard_wait_as_built`].
+    pub treat_guard_wait_as_built: bool,
+    /// Circuit purposes that get a tracking entry; empty means all.
+    ///
+    /// Mirrors [`BandguardsConfig::monitored_purposes`].
+    pub monitored_purposes: Vec<String>,
 }
 
 impl Default for BandwidthStats {
@@ -531,6 +649,7 @@ impl BandwidthStats {
             live_guard_conns: HashMap::new(),
             guards: HashMap::new(),
             circs_destroyed_total: 0,
+            circs_pruned_total: 0,
             no_conns_since: Some(
                 std::time::SystemTime::now()
                     .duration_since(std::time::UNIX_EPOCH)
@@ -542,7 +661,266 @@ impl BandwidthStats {
             max_fake_id: -1,
             disconnected_circs: false,
             disconnected_conns: false,
+            attack_records: VecDeque::new(),
+            closed_by_kind: BTreeMap::new(),
+            hsdir_launches: VecDeque::new(),
+            hsdir_rate_alerted: false,
+            last_limit_sweep: None,
+            treat_guard_wait_as_built: true,
+            monitored_purposes: Vec::new(),
+        }
+    }
+
+    /// Returns true if circuits with `purpose` should be tracked.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::bandguards::BandwidthStats;
+    ///
+    /// let mut stats = BandwidthStats::new();
+    /// assert!(stats.is_monitored_purpose("GENERAL"));
+    ///
+    /// stats.monitored_purposes = vec!["HS_*".to_string()];
+    /// assert!(stats.is_monitored_purpose("HS_SERVICE_REND"));
+    /// assert!(!stats.is_monitored_purpose("GENERAL"));
+    /// ```
+    pub fn is_monitored_purpose(&self, purpose: &str) -> bool {
+        self.monitored_purposes.is_empty()
+            || self
+                .monitored_purposes
+                .iter()
+                .any(|p| match p.strip_suffix('*') {
+                    Some(prefix) => purpose.starts_with(prefix),
+                    None => purpose == p,
+                })
+    }
+
+    /// Creates the tracking entry for a circuit first seen with `purpose`.
+    fn new_circuit_stat(circ_id: &str, purpose: &str, hs_state: Option<&str>) -> BwCircuitStat {
+        let is_hs = hs_state.is_some() || purpose.starts_with("HS");
+        let mut circ = BwCircuitStat::new(circ_id.to_string(), is_hs);
+
+        // Set service/client based on purpose
+        if purpose.starts_with("HS_CLIENT") {
+            circ.is_service = false;
+        } else if purpose.starts_with("HS_SERVICE") {
+            circ.is_service = true;
+        }
+
+        // Set HSDIR and intro flags
+        if purpose == "HS_CLIENT_HSDIR" || purpose == "HS_SERVICE_HSDIR" {
+            circ.is_hsdir = true;
+        } else if purpose == "HS_SERVICE_INTRO" {
+            circ.is_serv_intro = true;
+        }
+        circ
+    }
+
+    /// Returns true if the circuit limit sweep should run now.
+    ///
+    /// Always true when `limit_check_interval_ms` is 0. Otherwise true at
+    /// most once per interval; a true result starts the next interval.
+    pub fn limit_sweep_due(&mut self, config: &BandguardsConfig, now: f64) -> bool {
+        let interval = f64::from(config.limit_check_interval_ms) / 1000.0;
+        if self
+            .last_limit_sweep
+            .is_some_and(|last| now - last < interval)
+        {
+            return false;
+        }
+        self.last_limit_sweep = Some(now);
+        true
+    }
+
+    /// Finds guards whose connections were killed under live circuits more
+    /// than `max_guard_conn_kills` times in the last
+    /// `guard_conn_kill_window_secs`.
+    ///
+    /// Repeatedly closing the connection to a guard while circuits are on it
+    /// can push Tor to a new guard, which helps an adversary steer us onto
+    /// relays they run.
+    ///
+    /// # Returns
+    ///
+    /// One alert per such guard, ordered by fingerprint.
+    pub fn check_guard_kills(&self, config: &BandguardsConfig, now: f64) -> Vec<GuardKillAlert> {
+        if config.max_guard_conn_kills == 0 {
+            return Vec::new();
+        }
+        let window = f64::from(config.guard_conn_kill_window_secs);
+        let mut alerts: Vec<GuardKillAlert> = self
+            .guards
+            .values()
+            .filter_map(|g| {
+                let kills = g.kill_times.iter().filter(|&&t| now - t <= window).count();
+                (kills > config.max_guard_conn_kills as usize).then(|| GuardKillAlert {
+                    fingerprint: g.to_guard.clone(),
+                    kills,
+                    window_secs: config.guard_conn_kill_window_secs,
+                })
+            })
+            .collect();
+        alerts.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+        alerts
+    }
+
+    /// Checks the HSDIR circuit launch rate against `max_hsdir_rate`.
+    ///
+    /// # Returns
+    ///
+    /// The number of HSDIR circuits launched within the window, once per
+    /// burst, when it exceeds the limit. The alert re-arms after the rate
+    /// falls back to the limit.
+    pub fn check_hsdir_rate(&mut self, config: &BandguardsConfig, now: f64) -> Option<usize> {
+        while self
+            .hsdir_launches
+            .front()
+            .is_some_and(|&t| now - t > HSDIR_RATE_WINDOW_SECS)
+        {
+            self.hsdir_launches.pop_front();
+        }
+
+        if config.max_hsdir_rate == 0 || self.hsdir_launches.len() <= config.max_hsdir_rate as usize
+        {
+            self.hsdir_rate_alerted = false;
+            return None;
+        }
+        if self.hsdir_rate_alerted {
+            return None;
+        }
+        self.hsdir_rate_alerted = true;
+        Some(self.hsdir_launches.len())
+    }
+
+    /// Marks a circuit as having an attack of `kind` awaiting action.
+    ///
+    /// Returns `true` if the circuit is tracked and was not flagged before,
+    /// so a detection repeated on later checks is counted once.
+    pub fn flag_circuit(&mut self, circ_id: &str, kind: &'static str) -> bool {
+        match self.circs.get_mut(circ_id) {
+            Some(circ) => circ.flagged.replace(kind).is_none(),
+            None => false,
+        }
+    }
+
+    /// Attributes a circuit to `service` (an onion address or SOCKS username).
+    ///
+    /// The first attribution sticks; later CIRC events for the same circuit
+    /// do not change it. Does nothing if the circuit is unknown.
+    pub fn tag_service(&mut self, circ_id: &str, service: &str) {
+        if let Some(circ) = self.circs.get_mut(circ_id) {
+            circ.service.get_or_insert_with(|| service.to_string());
+        }
+    }
+
+    /// Records how a flagged circuit's detection ended and clears the flag.
+    ///
+    /// Does nothing if the circuit is unknown or not flagged.
+    pub fn record_attack(&mut self, circ_id: &str, outcome: AttackOutcome, at: f64) {
+        let Some(circ) = self.circs.get_mut(circ_id) else {
+            return;
+        };
+        if let Some(kind) = circ.flagged.take() {
+            if outcome == AttackOutcome::Closed {
+                *self.closed_by_kind.entry(kind).or_insert(0) += 1;
+            }
+            let service = circ.service.clone();
+            self.push_attack_record(AttackRecord {
+                circ_id: circ_id.to_string(),
+                kind,
+                outcome,
+                at,
+                service,
+            });
+        }
+    }
+
+    /// Groups [`attack_records`](Self::attack_records) by service.
+    ///
+    /// Unattributed detections form their own group with `service: None`,
+    /// which sorts first; the rest are ordered by service.
+    pub fn attack_summary(&self) -> Vec<ServiceAttacks> {
+        let mut groups: BTreeMap<Option<&str>, ServiceAttacks> = BTreeMap::new();
+        for record in &self.attack_records {
+            let group = groups
+                .entry(record.service.as_deref())
+                .or_insert_with(|| ServiceAttacks {
+                    service: record.service.clone(),
+                    ..ServiceAttacks::default()
+                });
+            group.detections += 1;
+            *group.kinds.entry(record.kind.to_string()).or_insert(0) += 1;
+            group.last_at = group.last_at.max(record.at);
+        }
+        groups.into_values().collect()
+    }
+
+    /// Returns a read-only copy of the tracked circuits and guards.
+    ///
+    /// Circuits are ordered by numeric ID and guards by fingerprint, so two
+    /// snapshots of the same state serialize identically.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::bandguards::BandwidthStats;
+    ///
+    /// let mut stats = BandwidthStats::new();
+    /// stats.circ_event("7", "LAUNCHED", "HS_VANGUARDS", None, &[], None, 1000.0);
+    ///
+    /// let snapshot = stats.snapshot();
+    /// assert_eq!(snapshot.circuits[0].circ_id, "7");
+    /// let json = serde_json::to_string(&snapshot).unwrap();
+    /// assert!(json.contains("\"purpose\":\"HS_VANGUARDS\""));
+    /// ```
+    pub fn snapshot(&self) -> BandwidthSnapshot {
+        let mut circuits: Vec<CircuitSummary> = self
+            .circs
+            .values()
+            .map(|c| CircuitSummary {
+                circ_id: c.circ_id.clone(),
+                total_bytes: c.total_bytes(),
+                dropped_read_cells: c.dropped_read_cells(),
+                age_secs: c.age_secs(),
+                purpose: c.purpose.clone(),
+            })
+            .collect();
+        circuits.sort_by(|a, b| {
+            let numeric = |id: &str| id.parse::<u64>().unwrap_or(u64::MAX);
+            numeric(&a.circ_id)
+                .cmp(&numeric(&b.circ_id))
+                .then_with(|| a.circ_id.cmp(&b.circ_id))
+        });
+
+        let mut guards: Vec<GuardSummary> = self
+            .guards
+            .values()
+            .map(|g| GuardSummary {
+                fingerprint: g.to_guard.clone(),
+                conns_made: g.conns_made,
+                killed_conns: g.killed_conns,
+                close_reasons: g
+                    .close_reasons
+                    .iter()
+                    .map(|(reason, n)| (reason.clone(), *n))
+                    .collect(),
+            })
+            .collect();
+        guards.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+
+        BandwidthSnapshot {
+            circuits,
+            guards,
+            circs_destroyed_total: self.circs_destroyed_total,
+        }
+    }
+
+    fn push_attack_record(&mut self, record: AttackRecord) {
+        if self.attack_records.len() >= MAX_ATTACK_RECORDS {
+            self.attack_records.pop_front();
         }
+        self.attack_records.push_back(record);
     }
 
     /// Handles an ORCONN event.
@@ -678,6 +1056,22 @@ impl BandwidthStats {
         // Handle circuit closure
         if status == "FAILED" || status == "CLOSED" {
             if let Some(circ) = self.circs.remove(circ_id) {
+                if let Some(kind) = circ.flagged {
+                    plog(
+                        LogLevel::Notice,
+                        &format!(
+                            "Circuit {} with a {} detection was closed by Tor before we acted.",
+                            circ_id, kind
+                        ),
+                    );
+                    self.push_attack_record(AttackRecord {
+                        circ_id: circ_id.to_string(),
+                        kind,
+                        outcome: AttackOutcome::ClosedByTor,
+                        at: arrived_at,
+                        service: circ.service.clone(),
+                    });
+                }
                 if circ.in_use && circ.possibly_destroyed_at.is_some() {
                     if let Some(destroyed_at) = circ.possibly_destroyed_at {
                         if arrived_at - destroyed_at <= MAX_CIRC_DESTROY_LAG_SECS as f64
@@ -688,6 +1082,10 @@ impl BandwidthStats {
                                 if let Some(guard) = self.guards.get_mut(guard_fp) {
                                     guard.killed_conn_at = 0.0;
                                     guard.killed_conns += 1;
+                                    if guard.kill_times.len() >= MAX_KILL_TIMES {
+                                        guard.kill_times.pop_front();
+                                    }
+                                    guard.kill_times.push_back(arrived_at);
                                 }
                             }
                             self.circs_destroyed_total += 1;
@@ -700,25 +1098,15 @@ impl BandwidthStats {
             return None;
         }
 
-        // Create circuit entry if needed
-        let is_hs = hs_state.is_some() || purpose.starts_with("HS");
+        // Create circuit entry if needed, unless its purpose isn't monitored
         if !self.circs.contains_key(circ_id) {
-            let mut circ = BwCircuitStat::new(circ_id.to_string(), is_hs);
-
-            // Set service/client based on purpose
-            if purpose.starts_with("HS_CLIENT") {
-                circ.is_service = false;
-            } else if purpose.starts_with("HS_SERVICE") {
-                circ.is_service = true;
+            if !self.is_monitored_purpose(purpose) {
+                return None;
             }
-
-            // Set HSDIR and intro flags
-            if purpose == "HS_CLIENT_HSDIR" || purpose == "HS_SERVICE_HSDIR" {
-                circ.is_hsdir = true;
-            } else if purpose == "HS_SERVICE_INTRO" {
-                circ.is_serv_intro = true;
+            let circ = Self::new_circuit_stat(circ_id, purpose, hs_state);
+            if circ.is_hsdir {
+                self.hsdir_launches.push_back(arrived_at);
             }
-
             self.circs.insert(circ_id.to_string(), circ);
         }
 
@@ -726,9 +1114,14 @@ impl BandwidthStats {
         if let Some(circ) = self.circs.get_mut(circ_id) {
             circ.purpose = Some(purpose.to_string());
             circ.hs_state = hs_state.map(|s| s.to_string());
-
-            // Handle BUILT and GUARD_WAIT
-            if status == "BUILT" || status == "GUARD_WAIT" {
+            circ.last_update = arrived_at;
+
+            // GUARD_WAIT circuits are held until their guard is confirmed
+            // and may never carry traffic, so they only count as built when
+            // configured to.
+            let built =
+                status == "BUILT" || (status == "GUARD_WAIT" && self.treat_guard_wait_as_built);
+            if built {
                 circ.built = true;
 
                 if self.disconnected_circs {
@@ -743,7 +1136,7 @@ impl BandwidthStats {
                         circ.guard_fp = Some(path[0].clone());
                     }
                 }
-            } else if status == "EXTENDED" {
+            } else if status == "EXTENDED" || status == "GUARD_WAIT" {
                 if self.disconnected_circs {
                     self.disconnected_circs = false;
                 }
@@ -759,6 +1152,12 @@ impl BandwidthStats {
     /// Tracks circuit purpose changes, particularly from HS_VANGUARDS to
     /// actual HS purposes.
     ///
+    /// When [`monitored_purposes`](Self::monitored_purposes) is set, a
+    /// circuit skipped at launch starts being tracked once it changes to a
+    /// monitored purpose. Purpose changes and cannibalization only happen
+    /// to open circuits, so it is tracked as built, and as in use if the
+    /// new purpose is a client or service one.
+    ///
     /// # Arguments
     ///
     /// * `circ_id` - Circuit ID
@@ -779,6 +1178,19 @@ impl BandwidthStats {
         old_hs_state: Option<&str>,
         path: &[String],
     ) {
+        if !self.circs.contains_key(circ_id)
+            && !self.monitored_purposes.is_empty()
+            && self.is_monitored_purpose(purpose)
+        {
+            let mut circ = Self::new_circuit_stat(circ_id, purpose, hs_state);
+            circ.built = true;
+            if purpose.starts_with("HS_CLIENT") || purpose.starts_with("HS_SERVICE") {
+                circ.in_use = true;
+                circ.guard_fp = path.first().cloned();
+            }
+            self.circs.insert(circ_id.to_string(), circ);
+        }
+
         if let Some(circ) = self.circs.get_mut(circ_id) {
             circ.purpose = Some(purpose.to_string());
             circ.hs_state = hs_state.map(|s| s.to_string());
@@ -811,7 +1223,14 @@ impl BandwidthStats {
 
     /// Handles a CIRC_BW event (bandwidth update).
     ///
-    /// Updates circuit bandwidth statistics and checks limits.
+    /// Updates circuit bandwidth statistics. The circuit's throughput is
+    /// the bytes since the last measurement divided by the time since then,
+    /// measured on `arrived_at` so that it does not depend on the wall clock.
+    /// Events less than a second after the last measurement only add their
+    /// bytes to the next one.
+    ///
+    /// Once the circuit's guard is known, the bytes are also added to that
+    /// guard's totals. Bytes from before then are not attributed.
     ///
     /// # Arguments
     ///
@@ -833,7 +1252,7 @@ impl BandwidthStats {
         delivered_written: u64,
         overhead_read: u64,
         overhead_written: u64,
-        _arrived_at: f64,
+        arrived_at: f64,
     ) {
         // Circuit bandwidth means circuits are working
         if self.disconnected_circs {
@@ -848,16 +1267,66 @@ impl BandwidthStats {
             circ.delivered_sent_bytes += delivered_written;
             circ.overhead_read_bytes += overhead_read;
             circ.overhead_sent_bytes += overhead_written;
+
+            match circ.last_bw_at {
+                Some(last) => {
+                    circ.rate_window_bytes += read + written;
+                    let elapsed = arrived_at - last;
+                    if elapsed >= MIN_RATE_INTERVAL_SECS {
+                        circ.bytes_per_sec = circ.rate_window_bytes as f64 / elapsed;
+                        circ.rate_window_bytes = 0;
+                        circ.last_bw_at = Some(arrived_at);
+                    }
+                }
+                // The first event only starts the measurement
+                None => circ.last_bw_at = Some(arrived_at),
+            }
+            circ.last_update = arrived_at;
+
+            if let Some(guard_fp) = &circ.guard_fp {
+                let guard = self
+                    .guards
+                    .entry(guard_fp.clone())
+                    .or_insert_with(|| BwGuardStat::new(guard_fp.clone()));
+                guard.total_read_bytes += read;
+                guard.total_sent_bytes += written;
+            }
         }
     }
 
+    /// Returns guard fingerprints with their total bytes, busiest first.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::BandwidthStats;
+    ///
+    /// let guard = "A".repeat(40);
+    /// let mut stats = BandwidthStats::new();
+    /// stats.circ_event("1", "LAUNCHED", "HS_SERVICE_REND", None, &[], None, 1000.0);
+    /// stats.circ_event("1", "BUILT", "HS_SERVICE_REND", None, &[guard.clone()], None, 1001.0);
+    /// stats.circbw_event("1", 1018, 509, 996, 498, 0, 0, 1002.0);
+    ///
+    /// assert_eq!(stats.guard_bandwidth_ranking(), vec![(guard, 1527)]);
+    /// ```
+    pub fn guard_bandwidth_ranking(&self) -> Vec<(String, u64)> {
+        let mut ranking: Vec<(String, u64)> = self
+            .guards
+            .values()
+            .map(|g| (g.to_guard.clone(), g.total_bytes()))
+            .collect();
+        ranking.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranking
+    }
+
     /// Checks circuit limits and returns circuits that should be closed.
     ///
     /// Checks for:
-    /// - Dropped cells (potential attack)
+    /// - Dropped cells in either direction (potential attack)
     /// - Maximum bytes exceeded
     /// - Maximum HSDIR bytes exceeded
     /// - Maximum service intro bytes exceeded
+    /// - Maximum throughput exceeded
     ///
     /// # Arguments
     ///
@@ -878,9 +1347,15 @@ impl BandwidthStats {
             None => return CircuitLimitResult::Ok,
         };
 
-        // Check dropped cells
-        let dropped = circ.dropped_read_cells();
-        if dropped > circ.dropped_cells_allowed as i64 {
+        // Check dropped cells, read direction first
+        for (dropped, sent) in [
+            (circ.dropped_read_cells(), false),
+            (circ.dropped_sent_cells(), true),
+        ] {
+            if dropped <= circ.dropped_cells_allowed as i64 {
+                continue;
+            }
+
             // Check for Tor bug workarounds
             let tor_bug = self.check_tor_bug_workaround(circ, dropped);
             if let Some(bug_id) = tor_bug {
@@ -891,8 +1366,14 @@ impl BandwidthStats {
             }
 
             if circ.built {
-                return CircuitLimitResult::DroppedCells {
-                    dropped_cells: dropped,
+                return if sent {
+                    CircuitLimitResult::DroppedSentCells {
+                        dropped_cells: dropped,
+                    }
+                } else {
+                    CircuitLimitResult::DroppedCells {
+                        dropped_cells: dropped,
+                    }
                 };
             }
         }
@@ -929,6 +1410,16 @@ impl BandwidthStats {
             };
         }
 
+        // Check throughput
+        if config.circ_max_bytes_per_sec > 0
+            && circ.bytes_per_sec > config.circ_max_bytes_per_sec as f64
+        {
+            return CircuitLimitResult::RateExceeded {
+                bytes_per_sec: circ.bytes_per_sec as u64,
+                limit: config.circ_max_bytes_per_sec,
+            };
+        }
+
         CircuitLimitResult::Ok
     }
 
@@ -997,6 +1488,29 @@ impl BandwidthStats {
             .collect()
     }
 
+    /// Stops tracking circuits with no event for more than `max_idle_secs`.
+    ///
+    /// Entries are normally removed on `FAILED` or `CLOSED`. One whose
+    /// closing event was missed, for example because the control connection
+    /// dropped, would otherwise stay in [`circs`](Self::circs) for good.
+    /// A `max_idle_secs` of 0 or less prunes nothing.
+    ///
+    /// # Returns
+    ///
+    /// How many circuits were removed; also added to
+    /// [`circs_pruned_total`](Self::circs_pruned_total).
+    pub fn prune_stale(&mut self, now: f64, max_idle_secs: f64) -> usize {
+        if max_idle_secs <= 0.0 {
+            return 0;
+        }
+        let before = self.circs.len();
+        self.circs
+            .retain(|_, circ| now - circ.last_update <= max_idle_secs);
+        let pruned = before - self.circs.len();
+        self.circs_pruned_total += pruned as u64;
+        pruned
+    }
+
     /// Checks connectivity status and returns warnings if disconnected.
     ///
     /// # Arguments
@@ -1082,6 +1596,17 @@ impl BandwidthStats {
     }
 }
 
+/// Names of the attacks [`CircuitLimitResult`] can report, as used in
+/// `attack_detected` IPC events and by the IPC `simulate` command.
+pub const ATTACK_KINDS: [&str; 6] = [
+    "dropped_cells",
+    "dropped_sent_cells",
+    "max_bytes",
+    "hsdir_bytes",
+    "serv_intro_bytes",
+    "bytes_per_sec",
+];
+
 /// Result of checking circuit limits.
 #[derive(Debug, Clone, PartialEq)]
 pub enum CircuitLimitResult {
@@ -1092,6 +1617,11 @@ pub enum CircuitLimitResult {
         /// Number of dropped cells.
         dropped_cells: i64,
     },
+    /// Circuit has dropped cells in the written direction.
+    DroppedSentCells {
+        /// Number of dropped cells.
+        dropped_cells: i64,
+    },
     /// Dropped cells due to known Tor bug.
     TorBug {
         /// Tor bug ID.
@@ -1120,6 +1650,171 @@ pub enum CircuitLimitResult {
         /// Configured limit.
         limit: u64,
     },
+    /// Circuit throughput exceeded the configured rate.
+    RateExceeded {
+        /// Throughput between the last two CIRC_BW events.
+        bytes_per_sec: u64,
+        /// Configured limit.
+        limit: u64,
+    },
+}
+
+impl CircuitLimitResult {
+    /// Returns the attack name from [`ATTACK_KINDS`], or `None` for results
+    /// that are not attacks.
+    pub fn attack_kind(&self) -> Option<&'static str> {
+        match self {
+            CircuitLimitResult::Ok | CircuitLimitResult::TorBug { .. } => None,
+            CircuitLimitResult::DroppedCells { .. } => Some("dropped_cells"),
+            CircuitLimitResult::DroppedSentCells { .. } => Some("dropped_sent_cells"),
+            CircuitLimitResult::MaxBytesExceeded { .. } => Some("max_bytes"),
+            CircuitLimitResult::HsdirBytesExceeded { .. } => Some("hsdir_bytes"),
+            CircuitLimitResult::ServIntroBytesExceeded { .. } => Some("serv_intro_bytes"),
+            CircuitLimitResult::RateExceeded { .. } => Some("bytes_per_sec"),
+        }
+    }
+
+    /// Builds a result as [`BandwidthStats::check_circuit_limits`] would for
+    /// the named attack, just over the configured limit.
+    ///
+    /// Used to exercise alerting without real traffic. Returns `None` for
+    /// names not in [`ATTACK_KINDS`].
+    pub fn synthetic(kind: &str, config: &BandguardsConfig) -> Option<Self> {
+        let over = |limit: u64| (limit + 1, limit);
+        match kind {
+            "dropped_cells" => Some(CircuitLimitResult::DroppedCells { dropped_cells: 1 }),
+            "dropped_sent_cells" => Some(CircuitLimitResult::DroppedSentCells { dropped_cells: 1 }),
+            "max_bytes" => {
+                let (bytes, limit) = over(config.circ_max_megabytes * BYTES_PER_MB);
+                Some(CircuitLimitResult::MaxBytesExceeded { bytes, limit })
+            }
+            "hsdir_bytes" => {
+                let (bytes, limit) = over(config.circ_max_hsdesc_kilobytes as u64 * BYTES_PER_KB);
+                Some(CircuitLimitResult::HsdirBytesExceeded { bytes, limit })
+            }
+            "serv_intro_bytes" => {
+                let (bytes, limit) =
+                    over(config.circ_max_serv_intro_kilobytes as u64 * BYTES_PER_KB);
+                Some(CircuitLimitResult::ServIntroBytesExceeded { bytes, limit })
+            }
+            "bytes_per_sec" => {
+                let (bytes_per_sec, limit) = over(config.circ_max_bytes_per_sec);
+                Some(CircuitLimitResult::RateExceeded {
+                    bytes_per_sec,
+                    limit,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How a detected attack ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttackOutcome {
+    /// We closed the circuit.
+    Closed,
+    /// Tor closed the circuit before we did.
+    ClosedByTor,
+}
+
+impl fmt::Display for AttackOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttackOutcome::Closed => write!(f, "closed"),
+            AttackOutcome::ClosedByTor => write!(f, "closed by Tor before action"),
+        }
+    }
+}
+
+/// A detected attack and its outcome.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttackRecord {
+    /// Circuit the attack was detected on.
+    pub circ_id: String,
+    /// Attack name from [`ATTACK_KINDS`].
+    pub kind: &'static str,
+    /// How the detection ended.
+    pub outcome: AttackOutcome,
+    /// Unix timestamp of the outcome.
+    pub at: f64,
+    /// Service the circuit was attributed to, if any.
+    pub service: Option<String>,
+}
+
+/// Detections for one service, from [`BandwidthStats::attack_summary`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServiceAttacks {
+    /// Onion address or SOCKS username; `None` for unattributed circuits.
+    pub service: Option<String>,
+    /// Number of detections.
+    pub detections: usize,
+    /// Detections by attack name, e.g. `dropped_cells`.
+    pub kinds: BTreeMap<String, usize>,
+    /// Unix timestamp of the most recent detection.
+    pub last_at: f64,
+}
+
+/// A guard whose connections keep being killed, from
+/// [`BandwidthStats::check_guard_kills`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuardKillAlert {
+    /// Guard fingerprint.
+    pub fingerprint: String,
+    /// Connections killed with live circuits within the window.
+    pub kills: usize,
+    /// Length of the window, in seconds.
+    pub window_secs: u32,
+}
+
+impl fmt::Display for GuardKillAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Connection to guard {} was killed with live circuits {} times in the last {}s. \
+             Possible guard discovery attack.",
+            self.fingerprint, self.kills, self.window_secs
+        )
+    }
+}
+
+/// Diagnostic copy of [`BandwidthStats`], from [`BandwidthStats::snapshot`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthSnapshot {
+    /// Tracked circuits, ordered by ID.
+    pub circuits: Vec<CircuitSummary>,
+    /// Guards we have connected to, ordered by fingerprint.
+    pub guards: Vec<GuardSummary>,
+    /// Circuits destroyed while carrying traffic.
+    pub circs_destroyed_total: u64,
+}
+
+/// One circuit in a [`BandwidthSnapshot`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CircuitSummary {
+    /// Circuit ID.
+    pub circ_id: String,
+    /// Bytes read plus bytes sent.
+    pub total_bytes: u64,
+    /// See [`BwCircuitStat::dropped_read_cells`].
+    pub dropped_read_cells: i64,
+    /// Seconds since the circuit was launched.
+    pub age_secs: f64,
+    /// Current circuit purpose.
+    pub purpose: Option<String>,
+}
+
+/// One guard in a [`BandwidthSnapshot`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GuardSummary {
+    /// Guard fingerprint.
+    pub fingerprint: String,
+    /// Total connections made to this guard.
+    pub conns_made: u32,
+    /// Connections closed while circuits were live on them.
+    pub killed_conns: u32,
+    /// Connection close reasons and their counts.
+    pub close_reasons: BTreeMap<String, u32>,
 }
 
 /// Connectivity status result.
@@ -1193,6 +1888,31 @@ mod tests {
         assert_eq!(circ.dropped_read_cells(), 2);
     }
 
+    #[test]
+    fn test_dropped_sent_cells() {
+        let mut circ = BwCircuitStat::new("123".to_string(), true);
+
+        // 10 cells sent, 8 delivered
+        circ.sent_bytes = 5090;
+        circ.delivered_sent_bytes = 3984;
+        circ.overhead_sent_bytes = 0;
+
+        assert_eq!(circ.dropped_sent_cells(), 2);
+        assert_eq!(circ.dropped_read_cells(), 0);
+    }
+
+    #[test]
+    fn test_dropped_sent_cells_with_overhead() {
+        let mut circ = BwCircuitStat::new("123".to_string(), true);
+
+        // 10 cells sent, 7 delivered + 1 overhead
+        circ.sent_bytes = 5090;
+        circ.delivered_sent_bytes = 3486;
+        circ.overhead_sent_bytes = 498;
+
+        assert_eq!(circ.dropped_sent_cells(), 2);
+    }
+
     #[test]
     fn test_bw_guard_stat_new() {
         let guard = BwGuardStat::new("A".repeat(40));
@@ -1200,6 +1920,7 @@ mod tests {
         assert_eq!(guard.killed_conns, 0);
         assert_eq!(guard.conns_made, 0);
         assert!(guard.close_reasons.is_empty());
+        assert_eq!(guard.total_bytes(), 0);
     }
 
     #[test]
@@ -1303,36 +2024,156 @@ mod tests {
     }
 
     #[test]
-    fn test_circbw_event() {
-        let mut stats = BandwidthStats::new();
+    fn test_guard_wait_then_built() {
+        let path = vec!["A".repeat(40)];
 
-        stats.circ_event("123", "LAUNCHED", "GENERAL", None, &[], None, 1000.0);
-        stats.circbw_event("123", 1000, 500, 800, 400, 100, 50, 1001.0);
+        for treat_as_built in [true, false] {
+            let mut stats = BandwidthStats::new();
+            stats.treat_guard_wait_as_built = treat_as_built;
 
-        let circ = stats.circs.get("123").unwrap();
-        assert_eq!(circ.read_bytes, 1000);
-        assert_eq!(circ.sent_bytes, 500);
-        assert_eq!(circ.delivered_read_bytes, 800);
-        assert_eq!(circ.delivered_sent_bytes, 400);
-        assert_eq!(circ.overhead_read_bytes, 100);
-        assert_eq!(circ.overhead_sent_bytes, 50);
+            for (status, at) in [("LAUNCHED", 1000.0), ("GUARD_WAIT", 1001.0)] {
+                stats.circ_event(
+                    "123",
+                    status,
+                    "HS_SERVICE_REND",
+                    Some("HSSR_CONNECTING"),
+                    &path,
+                    None,
+                    at,
+                );
+            }
+            let circ = stats.circs.get("123").unwrap();
+            assert_eq!(circ.built, treat_as_built);
+            assert_eq!(circ.in_use, treat_as_built);
+            assert_eq!(circ.guard_fp.is_some(), treat_as_built);
+
+            stats.circ_event(
+                "123",
+                "BUILT",
+                "HS_SERVICE_REND",
+                Some("HSSR_CONNECTING"),
+                &path,
+                None,
+                1002.0,
+            );
+            let circ = stats.circs.get("123").unwrap();
+            assert!(circ.built);
+            assert!(circ.in_use);
+            assert_eq!(circ.guard_fp, Some("A".repeat(40)));
+        }
     }
 
     #[test]
-    fn test_check_circuit_limits_ok() {
+    fn test_monitored_purposes_skip_general() {
         let mut stats = BandwidthStats::new();
-        let config = BandguardsConfig::default();
+        stats.monitored_purposes = vec!["HS_*".to_string()];
+        let path = vec!["A".repeat(40)];
 
-        stats.circ_event("123", "LAUNCHED", "GENERAL", None, &[], None, 1000.0);
+        for status in ["LAUNCHED", "BUILT"] {
+            assert_eq!(
+                stats.circ_event("1", status, "GENERAL", None, &path, None, 1000.0),
+                None
+            );
+        }
+        assert!(!stats.circs.contains_key("1"));
+        stats.circbw_event("1", 5000, 5000, 0, 0, 0, 0, 1001.0);
+        assert!(!stats.circs.contains_key("1"));
 
-        let result = stats.check_circuit_limits("123", &config);
-        assert_eq!(result, CircuitLimitResult::Ok);
+        stats.circ_event(
+            "2",
+            "LAUNCHED",
+            "HS_SERVICE_REND",
+            None,
+            &path,
+            None,
+            1000.0,
+        );
+        assert!(stats.circs.contains_key("2"));
     }
 
     #[test]
-    fn test_check_circuit_limits_max_bytes() {
+    fn test_cannibalized_circuit_becomes_monitored() {
         let mut stats = BandwidthStats::new();
-        let config = BandguardsConfig {
+        stats.monitored_purposes = vec!["HS_SERVICE_REND".to_string()];
+        let path = vec!["A".repeat(40), "B".repeat(40)];
+
+        stats.circ_event("9", "BUILT", "HS_VANGUARDS", None, &path, None, 1000.0);
+        assert!(!stats.circs.contains_key("9"));
+
+        stats.circ_minor_event(
+            "9",
+            "PURPOSE_CHANGED",
+            "HS_SERVICE_REND",
+            Some("HSSR_CONNECTING"),
+            Some("HS_VANGUARDS"),
+            None,
+            &path,
+        );
+        let circ = stats.circs.get("9").expect("now tracked");
+        assert!(circ.is_hs && circ.is_service);
+        assert!(circ.built && circ.in_use);
+        assert_eq!(circ.guard_fp, Some("A".repeat(40)));
+        assert_eq!(circ.old_purpose.as_deref(), Some("HS_VANGUARDS"));
+
+        // A change to another unmonitored purpose still creates nothing
+        stats.circ_minor_event("10", "PURPOSE_CHANGED", "GENERAL", None, None, None, &path);
+        assert!(!stats.circs.contains_key("10"));
+    }
+
+    #[test]
+    fn test_prune_stale_circuits() {
+        let mut stats = BandwidthStats::new();
+        let start = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        for id in ["quiet", "active"] {
+            stats.circ_event(id, "BUILT", "HS_SERVICE_REND", None, &[], None, start);
+        }
+        stats.circbw_event("active", 100, 100, 0, 0, 0, 0, start + 500.0);
+
+        assert_eq!(stats.prune_stale(start + 900.0, 0.0), 0);
+        assert_eq!(stats.prune_stale(start + 900.0, 600.0), 1);
+        assert!(!stats.circs.contains_key("quiet"));
+        assert!(stats.circs.contains_key("active"));
+        assert_eq!(stats.circs_pruned_total, 1);
+
+        assert_eq!(stats.prune_stale(start + 2000.0, 600.0), 1);
+        assert!(stats.circs.is_empty());
+        assert_eq!(stats.circs_pruned_total, 2);
+    }
+
+    #[test]
+    fn test_circbw_event() {
+        let mut stats = BandwidthStats::new();
+
+        stats.circ_event("123", "LAUNCHED", "GENERAL", None, &[], None, 1000.0);
+        stats.circbw_event("123", 1000, 500, 800, 400, 100, 50, 1001.0);
+
+        let circ = stats.circs.get("123").unwrap();
+        assert_eq!(circ.read_bytes, 1000);
+        assert_eq!(circ.sent_bytes, 500);
+        assert_eq!(circ.delivered_read_bytes, 800);
+        assert_eq!(circ.delivered_sent_bytes, 400);
+        assert_eq!(circ.overhead_read_bytes, 100);
+        assert_eq!(circ.overhead_sent_bytes, 50);
+    }
+
+    #[test]
+    fn test_check_circuit_limits_ok() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig::default();
+
+        stats.circ_event("123", "LAUNCHED", "GENERAL", None, &[], None, 1000.0);
+
+        let result = stats.check_circuit_limits("123", &config);
+        assert_eq!(result, CircuitLimitResult::Ok);
+    }
+
+    #[test]
+    fn test_check_circuit_limits_max_bytes() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig {
             circ_max_megabytes: 1, // 1 MB limit
             ..Default::default()
         };
@@ -1443,7 +2284,7 @@ mod tests {
 
         while read + 2 * chunk < limit {
             let delivered = (CELL_DATA_RATE * chunk as f64) as u64;
-            stats.circbw_event(circ_id, chunk, chunk, delivered, 0, 0, 0, 1000.0);
+            stats.circbw_event(circ_id, chunk, chunk, delivered, delivered, 0, 0, 1000.0);
             read += 2 * chunk;
 
             if let CircuitLimitResult::MaxBytesExceeded { .. } =
@@ -1475,9 +2316,9 @@ mod tests {
                 CELL_PAYLOAD_SIZE,
                 CELL_PAYLOAD_SIZE,
                 valid_bytes,
-                0,
                 valid_bytes,
-                0,
+                valid_bytes,
+                valid_bytes,
                 1000.0,
             );
             let result = stats.check_circuit_limits(circ_id, config);
@@ -1506,6 +2347,315 @@ mod tests {
         None
     }
 
+    fn check_dropped_sent_bytes(
+        stats: &mut BandwidthStats,
+        config: &BandguardsConfig,
+        circ_id: &str,
+        delivered_cells: u64,
+        dropped_cells: u64,
+    ) -> Option<CircuitLimitResult> {
+        let valid_bytes = (CELL_DATA_RATE * CELL_PAYLOAD_SIZE as f64 / 2.0) as u64;
+        for _ in 0..delivered_cells {
+            stats.circbw_event(
+                circ_id,
+                0,
+                CELL_PAYLOAD_SIZE,
+                0,
+                valid_bytes,
+                0,
+                valid_bytes,
+                1000.0,
+            );
+            let result = stats.check_circuit_limits(circ_id, config);
+            if !matches!(result, CircuitLimitResult::Ok) {
+                return Some(result);
+            }
+        }
+
+        for _ in 0..dropped_cells {
+            stats.circbw_event(circ_id, 0, CELL_PAYLOAD_SIZE, 0, 0, 0, 0, 1000.0);
+            let result = stats.check_circuit_limits(circ_id, config);
+            if !matches!(result, CircuitLimitResult::Ok) {
+                return Some(result);
+            }
+        }
+
+        None
+    }
+
+    #[test]
+    fn test_hsdir_rate_alert() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig {
+            max_hsdir_rate: 5,
+            ..BandguardsConfig::default()
+        };
+
+        for i in 0..5 {
+            let id = i.to_string();
+            stats.circ_event(
+                &id,
+                "LAUNCHED",
+                "HS_SERVICE_HSDIR",
+                None,
+                &[],
+                None,
+                1000.0 + i as f64,
+            );
+            assert_eq!(stats.check_hsdir_rate(&config, 1000.0 + i as f64), None);
+        }
+
+        // A general circuit does not count towards the rate
+        stats.circ_event("g", "LAUNCHED", "GENERAL", None, &[], None, 1005.0);
+        assert_eq!(stats.check_hsdir_rate(&config, 1005.0), None);
+
+        stats.circ_event("5", "LAUNCHED", "HS_CLIENT_HSDIR", None, &[], None, 1006.0);
+        assert_eq!(stats.check_hsdir_rate(&config, 1006.0), Some(6));
+
+        // Only reported once per burst
+        stats.circ_event("6", "LAUNCHED", "HS_CLIENT_HSDIR", None, &[], None, 1007.0);
+        assert_eq!(stats.check_hsdir_rate(&config, 1007.0), None);
+
+        // Launches age out of the window and the alert re-arms
+        assert_eq!(stats.check_hsdir_rate(&config, 1100.0), None);
+        assert!(stats.hsdir_launches.is_empty());
+        assert!(!stats.hsdir_rate_alerted);
+    }
+
+    #[test]
+    fn test_flagged_circuit_closed_by_tor_is_recorded() {
+        let mut stats = BandwidthStats::new();
+        for id in ["1", "2", "3"] {
+            stats.circ_event(id, "BUILT", "HS_SERVICE_REND", None, &[], None, 1000.0);
+        }
+
+        assert!(stats.flag_circuit("1", "dropped_cells"));
+        assert!(!stats.flag_circuit("1", "dropped_cells"));
+        assert!(stats.flag_circuit("2", "max_bytes"));
+        assert!(!stats.flag_circuit("unknown", "max_bytes"));
+        stats.record_attack("2", AttackOutcome::Closed, 1001.0);
+        stats.circ_event("2", "CLOSED", "HS_SERVICE_REND", None, &[], None, 1002.0);
+        stats.circ_event("1", "CLOSED", "HS_SERVICE_REND", None, &[], None, 1003.0);
+        stats.circ_event("3", "CLOSED", "HS_SERVICE_REND", None, &[], None, 1004.0);
+
+        let records: Vec<_> = stats.attack_records.iter().cloned().collect();
+        assert_eq!(
+            records,
+            vec![
+                AttackRecord {
+                    circ_id: "2".to_string(),
+                    kind: "max_bytes",
+                    outcome: AttackOutcome::Closed,
+                    at: 1001.0,
+                    service: None,
+                },
+                AttackRecord {
+                    circ_id: "1".to_string(),
+                    kind: "dropped_cells",
+                    outcome: AttackOutcome::ClosedByTor,
+                    at: 1003.0,
+                    service: None,
+                },
+            ]
+        );
+        assert_eq!(
+            AttackOutcome::ClosedByTor.to_string(),
+            "closed by Tor before action"
+        );
+    }
+
+    #[test]
+    fn test_limit_sweep_sampling_keeps_bytes_exact() {
+        let config = BandguardsConfig {
+            limit_check_interval_ms: 500,
+            ..BandguardsConfig::default()
+        };
+        let mut stats = BandwidthStats::new();
+        stats.circ_event("1", "LAUNCHED", "HS_SERVICE_REND", None, &[], None, 1000.0);
+
+        // 16 events, one every 125 ms
+        let mut sweeps = Vec::new();
+        for i in 0..16u64 {
+            let now = 1000.0 + i as f64 * 0.125;
+            stats.circbw_event("1", 509 * (i + 1), 509, 498 * i, 0, 0, 0, now);
+            if stats.limit_sweep_due(&config, now) {
+                sweeps.push(now);
+            }
+        }
+        assert_eq!(sweeps, vec![1000.0, 1000.5, 1001.0, 1001.5]);
+
+        let circ = &stats.circs["1"];
+        assert_eq!(circ.read_bytes, 509 * (1..=16).sum::<u64>());
+        assert_eq!(circ.sent_bytes, 509 * 16);
+        assert_eq!(circ.delivered_read_bytes, 498 * (0..16).sum::<u64>());
+
+        let every_event = BandguardsConfig::default();
+        assert!((0..5).all(|_| stats.limit_sweep_due(&every_event, 1002.0)));
+    }
+
+    #[test]
+    fn test_attack_summary_groups_by_service() {
+        let mut stats = BandwidthStats::new();
+        let first = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        let second = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+        for id in ["1", "2", "3", "4"] {
+            stats.circ_event(id, "LAUNCHED", "HS_SERVICE_REND", None, &[], None, 1000.0);
+        }
+        stats.tag_service("1", first);
+        stats.tag_service("2", second);
+        stats.tag_service("3", first);
+        // A later attribution does not move the circuit to another service
+        stats.tag_service("3", second);
+
+        stats.flag_circuit("1", "dropped_cells");
+        stats.flag_circuit("2", "max_bytes");
+        stats.flag_circuit("3", "max_bytes");
+        stats.flag_circuit("4", "dropped_cells");
+        stats.record_attack("1", AttackOutcome::Closed, 1001.0);
+        stats.record_attack("2", AttackOutcome::Closed, 1002.0);
+        stats.circ_event("3", "CLOSED", "HS_SERVICE_REND", None, &[], None, 1003.0);
+        stats.record_attack("4", AttackOutcome::Closed, 1004.0);
+
+        let summary = stats.attack_summary();
+        assert_eq!(summary.len(), 3);
+        assert_eq!(summary[0].service, None);
+        assert_eq!(summary[0].detections, 1);
+
+        assert_eq!(summary[1].service.as_deref(), Some(first));
+        assert_eq!(summary[1].detections, 2);
+        assert_eq!(summary[1].kinds.get("dropped_cells"), Some(&1));
+        assert_eq!(summary[1].kinds.get("max_bytes"), Some(&1));
+        assert_eq!(summary[1].last_at, 1003.0);
+
+        assert_eq!(summary[2].service.as_deref(), Some(second));
+        assert_eq!(summary[2].detections, 1);
+        assert_eq!(summary[2].kinds.get("max_bytes"), Some(&1));
+
+        // Only circuits we closed count towards the closed totals
+        assert_eq!(stats.closed_by_kind.get("dropped_cells"), Some(&2));
+        assert_eq!(stats.closed_by_kind.get("max_bytes"), Some(&1));
+    }
+
+    #[test]
+    fn test_check_guard_kills() {
+        let config = BandguardsConfig {
+            max_guard_conn_kills: 2,
+            guard_conn_kill_window_secs: 600,
+            ..Default::default()
+        };
+        let guard = "A".repeat(40);
+        let path = vec![guard.clone()];
+        let mut stats = BandwidthStats::new();
+
+        let kill = |stats: &mut BandwidthStats, n: u32, at: f64| {
+            let conn_id = format!("conn{}", n);
+            let circ_id = n.to_string();
+            stats.orconn_event(&conn_id, &guard, "CONNECTED", None, at);
+            stats.circ_event(
+                &circ_id,
+                "LAUNCHED",
+                "HS_SERVICE_REND",
+                None,
+                &path,
+                None,
+                at,
+            );
+            stats.circ_event(&circ_id, "BUILT", "HS_SERVICE_REND", None, &path, None, at);
+            stats.orconn_event(&conn_id, &guard, "CLOSED", Some("DONE"), at + 1.0);
+            let destroyed = stats.circ_event(
+                &circ_id,
+                "CLOSED",
+                "HS_SERVICE_REND",
+                None,
+                &path,
+                Some("CHANNEL_CLOSED"),
+                at + 1.5,
+            );
+            assert_eq!(destroyed, Some(true));
+        };
+
+        kill(&mut stats, 1, 1000.0);
+        kill(&mut stats, 2, 1100.0);
+        assert!(stats.check_guard_kills(&config, 1200.0).is_empty());
+
+        kill(&mut stats, 3, 1200.0);
+        let alerts = stats.check_guard_kills(&config, 1202.0);
+        assert_eq!(
+            alerts,
+            vec![GuardKillAlert {
+                fingerprint: guard.clone(),
+                kills: 3,
+                window_secs: 600,
+            }]
+        );
+        assert!(alerts[0].to_string().contains("3 times in the last 600s"));
+
+        // The first kill ages out of the window
+        assert!(stats.check_guard_kills(&config, 1700.0).is_empty());
+
+        let disabled = BandguardsConfig {
+            max_guard_conn_kills: 0,
+            ..config
+        };
+        assert!(stats.check_guard_kills(&disabled, 1202.0).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut stats = BandwidthStats::new();
+        let guard = "A".repeat(40);
+        let path = vec![guard.clone()];
+        stats.circ_event(
+            "10",
+            "LAUNCHED",
+            "HS_SERVICE_REND",
+            None,
+            &path,
+            None,
+            1000.0,
+        );
+        stats.circ_event("9", "LAUNCHED", "GENERAL", None, &[], None, 1000.0);
+        stats.circs.get_mut("10").unwrap().read_bytes = 5 * CELL_PAYLOAD_SIZE;
+        let mut guard_stat = BwGuardStat::new(guard.clone());
+        guard_stat.conns_made = 3;
+        guard_stat.killed_conns = 1;
+        guard_stat.close_reasons.insert("DONE".to_string(), 2);
+        stats.guards.insert(guard.clone(), guard_stat);
+
+        let snapshot = stats.snapshot();
+        let ids: Vec<&str> = snapshot
+            .circuits
+            .iter()
+            .map(|c| c.circ_id.as_str())
+            .collect();
+        assert_eq!(ids, ["9", "10"]);
+        assert_eq!(snapshot.circuits[1].total_bytes, 5 * CELL_PAYLOAD_SIZE);
+        assert_eq!(snapshot.circuits[1].dropped_read_cells, 5);
+        assert_eq!(
+            snapshot.circuits[1].purpose.as_deref(),
+            Some("HS_SERVICE_REND")
+        );
+        assert_eq!(snapshot.guards[0].fingerprint, guard);
+        assert_eq!(snapshot.guards[0].close_reasons.get("DONE"), Some(&2));
+
+        // Ages are wall-clock floats, so they are compared with a tolerance
+        // and the rest of the snapshot exactly
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let mut parsed: BandwidthSnapshot = serde_json::from_str(&json).unwrap();
+        let mut expected = snapshot.clone();
+        assert_eq!(parsed.circuits.len(), expected.circuits.len());
+        for (p, e) in parsed.circuits.iter_mut().zip(&mut expected.circuits) {
+            assert!((p.age_secs - e.age_secs).abs() < 1e-6);
+            p.age_secs = 0.0;
+            e.age_secs = 0.0;
+        }
+        assert_eq!(parsed, expected);
+
+        // Taking a snapshot leaves the tracker untouched
+        assert_eq!(stats.circs.len(), 2);
+        assert_eq!(stats.guards[&guard].conns_made, 3);
+    }
+
     #[test]
     fn test_circuit_built_failed_closed_removed_from_map() {
         let mut stats = BandwidthStats::new();
@@ -1773,6 +2923,251 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_regular_writing_ok() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig::default();
+
+        stats.circ_event("25", "LAUNCHED", "HS_VANGUARDS", None, &[], None, 1000.0);
+        stats.circ_event("25", "BUILT", "HS_VANGUARDS", None, &[], None, 1001.0);
+
+        let result = check_dropped_sent_bytes(&mut stats, &config, "25", 100, 0);
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_dropped_sent_cells_before_app_data() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig::default();
+
+        stats.circ_event("26", "LAUNCHED", "HS_VANGUARDS", None, &[], None, 1000.0);
+        stats.circ_event("26", "BUILT", "HS_VANGUARDS", None, &[], None, 1001.0);
+
+        let result = check_dropped_sent_bytes(&mut stats, &config, "26", 0, 1);
+        assert_eq!(
+            result,
+            Some(CircuitLimitResult::DroppedSentCells { dropped_cells: 1 })
+        );
+    }
+
+    #[test]
+    fn test_dropped_sent_cells_after_app_data() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig::default();
+
+        stats.circ_event("27", "LAUNCHED", "HS_VANGUARDS", None, &[], None, 1000.0);
+        stats.circ_event("27", "BUILT", "HS_VANGUARDS", None, &[], None, 1001.0);
+
+        let result = check_dropped_sent_bytes(&mut stats, &config, "27", 1000, 1);
+        assert!(matches!(
+            result,
+            Some(CircuitLimitResult::DroppedSentCells { .. })
+        ));
+    }
+
+    #[test]
+    fn test_dropped_sent_cells_allowed_on_not_built_circ() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig::default();
+
+        stats.circ_event("28", "LAUNCHED", "HS_VANGUARDS", None, &[], None, 1000.0);
+        stats.circ_event("28", "EXTENDED", "HS_VANGUARDS", None, &[], None, 1001.0);
+
+        let result = check_dropped_sent_bytes(&mut stats, &config, "28", 0, 1);
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_dropped_read_cells_reported_before_sent() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig::default();
+
+        stats.circ_event("29", "LAUNCHED", "GENERAL", None, &[], None, 1000.0);
+        stats.circ_event("29", "BUILT", "GENERAL", None, &[], None, 1001.0);
+        stats.circbw_event(
+            "29",
+            CELL_PAYLOAD_SIZE,
+            CELL_PAYLOAD_SIZE,
+            0,
+            0,
+            0,
+            0,
+            1002.0,
+        );
+
+        assert!(matches!(
+            stats.check_circuit_limits("29", &config),
+            CircuitLimitResult::DroppedCells { .. }
+        ));
+    }
+
+    #[test]
+    fn test_rate_exceeded() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig {
+            circ_max_bytes_per_sec: 100 * 1024,
+            ..BandguardsConfig::default()
+        };
+
+        stats.circ_event("30", "LAUNCHED", "HS_VANGUARDS", None, &[], None, 1000.0);
+        stats.circ_event("30", "BUILT", "HS_VANGUARDS", None, &[], None, 1001.0);
+
+        // The first event only sets the baseline, however large it is
+        let bytes = 200 * CELL_PAYLOAD_SIZE;
+        let delivered = 200 * RELAY_PAYLOAD_SIZE;
+        stats.circbw_event("30", bytes, 0, delivered, 0, 0, 0, 1002.0);
+        assert_eq!(
+            stats.check_circuit_limits("30", &config),
+            CircuitLimitResult::Ok
+        );
+
+        // 101800 bytes one second later is under 100 KiB/s
+        stats.circbw_event("30", bytes, 0, delivered, 0, 0, 0, 1003.0);
+        assert_eq!(
+            stats.check_circuit_limits("30", &config),
+            CircuitLimitResult::Ok
+        );
+
+        // 203600 bytes one second later is over
+        stats.circbw_event("30", 2 * bytes, 0, 2 * delivered, 0, 0, 0, 1004.0);
+        assert_eq!(
+            stats.check_circuit_limits("30", &config),
+            CircuitLimitResult::RateExceeded {
+                bytes_per_sec: 2 * bytes,
+                limit: 100 * 1024,
+            }
+        );
+
+        // Disabled by default
+        assert_eq!(
+            stats.check_circuit_limits("30", &BandguardsConfig::default()),
+            CircuitLimitResult::Ok
+        );
+    }
+
+    #[test]
+    fn test_rate_uses_event_timestamps() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig {
+            circ_max_bytes_per_sec: 1000,
+            ..BandguardsConfig::default()
+        };
+
+        stats.circ_event("31", "LAUNCHED", "GENERAL", None, &[], None, 1000.0);
+        stats.circ_event("31", "BUILT", "GENERAL", None, &[], None, 1001.0);
+
+        // 3 cells spread over 2 seconds is under the limit; over 1 it is not
+        let delivered = 3 * RELAY_PAYLOAD_SIZE;
+        stats.circbw_event("31", 0, 0, 0, 0, 0, 0, 1002.0);
+        stats.circbw_event("31", 3 * CELL_PAYLOAD_SIZE, 0, delivered, 0, 0, 0, 1004.0);
+        assert_eq!(stats.circs["31"].bytes_per_sec, 763.5);
+        assert_eq!(
+            stats.check_circuit_limits("31", &config),
+            CircuitLimitResult::Ok
+        );
+
+        stats.circbw_event("31", 3 * CELL_PAYLOAD_SIZE, 0, delivered, 0, 0, 0, 1005.0);
+        assert!(matches!(
+            stats.check_circuit_limits("31", &config),
+            CircuitLimitResult::RateExceeded {
+                bytes_per_sec: 1527,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_rate_ignores_bunched_events() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig {
+            circ_max_bytes_per_sec: 1000,
+            ..BandguardsConfig::default()
+        };
+
+        stats.circ_event("32", "LAUNCHED", "GENERAL", None, &[], None, 1000.0);
+        stats.circ_event("32", "BUILT", "GENERAL", None, &[], None, 1001.0);
+        stats.circbw_event("32", 0, 0, 0, 0, 0, 0, 1002.0);
+
+        // Two events a millisecond apart are not a 509 kB/s burst
+        let delivered = RELAY_PAYLOAD_SIZE;
+        stats.circbw_event("32", CELL_PAYLOAD_SIZE, 0, delivered, 0, 0, 0, 1002.500);
+        stats.circbw_event("32", CELL_PAYLOAD_SIZE, 0, delivered, 0, 0, 0, 1002.501);
+        assert_eq!(stats.circs["32"].bytes_per_sec, 0.0);
+        assert_eq!(
+            stats.check_circuit_limits("32", &config),
+            CircuitLimitResult::Ok
+        );
+
+        // Their bytes count towards the next full second
+        stats.circbw_event("32", 0, 0, 0, 0, 0, 0, 1004.0);
+        assert_eq!(stats.circs["32"].bytes_per_sec, 509.0);
+    }
+
+    #[test]
+    fn test_guard_bandwidth_ranking() {
+        let mut stats = BandwidthStats::new();
+        let light = "A".repeat(40);
+        let heavy = "B".repeat(40);
+        let path = |guard: &str| vec![guard.to_string(), "C".repeat(40), "D".repeat(40)];
+
+        stats.circ_event("1", "LAUNCHED", "HS_SERVICE_REND", None, &[], None, 1000.0);
+        stats.circ_event(
+            "1",
+            "BUILT",
+            "HS_SERVICE_REND",
+            None,
+            &path(&light),
+            None,
+            1001.0,
+        );
+        stats.circbw_event("1", 1000, 500, 0, 0, 0, 0, 1002.0);
+
+        stats.circ_event("2", "LAUNCHED", "HS_SERVICE_REND", None, &[], None, 1000.0);
+        stats.circ_event(
+            "2",
+            "BUILT",
+            "HS_SERVICE_REND",
+            None,
+            &path(&heavy),
+            None,
+            1001.0,
+        );
+        stats.circbw_event("2", 4000, 1000, 0, 0, 0, 0, 1002.0);
+
+        // A vanguard circuit's guard is only known once it is repurposed
+        stats.circ_event("3", "LAUNCHED", "HS_VANGUARDS", None, &[], None, 1000.0);
+        stats.circ_event(
+            "3",
+            "BUILT",
+            "HS_VANGUARDS",
+            None,
+            &path(&light),
+            None,
+            1001.0,
+        );
+        stats.circbw_event("3", 9000, 9000, 0, 0, 0, 0, 1002.0);
+        assert_eq!(stats.guards[&light].total_bytes(), 1500);
+        stats.circ_minor_event(
+            "3",
+            "PURPOSE_CHANGED",
+            "HS_SERVICE_REND",
+            None,
+            Some("HS_VANGUARDS"),
+            None,
+            &path(&light),
+        );
+        stats.circbw_event("3", 2000, 0, 0, 0, 0, 0, 1003.0);
+
+        assert_eq!(stats.guards[&light].total_read_bytes, 3000);
+        assert_eq!(stats.guards[&light].total_sent_bytes, 500);
+        assert_eq!(stats.guards[&heavy].total_read_bytes, 4000);
+        assert_eq!(stats.guards[&heavy].total_sent_bytes, 1000);
+        assert_eq!(
+            stats.guard_bandwidth_ranking(),
+            vec![(heavy, 5000), (light, 3500)]
+        );
+    }
+
     #[test]
     fn test_orconn_connected() {
         let mut stats = BandwidthStats::new();
@@ -2016,6 +3411,42 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_tor_bug_workaround_sent_direction() {
+        let mut stats = BandwidthStats::new();
+        let config = BandguardsConfig::default();
+
+        stats.circ_event(
+            "44",
+            "LAUNCHED",
+            "HS_SERVICE_INTRO",
+            Some("HSSI_ESTABLISHED"),
+            &[],
+            None,
+            1000.0,
+        );
+        stats.circ_event(
+            "44",
+            "BUILT",
+            "HS_SERVICE_INTRO",
+            Some("HSSI_ESTABLISHED"),
+            &[],
+            None,
+            1001.0,
+        );
+
+        stats.circbw_event("44", 0, CELL_PAYLOAD_SIZE, 0, 0, 0, 0, 1002.0);
+
+        let result = stats.check_circuit_limits("44", &config);
+        assert_eq!(
+            result,
+            CircuitLimitResult::TorBug {
+                bug_id: "#29699",
+                dropped_cells: 1,
+            }
+        );
+    }
+
     #[test]
     fn test_tor_bug_29927_workaround() {
         let mut stats = BandwidthStats::new();
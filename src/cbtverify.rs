@@ -22,6 +22,11 @@
 //! Elevated HS timeout rates compared to overall rates may indicate
 //! targeted attacks against hidden services.
 //!
+//! Tor also reports its own timeout rate with each `BUILDTIMEOUT_SET` event.
+//! [`TimeoutStats::is_anomalous`] flags that rate once it passes
+//! `cbt_max_timeout_rate`, since an adversary who can fail our circuits can
+//! push us through many guard candidates.
+//!
 //! # What This Module Does NOT Do
 //!
 //! - **Circuit building**: This module only monitors, not builds circuits
@@ -34,9 +39,11 @@
 //! - [`crate::bandguards`] - Related bandwidth monitoring
 //! - [Python vanguards cbtverify](https://github.com/mikeperry-tor/vanguards)
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
 
-use crate::config::LogLevel;
+use crate::config::{Config, LogLevel};
 use crate::logger::plog;
 
 /// Per-circuit tracking for timeout statistics.
@@ -151,6 +158,10 @@ pub struct TimeoutStats {
     pub hs_timeout: u64,
     /// Whether to record timeouts (false after RESET, true after COMPUTED).
     pub record_timeouts: bool,
+    /// Timeout rate Tor reported with its most recent CBT event.
+    pub last_timeout_rate: Option<f64>,
+    /// Last timeout rate Tor reported for each CBT event type.
+    pub set_type_rates: BTreeMap<String, f64>,
 }
 
 impl Default for TimeoutStats {
@@ -171,6 +182,8 @@ impl TimeoutStats {
             hs_built: 0,
             hs_timeout: 0,
             record_timeouts: true,
+            last_timeout_rate: None,
+            set_type_rates: BTreeMap::new(),
         }
     }
 
@@ -255,6 +268,8 @@ impl TimeoutStats {
     /// * `timeout_rate` - Tor's reported timeout rate (if available)
     pub fn cbt_event(&mut self, set_type: &str, timeout_rate: Option<f64>) {
         if let Some(rate) = timeout_rate {
+            self.last_timeout_rate = Some(rate);
+            self.set_type_rates.insert(set_type.to_string(), rate);
             plog(
                 LogLevel::Info,
                 &format!(
@@ -353,10 +368,101 @@ impl TimeoutStats {
         }
     }
 
+    /// Checks Tor's last reported timeout rate against `cbt_max_timeout_rate`.
+    ///
+    /// # Returns
+    ///
+    /// The reported rate if it exceeds the threshold, or `None` if it does
+    /// not or Tor has not reported one yet.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::cbtverify::TimeoutStats;
+    /// use vanguards_rs::config::Config;
+    ///
+    /// let config = Config::default();
+    /// let mut stats = TimeoutStats::new();
+    /// stats.cbt_event("COMPUTED", Some(0.2));
+    /// assert_eq!(stats.is_anomalous(&config), None);
+    /// stats.cbt_event("COMPUTED", Some(0.8));
+    /// assert_eq!(stats.is_anomalous(&config), Some(0.8));
+    /// ```
+    pub fn is_anomalous(&self, config: &Config) -> Option<f64> {
+        self.last_timeout_rate
+            .filter(|&rate| rate > config.cbt_max_timeout_rate)
+    }
+
+    /// Describes how an anomalous timeout `rate` compares with what we have
+    /// measured since the last reset.
+    pub fn anomaly_message(&self, rate: f64, config: &Config) -> String {
+        let average = self.timeout_rate_all();
+        let comparison = if self.all_launched == 0 {
+            "no measured average yet".to_string()
+        } else if rate > average {
+            format!(
+                "{:.2}x our measured average of {:.4}",
+                rate / average.max(f64::EPSILON),
+                average
+            )
+        } else {
+            format!("not above our measured average of {:.4}", average)
+        };
+        format!(
+            "Circuit build timeout rate {:.4} exceeds cbt_max_timeout_rate {:.4} ({}; \
+             hidden service timeout rate {:.4}). Possible guard discovery attempt.",
+            rate,
+            config.cbt_max_timeout_rate,
+            comparison,
+            self.timeout_rate_hs()
+        )
+    }
+
     /// Returns the number of circuits currently being tracked.
     pub fn pending_count(&self) -> usize {
         self.circuits.len()
     }
+
+    /// Returns a serializable summary of the verification results.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::cbtverify::TimeoutStats;
+    ///
+    /// let mut stats = TimeoutStats::new();
+    /// stats.add_circuit("1", true);
+    /// stats.cbt_event("COMPUTED", Some(0.2));
+    ///
+    /// let report = stats.report();
+    /// assert_eq!(report.tracked_circuits, 1);
+    /// let json = serde_json::to_string(&report).unwrap();
+    /// assert!(json.contains("\"COMPUTED\":0.2"));
+    /// ```
+    pub fn report(&self) -> TimeoutReport {
+        TimeoutReport {
+            timeout_rate: self.last_timeout_rate,
+            measured_timeout_rate: self.timeout_rate_all(),
+            hs_timeout_rate: self.timeout_rate_hs(),
+            tracked_circuits: self.pending_count(),
+            set_type_rates: self.set_type_rates.clone(),
+        }
+    }
+}
+
+/// Summary of [`TimeoutStats`], from [`TimeoutStats::report`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TimeoutReport {
+    /// Timeout rate Tor reported most recently.
+    pub timeout_rate: Option<f64>,
+    /// Our measured timeout rate over all circuits since the last reset.
+    pub measured_timeout_rate: f64,
+    /// Our measured timeout rate over hidden service circuits.
+    pub hs_timeout_rate: f64,
+    /// Circuits launched but not yet built or timed out.
+    pub tracked_circuits: usize,
+    /// Last reported timeout rate by CBT event type, e.g. `COMPUTED`.
+    pub set_type_rates: BTreeMap<String, f64>,
 }
 
 #[cfg(test)]
@@ -518,6 +624,75 @@ mod tests {
         assert!(stats.record_timeouts);
     }
 
+    #[test]
+    fn test_is_anomalous_normal_rate() {
+        let config = Config::default();
+        let mut stats = TimeoutStats::new();
+        assert_eq!(stats.is_anomalous(&config), None);
+
+        stats.cbt_event("COMPUTED", Some(0.2));
+        assert_eq!(stats.is_anomalous(&config), None);
+
+        // An event without a rate keeps the last one
+        stats.cbt_event("COMPUTED", None);
+        assert_eq!(stats.last_timeout_rate, Some(0.2));
+    }
+
+    #[test]
+    fn test_is_anomalous_elevated_rate() {
+        let config = Config::default();
+        let mut stats = TimeoutStats::new();
+        for i in 0..10 {
+            let id = i.to_string();
+            stats.add_circuit(&id, false);
+            if i < 2 {
+                stats.timeout_circuit(&id);
+            } else {
+                stats.built_circuit(&id);
+            }
+        }
+
+        stats.cbt_event("COMPUTED", Some(0.8));
+        assert_eq!(stats.is_anomalous(&config), Some(0.8));
+        let msg = stats.anomaly_message(0.8, &config);
+        assert!(
+            msg.contains("4.00x our measured average of 0.2000"),
+            "{}",
+            msg
+        );
+
+        let lenient = Config {
+            cbt_max_timeout_rate: 0.9,
+            ..Config::default()
+        };
+        assert_eq!(stats.is_anomalous(&lenient), None);
+    }
+
+    #[test]
+    fn test_report_round_trip() {
+        let mut stats = TimeoutStats::new();
+        for i in 0..4 {
+            stats.add_circuit(&i.to_string(), i % 2 == 0);
+        }
+        stats.timeout_circuit("0");
+        stats.built_circuit("1");
+        stats.cbt_event("COMPUTED", Some(0.25));
+        stats.cbt_event("RESET", Some(0.0));
+
+        let report = stats.report();
+        assert_eq!(report.timeout_rate, Some(0.0));
+        assert_eq!(report.tracked_circuits, 2);
+        assert_eq!(report.set_type_rates.get("COMPUTED"), Some(&0.25));
+        assert_eq!(report.set_type_rates.get("RESET"), Some(&0.0));
+
+        let json = serde_json::to_string(&report).unwrap();
+        let parsed: TimeoutReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, report);
+
+        // Reporting leaves the tracker untouched
+        assert_eq!(stats.pending_count(), 2);
+    }
+
     #[test]
     fn test_circ_event_launched() {
         let mut stats = TimeoutStats::new();
@@ -77,15 +77,24 @@
 //! control_ip = "127.0.0.1"
 //! control_port = 9051
 //! # control_socket = "/run/tor/control"  # Alternative: Unix socket
+//! # control_socket = "abstract:tor-control"  # Linux abstract socket
+//! # control_socket = ["/run/tor/control", "/var/run/tor/control"]  # Tried in order
 //! # control_pass = "my_password"         # If using password auth
+//! # control_pass_file = "/etc/vanguards/control_pass"  # Or read it from a mode 0600 file
 //!
 //! # File paths
 //! state_file = "vanguards.state"
+//! state_format = "pickle"  # pickle (Python vanguards compatible) or json
+//! state_backups = 0  # Previous state files kept as .1, .2, ...; 0 = none
 //!
 //! # Logging
 //! loglevel = "notice"  # debug, info, notice, warn, error
 //! # logfile = "/var/log/vanguards.log"  # Optional: log to file
 //! # logfile = ":syslog:"                 # Optional: log to syslog
+//! log_format = "text"  # text or json (one object per line)
+//! logfile_max_bytes = 0  # Rotate the log file past this size, 0 = never
+//! logfile_max_files = 5  # Rotated logs kept as .1, .2, ...
+//! anonymize_fingerprints_in_logs = false  # true = log ~<keyed hash> instead of full fingerprints
 //!
 //! # Component toggles
 //! enable_vanguards = true
@@ -93,29 +102,62 @@
 //! enable_rendguard = true
 //! enable_logguard = true
 //! enable_cbtverify = false
+//! cbt_max_timeout_rate = 0.5  # warn when Tor's CBT timeout rate goes above this
 //! enable_pathverify = false
+//! pathverify_close_on = []  # close HS circuits on: bad_length, wrong_layer, unexpected_guard
 //!
 //! # Operational settings
 //! close_circuits = true
 //! one_shot_vanguards = false
 //! # retry_limit = 10  # Optional: limit reconnection attempts
+//! reconnect_max_backoff_secs = 60  # Reconnect delay doubles from 1s up to this
+//! no_interactive = false  # true = never prompt for a control password
+//! # ipc_socket = "/run/vanguards/ipc.sock"  # Optional: local management socket
+//! # metrics_listen = "127.0.0.1:9099"  # Optional: Prometheus /metrics endpoint
+//! on_no_guards = "wait_and_retry"  # fail, warn_and_continue, wait_and_retry
+//! siem_format = "none"             # none, cef, leef
+//! # siem_output = "/var/log/vanguards/siem.log"  # Required with cef or leef
 //!
 //! [vanguards]
 //! num_layer1_guards = 2   # 0 = use Tor default
 //! num_layer2_guards = 4
 //! num_layer3_guards = 8
+//! enable_layer2 = true
+//! enable_layer3 = true   # false = layer2-only vanguards
 //! min_layer2_lifetime_hours = 24
-//! max_layer2_lifetime_hours = 1080  # 45 days
+//! max_layer2_lifetime_hours = 1080  # or "45d"; lifetimes take d, h, m or s suffixes
 //! min_layer3_lifetime_hours = 1
 //! max_layer3_lifetime_hours = 48
+//! rotation_cooldown_hours = 24
+//! avoid_badexit = true
+//! min_guard_age_hours = 0          # 0 = disabled
+//! lifetime_jitter_fraction = 0.0  # e.g. 0.1 = ±10%
+//! subnet_diversity = false
+//! enforce_guard_diversity = true   # no two members of one family per layer
+//! min_layer_bw_fraction = 0.0      # e.g. 0.001 = layer2 carries 0.1% of eligible weight
+//! revalidate_interval_secs = 300   # 0 = only on new consensus
+//! max_consensus_age_hours = 0      # Pick no new guards from an older consensus; 0 = off
+//! strict_layers = false            # Also set StrictNodes 1 (Tor-wide) so no circuit builds around ExcludeNodes
+//! # asn_map_file = "/etc/vanguards/asn.map"  # "FINGERPRINT ASN" lines; keep guards in distinct ASes
+//! min_weight_percentile = 0.0      # e.g. 0.5 = skip relays in the bottom half by weight
+//! exclude_min_ipv4_prefix = 8      # Ignore broader ExcludeNodes networks, such as 0.0.0.0/0
+//! exclude_min_ipv6_prefix = 16
 //!
 //! [bandguards]
 //! circ_max_megabytes = 0           # 0 = disabled
 //! circ_max_age_hours = 24
 //! circ_max_hsdesc_kilobytes = 30
 //! circ_max_serv_intro_kilobytes = 0
+//! circ_max_bytes_per_sec = 0       # 0 = disabled
 //! circ_max_disconnected_secs = 30
+//! circ_max_idle_secs = 86400       # Forget circuits with no events this long, 0 = never
 //! conn_max_disconnected_secs = 15
+//! max_hsdir_rate = 30              # HSDIR circuits per minute, 0 = disabled
+//! max_guard_conn_kills = 5         # Killed guard connections per window, 0 = disabled
+//! guard_conn_kill_window_secs = 3600
+//! limit_check_interval_ms = 0      # Throttle limit checks on busy services, 0 = every event
+//! treat_guard_wait_as_built = true # Count GUARD_WAIT circuits as built
+//! monitored_purposes = []          # e.g. ["HS_*"] to ignore GENERAL circuits; [] = all
 //!
 //! [rendguard]
 //! use_global_start_count = 1000
@@ -124,11 +166,32 @@
 //! use_max_use_to_bw_ratio = 5.0
 //! use_max_consensus_weight_churn = 1.0
 //! close_circuits_on_overuse = true
+//! max_not_in_consensus_entries = 1000
+//! use_window_hours = 0             # 0 = never forget uses; N = count the last N hours
 //!
 //! [logguard]
 //! protocol_warns = true
 //! dump_limit = 25
 //! dump_level = "notice"
+//! # extra_warn_patterns = ["^Rejecting INTRODUCE2"]  # Optional: warnings to flag at WARN
+//! warn_coalesce_secs = 10          # 0 = log every repeated warning
+//!
+//! # Optional: replace consensus bandwidth-weights, for offline analysis
+//! # [bw_weight_overrides]
+//! # Wmm = 5000
+//!
+//! # Optional: extra constraints checked by pathverify (repeatable)
+//! # [[path_policies]]
+//! # position = "middle"        # guard, middle, last, any
+//! # forbid_flags = ["Exit"]
+//! # close_circuits = false
+//!
+//! # Optional: backup Tor instances tried in order if the primary is unreachable
+//! # [[control_fallbacks]]
+//! # socket = "/run/tor-backup/control"
+//! # [[control_fallbacks]]
+//! # address = "127.0.0.1:9151"
+//! # password = "backup_password"
 //! ```
 //!
 //! # What This Module Does NOT Do
@@ -144,14 +207,17 @@
 //! - [`BandguardsConfig`] for bandwidth monitoring settings
 //! - [`RendguardConfig`] for rendezvous point monitoring settings
 //! - [`LogguardConfig`] for log monitoring settings
+//! - [`PathPolicy`] for custom path constraints
 //! - [`CliArgs`] for command-line argument parsing
 
 use clap::Parser;
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, ToSocketAddrs};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 
 use crate::error::{Error, Result};
+use crate::logger::plog;
 
 /// Log level for vanguards-rs output.
 ///
@@ -212,6 +278,172 @@ impl std::str::FromStr for LogLevel {
     }
 }
 
+/// A config value given either as a single item or as a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+/// Removes `key` from `table` and reads it as one value or a list.
+fn take_one_or_many<T: serde::de::DeserializeOwned>(
+    table: &mut toml::Table,
+    key: &str,
+) -> Result<Vec<T>> {
+    let Some(value) = table.remove(key) else {
+        return Ok(Vec::new());
+    };
+    let values = value
+        .try_into::<OneOrMany<T>>()
+        .map_err(|e| Error::Config(format!("{}: {}", key, e)))?;
+    Ok(match values {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Rewrites a `control_socket` or `control_port` list in a config file as
+/// its first entry plus `control_fallbacks` entries for the rest.
+///
+/// The rest of the program then sees a single primary endpoint, with the
+/// other entries tried after it in order, ahead of any fallbacks listed
+/// explicitly. Ports use the file's `control_ip`. Returns `true` if a
+/// list was found.
+fn expand_control_lists(table: &mut toml::Table) -> Result<bool> {
+    if !["control_socket", "control_port"]
+        .iter()
+        .any(|key| table.get(*key).is_some_and(toml::Value::is_array))
+    {
+        return Ok(false);
+    }
+    let control_ip = table
+        .get("control_ip")
+        .and_then(toml::Value::as_str)
+        .map_or_else(default_control_ip, str::to_string);
+
+    let mut extra = Vec::new();
+    let mut sockets = take_one_or_many::<PathBuf>(table, "control_socket")?.into_iter();
+    if let Some(first) = sockets.next() {
+        let first = toml::Value::String(first.to_string_lossy().into_owned());
+        table.insert("control_socket".to_string(), first);
+    }
+    extra.extend(sockets.map(|socket| ControlEndpoint {
+        socket: Some(socket),
+        ..ControlEndpoint::default()
+    }));
+    let mut ports = take_one_or_many::<u16>(table, "control_port")?.into_iter();
+    if let Some(first) = ports.next() {
+        table.insert(
+            "control_port".to_string(),
+            toml::Value::Integer(first.into()),
+        );
+    }
+    extra.extend(ports.map(|port| ControlEndpoint {
+        address: Some(format!("{}:{}", control_ip, port)),
+        ..ControlEndpoint::default()
+    }));
+
+    if !extra.is_empty() {
+        let mut fallbacks = match toml::Value::try_from(&extra) {
+            Ok(toml::Value::Array(fallbacks)) => fallbacks,
+            Ok(_) => Vec::new(),
+            Err(e) => return Err(Error::Config(e.to_string())),
+        };
+        if let Some(toml::Value::Array(listed)) = table.remove("control_fallbacks") {
+            fallbacks.extend(listed);
+        }
+        table.insert(
+            "control_fallbacks".to_string(),
+            toml::Value::Array(fallbacks),
+        );
+    }
+    Ok(true)
+}
+
+/// Deserializes a count of hours from a number or a string such as `"45d"`.
+fn de_hours<'de, D: serde::Deserializer<'de>>(d: D) -> std::result::Result<u32, D::Error> {
+    d.deserialize_any(DurationVisitor { unit_secs: 3600 })
+}
+
+/// Deserializes a count of seconds from a number or a string such as `"2m"`.
+fn de_secs<'de, D: serde::Deserializer<'de>>(d: D) -> std::result::Result<u32, D::Error> {
+    d.deserialize_any(DurationVisitor { unit_secs: 1 })
+}
+
+/// Accepts a bare number, already in the field's unit, or a duration string.
+struct DurationVisitor {
+    unit_secs: u64,
+}
+
+impl serde::de::Visitor<'_> for DurationVisitor {
+    type Value = u32;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("a whole number or a duration such as \"45d\", \"48h\", \"30m\" or \"30s\"")
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> std::result::Result<u32, E> {
+        u32::try_from(v).map_err(|_| E::custom(format!("duration {} is too large", v)))
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, v: i64) -> std::result::Result<u32, E> {
+        u64::try_from(v)
+            .map_err(|_| E::custom(format!("duration {} is negative", v)))
+            .and_then(|v| self.visit_u64(v))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> std::result::Result<u32, E> {
+        parse_duration(v, self.unit_secs).map_err(E::custom)
+    }
+}
+
+/// Parses `"45d"`, `"48h"`, `"30m"`, `"30s"` or a bare number into whole
+/// units of `unit_secs` seconds.
+///
+/// A bare number is taken to be in the target unit already. A duration that
+/// is not a whole number of target units, such as `"90m"` for an hours
+/// field, is rejected rather than rounded.
+fn parse_duration(s: &str, unit_secs: u64) -> std::result::Result<u32, String> {
+    let unit_name = if unit_secs == 3600 {
+        "hours"
+    } else {
+        "seconds"
+    };
+    let invalid = || {
+        format!(
+            "invalid duration {:?}: expected a number of {} or a number followed by d, h, m or s",
+            s, unit_name
+        )
+    };
+
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let suffix_secs = match suffix.trim() {
+        "" => unit_secs,
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(invalid()),
+    };
+
+    let secs = number
+        .checked_mul(suffix_secs)
+        .ok_or_else(|| format!("duration {:?} is too large", s))?;
+    if secs % unit_secs != 0 {
+        return Err(format!(
+            "duration {:?} is not a whole number of {}",
+            s, unit_name
+        ));
+    }
+    u32::try_from(secs / unit_secs).map_err(|_| format!("duration {:?} is too large", s))
+}
+
 /// Vanguard-specific configuration options.
 ///
 /// Controls the number of guards at each layer and their rotation lifetimes.
@@ -240,11 +472,33 @@ impl std::str::FromStr for LogLevel {
 /// | `num_layer1_guards` | 2 | Entry guards (0 = Tor default) |
 /// | `num_layer2_guards` | 4 | Layer2 vanguard count |
 /// | `num_layer3_guards` | 8 | Layer3 vanguard count |
+/// | `enable_layer2` | true | Select and apply layer2 guards (`HSLayer2Nodes`) |
+/// | `enable_layer3` | true | Select and apply layer3 guards (`HSLayer3Nodes`) |
 /// | `layer1_lifetime_days` | 0 | Entry guard lifetime (0 = Tor default) |
 /// | `min_layer2_lifetime_hours` | 24 | Minimum layer2 lifetime |
 /// | `max_layer2_lifetime_hours` | 1080 | Maximum layer2 lifetime (45 days) |
 /// | `min_layer3_lifetime_hours` | 1 | Minimum layer3 lifetime |
 /// | `max_layer3_lifetime_hours` | 48 | Maximum layer3 lifetime |
+/// | `rotation_cooldown_hours` | 24 | Avoid reselecting a rotated-out guard for this long (0 = off) |
+/// | `avoid_badexit` | true | Never pick relays the authorities flagged BadExit |
+/// | `min_guard_age_hours` | 0 | Skip relays first seen in the consensus less than this many hours ago (0 = off) |
+/// | `lifetime_jitter_fraction` | 0.0 | Scale each guard lifetime by a random factor in `1 ± fraction` (at most 0.5) |
+/// | `subnet_diversity` | false | Keep each layer's guards in distinct IPv4 /16s and IPv6 /32s where possible |
+/// | `enforce_guard_diversity` | true | Keep relays of one declared family out of the same layer |
+/// | `min_layer_bw_fraction` | 0.0 | Reselect new layer2 guards until together they carry this share of eligible weight (0 = off) |
+/// | `revalidate_interval_secs` | 300 | Re-check guards against the cached consensus and `ExcludeNodes` this often (0 = off) |
+/// | `max_consensus_age_hours` | 0 | Pick no new guards from a consensus whose `valid-after` is older than this (0 = off) |
+/// | `strict_layers` | false | Also set Tor's `StrictNodes 1` after `HSLayer2Nodes`/`HSLayer3Nodes` |
+/// | `asn_map_file` | (unset) | `FINGERPRINT ASN` file; keeps layer2 and layer3 guards in distinct ASes |
+/// | `min_weight_percentile` | 0.0 | Only select new guards at or above this cumulative-weight percentile, below 1 (0 = all relays) |
+/// | `exclude_min_ipv4_prefix` | 8 | Skip `ExcludeNodes` IPv4 networks broader than this prefix |
+/// | `exclude_min_ipv6_prefix` | 16 | Skip `ExcludeNodes` IPv6 networks broader than this prefix |
+///
+/// A disabled layer is skipped entirely: no guards are selected for it, any
+/// previously selected ones are dropped, and its Tor option is set empty.
+///
+/// The lifetime fields accept either a number of hours or a duration string
+/// such as `"45d"` or `"48h"`.
 ///
 /// # Security Considerations
 ///
@@ -282,21 +536,116 @@ pub struct VanguardsConfig {
     /// Number of layer3 guards.
     #[serde(default = "default_num_layer3_guards")]
     pub num_layer3_guards: u8,
+    /// Select and apply layer2 guards.
+    #[serde(default = "default_enable_layer")]
+    pub enable_layer2: bool,
+    /// Select and apply layer3 guards.
+    ///
+    /// When either layer is off its `HSLayerNNodes` option is set empty, so
+    /// Tor stops pinning it.
+    #[serde(default = "default_enable_layer")]
+    pub enable_layer3: bool,
     /// Layer1 guard lifetime in days. 0 means use Tor default.
     #[serde(default)]
     pub layer1_lifetime_days: u16,
     /// Minimum layer2 guard lifetime in hours.
-    #[serde(default = "default_min_layer2_lifetime_hours")]
+    #[serde(
+        default = "default_min_layer2_lifetime_hours",
+        deserialize_with = "de_hours"
+    )]
     pub min_layer2_lifetime_hours: u32,
     /// Maximum layer2 guard lifetime in hours.
-    #[serde(default = "default_max_layer2_lifetime_hours")]
+    #[serde(
+        default = "default_max_layer2_lifetime_hours",
+        deserialize_with = "de_hours"
+    )]
     pub max_layer2_lifetime_hours: u32,
     /// Minimum layer3 guard lifetime in hours.
-    #[serde(default = "default_min_layer3_lifetime_hours")]
+    #[serde(
+        default = "default_min_layer3_lifetime_hours",
+        deserialize_with = "de_hours"
+    )]
     pub min_layer3_lifetime_hours: u32,
     /// Maximum layer3 guard lifetime in hours.
-    #[serde(default = "default_max_layer3_lifetime_hours")]
+    #[serde(
+        default = "default_max_layer3_lifetime_hours",
+        deserialize_with = "de_hours"
+    )]
     pub max_layer3_lifetime_hours: u32,
+    /// Hours during which an expired guard is not reselected. 0 disables.
+    #[serde(default = "default_rotation_cooldown_hours")]
+    pub rotation_cooldown_hours: u32,
+    /// Never select relays flagged BadExit as vanguards.
+    #[serde(default = "default_avoid_badexit")]
+    pub avoid_badexit: bool,
+    /// Minimum hours a relay must have been in the consensus before it can
+    /// be picked as a vanguard. 0 disables.
+    ///
+    /// First-seen times are kept in the state file. Relays in the first
+    /// consensus seen count as old enough, and a relay that drops out of the
+    /// consensus starts over when it returns.
+    #[serde(default)]
+    pub min_guard_age_hours: u32,
+    /// Extra random scaling of guard lifetimes, as a fraction. 0 disables.
+    #[serde(default)]
+    pub lifetime_jitter_fraction: f64,
+    /// Avoid two guards in a layer sharing a /16 (IPv4) or /32 (IPv6).
+    ///
+    /// Relaxed, with a NOTICE, when there are not enough distinct subnets.
+    #[serde(default)]
+    pub subnet_diversity: bool,
+    /// Keep relays of one declared family out of the same layer. Subnets
+    /// are only kept apart with `subnet_diversity`.
+    ///
+    /// Relaxed, with a NOTICE, when no other candidate turns up.
+    #[serde(default = "default_enforce_guard_diversity")]
+    pub enforce_guard_diversity: bool,
+    /// Minimum share of the generator's total weight that the layer2 set
+    /// must carry together. 0 disables.
+    #[serde(default)]
+    pub min_layer_bw_fraction: f64,
+    /// Seconds between re-checks of the current guards. 0 disables.
+    #[serde(default = "default_revalidate_interval_secs")]
+    pub revalidate_interval_secs: u32,
+    /// Hours after its `valid-after` that a consensus may still replenish
+    /// the guard layers. Guards that left it, expired or became excluded are
+    /// dropped either way. 0 disables.
+    #[serde(default)]
+    pub max_consensus_age_hours: u32,
+    /// Set `StrictNodes 1` after the layer options.
+    ///
+    /// Tor has no strictness setting of its own for `HSLayer2Nodes` and
+    /// `HSLayer3Nodes`; `StrictNodes` is the one it exposes. It applies to
+    /// every circuit Tor builds, not only hidden service ones: Tor then
+    /// never routes through `ExcludeNodes` relays to keep a circuit working.
+    /// Off leaves `StrictNodes` as the torrc has it.
+    #[serde(default)]
+    pub strict_layers: bool,
+    /// File mapping relay fingerprints to AS numbers, one
+    /// `FINGERPRINT ASN` pair per line.
+    ///
+    /// When set, a relay sharing an AS with a layer2 or layer3 guard is
+    /// only chosen if nothing else qualifies. A file that cannot be read or
+    /// parsed fails validation. It is read once, and again only when the
+    /// configuration is reloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn_map_file: Option<PathBuf>,
+    /// Choose layer2 and layer3 guards only from relays at or above this
+    /// cumulative-weight percentile, 0.0 to 1.0.
+    ///
+    /// 0.5 leaves out the lightest relays that together carry half of the
+    /// eligible weight. 0 considers every relay.
+    #[serde(default)]
+    pub min_weight_percentile: f64,
+    /// Shortest IPv4 prefix honored in `ExcludeNodes` and `ExcludeExitNodes`.
+    ///
+    /// Broader networks are skipped with a warning, since they would
+    /// exclude so much that no guards could be chosen. 0 honors them all.
+    #[serde(default = "default_exclude_min_ipv4_prefix")]
+    pub exclude_min_ipv4_prefix: u8,
+    /// Shortest IPv6 prefix honored in `ExcludeNodes` and `ExcludeExitNodes`.
+    #[serde(default = "default_exclude_min_ipv6_prefix")]
+    pub exclude_min_ipv6_prefix: u8,
 }
 
 fn default_num_layer1_guards() -> u8 {
@@ -308,6 +657,9 @@ fn default_num_layer2_guards() -> u8 {
 fn default_num_layer3_guards() -> u8 {
     8
 }
+fn default_enable_layer() -> bool {
+    true
+}
 fn default_min_layer2_lifetime_hours() -> u32 {
     24
 }
@@ -320,6 +672,24 @@ fn default_min_layer3_lifetime_hours() -> u32 {
 fn default_max_layer3_lifetime_hours() -> u32 {
     48
 }
+fn default_rotation_cooldown_hours() -> u32 {
+    24
+}
+fn default_avoid_badexit() -> bool {
+    true
+}
+fn default_enforce_guard_diversity() -> bool {
+    true
+}
+fn default_revalidate_interval_secs() -> u32 {
+    300
+}
+fn default_exclude_min_ipv4_prefix() -> u8 {
+    crate::vanguards::DEFAULT_MIN_IPV4_PREFIX
+}
+fn default_exclude_min_ipv6_prefix() -> u8 {
+    crate::vanguards::DEFAULT_MIN_IPV6_PREFIX
+}
 
 impl Default for VanguardsConfig {
     fn default() -> Self {
@@ -327,11 +697,47 @@ impl Default for VanguardsConfig {
             num_layer1_guards: default_num_layer1_guards(),
             num_layer2_guards: default_num_layer2_guards(),
             num_layer3_guards: default_num_layer3_guards(),
+            enable_layer2: default_enable_layer(),
+            enable_layer3: default_enable_layer(),
             layer1_lifetime_days: 0,
             min_layer2_lifetime_hours: default_min_layer2_lifetime_hours(),
             max_layer2_lifetime_hours: default_max_layer2_lifetime_hours(),
             min_layer3_lifetime_hours: default_min_layer3_lifetime_hours(),
             max_layer3_lifetime_hours: default_max_layer3_lifetime_hours(),
+            rotation_cooldown_hours: default_rotation_cooldown_hours(),
+            avoid_badexit: default_avoid_badexit(),
+            min_guard_age_hours: 0,
+            lifetime_jitter_fraction: 0.0,
+            subnet_diversity: false,
+            enforce_guard_diversity: default_enforce_guard_diversity(),
+            min_layer_bw_fraction: 0.0,
+            revalidate_interval_secs: default_revalidate_interval_secs(),
+            max_consensus_age_hours: 0,
+            strict_layers: false,
+            asn_map_file: None,
+            min_weight_percentile: 0.0,
+            exclude_min_ipv4_prefix: default_exclude_min_ipv4_prefix(),
+            exclude_min_ipv6_prefix: default_exclude_min_ipv6_prefix(),
+        }
+    }
+}
+
+impl VanguardsConfig {
+    /// Number of layer2 guards to maintain, or 0 if layer2 is disabled.
+    pub fn layer2_guard_count(&self) -> u8 {
+        if self.enable_layer2 {
+            self.num_layer2_guards
+        } else {
+            0
+        }
+    }
+
+    /// Number of layer3 guards to maintain, or 0 if layer3 is disabled.
+    pub fn layer3_guard_count(&self) -> u8 {
+        if self.enable_layer3 {
+            self.num_layer3_guards
+        } else {
+            0
         }
     }
 }
@@ -363,9 +769,17 @@ impl Default for VanguardsConfig {
 ///      ├── Monitor HSDIR circuit bandwidth
 ///      └── Alert if exceeds circ_max_hsdesc_kilobytes
 ///
-///   4. Connectivity Monitoring
+///   4. HSDIR Circuit Flood
+///      ├── Count HSDIR circuit launches over the last minute
+///      └── Warn if exceeds max_hsdir_rate
+///
+///   5. Connectivity Monitoring
 ///      ├── Track disconnection duration
 ///      └── Warn if exceeds threshold
+///
+///   6. Guard Connection Kills
+///      ├── Count guard connections closed under live circuits
+///      └── Warn if exceeds max_guard_conn_kills per window
 /// ```
 ///
 /// # Fields
@@ -376,8 +790,30 @@ impl Default for VanguardsConfig {
 /// | `circ_max_age_hours` | 24 | Max circuit age in hours |
 /// | `circ_max_hsdesc_kilobytes` | 30 | Max HSDIR circuit size in KB |
 /// | `circ_max_serv_intro_kilobytes` | 0 | Max intro circuit size (0 = disabled) |
+/// | `circ_max_bytes_per_sec` | 0 | Max circuit throughput between `CIRC_BW` events (0 = disabled) |
 /// | `circ_max_disconnected_secs` | 30 | Warn after N seconds disconnected |
+/// | `circ_max_idle_secs` | 86400 | Stop tracking circuits with no events for N seconds (0 = never) |
 /// | `conn_max_disconnected_secs` | 15 | Warn after N seconds with no connections |
+/// | `max_hsdir_rate` | 30 | Warn above N HSDIR circuits per minute (0 = disabled) |
+/// | `max_guard_conn_kills` | 5 | Warn above N killed connections to one guard per window (0 = disabled) |
+/// | `guard_conn_kill_window_secs` | 3600 | Window for `max_guard_conn_kills` |
+/// | `limit_check_interval_ms` | 0 | Run the circuit limit sweep at most this often (0 = after every event) |
+/// | `treat_guard_wait_as_built` | true | Count `GUARD_WAIT` circuits as built and in use |
+/// | `monitored_purposes` | `[]` | Circuit purposes to track, such as `HS_SERVICE_REND`; a trailing `*` matches a prefix (empty = all) |
+///
+/// The `*_disconnected_secs`, `circ_max_idle_secs` and
+/// `guard_conn_kill_window_secs` fields accept
+/// either a number of seconds or a duration string such as `"2m"`.
+///
+/// # Limit Check Sampling
+///
+/// Byte counts are updated on every `CIRC_BW` event, but by default every
+/// tracked circuit is also checked against the limits after every event. On
+/// a busy service that sweep dominates CPU. With `limit_check_interval_ms`
+/// set, the sweep is skipped until the interval has passed since the last
+/// one. Tor sends a `BW` event every second, so a breach is acted on at most
+/// `limit_check_interval_ms` plus one second after it happens. The interval
+/// is capped at 10 seconds.
 ///
 /// # Example
 ///
@@ -411,14 +847,54 @@ pub struct BandguardsConfig {
     /// Maximum service intro circuit size in kilobytes. 0 disables.
     #[serde(default)]
     pub circ_max_serv_intro_kilobytes: u32,
+    /// Maximum circuit throughput in bytes per second. 0 disables.
+    #[serde(default)]
+    pub circ_max_bytes_per_sec: u64,
     /// Warn after this many seconds disconnected from circuits.
-    #[serde(default = "default_circ_max_disconnected_secs")]
+    #[serde(
+        default = "default_circ_max_disconnected_secs",
+        deserialize_with = "de_secs"
+    )]
     pub circ_max_disconnected_secs: u32,
+    /// Stop tracking a circuit after this many seconds without an event for
+    /// it, in case its CLOSED event was missed. 0 disables.
+    #[serde(default = "default_circ_max_idle_secs", deserialize_with = "de_secs")]
+    pub circ_max_idle_secs: u32,
     /// Warn after this many seconds with no connections.
-    #[serde(default = "default_conn_max_disconnected_secs")]
+    #[serde(
+        default = "default_conn_max_disconnected_secs",
+        deserialize_with = "de_secs"
+    )]
     pub conn_max_disconnected_secs: u32,
+    /// Warn when more HSDIR circuits than this launch within a minute. 0 disables.
+    #[serde(default = "default_max_hsdir_rate")]
+    pub max_hsdir_rate: u32,
+    /// Warn when more connections than this to one guard are killed under
+    /// live circuits within `guard_conn_kill_window_secs`. 0 disables.
+    #[serde(default = "default_max_guard_conn_kills")]
+    pub max_guard_conn_kills: u32,
+    /// Window `max_guard_conn_kills` is counted over, in seconds.
+    #[serde(
+        default = "default_guard_conn_kill_window_secs",
+        deserialize_with = "de_secs"
+    )]
+    pub guard_conn_kill_window_secs: u32,
+    /// Minimum milliseconds between circuit limit sweeps. 0 sweeps after every event.
+    #[serde(default)]
+    pub limit_check_interval_ms: u32,
+    /// Count `GUARD_WAIT` circuits as built and in use, like `BUILT` ones.
+    #[serde(default = "default_treat_guard_wait_as_built")]
+    pub treat_guard_wait_as_built: bool,
+    /// Circuit purposes to track. An entry ending in `*` matches every
+    /// purpose with that prefix. Empty tracks all circuits.
+    #[serde(default)]
+    pub monitored_purposes: Vec<String>,
 }
 
+/// Largest allowed [`BandguardsConfig::limit_check_interval_ms`], which
+/// bounds how late a limit breach can be detected.
+pub const MAX_LIMIT_CHECK_INTERVAL_MS: u32 = 10_000;
+
 fn default_circ_max_age_hours() -> u32 {
     24
 }
@@ -428,9 +904,24 @@ fn default_circ_max_hsdesc_kilobytes() -> u32 {
 fn default_circ_max_disconnected_secs() -> u32 {
     30
 }
+fn default_circ_max_idle_secs() -> u32 {
+    86400
+}
 fn default_conn_max_disconnected_secs() -> u32 {
     15
 }
+fn default_max_hsdir_rate() -> u32 {
+    30
+}
+fn default_max_guard_conn_kills() -> u32 {
+    5
+}
+fn default_guard_conn_kill_window_secs() -> u32 {
+    3600
+}
+fn default_treat_guard_wait_as_built() -> bool {
+    true
+}
 
 impl Default for BandguardsConfig {
     fn default() -> Self {
@@ -439,8 +930,16 @@ impl Default for BandguardsConfig {
             circ_max_age_hours: default_circ_max_age_hours(),
             circ_max_hsdesc_kilobytes: default_circ_max_hsdesc_kilobytes(),
             circ_max_serv_intro_kilobytes: 0,
+            circ_max_bytes_per_sec: 0,
             circ_max_disconnected_secs: default_circ_max_disconnected_secs(),
+            circ_max_idle_secs: default_circ_max_idle_secs(),
             conn_max_disconnected_secs: default_conn_max_disconnected_secs(),
+            max_hsdir_rate: default_max_hsdir_rate(),
+            max_guard_conn_kills: default_max_guard_conn_kills(),
+            guard_conn_kill_window_secs: default_guard_conn_kill_window_secs(),
+            limit_check_interval_ms: 0,
+            treat_guard_wait_as_built: true,
+            monitored_purposes: Vec::new(),
         }
     }
 }
@@ -481,6 +980,8 @@ impl Default for BandguardsConfig {
 /// | `use_max_use_to_bw_ratio` | 5.0 | Max ratio of use to bandwidth |
 /// | `use_max_consensus_weight_churn` | 1.0 | Max consensus weight churn % |
 /// | `close_circuits_on_overuse` | true | Close circuits on overuse detection |
+/// | `max_not_in_consensus_entries` | 1000 | Cap on non-consensus RPs tracked individually (LRU) |
+/// | `use_window_hours` | 0 | Only count uses from this many hours back; 0 disables |
 ///
 /// # Example
 ///
@@ -521,6 +1022,15 @@ pub struct RendguardConfig {
     /// Close circuits on rendezvous point overuse.
     #[serde(default = "default_close_circuits_on_overuse")]
     pub close_circuits_on_overuse: bool,
+    /// Maximum distinct non-consensus rendezvous points tracked individually.
+    #[serde(default = "default_max_not_in_consensus_entries")]
+    pub max_not_in_consensus_entries: usize,
+    /// Forget rendezvous uses older than this many hours (0 = never).
+    ///
+    /// When set, uses decay out of a sliding window instead of being
+    /// halved at `use_scale_at_count`.
+    #[serde(default)]
+    pub use_window_hours: u32,
 }
 
 fn default_use_global_start_count() -> u32 {
@@ -541,6 +1051,9 @@ fn default_use_max_consensus_weight_churn() -> f64 {
 fn default_close_circuits_on_overuse() -> bool {
     true
 }
+fn default_max_not_in_consensus_entries() -> usize {
+    1000
+}
 
 impl Default for RendguardConfig {
     fn default() -> Self {
@@ -551,6 +1064,8 @@ impl Default for RendguardConfig {
             use_max_use_to_bw_ratio: default_use_max_use_to_bw_ratio(),
             use_max_consensus_weight_churn: default_use_max_consensus_weight_churn(),
             close_circuits_on_overuse: default_close_circuits_on_overuse(),
+            max_not_in_consensus_entries: default_max_not_in_consensus_entries(),
+            use_window_hours: 0,
         }
     }
 }
@@ -569,6 +1084,14 @@ pub struct LogguardConfig {
     /// Minimum log level to buffer.
     #[serde(default)]
     pub dump_level: LogLevel,
+    /// Regexes for Tor warnings to report at WARN as protocol warnings.
+    /// There are no built-in patterns.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_warn_patterns: Vec<String>,
+    /// Seconds to fold repeats of an identical Tor warning into one line
+    /// (0 = log every repeat).
+    #[serde(default = "default_warn_coalesce_secs")]
+    pub warn_coalesce_secs: u64,
 }
 
 fn default_protocol_warns() -> bool {
@@ -577,6 +1100,9 @@ fn default_protocol_warns() -> bool {
 fn default_dump_limit() -> usize {
     25
 }
+fn default_warn_coalesce_secs() -> u64 {
+    10
+}
 
 impl Default for LogguardConfig {
     fn default() -> Self {
@@ -584,10 +1110,245 @@ impl Default for LogguardConfig {
             protocol_warns: default_protocol_warns(),
             dump_limit: default_dump_limit(),
             dump_level: LogLevel::Notice,
+            extra_warn_patterns: Vec::new(),
+            warn_coalesce_secs: default_warn_coalesce_secs(),
         }
     }
 }
 
+/// Where in a circuit path a [`PathPolicy`] is checked.
+///
+/// `Middle` covers every hop except the first and the last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PathPosition {
+    /// The first hop (the entry guard).
+    Guard,
+    /// Every hop between the guard and the last hop.
+    #[default]
+    Middle,
+    /// The last hop.
+    Last,
+    /// Every hop.
+    Any,
+}
+
+/// A kind of problem pathverify finds with a hidden service circuit.
+///
+/// Listed in `pathverify_close_on` to close circuits that have it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathViolationKind {
+    /// The hop count does not match the circuit's purpose.
+    BadLength,
+    /// The second or third hop is outside its vanguard layer.
+    WrongLayer,
+    /// The first hop is not one of the layer 1 guards.
+    UnexpectedGuard,
+}
+
+/// A user-supplied constraint on the relays path-verified circuits may use.
+///
+/// Policies are checked by [`PathVerify`](crate::pathverify::PathVerify)
+/// against each built hidden service circuit. Every relay at `position` must
+/// satisfy all the `require_*` lists and none of the `forbid_*` lists; empty
+/// lists impose no constraint.
+///
+/// # Fields
+///
+/// | Field | Default | Description |
+/// |-------|---------|-------------|
+/// | `position` | `"middle"` | `guard`, `middle`, `last`, or `any` |
+/// | `require_flags` / `forbid_flags` | `[]` | Consensus flags (e.g. `"Exit"`) |
+/// | `require_countries` / `forbid_countries` | `[]` | Two-letter country codes, via Tor's GeoIP |
+/// | `require_networks` / `forbid_networks` | `[]` | CIDR networks (e.g. `"10.0.0.0/8"`) |
+/// | `close_circuits` | false | Close violating circuits instead of only logging |
+///
+/// A relay whose country Tor cannot resolve never satisfies
+/// `require_countries` and never matches `forbid_countries`.
+///
+/// # Example
+///
+/// ```toml
+/// [[path_policies]]
+/// position = "middle"
+/// forbid_flags = ["Exit"]
+/// forbid_countries = ["xx"]
+/// close_circuits = true
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct PathPolicy {
+    /// Which hops the policy applies to.
+    #[serde(default)]
+    pub position: PathPosition,
+    /// Flags every matching relay must have.
+    #[serde(default)]
+    pub require_flags: Vec<String>,
+    /// Flags no matching relay may have.
+    #[serde(default)]
+    pub forbid_flags: Vec<String>,
+    /// Countries every matching relay must be in (lowercase codes).
+    #[serde(default)]
+    pub require_countries: Vec<String>,
+    /// Countries no matching relay may be in (lowercase codes).
+    #[serde(default)]
+    pub forbid_countries: Vec<String>,
+    /// Networks every matching relay's address must fall in.
+    #[serde(default)]
+    pub require_networks: Vec<String>,
+    /// Networks no matching relay's address may fall in.
+    #[serde(default)]
+    pub forbid_networks: Vec<String>,
+    /// Close circuits that violate this policy.
+    #[serde(default)]
+    pub close_circuits: bool,
+}
+
+impl PathPolicy {
+    /// Returns `true` if the policy needs relay country codes.
+    pub fn uses_countries(&self) -> bool {
+        !self.require_countries.is_empty() || !self.forbid_countries.is_empty()
+    }
+}
+
+/// Prefix marking a control socket as a Linux abstract socket.
+///
+/// Abstract sockets have no file; `abstract:tor-control` names the socket
+/// Tor lists as `@tor-control` in `ss -x`.
+pub const ABSTRACT_SOCKET_PREFIX: &str = "abstract:";
+
+/// Returns the name of an abstract control socket, or `None` for a
+/// filesystem path.
+///
+/// # Example
+///
+/// ```rust
+/// use std::path::Path;
+/// use vanguards_rs::config::abstract_socket_name;
+///
+/// assert_eq!(abstract_socket_name(Path::new("abstract:tor")), Some("tor"));
+/// assert_eq!(abstract_socket_name(Path::new("/run/tor/control")), None);
+/// ```
+pub fn abstract_socket_name(path: &Path) -> Option<&str> {
+    path.to_str()?.strip_prefix(ABSTRACT_SOCKET_PREFIX)
+}
+
+/// Checks an `abstract:` socket setting names a socket this platform can open.
+fn validate_socket(path: &Path) -> Result<()> {
+    match abstract_socket_name(path) {
+        Some("") => Err(Error::Config(format!(
+            "abstract control socket needs a name after \"{}\"",
+            ABSTRACT_SOCKET_PREFIX
+        ))),
+        Some(_) if !cfg!(target_os = "linux") => Err(Error::Config(format!(
+            "abstract control sockets are only supported on Linux: {}",
+            path.display()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A backup Tor control endpoint.
+///
+/// Used by `control_fallbacks` when the primary control port or socket
+/// cannot be reached. Exactly one of `socket` and `address` must be set.
+///
+/// | Field | Default | Description |
+/// |-------|---------|-------------|
+/// | `socket` | (unset) | Path to a control socket, or `abstract:name` |
+/// | `address` | (unset) | Control port as `ip:port` |
+/// | `password` | (unset) | Password for this endpoint; cookie or no auth if unset |
+///
+/// # Example
+///
+/// ```toml
+/// [[control_fallbacks]]
+/// address = "127.0.0.1:9151"
+/// password = "backup_password"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct ControlEndpoint {
+    /// Path to the endpoint's control socket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<PathBuf>,
+    /// Control port address as `ip:port`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Password for this endpoint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl std::fmt::Display for ControlEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.socket, &self.address) {
+            (Some(socket), _) => write!(f, "socket {}", socket.display()),
+            (None, Some(address)) => write!(f, "control port {}", address),
+            (None, None) => write!(f, "(unset endpoint)"),
+        }
+    }
+}
+
+/// What to do when the first consensus yields no vanguards.
+///
+/// Only applies with `enable_vanguards = true`. On a tiny test network or
+/// with very broad `ExcludeNodes`, no relay may qualify as a layer2 or
+/// layer3 guard, and Tor would then build hidden service circuits without
+/// vanguards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NoGuardsAction {
+    /// Exit with an error.
+    Fail,
+    /// Log a warning and start monitoring without vanguards. Selection is
+    /// tried again on the next consensus.
+    WarnAndContinue,
+    /// Keep fetching the consensus until guards can be chosen, without
+    /// starting any monitoring in the meantime.
+    #[default]
+    WaitAndRetry,
+}
+
+/// Format of the vanguard state file.
+///
+/// Reading detects the format, so switching `state_format` keeps the
+/// existing guards; the file is rewritten in the new format on next save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StateFormat {
+    /// Python pickle, readable by the original Python vanguards.
+    #[default]
+    Pickle,
+    /// Pretty-printed JSON, for inspecting and diffing.
+    Json,
+}
+
+/// Format of log lines written by [`crate::logger`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers.
+    Json,
+}
+
+/// Format for exporting bandguards detections to a SIEM.
+///
+/// See [`crate::siem`] for the record layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SiemFormat {
+    /// No export.
+    #[default]
+    None,
+    /// ArcSight Common Event Format.
+    Cef,
+    /// IBM QRadar Log Event Extended Format 1.0.
+    Leef,
+}
+
 /// Main configuration struct for vanguards-rs.
 ///
 /// This struct contains all configuration options for the vanguards-rs library
@@ -601,15 +1362,19 @@ impl Default for LogguardConfig {
 /// | Field | Type | Default | Description |
 /// |-------|------|---------|-------------|
 /// | `control_ip` | `String` | `"127.0.0.1"` | Tor control port IP address |
-/// | `control_port` | `Option<u16>` | `None` | Tor control port number |
-/// | `control_socket` | `Option<PathBuf>` | `None` | Unix socket path (alternative to TCP) |
+/// | `control_port` | `Option<u16>` | `None` | Tor control port number; a list in the file adds the rest as fallbacks |
+/// | `control_socket` | `Option<PathBuf>` | `None` | Unix socket path (alternative to TCP), or `abstract:name` on Linux; a list in the file adds the rest as fallbacks |
 /// | `control_pass` | `Option<String>` | `None` | Control port password |
+/// | `control_pass_file` | `Option<PathBuf>` | `None` | File holding the control password, used when `control_pass` is unset |
+/// | `control_fallbacks` | `Vec<ControlEndpoint>` | `[]` | Backup control endpoints, tried in order |
 ///
 /// ## File Settings
 ///
 /// | Field | Type | Default | Description |
 /// |-------|------|---------|-------------|
 /// | `state_file` | `PathBuf` | `"vanguards.state"` | Vanguard state persistence file |
+/// | `state_format` | `StateFormat` | `Pickle` | Format the state file is written in |
+/// | `state_backups` | `u32` | `0` | Hourly previous state files kept as `.1`, `.2`, ... |
 ///
 /// ## Logging Settings
 ///
@@ -617,6 +1382,10 @@ impl Default for LogguardConfig {
 /// |-------|------|---------|-------------|
 /// | `loglevel` | `LogLevel` | `Notice` | Log verbosity level |
 /// | `logfile` | `Option<String>` | `None` | Log destination (file, `:syslog:`, or stdout) |
+/// | `log_format` | `LogFormat` | `Text` | Plain text or one JSON object per line |
+/// | `logfile_max_bytes` | `u64` | `0` | Rotate a file `logfile` past this size (0 = never) |
+/// | `logfile_max_files` | `u32` | `5` | Rotated logs kept as `.1`, `.2`, ... |
+/// | `anonymize_fingerprints_in_logs` | `bool` | `false` | Replace relay fingerprints in log output with keyed hashes |
 ///
 /// ## Component Toggles
 ///
@@ -627,7 +1396,16 @@ impl Default for LogguardConfig {
 /// | `enable_rendguard` | `bool` | `true` | Enable rendezvous point monitoring |
 /// | `enable_logguard` | `bool` | `true` | Enable log monitoring |
 /// | `enable_cbtverify` | `bool` | `false` | Enable circuit build timeout verification |
+/// | `cbt_max_timeout_rate` | `f64` | `0.5` | Warn when Tor's build timeout rate exceeds this (1.0 = never) |
 /// | `enable_pathverify` | `bool` | `false` | Enable path verification |
+/// | `path_policies` | `Vec<PathPolicy>` | `[]` | Extra path constraints checked by pathverify |
+/// | `pathverify_close_on` | `Vec<PathViolationKind>` | `[]` | Pathverify problems that close the HS circuit |
+///
+/// ## Analysis Settings
+///
+/// | Field | Type | Default | Description |
+/// |-------|------|---------|-------------|
+/// | `bw_weight_overrides` | `BTreeMap<String, i64>` | `{}` | Bandwidth-weights merged over the consensus values |
 ///
 /// ## Operational Settings
 ///
@@ -636,6 +1414,13 @@ impl Default for LogguardConfig {
 /// | `close_circuits` | `bool` | `true` | Close circuits on detected attacks |
 /// | `one_shot_vanguards` | `bool` | `false` | Set vanguards and exit immediately |
 /// | `retry_limit` | `Option<u32>` | `None` | Max reconnection attempts (None = infinite) |
+/// | `reconnect_max_backoff_secs` | `u64` | `60` | Cap for the reconnect delay, which starts at 1s and doubles |
+/// | `no_interactive` | `bool` | `false` | Fail instead of prompting when Tor needs an unconfigured password |
+/// | `on_no_guards` | `NoGuardsAction` | `WaitAndRetry` | What to do when the first consensus yields no vanguards |
+/// | `ipc_socket` | `Option<PathBuf>` | `None` | Unix socket for local event streaming and commands |
+/// | `metrics_listen` | `Option<SocketAddr>` | `None` | HTTP address for the Prometheus `/metrics` endpoint |
+/// | `siem_format` | `SiemFormat` | `None` | Export detections as CEF or LEEF lines |
+/// | `siem_output` | `Option<PathBuf>` | `None` | File the SIEM lines are appended to |
 ///
 /// # Example
 ///
@@ -699,24 +1484,74 @@ pub struct Config {
     /// Port number of the Tor control port.
     #[serde(default)]
     pub control_port: Option<u16>,
-    /// Path to the Tor control socket.
+    /// Path to the Tor control socket, or `abstract:name` for a Linux
+    /// abstract socket.
     #[serde(default)]
     pub control_socket: Option<PathBuf>,
     /// Password for Tor control authentication.
     #[serde(default)]
     pub control_pass: Option<String>,
+    /// File to read the control password from when `control_pass` is unset.
+    ///
+    /// Keeps the password out of the process list and the config file. It
+    /// should be readable by its owner only.
+    #[serde(default)]
+    pub control_pass_file: Option<PathBuf>,
     /// Path to the vanguard state file.
     #[serde(default = "default_state_file")]
     pub state_file: PathBuf,
+    /// Format the state file is written in. Either format is read.
+    #[serde(default)]
+    pub state_format: StateFormat,
+    /// Number of previous state files to keep as `<state_file>.1`,
+    /// `<state_file>.2`, ..., newest first, taken at most once an hour.
+    /// 0 keeps none.
+    #[serde(default)]
+    pub state_backups: u32,
     /// Log level for output.
     #[serde(default)]
     pub loglevel: LogLevel,
     /// Log file path. None for stdout, ":syslog:" for syslog.
     #[serde(default)]
     pub logfile: Option<String>,
+    /// Format of log lines.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Size in bytes at which a file `logfile` is rotated. 0 disables rotation.
+    #[serde(default)]
+    pub logfile_max_bytes: u64,
+    /// Number of rotated log files to keep.
+    #[serde(default = "default_logfile_max_files")]
+    pub logfile_max_files: u32,
+    /// Replace relay fingerprints in log lines with a hash keyed per process.
+    ///
+    /// The state file and IPC replies still carry full fingerprints.
+    #[serde(default)]
+    pub anonymize_fingerprints_in_logs: bool,
     /// Maximum reconnection attempts. None for infinite.
     #[serde(default)]
     pub retry_limit: Option<u32>,
+    /// Longest delay between reconnection attempts, in seconds.
+    #[serde(default = "default_reconnect_max_backoff_secs")]
+    pub reconnect_max_backoff_secs: u64,
+    /// Never prompt for a control password; fail if one is needed but unset.
+    #[serde(default)]
+    pub no_interactive: bool,
+    /// Unix socket to serve local IPC clients on. None disables IPC.
+    #[serde(default)]
+    pub ipc_socket: Option<PathBuf>,
+    /// What to do when the first consensus yields no vanguards.
+    #[serde(default)]
+    pub on_no_guards: NoGuardsAction,
+    /// Address to serve Prometheus metrics on. None disables the exporter.
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Format for exporting detections to a SIEM.
+    #[serde(default)]
+    pub siem_format: SiemFormat,
+    /// File SIEM lines are appended to. Required when `siem_format` is set.
+    #[serde(default)]
+    pub siem_output: Option<PathBuf>,
     /// Set vanguards and exit immediately.
     #[serde(default)]
     pub one_shot_vanguards: bool,
@@ -738,6 +1573,12 @@ pub struct Config {
     /// Enable circuit build timeout verification.
     #[serde(default)]
     pub enable_cbtverify: bool,
+    /// Build timeout rate above which cbtverify warns.
+    ///
+    /// Tor's timeout is set so that about 20% of builds time out; a rate
+    /// well above that can mean someone is failing our circuits on purpose.
+    #[serde(default = "default_cbt_max_timeout_rate")]
+    pub cbt_max_timeout_rate: f64,
     /// Enable path verification.
     #[serde(default)]
     pub enable_pathverify: bool,
@@ -753,6 +1594,22 @@ pub struct Config {
     /// Log monitoring configuration.
     #[serde(default)]
     pub logguard: LogguardConfig,
+    /// User path policies checked by pathverify.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path_policies: Vec<PathPolicy>,
+    /// Kinds of pathverify problems that close the HS circuit they are
+    /// found on. Other problems are only logged.
+    #[serde(default)]
+    pub pathverify_close_on: BTreeSet<PathViolationKind>,
+    /// Bandwidth-weights (e.g. `Wmm`) that replace the consensus values.
+    ///
+    /// Meant for exploring selection offline with `--analyze-consensus`;
+    /// leave empty in production.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bw_weight_overrides: BTreeMap<String, i64>,
+    /// Control endpoints to try, in order, when the primary is unreachable.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub control_fallbacks: Vec<ControlEndpoint>,
 }
 
 fn default_control_ip() -> String {
@@ -761,6 +1618,12 @@ fn default_control_ip() -> String {
 fn default_state_file() -> PathBuf {
     PathBuf::from("vanguards.state")
 }
+fn default_reconnect_max_backoff_secs() -> u64 {
+    60
+}
+fn default_logfile_max_files() -> u32 {
+    5
+}
 fn default_close_circuits() -> bool {
     true
 }
@@ -776,6 +1639,9 @@ fn default_enable_rendguard() -> bool {
 fn default_enable_logguard() -> bool {
     true
 }
+fn default_cbt_max_timeout_rate() -> f64 {
+    0.5
+}
 
 impl Default for Config {
     fn default() -> Self {
@@ -784,22 +1650,41 @@ impl Default for Config {
             control_port: None,
             control_socket: None,
             control_pass: None,
+            control_pass_file: None,
             state_file: default_state_file(),
+            state_format: StateFormat::default(),
+            state_backups: 0,
             loglevel: LogLevel::default(),
             logfile: None,
+            log_format: LogFormat::default(),
+            logfile_max_bytes: 0,
+            logfile_max_files: default_logfile_max_files(),
+            anonymize_fingerprints_in_logs: false,
             retry_limit: None,
+            ipc_socket: None,
+            metrics_listen: None,
+            on_no_guards: NoGuardsAction::default(),
+            siem_format: SiemFormat::default(),
+            siem_output: None,
             one_shot_vanguards: false,
+            no_interactive: false,
+            reconnect_max_backoff_secs: default_reconnect_max_backoff_secs(),
             close_circuits: default_close_circuits(),
             enable_vanguards: default_enable_vanguards(),
             enable_bandguards: default_enable_bandguards(),
             enable_rendguard: default_enable_rendguard(),
             enable_logguard: default_enable_logguard(),
             enable_cbtverify: false,
+            cbt_max_timeout_rate: default_cbt_max_timeout_rate(),
             enable_pathverify: false,
             vanguards: VanguardsConfig::default(),
             bandguards: BandguardsConfig::default(),
             rendguard: RendguardConfig::default(),
             logguard: LogguardConfig::default(),
+            path_policies: Vec::new(),
+            pathverify_close_on: BTreeSet::new(),
+            bw_weight_overrides: BTreeMap::new(),
+            control_fallbacks: Vec::new(),
         }
     }
 }
@@ -813,7 +1698,13 @@ impl Config {
     /// Returns [`Error::Config`] if the TOML is invalid.
     pub fn from_file(path: &std::path::Path) -> Result<Self> {
         let content = std::fs::read_to_string(path)?;
-        toml::from_str(&content).map_err(|e| Error::Config(e.to_string()))
+        let mut table: toml::Table =
+            toml::from_str(&content).map_err(|e| Error::Config(e.to_string()))?;
+        if !expand_control_lists(&mut table)? {
+            // Parse the text itself, whose errors carry line numbers
+            return toml::from_str(&content).map_err(|e| Error::Config(e.to_string()));
+        }
+        table.try_into().map_err(|e| Error::Config(e.to_string()))
     }
 
     /// Serialize configuration to TOML string.
@@ -825,15 +1716,88 @@ impl Config {
         toml::to_string_pretty(self).map_err(|e| Error::Config(e.to_string()))
     }
 
+    /// Serialize and re-parse this configuration, reporting fields that change.
+    ///
+    /// A config that does not survive `to_toml()` followed by parsing points to
+    /// a serialization bug (for example a serde default that disagrees with
+    /// [`Default`]), so the file written by `--generate_config` would not load
+    /// back as the same settings.
+    ///
+    /// # Returns
+    ///
+    /// One `"path: before -> after"` entry per changed field, using dotted
+    /// paths for nested sections (e.g. `"vanguards.num_layer2_guards: 4 -> 3"`).
+    /// Empty if the configuration round-trips exactly.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Config`] if serialization or re-parsing fails.
+    pub fn round_trip_changes(&self) -> Result<Vec<String>> {
+        let reloaded: Config =
+            toml::from_str(&self.to_toml()?).map_err(|e| Error::Config(e.to_string()))?;
+        let before = toml::Value::try_from(self).map_err(|e| Error::Config(e.to_string()))?;
+        let after = toml::Value::try_from(&reloaded).map_err(|e| Error::Config(e.to_string()))?;
+
+        let mut changes = Vec::new();
+        diff_values("", Some(&before), Some(&after), &mut changes);
+        Ok(changes)
+    }
+
+    /// Lists the fields that differ between `old` and this configuration.
+    ///
+    /// Entries have the same `"path: before -> after"` form as
+    /// [`Self::round_trip_changes`], with `old`'s value first.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Config`] if either configuration cannot be serialized.
+    pub fn changes_from(&self, old: &Config) -> Result<Vec<String>> {
+        let before = toml::Value::try_from(old).map_err(|e| Error::Config(e.to_string()))?;
+        let after = toml::Value::try_from(self).map_err(|e| Error::Config(e.to_string()))?;
+
+        let mut changes = Vec::new();
+        diff_values("", Some(&before), Some(&after), &mut changes);
+        Ok(changes)
+    }
+
     /// Validate configuration values.
     ///
-    /// Checks that all configuration values are within acceptable ranges
-    /// and that required fields are present.
+    /// Checks that all configuration values are within acceptable ranges,
+    /// that required fields are present and that no two settings contradict
+    /// each other. Settings that are legal but probably a mistake, listed by
+    /// [`Self::warnings`], are logged at WARN.
     ///
     /// # Errors
     ///
     /// Returns [`Error::Config`] if validation fails.
     pub fn validate(&self) -> Result<()> {
+        if self.control_port.is_some() && self.control_socket.is_some() {
+            return Err(Error::Config(
+                "control_port and control_socket are both set; the socket would always win. \
+                 Set one, and list the other under control_fallbacks if needed"
+                    .to_string(),
+            ));
+        }
+        if let Some(socket) = &self.control_socket {
+            validate_socket(socket)?;
+        }
+        if self.enable_vanguards {
+            let vanguards = &self.vanguards;
+            if vanguards.enable_layer2 && vanguards.num_layer2_guards == 0 {
+                return Err(Error::Config(
+                    "num_layer2_guards must be at least 1 with vanguards enabled \
+                     (set enable_layer2 = false to skip layer2)"
+                        .to_string(),
+                ));
+            }
+            if vanguards.enable_layer3 && vanguards.num_layer3_guards == 0 {
+                return Err(Error::Config(
+                    "num_layer3_guards must be at least 1 with vanguards enabled \
+                     (set enable_layer3 = false to skip layer3)"
+                        .to_string(),
+                ));
+            }
+        }
         if self.vanguards.min_layer2_lifetime_hours > self.vanguards.max_layer2_lifetime_hours {
             return Err(Error::Config(
                 "min_layer2_lifetime_hours must be <= max_layer2_lifetime_hours".to_string(),
@@ -844,6 +1808,62 @@ impl Config {
                 "min_layer3_lifetime_hours must be <= max_layer3_lifetime_hours".to_string(),
             ));
         }
+        if !(0.0..=0.5).contains(&self.vanguards.lifetime_jitter_fraction) {
+            return Err(Error::Config(
+                "lifetime_jitter_fraction must be between 0 and 0.5".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.vanguards.min_layer_bw_fraction) {
+            return Err(Error::Config(
+                "min_layer_bw_fraction must be between 0 and 1".to_string(),
+            ));
+        }
+        // 1.0 would leave only the single heaviest relay
+        if !(0.0..1.0).contains(&self.vanguards.min_weight_percentile) {
+            return Err(Error::Config(
+                "min_weight_percentile must be at least 0 and below 1".to_string(),
+            ));
+        }
+        if let Some(path) = &self.vanguards.asn_map_file {
+            crate::node_selection::AsRestriction::from_file(path)?;
+        }
+        if self.vanguards.exclude_min_ipv4_prefix > 32 {
+            return Err(Error::Config(
+                "exclude_min_ipv4_prefix must be at most 32".to_string(),
+            ));
+        }
+        if self.vanguards.exclude_min_ipv6_prefix > 128 {
+            return Err(Error::Config(
+                "exclude_min_ipv6_prefix must be at most 128".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.cbt_max_timeout_rate) {
+            return Err(Error::Config(
+                "cbt_max_timeout_rate must be between 0 and 1".to_string(),
+            ));
+        }
+        if self.bandguards.limit_check_interval_ms > MAX_LIMIT_CHECK_INTERVAL_MS {
+            return Err(Error::Config(format!(
+                "limit_check_interval_ms must be at most {}",
+                MAX_LIMIT_CHECK_INTERVAL_MS
+            )));
+        }
+        if self.reconnect_max_backoff_secs == 0 {
+            return Err(Error::Config(
+                "reconnect_max_backoff_secs must be at least 1".to_string(),
+            ));
+        }
+        if self.logfile_max_bytes > 0 && self.logfile_max_files == 0 {
+            return Err(Error::Config(
+                "logfile_max_files must be at least 1 when logfile_max_bytes is set".to_string(),
+            ));
+        }
+        crate::logguard::compile_warn_patterns(&self.logguard.extra_warn_patterns)?;
+        if self.siem_format != SiemFormat::None && self.siem_output.is_none() {
+            return Err(Error::Config(
+                "siem_output must be set when siem_format is enabled".to_string(),
+            ));
+        }
         if self.rendguard.use_max_use_to_bw_ratio <= 0.0 {
             return Err(Error::Config(
                 "use_max_use_to_bw_ratio must be positive".to_string(),
@@ -854,9 +1874,90 @@ impl Config {
                 "use_max_consensus_weight_churn must be non-negative".to_string(),
             ));
         }
+        for endpoint in &self.control_fallbacks {
+            match (&endpoint.socket, &endpoint.address) {
+                (Some(socket), None) => validate_socket(socket)?,
+                (None, Some(address)) if address.parse::<std::net::SocketAddr>().is_ok() => {}
+                (None, Some(address)) => {
+                    return Err(Error::Config(format!(
+                        "invalid control fallback address: {}",
+                        address
+                    )));
+                }
+                _ => {
+                    return Err(Error::Config(
+                        "each control fallback needs exactly one of socket or address".to_string(),
+                    ));
+                }
+            }
+        }
+        if let Some(key) = self.bw_weight_overrides.keys().find(|k| !is_weight_key(k)) {
+            return Err(Error::Config(format!(
+                "invalid bandwidth-weight key: {}",
+                key
+            )));
+        }
+        for policy in &self.path_policies {
+            for cc in policy
+                .require_countries
+                .iter()
+                .chain(&policy.forbid_countries)
+            {
+                if !crate::node_selection::is_valid_country_code(cc) {
+                    return Err(Error::Config(format!(
+                        "invalid country code in path policy: {}",
+                        cc
+                    )));
+                }
+            }
+            for network in policy
+                .require_networks
+                .iter()
+                .chain(&policy.forbid_networks)
+            {
+                if network.parse::<ipnetwork::IpNetwork>().is_err() {
+                    return Err(Error::Config(format!(
+                        "invalid network in path policy: {}",
+                        network
+                    )));
+                }
+            }
+        }
+        for warning in self.warnings() {
+            plog(LogLevel::Warn, &warning);
+        }
         Ok(())
     }
 
+    /// Lists settings that are legal but weaken protection, such as a
+    /// single layer2 guard.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.enable_vanguards {
+            for (layer, count) in [
+                (2, self.vanguards.layer2_guard_count()),
+                (3, self.vanguards.layer3_guard_count()),
+            ] {
+                if count == 1 {
+                    warnings.push(format!(
+                        "num_layer{}_guards is 1: every hidden service circuit shares one \
+                         layer{} relay, which is easy to find and to block",
+                        layer, layer
+                    ));
+                }
+            }
+        }
+        if self.control_pass.is_some() && self.control_pass_file.is_some() {
+            warnings.push("control_pass_file is ignored because control_pass is set".to_string());
+        }
+        if !self.close_circuits && (self.enable_bandguards || self.enable_rendguard) {
+            warnings.push(
+                "close_circuits is false: detected attacks are logged but not stopped".to_string(),
+            );
+        }
+        warnings
+    }
+
     /// Resolve hostname to IP address if control_ip is a domain name.
     ///
     /// # Errors
@@ -914,6 +2015,7 @@ impl Config {
 /// | `--control-port <PORT>` | Tor control port number (typically 9051) |
 /// | `--control-socket <PATH>` | Path to Tor control socket (e.g., /run/tor/control) |
 /// | `--control-pass <PASS>` | Tor control port password for authentication |
+/// | `--control-pass-file <PATH>` | Read the control password from a file |
 ///
 /// ## File Options
 ///
@@ -922,6 +2024,9 @@ impl Config {
 /// | `--state <FILE>` | Path to the vanguard state file [env: VANGUARDS_STATE] |
 /// | `--config <FILE>` | Path to configuration file [env: VANGUARDS_CONFIG] [default: vanguards.conf] |
 /// | `--generate_config <FILE>` | Write default config to file and exit |
+/// | `--check-config <FILE>` | Report settings that change when the file is re-saved, then exit |
+/// | `--analyze-consensus <FILE>` | Print layer2/layer3 selection probabilities for a cached consensus, then exit |
+/// | `--weights <LIST>` | Bandwidth-weight overrides, e.g. `Wmm=5000,Wmg=0` |
 ///
 /// ## Logging Options
 ///
@@ -947,6 +2052,7 @@ impl Config {
 /// |--------|-------------|
 /// | `--retry-limit <N>` | Reconnection attempt limit (default: infinite) |
 /// | `--one-shot-vanguards` | Set vanguards and exit immediately |
+/// | `--check` | Connect, authenticate and read the consensus, print OK or FAIL, then exit |
 ///
 /// ## Help Options
 ///
@@ -1020,6 +2126,27 @@ pub struct CliArgs {
     #[arg(long = "generate_config")]
     pub generate_config: Option<PathBuf>,
 
+    /// Check that a config file survives a save/load round-trip and exit.
+    ///
+    /// Loads the file, re-serializes it, and reports any setting whose value
+    /// changes on the way through. Exits non-zero if anything changed.
+    #[arg(long = "check-config")]
+    pub check_config: Option<PathBuf>,
+
+    /// Print vanguard selection probabilities for a consensus file and exit.
+    ///
+    /// Reads a `cached-microdesc-consensus` without contacting Tor. Combine
+    /// with `--weights` to see how other bandwidth-weights shift selection.
+    #[arg(long = "analyze-consensus")]
+    pub analyze_consensus: Option<PathBuf>,
+
+    /// Bandwidth-weight overrides, e.g. `Wmm=5000,Wmg=0`.
+    ///
+    /// Merged over `bw_weight_overrides` from the config file, which are in
+    /// turn merged over the weights in the consensus.
+    #[arg(long, value_parser = parse_weight_overrides)]
+    pub weights: Option<BTreeMap<String, i64>>,
+
     /// Log verbosity (DEBUG, INFO, NOTICE, WARN, ERROR).
     ///
     /// Controls the amount of output. DEBUG is most verbose, ERROR is least.
@@ -1063,7 +2190,8 @@ pub struct CliArgs {
     ///
     /// Unix domain socket path for Tor control connection.
     /// Takes precedence over TCP connection if specified.
-    /// Common paths: /run/tor/control, /var/run/tor/control
+    /// Common paths: /run/tor/control, /var/run/tor/control.
+    /// Use abstract:NAME for an abstract socket (Linux only).
     #[arg(long)]
     pub control_socket: Option<PathBuf>,
 
@@ -1074,6 +2202,14 @@ pub struct CliArgs {
     #[arg(long)]
     pub control_pass: Option<String>,
 
+    /// File containing the Tor control port password.
+    ///
+    /// Keeps the password out of the process list and shell history.
+    /// Overrides control_pass from the config file; ignored if
+    /// --control-pass is given.
+    #[arg(long)]
+    pub control_pass_file: Option<PathBuf>,
+
     /// Reconnection attempt limit (default: infinite).
     ///
     /// Maximum number of times to attempt reconnection to Tor after
@@ -1088,6 +2224,22 @@ pub struct CliArgs {
     #[arg(long)]
     pub one_shot_vanguards: bool,
 
+    /// Check that Tor can be reached and exit.
+    ///
+    /// Connects, authenticates and reads the consensus without changing
+    /// any Tor settings, prints a one-line OK or FAIL summary, and exits 0
+    /// or 1. A config that fails to load is a FAIL too, and no password is
+    /// ever prompted for. Meant for monitoring probes.
+    #[arg(long)]
+    pub check: bool,
+
+    /// Never prompt for a control password.
+    ///
+    /// Exit with an error instead if Tor requires a password that is not
+    /// configured. Use this for unattended deployments.
+    #[arg(long)]
+    pub no_interactive: bool,
+
     /// Disable vanguard selection.
     ///
     /// Prevents vanguards-rs from selecting and configuring vanguard relays.
@@ -1148,21 +2300,40 @@ impl CliArgs {
         if let Some(ref control_ip) = self.control_ip {
             config.control_ip = control_ip.clone();
         }
+        // Either one on the command line replaces the other from the config
+        // file, which validate would otherwise reject as a contradiction
         if let Some(control_port) = self.control_port {
             config.control_port = Some(control_port);
+            if self.control_socket.is_none() {
+                config.control_socket = None;
+            }
         }
         if let Some(ref control_socket) = self.control_socket {
             config.control_socket = Some(control_socket.clone());
+            if self.control_port.is_none() {
+                config.control_port = None;
+            }
         }
         if let Some(ref control_pass) = self.control_pass {
             config.control_pass = Some(control_pass.clone());
         }
+        if let Some(ref control_pass_file) = self.control_pass_file {
+            config.control_pass_file = Some(control_pass_file.clone());
+            // A file named on the command line beats a password from the
+            // config file; only --control-pass beats it
+            if self.control_pass.is_none() {
+                config.control_pass = None;
+            }
+        }
         if let Some(retry_limit) = self.retry_limit {
             config.retry_limit = Some(retry_limit);
         }
         if self.one_shot_vanguards {
             config.one_shot_vanguards = true;
         }
+        if self.no_interactive {
+            config.no_interactive = true;
+        }
         if self.disable_vanguards {
             config.enable_vanguards = false;
         }
@@ -1181,6 +2352,73 @@ impl CliArgs {
         if self.enable_pathverify {
             config.enable_pathverify = true;
         }
+        if let Some(ref weights) = self.weights {
+            config.bw_weight_overrides.extend(weights.clone());
+        }
+    }
+}
+
+/// Returns `true` for keys shaped like consensus bandwidth-weights (`Wmm`, `Wgd`, ...).
+fn is_weight_key(key: &str) -> bool {
+    key.len() == 3 && key.starts_with('W') && key[1..].chars().all(|c| c.is_ascii_lowercase())
+}
+
+/// Parses a comma-separated list of bandwidth-weight overrides.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::config::parse_weight_overrides;
+///
+/// let weights = parse_weight_overrides("Wmm=5000, Wmg=0").unwrap();
+/// assert_eq!(weights["Wmm"], 5000);
+/// assert_eq!(weights["Wmg"], 0);
+/// ```
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] for entries that are not `Wxx=<integer>`.
+pub fn parse_weight_overrides(spec: &str) -> Result<BTreeMap<String, i64>> {
+    let mut weights = BTreeMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (key, value) = entry
+            .split_once('=')
+            .filter(|(key, _)| is_weight_key(key))
+            .ok_or_else(|| {
+                Error::Config(format!("invalid bandwidth-weight override: {}", entry))
+            })?;
+        let value = value
+            .parse()
+            .map_err(|_| Error::Config(format!("invalid bandwidth-weight value: {}", entry)))?;
+        weights.insert(key.to_string(), value);
+    }
+    Ok(weights)
+}
+
+/// Recursively compare two TOML values, recording changed leaves by dotted path.
+fn diff_values(
+    path: &str,
+    before: Option<&toml::Value>,
+    after: Option<&toml::Value>,
+    changes: &mut Vec<String>,
+) {
+    if let (Some(toml::Value::Table(a)), Some(toml::Value::Table(b))) = (before, after) {
+        let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let child = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", path, key)
+            };
+            diff_values(&child, a.get(key), b.get(key), changes);
+        }
+        return;
+    }
+    if before != after {
+        let show = |v: Option<&toml::Value>| v.map_or("(unset)".to_string(), |v| v.to_string());
+        changes.push(format!("{}: {} -> {}", path, show(before), show(after)));
     }
 }
 
@@ -1207,3 +2445,301 @@ pub fn load_config(args: &CliArgs) -> Result<Config> {
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abstract_socket_prefix() {
+        assert_eq!(
+            abstract_socket_name(Path::new("abstract:tor-control")),
+            Some("tor-control")
+        );
+        assert_eq!(abstract_socket_name(Path::new("/run/tor/control")), None);
+        assert_eq!(abstract_socket_name(Path::new("./abstract:tor")), None);
+
+        let mut config = Config {
+            control_socket: Some(PathBuf::from("abstract:")),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        config.control_socket = Some(PathBuf::from("abstract:tor-control"));
+        assert_eq!(config.validate().is_ok(), cfg!(target_os = "linux"));
+    }
+
+    #[test]
+    fn test_control_lists_become_fallbacks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.conf");
+        let load = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            Config::from_file(&path).unwrap()
+        };
+
+        let config = load("control_port = 9051\n");
+        assert_eq!(config.control_port, Some(9051));
+        assert!(config.control_fallbacks.is_empty());
+
+        let config = load(
+            "control_ip = \"127.0.0.2\"\ncontrol_port = [9051, 9151]\n\n\
+             [[control_fallbacks]]\nsocket = \"/run/tor/control\"\n",
+        );
+        assert_eq!(config.control_port, Some(9051));
+        let fallbacks: Vec<String> = config
+            .control_fallbacks
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            fallbacks,
+            ["control port 127.0.0.2:9151", "socket /run/tor/control"]
+        );
+
+        let config = load("control_socket = [\"/run/tor/control\"]\n");
+        assert_eq!(
+            config.control_socket,
+            Some(PathBuf::from("/run/tor/control"))
+        );
+        assert!(config.control_fallbacks.is_empty());
+
+        std::fs::write(&path, "control_port = [\"x\"]\n").unwrap();
+        assert!(Config::from_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_default_config_round_trips() {
+        assert!(Config::default().round_trip_changes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_generated_config_loads_identically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.conf");
+        std::fs::write(&path, Config::default().to_toml().unwrap()).unwrap();
+
+        assert_eq!(Config::from_file(&path).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_parse_weight_overrides() {
+        let weights = parse_weight_overrides("Wmm=5000,Wgd=0,").unwrap();
+        assert_eq!(weights.len(), 2);
+        assert_eq!(weights["Wgd"], 0);
+        assert!(parse_weight_overrides("Wmm").is_err());
+        assert!(parse_weight_overrides("bogus=1").is_err());
+        assert!(parse_weight_overrides("Wmm=lots").is_err());
+
+        let config = Config {
+            bw_weight_overrides: weights,
+            ..Config::default()
+        };
+        assert!(config.round_trip_changes().unwrap().is_empty());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_duration_strings() {
+        let config: Config = toml::from_str(
+            "[vanguards]\nmax_layer2_lifetime_hours = \"45d\"\nmin_layer3_lifetime_hours = \"2h\"\n\
+             [bandguards]\ncirc_max_disconnected_secs = \"2m\"\nconn_max_disconnected_secs = 20\n",
+        )
+        .unwrap();
+        assert_eq!(config.vanguards.max_layer2_lifetime_hours, 1080);
+        assert_eq!(config.vanguards.min_layer3_lifetime_hours, 2);
+        assert_eq!(config.bandguards.circ_max_disconnected_secs, 120);
+        assert_eq!(config.bandguards.conn_max_disconnected_secs, 20);
+
+        assert_eq!(parse_duration("48", 3600), Ok(48));
+        assert!(parse_duration("90m", 3600)
+            .unwrap_err()
+            .contains("not a whole number of hours"));
+
+        let err = toml::from_str::<Config>("[vanguards]\nmax_layer2_lifetime_hours = \"banana\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("invalid duration \"banana\""), "{}", err);
+        assert!(
+            toml::from_str::<Config>("[bandguards]\ncirc_max_disconnected_secs = -5\n").is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_contradictions() {
+        fn rejected(mutate: impl FnOnce(&mut Config)) -> String {
+            let mut config = Config::default();
+            mutate(&mut config);
+            config.validate().unwrap_err().to_string()
+        }
+
+        assert!(rejected(|c| {
+            c.vanguards.min_layer2_lifetime_hours = 48;
+            c.vanguards.max_layer2_lifetime_hours = 24;
+        })
+        .contains("min_layer2_lifetime_hours must be <= max_layer2_lifetime_hours"));
+        assert!(rejected(|c| c.vanguards.min_layer3_lifetime_hours = 49)
+            .contains("min_layer3_lifetime_hours must be <= max_layer3_lifetime_hours"));
+        assert!(rejected(|c| c.vanguards.min_weight_percentile = 1.5)
+            .contains("min_weight_percentile must be at least 0 and below 1"));
+        assert!(rejected(|c| c.vanguards.min_weight_percentile = 1.0)
+            .contains("min_weight_percentile must be at least 0 and below 1"));
+        assert!(rejected(|c| c.vanguards.exclude_min_ipv4_prefix = 33)
+            .contains("exclude_min_ipv4_prefix must be at most 32"));
+        assert!(rejected(|c| c.vanguards.exclude_min_ipv6_prefix = 129)
+            .contains("exclude_min_ipv6_prefix must be at most 128"));
+        assert!(rejected(|c| c.vanguards.num_layer2_guards = 0)
+            .contains("num_layer2_guards must be at least 1"));
+        assert!(rejected(|c| c.vanguards.num_layer3_guards = 0)
+            .contains("num_layer3_guards must be at least 1"));
+        assert!(rejected(|c| {
+            c.control_port = Some(9051);
+            c.control_socket = Some(PathBuf::from("/run/tor/control"));
+        })
+        .contains("control_port and control_socket are both set"));
+        assert!(
+            rejected(|c| c.vanguards.asn_map_file = Some(PathBuf::from("/nonexistent/asn")))
+                .contains("cannot read ASN map /nonexistent/asn")
+        );
+
+        // Empty layers are fine once they are switched off or unused
+        let mut config = Config::default();
+        config.vanguards.num_layer2_guards = 0;
+        config.vanguards.enable_layer2 = false;
+        assert!(config.validate().is_ok());
+        config.enable_vanguards = false;
+        config.vanguards.num_layer3_guards = 0;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_cli_control_pass_file_beats_config_password() {
+        let mut config = Config {
+            control_pass: Some("from-config".to_string()),
+            ..Config::default()
+        };
+        let args = CliArgs::parse_from(["vanguards", "--control-pass-file", "/run/pass"]);
+        args.apply_to(&mut config);
+        assert_eq!(config.control_pass, None);
+        assert_eq!(config.control_pass_file, Some(PathBuf::from("/run/pass")));
+
+        let args = CliArgs::parse_from([
+            "vanguards",
+            "--control-pass",
+            "from-cli",
+            "--control-pass-file",
+            "/run/pass",
+        ]);
+        args.apply_to(&mut config);
+        assert_eq!(config.control_pass.as_deref(), Some("from-cli"));
+    }
+
+    #[test]
+    fn test_cli_control_endpoint_beats_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.conf");
+        let config_file = path.to_str().unwrap();
+
+        std::fs::write(&path, "control_socket = \"/run/tor/control\"\n").unwrap();
+        let args = CliArgs::parse_from([
+            "vanguards",
+            "--config",
+            config_file,
+            "--control-port",
+            "9151",
+        ]);
+        let config = load_config(&args).unwrap();
+        assert_eq!(config.control_port, Some(9151));
+        assert_eq!(config.control_socket, None);
+
+        std::fs::write(&path, "control_port = 9051\n").unwrap();
+        let args = CliArgs::parse_from([
+            "vanguards",
+            "--config",
+            config_file,
+            "--control-socket",
+            "/run/tor/control",
+        ]);
+        let config = load_config(&args).unwrap();
+        assert_eq!(config.control_port, None);
+        assert_eq!(
+            config.control_socket,
+            Some(PathBuf::from("/run/tor/control"))
+        );
+
+        // Both on the command line is still a contradiction
+        let args = CliArgs::parse_from([
+            "vanguards",
+            "--config",
+            config_file,
+            "--control-port",
+            "9151",
+            "--control-socket",
+            "/run/tor/control",
+        ]);
+        assert!(load_config(&args).is_err());
+    }
+
+    #[test]
+    fn test_config_warnings() {
+        assert!(Config::default().warnings().is_empty());
+
+        let mut config = Config::default();
+        config.vanguards.num_layer2_guards = 1;
+        config.close_circuits = false;
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("num_layer2_guards is 1"));
+        assert!(warnings[1].starts_with("close_circuits is false"));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_path_policies_round_trip() {
+        let config = Config {
+            path_policies: vec![PathPolicy {
+                position: PathPosition::Any,
+                forbid_flags: vec!["Exit".to_string()],
+                forbid_networks: vec!["10.0.0.0/8".to_string()],
+                ..Default::default()
+            }],
+            ..Config::default()
+        };
+        assert!(config.round_trip_changes().unwrap().is_empty());
+        assert!(config.validate().is_ok());
+
+        let mut bad = config.clone();
+        bad.path_policies[0].forbid_networks = vec!["10.0.0.0/99".to_string()];
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn test_pathverify_close_on_kinds() {
+        let config: Config =
+            toml::from_str("pathverify_close_on = [\"wrong_layer\", \"bad_length\"]\n").unwrap();
+        assert_eq!(
+            config.pathverify_close_on,
+            BTreeSet::from([PathViolationKind::BadLength, PathViolationKind::WrongLayer])
+        );
+        assert!(config.round_trip_changes().unwrap().is_empty());
+        assert!(toml::from_str::<Config>("pathverify_close_on = [\"everything\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_diff_values_reports_changed_fields() {
+        let before: toml::Value =
+            toml::from_str("control_ip = \"127.0.0.1\"\n[vanguards]\nnum_layer2_guards = 4\n")
+                .unwrap();
+        let after: toml::Value = toml::from_str("[vanguards]\nnum_layer2_guards = 3\n").unwrap();
+
+        let mut changes = Vec::new();
+        diff_values("", Some(&before), Some(&after), &mut changes);
+        assert_eq!(
+            changes,
+            vec![
+                "control_ip: \"127.0.0.1\" -> (unset)".to_string(),
+                "vanguards.num_layer2_guards: 4 -> 3".to_string(),
+            ]
+        );
+    }
+}
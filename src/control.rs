@@ -204,39 +204,112 @@
 //! - [Python vanguards control](https://github.com/mikeperry-tor/vanguards) - Original implementation
 //! - [Tor Control Protocol](https://spec.torproject.org/control-spec) - Protocol specification
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rand::Rng;
 use stem_rs::controller::{CircuitId, Controller};
 use stem_rs::descriptor::router_status::RouterStatusEntry;
 use stem_rs::events::ParsedEvent;
 use stem_rs::version::Version;
 use stem_rs::EventType;
 
-use crate::bandguards::BandwidthStats;
+use crate::api::{SecurePassword, ShutdownHandle, VanguardsStats};
+use crate::bandguards::{AttackOutcome, BandwidthStats, CircuitLimitResult, ConnectivityStatus};
 use crate::cbtverify::TimeoutStats;
-use crate::config::{Config, LogLevel};
+use crate::config::{
+    abstract_socket_name, load_config, CliArgs, Config, ControlEndpoint, LogLevel, NoGuardsAction,
+    SiemFormat, VanguardsConfig, ABSTRACT_SOCKET_PREFIX,
+};
 use crate::error::{Error, Result};
-use crate::logger::plog;
+use crate::health::ProtectionScore;
+use crate::ipc::{IpcState, VanguardEvent};
+use crate::logger::{plog, plog_with, LogContext};
 use crate::logguard::LogGuard;
-use crate::node_selection::{BwWeightedGenerator, FlagsRestriction, NodeRestrictionList, Position};
-use crate::pathverify::PathVerify;
+use crate::metrics::{Handler, HandlerLatencies, MetricsServer, MetricsSnapshot};
+use crate::node_selection::{
+    is_valid_fingerprint, AsRestriction, BwWeightedGenerator, FlagsRestriction, MinAgeRestriction,
+    NodeRestriction, NodeRestrictionList, Position, RelayFamilies,
+};
+use crate::pathverify::{PathVerify, PolicyRelay};
+use crate::siem::{Detection, SiemWriter};
 use crate::vanguards::{ExcludeNodes, VanguardState};
 
 /// Library version string.
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-/// Minimum Tor version required for CIRC_BW and CIRC_MINOR events.
-#[allow(dead_code)]
-const MIN_TOR_VERSION_FOR_BW: &str = "0.3.4.10";
+/// Features of the connected Tor that vanguards-rs depends on.
+///
+/// Computed once from `GETINFO version` when a control session starts, so
+/// event subscription and configuration agree on what Tor can do.
+///
+/// | Capability | Minimum Tor |
+/// |------------|-------------|
+/// | `has_conf_changed` | 0.2.3.3 |
+/// | `has_hslayer` | 0.3.3.0 |
+/// | `has_circ_bw` | 0.3.4.10 |
+///
+/// # Example
+///
+/// ```rust
+/// use stem_rs::version::Version;
+/// use vanguards_rs::control::TorCapabilities;
+///
+/// let caps = TorCapabilities::from_version(&Version::new(0, 3, 3).with_patch(7));
+/// assert!(caps.has_hslayer);
+/// assert!(!caps.has_circ_bw);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TorCapabilities {
+    /// `CIRC_BW` and `CIRC_MINOR` events carry the fields bandguards needs.
+    pub has_circ_bw: bool,
+    /// `HSLayer2Nodes` and `HSLayer3Nodes` are recognized.
+    pub has_hslayer: bool,
+    /// The `CONF_CHANGED` event is available.
+    pub has_conf_changed: bool,
+    /// Largest layer Tor can pin: unbounded with `has_hslayer`, 0 without.
+    pub max_hslayer_nodes: usize,
+}
 
-/// Minimum Tor version required for HSLayer2Nodes support.
-#[allow(dead_code)]
-const MIN_TOR_VERSION_FOR_VANGUARDS: &str = "0.3.3.0";
+impl TorCapabilities {
+    /// Derives the capability set from a Tor version.
+    pub fn from_version(version: &Version) -> Self {
+        let has_hslayer = *version >= Version::new(0, 3, 3).with_patch(0);
+        Self {
+            has_circ_bw: *version >= Version::new(0, 3, 4).with_patch(10),
+            has_hslayer,
+            has_conf_changed: *version >= Version::new(0, 2, 3).with_patch(3),
+            max_hslayer_nodes: if has_hslayer { usize::MAX } else { 0 },
+        }
+    }
+
+    /// Checks that every enabled layer fits within what Tor supports.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::UnsupportedTor`] naming the first enabled layer when
+    /// Tor cannot pin layers at all.
+    pub fn check_layers(&self, config: &VanguardsConfig) -> Result<()> {
+        for (option, count) in [
+            ("HSLayer2Nodes", config.layer2_guard_count()),
+            ("HSLayer3Nodes", config.layer3_guard_count()),
+        ] {
+            if usize::from(count) > self.max_hslayer_nodes {
+                return Err(Error::UnsupportedTor(format!(
+                    "{} requires Tor 0.3.3.0 or newer",
+                    option
+                )));
+            }
+        }
+        Ok(())
+    }
+}
 
 /// Global flag for close circuits configuration.
 ///
@@ -244,6 +317,36 @@ const MIN_TOR_VERSION_FOR_VANGUARDS: &str = "0.3.3.0";
 /// When false, attacks are logged but circuits remain open (monitoring mode).
 static CLOSE_CIRCUITS: AtomicBool = AtomicBool::new(true);
 
+/// Serializes tests that toggle [`CLOSE_CIRCUITS`].
+#[cfg(test)]
+pub(crate) static CLOSE_CIRCUITS_TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Set by the `SIGUSR2` handler; the event loop logs
+/// [`AppState::state_summary`] and clears it.
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set once the loose permissions of `control_pass_file` have been
+/// reported, so reconnects do not repeat the warning.
+static PASSWORD_FILE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// The ASN map last read, with the file it came from, so that it is not
+/// read again on every consensus. Cleared when the configuration is
+/// reloaded.
+static ASN_MAP: std::sync::Mutex<Option<(PathBuf, AsRestriction)>> = std::sync::Mutex::new(None);
+
+/// Arguments the configuration was loaded with, for reloading it on SIGHUP.
+static RELOAD_ARGS: std::sync::Mutex<Option<CliArgs>> = std::sync::Mutex::new(None);
+
+/// Remembers the command-line arguments the configuration was loaded with.
+///
+/// When Tor reports a SIGHUP, the config file is then loaded again with
+/// the same overrides and its thresholds applied without reconnecting. See
+/// [`AppState::apply_reloaded_config`] for which settings change at once.
+/// Without this, SIGHUP only reapplies the vanguards to Tor.
+pub fn set_reload_args(args: CliArgs) {
+    *RELOAD_ARGS.lock().unwrap_or_else(|e| e.into_inner()) = Some(args);
+}
+
 /// Sets the global close circuits flag.
 ///
 /// Controls whether circuits are actually closed when attacks are detected.
@@ -302,15 +405,23 @@ pub fn get_close_circuits() -> bool {
     CLOSE_CIRCUITS.load(Ordering::SeqCst)
 }
 
+/// HMAC key for the hash Tor sends during SAFECOOKIE authentication.
+const SAFECOOKIE_SERVER_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+
+/// HMAC key for the hash vanguards sends during SAFECOOKIE authentication.
+const SAFECOOKIE_CLIENT_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
 /// Authenticates with Tor using any available method.
 ///
-/// Attempts authentication in this order:
+/// The methods Tor offers are read from `PROTOCOLINFO`, then one is used:
 /// 1. No authentication (if control port is open)
-/// 2. Password authentication (if provided)
-/// 3. Cookie authentication
+/// 2. Password authentication (if a password is provided)
+/// 3. SAFECOOKIE challenge-response (if the cookie file is readable)
+/// 4. Cookie authentication (if the cookie file is readable)
+/// 5. Password authentication without a password
 ///
-/// If password authentication fails and no password was provided,
-/// prompts the user interactively for a password.
+/// When Tor requires a password and none was provided, prompts the user
+/// interactively for one. Use [`authenticate_any_with`] to fail instead.
 ///
 /// # Arguments
 ///
@@ -321,7 +432,37 @@ pub fn get_close_circuits() -> bool {
 ///
 /// Returns [`Error::Control`] if authentication fails.
 pub async fn authenticate_any(controller: &mut Controller, password: Option<&str>) -> Result<()> {
-    let result = controller.authenticate(password).await;
+    authenticate_any_with(controller, password, false).await
+}
+
+/// Authenticates like [`authenticate_any`], optionally without prompting.
+///
+/// With `no_interactive` set, a Tor that requires a password nobody
+/// configured is an error rather than a prompt on the terminal.
+///
+/// # Errors
+///
+/// Returns [`Error::Control`] if authentication fails, or [`Error::Config`]
+/// if Tor requires a password that is not configured and `no_interactive`
+/// is set.
+pub async fn authenticate_any_with(
+    controller: &mut Controller,
+    password: Option<&str>,
+    no_interactive: bool,
+) -> Result<()> {
+    authenticate_prompting(controller, password, no_interactive)
+        .await
+        .map(|_| ())
+}
+
+/// Authenticates like [`authenticate_any_with`], returning the password
+/// typed at the prompt, if any, so a second connection can reuse it.
+async fn authenticate_prompting(
+    controller: &mut Controller,
+    password: Option<&str>,
+    no_interactive: bool,
+) -> Result<Option<SecurePassword>> {
+    let result = authenticate_controller(controller, password).await;
 
     match result {
         Ok(()) => {
@@ -333,12 +474,20 @@ pub async fn authenticate_any(controller: &mut Controller, password: Option<&str
                     VERSION, version
                 ),
             );
-            Ok(())
+            Ok(None)
+        }
+        Err(stem_rs::Error::Authentication(stem_rs::AuthError::MissingPassword))
+            if no_interactive =>
+        {
+            Err(Error::Config(
+                "Tor requires a control password but none is configured (no_interactive is set)"
+                    .to_string(),
+            ))
         }
         Err(stem_rs::Error::Authentication(stem_rs::AuthError::MissingPassword)) => {
             // Prompt for password interactively
             let passwd = prompt_password()?;
-            controller.authenticate(Some(&passwd)).await?;
+            authenticate_password(controller, &passwd).await?;
             let version = controller.get_version().await?;
             plog(
                 LogLevel::Notice,
@@ -347,12 +496,171 @@ pub async fn authenticate_any(controller: &mut Controller, password: Option<&str
                     VERSION, version
                 ),
             );
-            Ok(())
+            Ok(Some(SecurePassword::new(passwd)))
         }
         Err(e) => Err(Error::Control(e)),
     }
 }
 
+/// Picks an authentication method from `PROTOCOLINFO` and authenticates.
+///
+/// Tor allows only one `PROTOCOLINFO` before authentication, so this sends
+/// the `AUTHENTICATE` commands itself instead of calling
+/// [`Controller::authenticate`] after querying the methods.
+async fn authenticate_controller(
+    controller: &mut Controller,
+    password: Option<&str>,
+) -> std::result::Result<(), stem_rs::Error> {
+    use stem_rs::AuthError;
+
+    let info = controller.get_protocolinfo().await?;
+    let offers = |method: &str| info.auth_methods.iter().any(|m| m == method);
+    if offers("NULL") {
+        return auth_command(controller, "AUTHENTICATE", AuthError::SecurityFailure).await;
+    }
+    if let (true, Some(password)) = (offers("HASHEDPASSWORD"), password) {
+        return authenticate_password(controller, password).await;
+    }
+
+    // A cookie that cannot be read has not been sent yet, so a password can
+    // still be tried afterwards.
+    let mut cookie_error = None;
+    if let Some(path) = info.cookie_file.as_deref().map(Path::new) {
+        if offers("SAFECOOKIE") {
+            match read_auth_cookie(path) {
+                Ok(cookie) => return authenticate_safecookie(controller, &cookie).await,
+                Err(e) => cookie_error = Some(e),
+            }
+        } else if offers("COOKIE") {
+            match read_auth_cookie(path) {
+                Ok(cookie) => {
+                    let command = format!("AUTHENTICATE {}", hex_encode(&cookie));
+                    return auth_command(controller, &command, AuthError::IncorrectCookie).await;
+                }
+                Err(e) => cookie_error = Some(e),
+            }
+        }
+    }
+
+    if offers("HASHEDPASSWORD") {
+        return Err(stem_rs::Error::Authentication(AuthError::MissingPassword));
+    }
+    Err(cookie_error.unwrap_or(stem_rs::Error::Authentication(AuthError::NoMethods)))
+}
+
+/// Sends an `AUTHENTICATE` with a password.
+async fn authenticate_password(
+    controller: &mut Controller,
+    password: &str,
+) -> std::result::Result<(), stem_rs::Error> {
+    let command = format!("AUTHENTICATE {}", hex_encode(password.as_bytes()));
+    auth_command(controller, &command, stem_rs::AuthError::IncorrectPassword).await
+}
+
+/// Performs the SAFECOOKIE handshake: `AUTHCHALLENGE` with a random nonce,
+/// a check of Tor's hash to prove it knows the cookie, then `AUTHENTICATE`
+/// with our own hash. The cookie itself never goes over the socket.
+async fn authenticate_safecookie(
+    controller: &mut Controller,
+    cookie: &[u8],
+) -> std::result::Result<(), stem_rs::Error> {
+    use stem_rs::AuthError;
+
+    let client_nonce: [u8; 32] = rand::thread_rng().gen();
+    let reply = controller
+        .msg(&format!(
+            "AUTHCHALLENGE SAFECOOKIE {}",
+            hex_encode(&client_nonce)
+        ))
+        .await
+        .map_err(|e| match e {
+            stem_rs::Error::OperationFailed { .. } => {
+                stem_rs::Error::Authentication(AuthError::ChallengeUnsupported)
+            }
+            e => e,
+        })?;
+
+    let field = |name: &str| {
+        reply
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix(name))
+            .and_then(hex_decode)
+    };
+    let (Some(server_hash), Some(server_nonce)) = (field("SERVERHASH="), field("SERVERNONCE="))
+    else {
+        return Err(stem_rs::Error::Protocol(format!(
+            "malformed AUTHCHALLENGE reply: {}",
+            reply.trim()
+        )));
+    };
+
+    let expected = safecookie_hmac(SAFECOOKIE_SERVER_KEY, cookie, &client_nonce, &server_nonce);
+    if !stem_rs::util::secure_compare(&server_hash, &expected) {
+        return Err(stem_rs::Error::Authentication(AuthError::ChallengeFailed));
+    }
+
+    let client_hash = safecookie_hmac(SAFECOOKIE_CLIENT_KEY, cookie, &client_nonce, &server_nonce);
+    let command = format!("AUTHENTICATE {}", hex_encode(&client_hash));
+    auth_command(controller, &command, AuthError::ChallengeFailed).await
+}
+
+/// Sends one authentication command, reporting a refusal as `refused`.
+async fn auth_command(
+    controller: &mut Controller,
+    command: &str,
+    refused: stem_rs::AuthError,
+) -> std::result::Result<(), stem_rs::Error> {
+    match controller.msg(command).await {
+        Ok(_) => Ok(()),
+        Err(stem_rs::Error::OperationFailed { .. }) => Err(stem_rs::Error::Authentication(refused)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads Tor's 32-byte authentication cookie.
+fn read_auth_cookie(path: &Path) -> std::result::Result<Vec<u8>, stem_rs::Error> {
+    use stem_rs::AuthError;
+
+    let cookie = std::fs::read(path).map_err(|e| {
+        stem_rs::Error::Authentication(AuthError::CookieUnreadable(format!(
+            "{}: {}",
+            path.display(),
+            e
+        )))
+    })?;
+    if cookie.len() != 32 {
+        return Err(stem_rs::Error::Authentication(
+            AuthError::IncorrectCookieSize,
+        ));
+    }
+    Ok(cookie)
+}
+
+/// HMAC-SHA256 keyed with `key` over `cookie || client_nonce || server_nonce`.
+fn safecookie_hmac(key: &[u8], cookie: &[u8], client_nonce: &[u8], server_nonce: &[u8]) -> Vec<u8> {
+    use hmac::{Hmac, Mac};
+
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(cookie);
+    mac.update(client_nonce);
+    mac.update(server_nonce);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
 /// Prompts the user for a password interactively.
 fn prompt_password() -> Result<String> {
     eprint!("Controller password: ");
@@ -363,6 +671,109 @@ fn prompt_password() -> Result<String> {
     Ok(password.trim().to_string())
 }
 
+/// Reads the control password from `path`.
+///
+/// One trailing newline is removed; other whitespace is part of the
+/// password. On Unix, a file other users can access is still used, but a
+/// warning is logged the first time it is read.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] if the file cannot be read.
+pub fn read_password_file(path: &Path) -> Result<SecurePassword> {
+    let mut password = std::fs::read_to_string(path).map_err(|e| {
+        Error::Config(format!(
+            "failed to read control_pass_file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    if let Some(warning) = password_file_warning(path) {
+        if !PASSWORD_FILE_WARNED.swap(true, Ordering::SeqCst) {
+            plog(LogLevel::Warn, &warning);
+        }
+    }
+    if password.ends_with('\n') {
+        password.pop();
+        if password.ends_with('\r') {
+            password.pop();
+        }
+    }
+    Ok(SecurePassword::new(password))
+}
+
+/// Describes what is wrong with the permissions of a password file, if
+/// anything.
+#[cfg(unix)]
+fn password_file_warning(path: &Path) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path).ok()?.permissions().mode() & 0o777;
+    (mode & 0o077 != 0).then(|| {
+        format!(
+            "control_pass_file {} has mode {:04o}; run chmod 600 on it so other users \
+             cannot read the password",
+            path.display(),
+            mode
+        )
+    })
+}
+
+#[cfg(not(unix))]
+fn password_file_warning(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Returns the password to authenticate with: `control_pass` if set,
+/// otherwise the contents of `control_pass_file`.
+///
+/// `None` leaves [`authenticate_any`] to prompt if Tor wants a password.
+pub(crate) fn configured_password(config: &Config) -> Result<Option<SecurePassword>> {
+    if let Some(ref password) = config.control_pass {
+        return Ok(Some(SecurePassword::new(password.clone())));
+    }
+    config
+        .control_pass_file
+        .as_deref()
+        .map(read_password_file)
+        .transpose()
+}
+
+/// `GETINFO` key for the current consensus, used when the cached file is
+/// unreadable.
+const CONSENSUS_GETINFO_KEY: &str = "dir/status-vote/current/consensus";
+
+/// Consensus files Tor may keep in its cache directory, in the order tried.
+///
+/// Clients cache the microdesc flavor; relays, bridges and clients with
+/// `UseMicrodescriptors 0` cache the full one.
+const CONSENSUS_FILENAMES: [&str; 2] = ["cached-microdesc-consensus", "cached-consensus"];
+
+/// Returns the first consensus file found in `dirs`.
+///
+/// Every name in [`CONSENSUS_FILENAMES`] is tried in the first directory
+/// before moving on to the next.
+///
+/// # Errors
+///
+/// Returns [`Error::Consensus`] listing every path tried if none exists.
+fn find_consensus_file(dirs: &[String]) -> Result<std::path::PathBuf> {
+    let mut tried = Vec::new();
+    for dir in dirs {
+        for name in CONSENSUS_FILENAMES {
+            let path = Path::new(dir).join(name);
+            if path.is_file() {
+                return Ok(path);
+            }
+            tried.push(path.display().to_string());
+        }
+    }
+    Err(Error::Consensus(format!(
+        "no cached consensus found; tried {}",
+        tried.join(", ")
+    )))
+}
+
 /// Parses consensus bandwidth weights from a cached-microdesc-consensus file.
 ///
 /// Bandwidth weights are used by Tor clients to select relays proportionally
@@ -395,6 +806,10 @@ fn prompt_password() -> Result<String> {
 /// Returns [`Error::Consensus`] if:
 /// - The file cannot be opened or read
 /// - No `bandwidth-weights` line is found in the file
+/// - A key from [`CRITICAL_WEIGHT_KEYS`] appears twice with different values
+///
+/// Other repeated keys are logged at WARN, since honest authorities never
+/// produce them, and the last value wins.
 ///
 /// # File Format
 ///
@@ -426,31 +841,47 @@ fn prompt_password() -> Result<String> {
 /// - [`BwWeightedGenerator`] - Uses these weights
 /// - [dir-spec.txt](https://spec.torproject.org/dir-spec) - Consensus format specification
 pub fn get_consensus_weights(consensus_filename: &Path) -> Result<HashMap<String, i64>> {
-    let file = std::fs::File::open(consensus_filename).map_err(|e| {
+    let consensus = std::fs::read_to_string(consensus_filename).map_err(|e| {
         Error::Consensus(format!(
             "cannot read {}: {}",
             consensus_filename.display(),
             e
         ))
     })?;
-    let reader = BufReader::new(file);
-
-    let mut weights = HashMap::new();
+    parse_bandwidth_weights(&consensus)
+}
 
-    for line in reader.lines() {
-        let line = line.map_err(|e| Error::Consensus(format!("read error: {}", e)))?;
-        if line.starts_with("bandwidth-weights ") {
-            // Parse bandwidth-weights line
-            // Format: bandwidth-weights Wbd=0 Wbe=0 Wbg=4194 Wbm=10000 ...
-            for part in line.split_whitespace().skip(1) {
-                if let Some((key, value)) = part.split_once('=') {
-                    if let Ok(v) = value.parse::<i64>() {
-                        weights.insert(key.to_string(), v);
-                    }
-                }
-            }
-            break;
-        }
+/// Parses the bandwidth weights out of a consensus document.
+///
+/// This is [`get_consensus_weights`] for a consensus already in memory, such
+/// as one fetched with `GETINFO dir/status-vote/current/consensus`.
+///
+/// # Errors
+///
+/// Returns [`Error::Consensus`] if there is no `bandwidth-weights` line or
+/// a key from [`CRITICAL_WEIGHT_KEYS`] appears twice with different values.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::control::parse_bandwidth_weights;
+///
+/// let consensus = "network-status-version 3\nbandwidth-weights Wmd=0 Wmm=10000\n";
+/// let weights = parse_bandwidth_weights(consensus).unwrap();
+/// assert_eq!(weights["Wmm"], 10000);
+/// ```
+pub fn parse_bandwidth_weights(consensus: &str) -> Result<HashMap<String, i64>> {
+    let line = consensus
+        .lines()
+        .find(|line| line.starts_with("bandwidth-weights "))
+        .ok_or_else(|| Error::Consensus("no bandwidth-weights found in consensus".to_string()))?;
+
+    let (weights, duplicates) = parse_weight_line(line)?;
+    for message in duplicates {
+        plog(
+            LogLevel::Warn,
+            &format!("{}. The consensus may have been tampered with.", message),
+        );
     }
 
     if weights.is_empty() {
@@ -462,6 +893,268 @@ pub fn get_consensus_weights(consensus_filename: &Path) -> Result<HashMap<String
     Ok(weights)
 }
 
+/// Weights guard selection depends on: the middle-position weights vanguards
+/// are drawn with and the exit-position weights rendguard repairs exits with.
+pub const CRITICAL_WEIGHT_KEYS: [&str; 7] = ["Wmd", "Wme", "Wmg", "Wmm", "Wed", "Wee", "Weg"];
+
+/// Parses a `bandwidth-weights` line.
+///
+/// # Returns
+///
+/// The weights, and a description of each repeated key.
+///
+/// # Errors
+///
+/// Returns [`Error::Consensus`] if a critical key repeats with a different value.
+fn parse_weight_line(line: &str) -> Result<(HashMap<String, i64>, Vec<String>)> {
+    let mut weights = HashMap::new();
+    let mut duplicates = Vec::new();
+
+    // Format: bandwidth-weights Wbd=0 Wbe=0 Wbg=4194 Wbm=10000 ...
+    for part in line.split_whitespace().skip(1) {
+        let Some((key, value)) = part.split_once('=') else {
+            continue;
+        };
+        let Ok(v) = value.parse::<i64>() else {
+            continue;
+        };
+        let Some(previous) = weights.insert(key.to_string(), v) else {
+            continue;
+        };
+        if previous != v && CRITICAL_WEIGHT_KEYS.contains(&key) {
+            return Err(Error::Consensus(format!(
+                "bandwidth weight {} given conflicting values {} and {}",
+                key, previous, v
+            )));
+        }
+        duplicates.push(format!(
+            "Bandwidth weight {} appears more than once ({} then {})",
+            key, previous, v
+        ));
+    }
+
+    Ok((weights, duplicates))
+}
+
+/// Reads the `valid-after` time from a cached consensus file.
+///
+/// The timestamp says when the consensus became valid, so comparing it with
+/// the current time shows how stale the relay information vanguards is
+/// working from has become.
+///
+/// # Arguments
+///
+/// * `consensus_filename` - Path to the cached consensus file
+///
+/// # Errors
+///
+/// Returns [`Error::Consensus`] if the file cannot be read or has no
+/// parseable `valid-after` line.
+pub fn get_consensus_valid_after(consensus_filename: &Path) -> Result<DateTime<Utc>> {
+    read_consensus_time(consensus_filename, "valid-after")
+}
+
+/// Reads the first `keyword` timestamp line from a cached consensus file.
+fn read_consensus_time(consensus_filename: &Path, keyword: &str) -> Result<DateTime<Utc>> {
+    let file = std::fs::File::open(consensus_filename).map_err(|e| {
+        Error::Consensus(format!(
+            "cannot read {}: {}",
+            consensus_filename.display(),
+            e
+        ))
+    })?;
+
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| Error::Consensus(format!("read error: {}", e)))?;
+        if let Some(value) = line.strip_prefix(keyword).and_then(|v| v.strip_prefix(' ')) {
+            return parse_consensus_time(value, keyword);
+        }
+    }
+
+    Err(Error::Consensus(format!(
+        "no {} found in consensus",
+        keyword
+    )))
+}
+
+/// Parses the `valid-until` time out of a consensus document in memory.
+///
+/// Tor stops using a consensus once `valid-until` has passed, so a cached
+/// consensus past that point means Tor has not fetched a newer one, usually
+/// because of a wrong clock or no network.
+///
+/// # Errors
+///
+/// Returns [`Error::Consensus`] if there is no parseable `valid-until` line.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::control::parse_consensus_valid_until;
+///
+/// let consensus = "network-status-version 3\nvalid-until 2024-01-01 03:00:00\n";
+/// let until = parse_consensus_valid_until(consensus).unwrap();
+/// assert_eq!(until.to_rfc3339(), "2024-01-01T03:00:00+00:00");
+/// ```
+pub fn parse_consensus_valid_until(consensus: &str) -> Result<DateTime<Utc>> {
+    parse_consensus_validity(consensus).1
+}
+
+/// Parses `valid-after` and `valid-until` out of a consensus document in one
+/// pass, stopping once both are found. Both sit in the header, ahead of the
+/// relay list.
+fn parse_consensus_validity(consensus: &str) -> (Result<DateTime<Utc>>, Result<DateTime<Utc>>) {
+    let mut valid_after = None;
+    let mut valid_until = None;
+    for line in consensus.lines() {
+        if let Some(value) = line.strip_prefix("valid-after ") {
+            valid_after.get_or_insert(value);
+        } else if let Some(value) = line.strip_prefix("valid-until ") {
+            valid_until.get_or_insert(value);
+        }
+        if valid_after.is_some() && valid_until.is_some() {
+            break;
+        }
+    }
+    let parse = |value: Option<&str>, keyword: &str| {
+        value
+            .ok_or_else(|| Error::Consensus(format!("no {} found in consensus", keyword)))
+            .and_then(|value| parse_consensus_time(value, keyword))
+    };
+    (
+        parse(valid_after, "valid-after"),
+        parse(valid_until, "valid-until"),
+    )
+}
+
+fn parse_consensus_time(value: &str, keyword: &str) -> Result<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(value.trim(), "%Y-%m-%d %H:%M:%S")
+        .map(|t| t.and_utc())
+        .map_err(|e| Error::Consensus(format!("invalid {}: {}", keyword, e)))
+}
+
+/// Reads relay families from the microdescriptors in Tor's `data_dir`.
+///
+/// The microdesc consensus maps each relay to the SHA-256 digest of its
+/// microdescriptor; the `family` line of the matching microdescriptor in
+/// `cached-microdescs` or `cached-microdescs.new` lists the relays it claims.
+/// Relays whose microdescriptor is missing are treated as having no family.
+///
+/// # Errors
+///
+/// Returns [`Error::Consensus`] if the microdesc consensus cannot be read.
+fn read_relay_families(data_dir: &Path) -> Result<RelayFamilies> {
+    use sha2::{Digest, Sha256};
+
+    let consensus_file = data_dir.join("cached-microdesc-consensus");
+    let consensus = std::fs::read_to_string(&consensus_file).map_err(|e| {
+        Error::Consensus(format!("cannot read {}: {}", consensus_file.display(), e))
+    })?;
+
+    let mut by_digest = HashMap::new();
+    let mut identity = None;
+    for line in consensus.lines() {
+        if let Some(r) = line.strip_prefix("r ") {
+            identity = r
+                .split(' ')
+                .nth(1)
+                .and_then(|b64| decode_base64_fingerprint(b64).ok());
+        } else if let Some(digest) = line.strip_prefix("m ") {
+            if let (Some(fp), Some(digest)) = (identity.take(), base64_decode(digest.trim())) {
+                by_digest.insert(digest, fp);
+            }
+        }
+    }
+
+    let mut declared = HashMap::new();
+    for name in ["cached-microdescs", "cached-microdescs.new"] {
+        let Ok(text) = std::fs::read_to_string(data_dir.join(name)) else {
+            continue;
+        };
+        for md in split_microdescriptors(&text) {
+            let Some(fp) = by_digest.get(Sha256::digest(md.as_bytes()).as_slice()) else {
+                continue;
+            };
+            let family: Vec<String> = md
+                .lines()
+                .filter_map(|line| line.strip_prefix("family "))
+                .flat_map(|members| members.split(' '))
+                .filter_map(|member| member.strip_prefix('$'))
+                .filter_map(|member| member.get(..40))
+                .filter(|hex| is_valid_fingerprint(hex))
+                .map(str::to_string)
+                .collect();
+            declared.insert(fp.clone(), family);
+        }
+    }
+    Ok(RelayFamilies::from_declared(&declared))
+}
+
+/// Splits a microdescriptor cache into the signed text of each entry, from
+/// its `onion-key` line up to the next entry or `@` annotation.
+fn split_microdescriptors(text: &str) -> Vec<&str> {
+    let mut starts: Vec<usize> = Vec::new();
+    let mut ends: Vec<usize> = Vec::new();
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if line.starts_with("onion-key") || line.starts_with('@') {
+            if starts.len() > ends.len() {
+                ends.push(offset);
+            }
+            if line.starts_with("onion-key") {
+                starts.push(offset);
+            }
+        }
+        offset += line.len();
+    }
+    if starts.len() > ends.len() {
+        ends.push(text.len());
+    }
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(|(start, end)| &text[start..end])
+        .collect()
+}
+
+/// Computes vanguard selection probabilities from a consensus file, offline.
+///
+/// Applies the same flag restrictions as live guard selection and merges
+/// `config.bw_weight_overrides` over the file's bandwidth-weights, so
+/// researchers can see how a weight change would shift selection without
+/// waiting for Tor to fetch a new consensus.
+///
+/// # Arguments
+///
+/// * `consensus_filename` - Path to a `cached-microdesc-consensus` file
+/// * `config` - Configuration supplying restrictions and weight overrides
+///
+/// # Returns
+///
+/// `(fingerprint, probability)` pairs for every eligible relay, most likely first.
+///
+/// # Errors
+///
+/// Returns [`Error::Consensus`] if the file cannot be read or lacks
+/// bandwidth-weights, and [`Error::NoNodesRemain`] if no relay is eligible.
+pub fn analyze_consensus(consensus_filename: &Path, config: &Config) -> Result<Vec<(String, f64)>> {
+    let content = std::fs::read_to_string(consensus_filename).map_err(|e| {
+        Error::Consensus(format!(
+            "cannot read {}: {}",
+            consensus_filename.display(),
+            e
+        ))
+    })?;
+    let mut weights = get_consensus_weights(consensus_filename)?;
+    weights.extend(config.bw_weight_overrides.clone());
+
+    let routers = parse_network_statuses(&content)?;
+    let mut probabilities =
+        vanguard_generator(routers, &weights, config, None)?.selection_probabilities();
+    probabilities.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(probabilities)
+}
+
 /// Attempts to close a circuit, optionally dumping logs first.
 ///
 /// This function is called when an attack is detected and a circuit needs
@@ -480,11 +1173,16 @@ pub fn get_consensus_weights(consensus_filename: &Path) -> Result<HashMap<String
 /// 2. If `close_circuits` global flag is true, sends CLOSECIRCUIT command
 /// 3. Logs success or failure of the close operation
 ///
+/// # Returns
+///
+/// `true` if Tor accepted the CLOSECIRCUIT command.
+///
 /// # Global Flag
 ///
 /// The `close_circuits` flag (set via [`set_close_circuits`]) controls whether
-/// circuits are actually closed. When false, the function logs but doesn't close.
-/// This is useful for testing or monitoring-only mode.
+/// circuits are actually closed. When false, the function logs at NOTICE that
+/// the circuit was left open in monitoring mode, so a detection without a
+/// close is never mistaken for one that was acted on.
 ///
 /// # Example
 ///
@@ -510,29 +1208,78 @@ pub async fn try_close_circuit(
     controller: &mut Controller,
     circ_id: &str,
     logguard: Option<&mut LogGuard>,
-) {
+) -> bool {
     // Dump logs before closing
     if let Some(lg) = logguard {
         lg.dump_log_queue(circ_id, "Pre");
     }
 
-    if get_close_circuits() {
+    let context = LogContext::circuit(circ_id);
+    if let Some(reason) = close_suppression(false, get_close_circuits()) {
+        plog_with(
+            LogLevel::Notice,
+            &suppressed_close_message(circ_id, reason),
+            &context,
+        );
+    } else {
         let circuit_id = CircuitId::new(circ_id);
         match controller.close_circuit(&circuit_id).await {
             Ok(()) => {
-                plog(
-                    LogLevel::Info,
-                    &format!("We force-closed circuit {}", circ_id),
-                );
+                plog_with(LogLevel::Info, "We force-closed circuit", &context);
+                return true;
             }
             Err(e) => {
-                plog(
+                plog_with(
                     LogLevel::Info,
-                    &format!("Failed to close circuit {}: {}", circ_id, e),
+                    &format!("Failed to close circuit: {}", e),
+                    &context,
                 );
             }
         }
     }
+    false
+}
+
+/// Why a circuit with a detection was left open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CloseSuppression {
+    /// `close_circuits` is off, or closing was paused over IPC.
+    MonitoringMode,
+    /// The detection was injected with the IPC `simulate` command.
+    Synthetic,
+}
+
+impl std::fmt::Display for CloseSuppression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseSuppression::MonitoringMode => {
+                write!(f, "monitoring mode (close_circuits off or paused)")
+            }
+            CloseSuppression::Synthetic => write!(f, "synthetic detection"),
+        }
+    }
+}
+
+/// Returns why a detection on a circuit must not close it, if anything.
+///
+/// Synthetic detections take precedence, since they are never closed even
+/// when enforcing.
+fn close_suppression(synthetic: bool, enforcing: bool) -> Option<CloseSuppression> {
+    if synthetic {
+        Some(CloseSuppression::Synthetic)
+    } else if !enforcing {
+        Some(CloseSuppression::MonitoringMode)
+    } else {
+        None
+    }
+}
+
+/// Log line for a detection that was deliberately not acted on.
+fn suppressed_close_message(circ_id: &str, reason: CloseSuppression) -> String {
+    format!(
+        "Detection on circuit {} NOT acted on: circuit left open ({}).",
+        circ_id, reason
+    )
 }
 
 /// Configures Tor with the current vanguard settings.
@@ -548,8 +1295,12 @@ pub async fn try_close_circuit(
 /// | `NumEntryGuards` | Number of layer 1 guards | If > 0 |
 /// | `NumDirectoryGuards` | Number of directory guards | If > 0 |
 /// | `GuardLifetime` | Layer 1 guard lifetime | If > 0 days |
-/// | `HSLayer2Nodes` | Layer 2 guard fingerprints | Always |
-/// | `HSLayer3Nodes` | Layer 3 guard fingerprints | If num_layer3 > 0 |
+/// | `HSLayer2Nodes` | Layer 2 guard fingerprints | If layer2 enabled and num_layer2 > 0 |
+/// | `HSLayer3Nodes` | Layer 3 guard fingerprints | If layer3 enabled and num_layer3 > 0 |
+/// | `StrictNodes` | `1` | If `strict_layers` |
+///
+/// A Tor that answers `552 Unrecognized option` to `StrictNodes` is logged at
+/// NOTICE and configured without it.
 ///
 /// # Arguments
 ///
@@ -563,8 +1314,10 @@ pub async fn try_close_circuit(
 ///
 /// # Errors
 ///
-/// Returns [`Error::Control`] if Tor configuration fails. This typically
-/// indicates an incompatible Tor version (requires 0.3.3.x or newer).
+/// Returns [`Error::UnsupportedTor`] if Tor answers `552 Unrecognized option`
+/// to `HSLayer2Nodes` or `HSLayer3Nodes`, which means it is older than
+/// 0.3.3.x. Returns [`Error::Control`] for any other failure to apply the
+/// configuration, such as a guard set Tor refuses to parse.
 ///
 /// # Tor Version Requirements
 ///
@@ -599,9 +1352,31 @@ pub async fn configure_tor(
     controller: &mut Controller,
     state: &VanguardState,
     config: &Config,
+) -> Result<()> {
+    configure_tor_with(controller, state, config, None).await
+}
+
+/// Body of [`configure_tor`], refusing early when `caps` rules out the layers.
+///
+/// Without known capabilities, an old Tor is only detected once it reports
+/// a layer option as unrecognized.
+async fn configure_tor_with(
+    controller: &mut Controller,
+    state: &VanguardState,
+    config: &Config,
+    caps: Option<&TorCapabilities>,
 ) -> Result<()> {
     let vg_config = &config.vanguards;
 
+    if let Some(caps) = caps {
+        caps.check_layers(vg_config).inspect_err(|_| {
+            plog(
+                LogLevel::Error,
+                "Vanguards requires Tor 0.3.3.x (and ideally 0.3.4.x or newer).",
+            )
+        })?;
+    }
+
     // Set NumEntryGuards and NumDirectoryGuards if configured
     if vg_config.num_layer1_guards > 0 {
         controller
@@ -625,31 +1400,65 @@ pub async fn configure_tor(
             .await?;
     }
 
-    // Set HSLayer2Nodes
-    let layer2_guardset = state.layer2_guardset();
-    controller
-        .set_conf("HSLayer2Nodes", &layer2_guardset)
-        .await
-        .inspect_err(|_e| {
-            plog(
-                LogLevel::Error,
-                "Vanguards requires Tor 0.3.3.x (and ideally 0.3.4.x or newer).",
-            );
-        })?;
-
-    // Set HSLayer3Nodes if configured
-    if vg_config.num_layer3_guards > 0 {
-        let layer3_guardset = state.layer3_guardset();
+    for (option, value) in layer_conf_settings(state, vg_config) {
+        // Nothing to clear on a Tor that cannot pin layers at all
+        if value.is_empty() && caps.is_some_and(|caps| !caps.has_hslayer) {
+            continue;
+        }
         controller
-            .set_conf("HSLayer3Nodes", &layer3_guardset)
-            .await?;
+            .set_conf(option, &value)
+            .await
+            .map_err(|e| match e {
+                stem_rs::Error::OperationFailed {
+                    ref code,
+                    ref message,
+                } if code == "552" && message.starts_with("Unrecognized option") => {
+                    plog(
+                        LogLevel::Error,
+                        "Vanguards requires Tor 0.3.3.x (and ideally 0.3.4.x or newer).",
+                    );
+                    Error::UnsupportedTor(format!("Tor rejected {}: {}", option, e))
+                }
+                e => Error::Control(e),
+            })?;
     }
 
+    let mut strict = vg_config.strict_layers;
+    for (option, value) in strict_conf_settings(vg_config) {
+        match controller.set_conf(option, value).await {
+            Ok(()) => {}
+            Err(stem_rs::Error::OperationFailed {
+                ref code,
+                ref message,
+            }) if code == "552" && message.starts_with("Unrecognized option") => {
+                plog(
+                    LogLevel::Notice,
+                    &format!(
+                        "This Tor does not support {}. Continuing without strict layers.",
+                        option
+                    ),
+                );
+                strict = false;
+            }
+            Err(e) => return Err(Error::Control(e)),
+        }
+    }
     plog(
-        LogLevel::Info,
-        &format!("Layer2 guards: {}", layer2_guardset),
+        LogLevel::Notice,
+        if strict {
+            "Layer enforcement: strict (StrictNodes 1)"
+        } else {
+            "Layer enforcement: loose (StrictNodes as set in torrc)"
+        },
     );
-    if vg_config.num_layer3_guards > 0 {
+
+    if vg_config.layer2_guard_count() > 0 {
+        plog(
+            LogLevel::Info,
+            &format!("Layer2 guards: {}", state.layer2_guardset()),
+        );
+    }
+    if vg_config.layer3_guard_count() > 0 {
         plog(
             LogLevel::Info,
             &format!("Layer3 guards: {}", state.layer3_guardset()),
@@ -659,6 +1468,36 @@ pub async fn configure_tor(
     Ok(())
 }
 
+/// Returns the `HSLayer2Nodes`/`HSLayer3Nodes` settings.
+///
+/// A disabled layer gets an empty value, so Tor stops pinning a layer that
+/// was enabled before a reload.
+fn layer_conf_settings(
+    state: &VanguardState,
+    config: &VanguardsConfig,
+) -> Vec<(&'static str, String)> {
+    let guardset = |count: u8, guardset: String| if count > 0 { guardset } else { String::new() };
+    vec![
+        (
+            "HSLayer2Nodes",
+            guardset(config.layer2_guard_count(), state.layer2_guardset()),
+        ),
+        (
+            "HSLayer3Nodes",
+            guardset(config.layer3_guard_count(), state.layer3_guardset()),
+        ),
+    ]
+}
+
+/// Returns the options `strict_layers` sets after the layer options.
+fn strict_conf_settings(config: &VanguardsConfig) -> Vec<(&'static str, &'static str)> {
+    if config.strict_layers {
+        vec![("StrictNodes", "1")]
+    } else {
+        Vec::new()
+    }
+}
+
 /// Handles a new consensus event by updating vanguard state.
 ///
 /// This function is called when a new consensus is received from Tor. It performs
@@ -671,14 +1510,14 @@ pub async fn configure_tor(
 /// │                  new_consensus_event()                       │
 /// │                                                              │
 /// │  1. Get router list from Tor (GETINFO ns/all)               │
-/// │  2. Get ExcludeNodes configuration                          │
-/// │  3. Parse consensus weights from cached-microdesc-consensus │
+/// │  2. Get ExcludeNodes and ExcludeExitNodes configuration     │
+/// │  3. Parse consensus weights from the cached consensus       │
 /// │  4. Update vanguard state:                                  │
 /// │     • Remove guards no longer in consensus                  │
 /// │     • Remove expired guards                                 │
 /// │     • Remove excluded guards                                │
 /// │     • Replenish guard layers                                │
-/// │  5. Update rendguard use counts                             │
+/// │  5. Update rendguard use counts, minus ExcludeExitNodes     │
 /// │  6. Configure Tor with new HSLayer2/3Nodes                  │
 /// │  7. Write state to file                                     │
 /// └─────────────────────────────────────────────────────────────┘
@@ -694,12 +1533,19 @@ pub async fn configure_tor(
 ///
 /// Returns `Ok(())` on successful update.
 ///
+/// This function keeps no state between calls. The control loop goes through
+/// [`AppState`], which remembers the last consensus and reuses it for up to
+/// [`CONSENSUS_VALIDITY_SECS`] after its `valid-after` if Tor briefly cannot
+/// serve `ns/all`.
+///
 /// # Errors
 ///
 /// - [`Error::DescriptorUnavailable`] - Tor doesn't have descriptors yet (retry later)
-/// - [`Error::Consensus`] - Failed to parse consensus file
+/// - [`Error::Consensus`] - No cached consensus in `CacheDirectory` or
+///   `DataDirectory`, or it failed to parse
 /// - [`Error::Config`] - DataDirectory not configured in Tor
 /// - [`Error::Control`] - Failed to configure Tor
+/// - [`Error::UnsupportedTor`] - Tor is too old to support vanguards
 ///
 /// # Example
 ///
@@ -731,12 +1577,72 @@ pub async fn new_consensus_event(
     state: &mut VanguardState,
     config: &Config,
 ) -> Result<()> {
-    // Get routers from Tor
-    let routers = get_network_statuses(controller).await?;
+    apply_consensus(controller, state, config, None, None)
+        .await
+        .map(|_| ())
+}
+
+/// How long after its `valid-after` a consensus may still be used.
+///
+/// Directory authorities publish consensuses that stay valid for three hours.
+pub const CONSENSUS_VALIDITY_SECS: i64 = 3 * 3600;
+
+/// Relays and bandwidth weights from the last consensus applied.
+///
+/// Kept so guards can be re-checked between consensus updates without
+/// fetching `ns/all` again, and so a consensus update can go ahead on the
+/// cached data when Tor briefly cannot serve `ns/all`.
+#[derive(Debug, Clone, Default)]
+pub struct CachedConsensus {
+    /// Relays from `ns/all`.
+    pub routers: Vec<RouterStatusEntry>,
+    /// Bandwidth weights, including any configured overrides.
+    pub weights: HashMap<String, i64>,
+    /// `valid-after` of the consensus, if it could be read.
+    pub valid_after: Option<DateTime<Utc>>,
+    /// `valid-until` of the consensus, if it could be read.
+    pub valid_until: Option<DateTime<Utc>>,
+    /// Relay families, read when `enforce_guard_diversity` is set.
+    pub families: RelayFamilies,
+}
+
+impl CachedConsensus {
+    /// Returns true if the consensus is still within its validity window.
+    ///
+    /// A consensus whose `valid-after` is unknown is never considered valid.
+    pub fn is_valid_at(&self, now: DateTime<Utc>) -> bool {
+        self.valid_after
+            .is_some_and(|t| now < t + chrono::Duration::seconds(CONSENSUS_VALIDITY_SECS))
+    }
+
+    /// Returns true if the consensus's `valid-until` has passed.
+    ///
+    /// A consensus whose `valid-until` is unknown is never considered expired.
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.valid_until.is_some_and(|t| now >= t)
+    }
+
+    /// Returns true if the consensus became valid more than `max_age_hours`
+    /// before `now`. A limit of 0, or an unknown `valid-after`, never is.
+    pub fn is_older_than(&self, now: DateTime<Utc>, max_age_hours: u32) -> bool {
+        max_age_hours > 0
+            && self
+                .valid_after
+                .is_some_and(|t| now - t > chrono::Duration::hours(i64::from(max_age_hours)))
+    }
+}
 
-    // Get ExcludeNodes configuration
+/// Reads and parses one of Tor's exclusion options, such as `ExcludeNodes`.
+///
+/// `GeoIPExcludeUnknown` is applied to it, as Tor does. Networks broader
+/// than the configured minimum prefixes are skipped.
+async fn get_exclude_nodes(
+    controller: &mut Controller,
+    option: &str,
+    config: &VanguardsConfig,
+) -> ExcludeNodes {
     let exclude_nodes_conf = controller
-        .get_conf("ExcludeNodes")
+        .get_conf(option)
         .await
         .ok()
         .and_then(|v| v.first().cloned())
@@ -746,27 +1652,163 @@ pub async fn new_consensus_event(
         .await
         .ok()
         .and_then(|v| v.first().cloned());
-    let exclude = ExcludeNodes::parse(&exclude_nodes_conf, geoip_exclude.as_deref());
+    ExcludeNodes::parse_with_min_prefixes(
+        &exclude_nodes_conf,
+        geoip_exclude.as_deref(),
+        config.exclude_min_ipv4_prefix,
+        config.exclude_min_ipv6_prefix,
+    )
+}
+
+/// Fetches the relays, bandwidth weights and `valid-after` of Tor's current
+/// consensus.
+///
+/// A missing or unparseable `valid-after` is logged rather than failing the
+/// fetch, since guard selection does not depend on it.
+async fn fetch_consensus(controller: &mut Controller, config: &Config) -> Result<CachedConsensus> {
+    // Get routers from Tor
+    let routers = get_network_statuses(controller).await?;
 
-    // Get DataDirectory for consensus file
+    // Tor keeps the consensus in CacheDirectory, which defaults to DataDirectory
+    let cache_dir = controller
+        .get_conf("CacheDirectory")
+        .await
+        .ok()
+        .and_then(|v| v.first().cloned())
+        .filter(|d| !d.is_empty());
     let data_dir = controller
         .get_conf("DataDirectory")
         .await?
         .first()
         .cloned()
-        .ok_or_else(|| {
-            Error::Config("You must set a DataDirectory location option in your torrc.".to_string())
-        })?;
+        .filter(|d| !d.is_empty());
+    let mut dirs: Vec<String> = cache_dir.into_iter().collect();
+    dirs.extend(data_dir.filter(|d| !dirs.contains(d)));
+
+    let from_file = if dirs.is_empty() {
+        Err(Error::Config(
+            "You must set a DataDirectory location option in your torrc.".to_string(),
+        ))
+    } else {
+        find_consensus_file(&dirs).and_then(|file| {
+            let consensus = std::fs::read_to_string(&file)
+                .map_err(|e| Error::Consensus(format!("cannot read {}: {}", file.display(), e)))?;
+            let weights = parse_bandwidth_weights(&consensus)?;
+            Ok((file, consensus, weights))
+        })
+    };
+
+    // A sandboxed Tor may keep its cache out of our reach; ask for the
+    // consensus over the control connection instead
+    let (mut weights, consensus, consensus_dir) = match from_file {
+        Ok((file, consensus, weights)) => {
+            (weights, consensus, file.parent().map(Path::to_path_buf))
+        }
+        Err(file_err) => {
+            plog(
+                LogLevel::Info,
+                &format!(
+                    "Cannot use the cached consensus ({}). Fetching it from Tor.",
+                    file_err
+                ),
+            );
+            let consensus = controller
+                .get_info(CONSENSUS_GETINFO_KEY)
+                .await
+                .map_err(|e| {
+                    Error::Consensus(format!(
+                        "{}; GETINFO {} failed too: {}",
+                        file_err, CONSENSUS_GETINFO_KEY, e
+                    ))
+                })?;
+            (parse_bandwidth_weights(&consensus)?, consensus, None)
+        }
+    };
+    weights.extend(config.bw_weight_overrides.clone());
+    let (valid_after, valid_until) = parse_consensus_validity(&consensus);
+    let valid_after = match valid_after {
+        Ok(t) => Some(t),
+        Err(e) => {
+            plog(LogLevel::Info, &format!("Consensus age unknown: {}", e));
+            None
+        }
+    };
+    let families = match consensus_dir {
+        Some(dir) if config.vanguards.enforce_guard_diversity => read_relay_families(&dir)
+            .unwrap_or_else(|e| {
+                plog(LogLevel::Info, &format!("Relay families unknown: {}", e));
+                RelayFamilies::default()
+            }),
+        _ => RelayFamilies::default(),
+    };
+
+    Ok(CachedConsensus {
+        routers,
+        weights,
+        valid_after,
+        valid_until: valid_until.ok(),
+        families,
+    })
+}
+
+/// Body of [`new_consensus_event`], returning the consensus it applied.
+///
+/// If the consensus cannot be fetched but `cache` is still within its
+/// validity window, the update goes ahead on the cached relays and weights.
+async fn apply_consensus(
+    controller: &mut Controller,
+    state: &mut VanguardState,
+    config: &Config,
+    caps: Option<&TorCapabilities>,
+    cache: Option<&CachedConsensus>,
+) -> Result<CachedConsensus> {
+    let consensus = match fetch_consensus(controller, config).await {
+        Ok(consensus) => consensus,
+        Err(e) => match cache.filter(|c| c.is_valid_at(Utc::now())) {
+            Some(cached) => {
+                plog(
+                    LogLevel::Notice,
+                    &format!(
+                        "Cannot fetch the consensus ({}). Using the cached one from {}.",
+                        e,
+                        cached
+                            .valid_after
+                            .map(|t| t.to_rfc3339())
+                            .unwrap_or_default()
+                    ),
+                );
+                cached.clone()
+            }
+            None => return Err(e),
+        },
+    };
+    if consensus.is_expired_at(Utc::now()) {
+        plog(
+            LogLevel::Warn,
+            &format!(
+                "Tor's consensus expired at {}; bandwidth weights may be stale. \
+                 Check the system clock and Tor's network connection.",
+                consensus
+                    .valid_until
+                    .map(|t| t.to_rfc3339())
+                    .unwrap_or_default()
+            ),
+        );
+    }
 
-    let consensus_file = Path::new(&data_dir).join("cached-microdesc-consensus");
-    let weights = get_consensus_weights(&consensus_file)?;
+    // Get ExcludeNodes and ExcludeExitNodes configuration
+    let exclude = get_exclude_nodes(controller, "ExcludeNodes", &config.vanguards).await;
+    let exclude_exits = get_exclude_nodes(controller, "ExcludeExitNodes", &config.vanguards).await;
 
     // Update vanguard state
-    consensus_update(state, &routers, &weights, &exclude, config)?;
+    update_from_consensus(state, &consensus, &exclude, &exclude_exits, config)?;
 
     // Configure Tor if vanguards enabled
     if config.enable_vanguards {
-        configure_tor(controller, state, config).await?;
+        configure_tor_with(controller, state, config, caps).await?;
+        if config.vanguards.num_layer1_guards > 0 {
+            record_layer1_guards(controller, state, config).await;
+        }
     }
 
     // Write state to file
@@ -779,69 +1821,242 @@ pub async fn new_consensus_event(
         e
     })?;
 
-    Ok(())
+    Ok(consensus)
 }
 
-/// Updates vanguard state based on new consensus.
-fn consensus_update(
+/// Tor's entry guard lifetime when `layer1_lifetime_days` leaves it unset,
+/// from the `guard-lifetime-days` consensus parameter default.
+const TOR_DEFAULT_GUARD_LIFETIME_DAYS: u16 = 120;
+
+/// Records the entry guards Tor is using in `state.layer1`.
+///
+/// A Tor that cannot report them leaves layer1 unchanged; the state is
+/// informational and must not hold up applying the consensus.
+async fn record_layer1_guards(
+    controller: &mut Controller,
     state: &mut VanguardState,
-    routers: &[RouterStatusEntry],
-    weights: &HashMap<String, i64>,
-    exclude: &ExcludeNodes,
     config: &Config,
-) -> Result<()> {
-    // Sort routers by measured bandwidth
-    let mut sorted_routers: Vec<RouterStatusEntry> = routers.to_vec();
-    sorted_routers.sort_by(|a, b| {
-        let bw_a = a.measured.or(a.bandwidth).unwrap_or(0);
-        let bw_b = b.measured.or(b.bandwidth).unwrap_or(0);
-        bw_b.cmp(&bw_a)
-    });
+) {
+    let info = match controller.get_info("entry-guards").await {
+        Ok(info) => info,
+        Err(e) => {
+            plog(
+                LogLevel::Info,
+                &format!("Cannot read entry guards from Tor: {}", e),
+            );
+            return;
+        }
+    };
+    let mut guards = parse_entry_guards(&info);
+    guards.truncate(config.vanguards.num_layer1_guards as usize);
 
-    // Create router map for lookups
-    let router_map: HashMap<String, &RouterStatusEntry> = sorted_routers
-        .iter()
-        .map(|r| (r.fingerprint.clone(), r))
-        .collect();
+    let days = match config.vanguards.layer1_lifetime_days {
+        0 => TOR_DEFAULT_GUARD_LIFETIME_DAYS,
+        days => days,
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    if state.update_layer1(&guards, now, f64::from(days) * 86400.0) {
+        plog(
+            LogLevel::Info,
+            &format!("Layer1 guards: {}", state.layer1_guardset()),
+        );
+    }
+}
 
-    // Create consensus fingerprint set
-    let consensus_fps: std::collections::HashSet<String> = sorted_routers
-        .iter()
-        .map(|r| r.fingerprint.clone())
-        .collect();
+/// Extracts fingerprints from a `GETINFO entry-guards` reply, in Tor's
+/// order, skipping guards Tor lists as `unusable` or `unlisted`.
+///
+/// Entries look like `$<fingerprint>~<nickname> <status>`; older Tors use
+/// `=` instead of `~`.
+fn parse_entry_guards(info: &str) -> Vec<String> {
+    info.lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let name = parts.next()?.strip_prefix('$')?;
+            if matches!(parts.next(), Some("unusable" | "unlisted")) {
+                return None;
+            }
+            let fp = name.split(['~', '=']).next()?.to_uppercase();
+            is_valid_fingerprint(&fp).then_some(fp)
+        })
+        .collect()
+}
 
-    // Create generator for vanguard selection
-    let restriction = FlagsRestriction::new(
+/// The flags a vanguard must have, and those it must not.
+///
+/// These are the only restrictions that disqualify a guard already chosen;
+/// see [`refresh_guard_layers`].
+fn vanguard_flags_restriction(config: &Config) -> FlagsRestriction {
+    // BadExit relays misbehave as exits, which is reason enough to keep them
+    // out of the middle too.
+    let mut banned_flags = vec!["Authority".to_string()];
+    if config.vanguards.avoid_badexit {
+        banned_flags.push("BadExit".to_string());
+    }
+    FlagsRestriction::new(
         vec![
             "Fast".to_string(),
             "Stable".to_string(),
             "Valid".to_string(),
         ],
-        vec!["Authority".to_string()],
+        banned_flags,
+    )
+}
+
+/// Builds the generator layer2 and layer3 guards are drawn from.
+///
+/// `routers` should already be sorted by bandwidth, highest first.
+///
+/// `first_seen` holds the times `min_guard_age_hours` is checked against.
+/// Without it, as when analyzing a consensus file offline, relay age is not
+/// checked.
+fn vanguard_generator(
+    routers: Vec<RouterStatusEntry>,
+    weights: &HashMap<String, i64>,
+    config: &Config,
+    first_seen: Option<&HashMap<String, f64>>,
+) -> Result<BwWeightedGenerator> {
+    let restriction = vanguard_flags_restriction(config);
+    let mut restrictions: Vec<Box<dyn NodeRestriction>> = vec![Box::new(restriction)];
+    if let Some(first_seen) = first_seen.filter(|_| config.vanguards.min_guard_age_hours > 0) {
+        restrictions.push(Box::new(MinAgeRestriction::new(
+            config.vanguards.min_guard_age_hours,
+            first_seen.clone(),
+            Utc::now().timestamp() as f64,
+        )));
+    }
+    let restrictions = NodeRestrictionList::new(restrictions);
+    let generator =
+        BwWeightedGenerator::new(routers, restrictions, weights.clone(), Position::Middle)?;
+
+    // A percentile that leaves fewer relays than a layer holds would make
+    // every guard in that layer a near-certain pick, so refuse it
+    let percentile = config.vanguards.min_weight_percentile;
+    let candidates = generator.routers().len();
+    let generator = generator.with_min_weight_percentile(percentile);
+    let needed = usize::from(
+        config
+            .vanguards
+            .layer2_guard_count()
+            .max(config.vanguards.layer3_guard_count()),
     );
-    let restrictions = NodeRestrictionList::new(vec![Box::new(restriction)]);
-    let generator = BwWeightedGenerator::new(
-        sorted_routers.clone(),
-        restrictions,
-        weights.clone(),
-        Position::Middle,
-    )?;
+    if config.enable_vanguards && candidates >= needed && generator.routers().len() < needed {
+        return Err(Error::Config(format!(
+            "min_weight_percentile {} leaves {} of {} relays, fewer than the {} guards a \
+             layer needs",
+            percentile,
+            generator.routers().len(),
+            candidates,
+            needed
+        )));
+    }
 
-    if state.enable_vanguards {
-        // Remove guards that are no longer in consensus
-        VanguardState::remove_down_from_layer(&mut state.layer2, &consensus_fps);
-        VanguardState::remove_down_from_layer(&mut state.layer3, &consensus_fps);
+    match &config.vanguards.asn_map_file {
+        Some(path) => Ok(generator.with_as_restriction(asn_map(path)?)),
+        None => Ok(generator),
+    }
+}
+
+/// Returns the ASN map at `path`, reading the file only if it is not the
+/// one cached in [`ASN_MAP`].
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] if the file has to be read and cannot be;
+/// guards are not picked without the AS diversity asked for.
+fn asn_map(path: &Path) -> Result<AsRestriction> {
+    let mut cached = ASN_MAP.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, map)) = cached
+        .as_ref()
+        .filter(|(cached_path, _)| cached_path == path)
+    {
+        return Ok(map.clone());
+    }
+    let map = AsRestriction::from_file(path)?;
+    *cached = Some((path.to_path_buf(), map.clone()));
+    Ok(map)
+}
 
-        // Remove expired guards
-        VanguardState::remove_expired_from_layer(&mut state.layer2);
-        VanguardState::remove_expired_from_layer(&mut state.layer3);
+/// Updates vanguard state based on a new consensus.
+///
+/// Refreshes the guard layers (when vanguards are enabled) and moves
+/// rendguard's use counts over to the new relay set. Nothing is sent to Tor.
+///
+/// # Arguments
+///
+/// * `state` - Vanguard state to update
+/// * `routers` - Relays from the consensus, in any order
+/// * `weights` - Bandwidth weights from the consensus footer
+/// * `exclude` - Relays Tor is configured to avoid
+/// * `config` - Application configuration
+///
+/// Tor's `ExcludeExitNodes` is not known here, so no relay is left out of
+/// rendguard's expected shares on its account; the control loop reads it
+/// from Tor.
+///
+/// # Errors
+///
+/// Returns an error if no eligible relays remain to select from.
+pub fn consensus_update(
+    state: &mut VanguardState,
+    routers: &[RouterStatusEntry],
+    weights: &HashMap<String, i64>,
+    exclude: &ExcludeNodes,
+    config: &Config,
+) -> Result<()> {
+    let consensus = CachedConsensus {
+        routers: routers.to_vec(),
+        weights: weights.clone(),
+        ..CachedConsensus::default()
+    };
+    update_from_consensus(state, &consensus, exclude, &ExcludeNodes::new(), config)
+}
 
-        // Remove excluded guards
-        VanguardState::remove_excluded_from_layer(&mut state.layer2, &router_map, exclude);
-        VanguardState::remove_excluded_from_layer(&mut state.layer3, &router_map, exclude);
+/// Body of [`consensus_update`], taking relay families along with the relays
+/// and the `ExcludeExitNodes` relays to leave out of rendguard's expected
+/// shares.
+fn update_from_consensus(
+    state: &mut VanguardState,
+    consensus: &CachedConsensus,
+    exclude: &ExcludeNodes,
+    exclude_exits: &ExcludeNodes,
+    config: &Config,
+) -> Result<()> {
+    let sorted_routers = sort_by_bandwidth(&consensus.routers);
+    let weights = &consensus.weights;
+
+    // Relay ages for min_guard_age_hours, kept only while it is set
+    if config.vanguards.min_guard_age_hours > 0 {
+        state.track_first_seen(&consensus.routers, Utc::now().timestamp() as f64);
+    } else {
+        state.first_seen.clear();
+    }
 
-        // Replenish guard layers
-        state.replenish_layers(&generator, exclude, &config.vanguards)?;
+    // Create generator for vanguard selection
+    let generator = vanguard_generator(
+        sorted_routers.clone(),
+        weights,
+        config,
+        Some(&state.first_seen),
+    )?
+    .with_families(consensus.families.clone());
+
+    let max_age_hours = config.vanguards.max_consensus_age_hours;
+    if state.enable_vanguards && consensus.is_older_than(Utc::now(), max_age_hours) {
+        plog(
+            LogLevel::Warn,
+            &format!(
+                "Consensus is more than {} hours old (max_consensus_age_hours). \
+                 Not choosing new guards until a fresh one arrives.",
+                max_age_hours
+            ),
+        );
+        drop_unusable_guards(state, &sorted_routers, exclude, config);
+    } else if state.enable_vanguards {
+        refresh_guard_layers(state, &sorted_routers, &generator, exclude, config)?;
     }
 
     // Create generator for rendguard (with Exit flag allowed)
@@ -860,58 +2075,300 @@ fn consensus_update(
     // Repair exit weights for RP selection
     rend_generator.repair_exits();
 
-    // Update rendguard use counts
+    // Update rendguard use counts. Relays the operator excluded as exits
+    // must not dilute the expected shares of the rest, but clients still
+    // choose them as rendezvous points, so they keep their own entries.
     state
         .rendguard
-        .xfer_use_counts(&rend_generator, &config.rendguard);
+        .xfer_use_counts_excluding(&rend_generator, exclude_exits, &config.rendguard);
 
     Ok(())
 }
 
-/// Gets network statuses from Tor.
-async fn get_network_statuses(controller: &mut Controller) -> Result<Vec<RouterStatusEntry>> {
-    let response = controller
-        .get_info("ns/all")
-        .await
-        .map_err(|e| Error::DescriptorUnavailable(format!("Cannot get network statuses: {}", e)))?;
-
-    parse_network_statuses(&response)
+/// Sorts relays by measured bandwidth, highest first.
+fn sort_by_bandwidth(routers: &[RouterStatusEntry]) -> Vec<RouterStatusEntry> {
+    let mut sorted_routers = routers.to_vec();
+    sorted_routers.sort_by(|a, b| {
+        let bw_a = a.measured.or(a.bandwidth).unwrap_or(0);
+        let bw_b = b.measured.or(b.bandwidth).unwrap_or(0);
+        bw_b.cmp(&bw_a)
+    });
+    sorted_routers
 }
 
-/// Parses network status entries from GETINFO ns/all response.
-fn parse_network_statuses(response: &str) -> Result<Vec<RouterStatusEntry>> {
-    use chrono::Utc;
-    use stem_rs::descriptor::router_status::RouterStatusEntryType;
+/// Drops guards that are down, expired, excluded or missing required flags,
+/// then refills both layers from `generator`.
+///
+/// The generator's other filters, such as `min_guard_age_hours` and
+/// `min_weight_percentile`, only steer new picks. A guard is not replaced
+/// because a relay's weight shifted or a family or AS overlap appeared.
+fn refresh_guard_layers(
+    state: &mut VanguardState,
+    sorted_routers: &[RouterStatusEntry],
+    generator: &BwWeightedGenerator,
+    exclude: &ExcludeNodes,
+    config: &Config,
+) -> Result<()> {
+    drop_unusable_guards(state, sorted_routers, exclude, config);
 
-    let mut routers = Vec::new();
-    let mut current_router: Option<RouterStatusEntry> = None;
+    // Replenish guard layers
+    state
+        .replenish_layers(generator, exclude, &config.vanguards)
+        .map(|_| ())
+}
 
-    for line in response.lines() {
-        if line.starts_with("r ") {
-            // Save previous router if any
-            if let Some(router) = current_router.take() {
-                routers.push(router);
-            }
+/// Drops guards that left the consensus, expired, became excluded or lost a
+/// required flag, without choosing replacements.
+fn drop_unusable_guards(
+    state: &mut VanguardState,
+    sorted_routers: &[RouterStatusEntry],
+    exclude: &ExcludeNodes,
+    config: &Config,
+) {
+    // Create router map for lookups
+    let router_map: HashMap<String, &RouterStatusEntry> = sorted_routers
+        .iter()
+        .map(|r| (r.fingerprint.clone(), r))
+        .collect();
 
-            // Parse r line: r nickname identity digest published IP ORPort DirPort
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 8 {
-                let nickname = parts[1].to_string();
-                let fingerprint = decode_base64_fingerprint(parts[2]);
-                let address = parts[5]
-                    .parse()
-                    .unwrap_or_else(|_| "0.0.0.0".parse().unwrap());
-                let or_port = parts[6].parse().unwrap_or(9001);
+    // Create consensus fingerprint set
+    let consensus_fps: HashSet<String> = sorted_routers
+        .iter()
+        .map(|r| r.fingerprint.clone())
+        .collect();
+
+    // Relays with the flags a vanguard needs
+    let flags = vanguard_flags_restriction(config);
+    let eligible_fps: HashSet<String> = sorted_routers
+        .iter()
+        .filter(|r| flags.r_is_ok(r))
+        .map(|r| r.fingerprint.clone())
+        .collect();
+
+    // Remove guards that are no longer in consensus
+    VanguardState::remove_down_from_layer(&mut state.layer2, &consensus_fps);
+    VanguardState::remove_down_from_layer(&mut state.layer3, &consensus_fps);
+
+    // Remove guards that picked up a disqualifying flag, such as BadExit
+    VanguardState::remove_down_from_layer(&mut state.layer2, &eligible_fps);
+    VanguardState::remove_down_from_layer(&mut state.layer3, &eligible_fps);
+
+    // Remove expired guards, remembering them for the rotation cooldown
+    state.remove_expired_guards(&config.vanguards);
+
+    // Remove excluded guards
+    VanguardState::remove_excluded_from_layer(&mut state.layer2, &router_map, exclude);
+    VanguardState::remove_excluded_from_layer(&mut state.layer3, &router_map, exclude);
+}
+
+/// Re-checks the current guards against a cached consensus.
+///
+/// Guards that have expired, become excluded, left the consensus or lost a
+/// required flag are replaced; see [`refresh_guard_layers`].
+///
+/// # Returns
+///
+/// `true` if either layer changed.
+fn revalidate_guards(
+    state: &mut VanguardState,
+    cached: &CachedConsensus,
+    exclude: &ExcludeNodes,
+    config: &Config,
+) -> Result<bool> {
+    let fingerprints = |state: &VanguardState| -> Vec<String> {
+        state
+            .layer2
+            .iter()
+            .chain(&state.layer3)
+            .map(|g| g.idhex.clone())
+            .collect()
+    };
+    let before = fingerprints(state);
+
+    let sorted_routers = sort_by_bandwidth(&cached.routers);
+    let generator = vanguard_generator(
+        sorted_routers.clone(),
+        &cached.weights,
+        config,
+        Some(&state.first_seen),
+    )?
+    .with_families(cached.families.clone());
+    refresh_guard_layers(state, &sorted_routers, &generator, exclude, config)?;
+
+    Ok(fingerprints(state) != before)
+}
+
+/// Gets network statuses from Tor.
+async fn get_network_statuses(controller: &mut Controller) -> Result<Vec<RouterStatusEntry>> {
+    let response = controller
+        .get_info("ns/all")
+        .await
+        .map_err(|e| Error::DescriptorUnavailable(format!("Cannot get network statuses: {}", e)))?;
+
+    parse_network_statuses(&response)
+}
+
+/// Loads the layers `pv` checks paths against from Tor's `HSLayer2Nodes` and
+/// `HSLayer3Nodes`.
+///
+/// Reading them back from Tor rather than from our state means that with
+/// `enable_vanguards = false` paths are audited against whatever guards the
+/// operator configured by hand.
+async fn load_pathverify_layers(controller: &mut Controller, pv: &mut PathVerify) {
+    let mut layers = Vec::with_capacity(2);
+    for option in ["HSLayer2Nodes", "HSLayer3Nodes"] {
+        let value = controller
+            .get_conf(option)
+            .await
+            .ok()
+            .and_then(|v| v.first().cloned());
+        layers.push(value);
+    }
+    pv.init_layers(layers[0].as_deref(), layers[1].as_deref());
+}
+
+/// Loads the relay details path policies are checked against into `pv`.
+///
+/// Country codes are only looked up, via Tor's `ip-to-country` GeoIP
+/// queries, when some policy constrains countries.
+async fn refresh_policy_relays(controller: &mut Controller, pv: &mut PathVerify) -> Result<()> {
+    let routers = get_network_statuses(controller).await?;
+
+    let mut countries: HashMap<IpAddr, String> = HashMap::new();
+    if pv.policies.iter().any(|p| p.uses_countries()) {
+        let addresses: Vec<IpAddr> = routers
+            .iter()
+            .map(|r| r.address)
+            .collect::<std::collections::HashSet<_>>()
+            .into_iter()
+            .collect();
+        for chunk in addresses.chunks(256) {
+            let keys: Vec<String> = chunk
+                .iter()
+                .map(|a| format!("ip-to-country/{}", a))
+                .collect();
+            let response = controller
+                .msg(&format!("GETINFO {}", keys.join(" ")))
+                .await?;
+            countries.extend(parse_ip_to_country(&response));
+        }
+    }
+
+    pv.policy_relays = routers
+        .into_iter()
+        .map(|r| {
+            let relay = PolicyRelay {
+                country: countries.get(&r.address).cloned(),
+                address: Some(r.address),
+                flags: r.flags,
+            };
+            (r.fingerprint, relay)
+        })
+        .collect();
+    Ok(())
+}
+
+/// Parses `ip-to-country/<addr>=<cc>` lines from a GETINFO response.
+///
+/// Tor answers `??` for addresses it cannot place; those are left out.
+fn parse_ip_to_country(response: &str) -> HashMap<IpAddr, String> {
+    response
+        .lines()
+        .filter_map(|line| {
+            let (_, rest) = line.split_once("ip-to-country/")?;
+            let (addr, cc) = rest.split_once('=')?;
+            let cc = cc.trim().to_lowercase();
+            if cc == "??" {
+                return None;
+            }
+            Some((addr.trim_matches(['[', ']']).parse().ok()?, cc))
+        })
+        .collect()
+}
+
+/// Parses network status entries from a `GETINFO ns/all` response.
+///
+/// Only the `r`, `a`, `s` and `w` lines are read; anything else is skipped.
+/// A relay whose `r` line is too short to parse is dropped.
+///
+/// Both the `ns` layout (with a descriptor digest) and the microdescriptor
+/// layout of `r` lines are understood. The address there may be IPv4 or a
+/// bare or bracketed IPv6 address. Each `a` line adds an OR address, such
+/// as `[2001:db8::1]:9001`, to the relay's `or_addresses`, so IPv6 entries
+/// in `ExcludeNodes` match.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::control::parse_network_statuses;
+///
+/// let response = "r relay AAAAAAAAAAAAAAAAAAAAAAAAAAA 2024-01-01 00:00:00 192.0.2.1 9001 0\n\
+///                 s Fast Running Stable Valid\n\
+///                 w Bandwidth=1000 Measured=900\n";
+/// let routers = parse_network_statuses(response).unwrap();
+/// assert_eq!(routers.len(), 1);
+/// assert_eq!(routers[0].measured, Some(900));
+/// ```
+pub fn parse_network_statuses(response: &str) -> Result<Vec<RouterStatusEntry>> {
+    use chrono::Utc;
+    use stem_rs::descriptor::router_status::RouterStatusEntryType;
+
+    let mut routers = Vec::new();
+    let mut current_router: Option<RouterStatusEntry> = None;
+
+    for line in response.lines() {
+        if line.starts_with("r ") {
+            // Save previous router if any
+            if let Some(router) = current_router.take() {
+                routers.push(router);
+            }
+
+            // Parse r line: r nickname identity [digest] published IP ORPort DirPort
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() >= 8 {
+                let nickname = parts[1].to_string();
+                let fingerprint = match decode_base64_fingerprint(parts[2]) {
+                    Ok(fingerprint) => fingerprint,
+                    Err(e) => {
+                        plog(
+                            LogLevel::Warn,
+                            &format!("Skipping router {} in consensus: {}", nickname, e),
+                        );
+                        continue;
+                    }
+                };
+                // The address follows the two-token published time
+                let (published, address_at) = match find_published(&parts) {
+                    Some((i, published)) => (published, i + 2),
+                    None => (Utc::now(), 5),
+                };
+                let address = parts
+                    .get(address_at)
+                    .and_then(|a| parse_relay_ip(a))
+                    .unwrap_or_else(|| "0.0.0.0".parse().unwrap());
+                let or_port = parts
+                    .get(address_at + 1)
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or(9001);
 
                 current_router = Some(RouterStatusEntry::new(
                     RouterStatusEntryType::V3,
                     nickname,
                     fingerprint,
-                    Utc::now(),
+                    published,
                     address,
                     or_port,
                 ));
             }
+        } else if let Some(stripped) = line.strip_prefix("a ") {
+            // Parse a line: a [IPv6]:ORPort or a IPv4:ORPort
+            if let Some(ref mut router) = current_router {
+                if let Ok(addr) = stripped.trim().parse::<std::net::SocketAddr>() {
+                    router
+                        .or_addresses
+                        .push((addr.ip(), addr.port(), addr.is_ipv6()));
+                }
+            }
         } else if let Some(stripped) = line.strip_prefix("s ") {
             // Parse s line: s Flag1 Flag2 ...
             if let Some(ref mut router) = current_router {
@@ -943,33 +2400,82 @@ fn parse_network_statuses(response: &str) -> Result<Vec<RouterStatusEntry>> {
     Ok(routers)
 }
 
-/// Decodes a base64-encoded fingerprint to hex.
-fn decode_base64_fingerprint(b64: &str) -> String {
-    // Add padding if needed
-    let padded = match b64.len() % 4 {
-        0 => b64.to_string(),
-        2 => format!("{}==", b64),
-        3 => format!("{}=", b64),
-        _ => b64.to_string(),
-    };
+/// Finds the `YYYY-MM-DD HH:MM:SS` published time in a split `r` line,
+/// returning it with the index of its date token.
+///
+/// The date sits after the digest in `ns` entries but directly after the
+/// identity in microdescriptor entries, so look for it rather than indexing.
+fn find_published(parts: &[&str]) -> Option<(usize, DateTime<Utc>)> {
+    parts.windows(2).enumerate().skip(3).find_map(|(i, w)| {
+        NaiveDateTime::parse_from_str(&format!("{} {}", w[0], w[1]), "%Y-%m-%d %H:%M:%S")
+            .ok()
+            .map(|t| (i, t.and_utc()))
+    })
+}
+
+/// Parses the address in an `r` line, which may be IPv4 or a bare or
+/// bracketed IPv6 address.
+fn parse_relay_ip(s: &str) -> Option<IpAddr> {
+    s.strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(s)
+        .parse()
+        .ok()
+}
 
-    // Decode base64
-    let decoded = base64_decode(&padded).unwrap_or_default();
+/// Length of a relay identity in an `r` line: 20 bytes of unpadded base64.
+const FINGERPRINT_BASE64_LEN: usize = 27;
 
-    // Convert to hex
-    decoded.iter().map(|b| format!("{:02X}", b)).collect()
+/// Decodes the base64 identity from an `r` line to a hex fingerprint.
+///
+/// # Errors
+///
+/// Returns [`Error::Consensus`] unless the input is 27 base64 characters
+/// (optionally followed by one `=`) that decode to exactly 20 bytes.
+fn decode_base64_fingerprint(b64: &str) -> Result<String> {
+    let unpadded = b64.strip_suffix('=').unwrap_or(b64);
+    if unpadded.len() != FINGERPRINT_BASE64_LEN {
+        return Err(Error::Consensus(format!(
+            "identity {:?} is {} base64 characters, expected {}",
+            b64,
+            unpadded.len(),
+            FINGERPRINT_BASE64_LEN
+        )));
+    }
+    let decoded = base64_decode(unpadded)
+        .ok_or_else(|| Error::Consensus(format!("identity {:?} is not valid base64", b64)))?;
+    if decoded.len() != 20 {
+        return Err(Error::Consensus(format!(
+            "identity {:?} decodes to {} bytes, expected 20",
+            b64,
+            decoded.len()
+        )));
+    }
+    Ok(decoded.iter().map(|b| format!("{:02X}", b)).collect())
 }
 
-/// Simple base64 decoder.
+/// Decodes standard base64 (RFC 4648 section 4), with or without padding.
+///
+/// Returns `None` for characters outside the alphabet, misplaced or excess
+/// padding, a length that leaves a lone trailing character, or non-zero
+/// unused bits in the last character.
 fn base64_decode(input: &str) -> Option<Vec<u8>> {
     const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
 
-    let input = input.trim_end_matches('=');
-    let mut output = Vec::new();
+    let data = input.trim_end_matches('=');
+    let padding = input.len() - data.len();
+    if padding > 0 && (!input.len().is_multiple_of(4) || padding > 2) {
+        return None;
+    }
+    if data.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(data.len() * 3 / 4);
     let mut buffer = 0u32;
     let mut bits = 0;
 
-    for c in input.bytes() {
+    for c in data.bytes() {
         let value = ALPHABET.iter().position(|&x| x == c)? as u32;
         buffer = (buffer << 6) | value;
         bits += 6;
@@ -981,6 +2487,10 @@ fn base64_decode(input: &str) -> Option<Vec<u8>> {
         }
     }
 
+    // Leftover bits are only there to complete the last character
+    if buffer != 0 {
+        return None;
+    }
     Some(output)
 }
 
@@ -1035,13 +2545,214 @@ pub async fn signal_event(
     config: &Config,
     signal: &str,
 ) -> Result<()> {
-    if signal == "RELOAD" {
+    reload_on_signal(controller, state, config, signal, None).await
+}
+
+/// Body of [`signal_event`], passing known capabilities to [`configure_tor_with`].
+async fn reload_on_signal(
+    controller: &mut Controller,
+    state: &VanguardState,
+    config: &Config,
+    signal: &str,
+    caps: Option<&TorCapabilities>,
+) -> Result<()> {
+    if signal == "RELOAD" || signal == "HUP" {
         plog(LogLevel::Notice, "Tor got SIGHUP. Reapplying vanguards.");
-        configure_tor(controller, state, config).await?;
+        configure_tor_with(controller, state, config, caps).await?;
     }
     Ok(())
 }
 
+/// Category of [`WouldCloseTally`] for rendguard overuse.
+const REND_OVERUSE: &str = "rend_overuse";
+
+/// Close decisions made during a run, by category.
+///
+/// Counted whether or not `close_circuits` let the close go ahead, so in
+/// monitoring mode the report logged at shutdown shows what enforcement
+/// would have done.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::control::WouldCloseTally;
+///
+/// let mut tally = WouldCloseTally::default();
+/// tally.record("dropped_cells");
+/// tally.record("rend_overuse");
+/// assert_eq!(tally.total(), 2);
+/// assert!(tally.to_string().contains("dropped cells:"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WouldCloseTally {
+    /// Circuits with dropped cells.
+    pub dropped_cells: u64,
+    /// Circuits with dropped cells in the written direction.
+    pub dropped_sent_cells: u64,
+    /// Circuits over `circ_max_megabytes`.
+    pub max_bytes: u64,
+    /// HSDIR circuits over `circ_max_hsdesc_kilobytes`.
+    pub hsdir_bytes: u64,
+    /// Service intro circuits over `circ_max_serv_intro_kilobytes`.
+    pub serv_intro_bytes: u64,
+    /// Circuits over `circ_max_bytes_per_sec`.
+    pub bytes_per_sec: u64,
+    /// Rendezvous circuits through an overused rendezvous point.
+    pub rend_overuse: u64,
+}
+
+impl WouldCloseTally {
+    /// Counts one decision for `kind`, one of
+    /// [`ATTACK_KINDS`](crate::bandguards::ATTACK_KINDS) or `"rend_overuse"`.
+    /// Unknown kinds are ignored.
+    pub fn record(&mut self, kind: &str) {
+        let count = match kind {
+            "dropped_cells" => &mut self.dropped_cells,
+            "dropped_sent_cells" => &mut self.dropped_sent_cells,
+            "max_bytes" => &mut self.max_bytes,
+            "hsdir_bytes" => &mut self.hsdir_bytes,
+            "serv_intro_bytes" => &mut self.serv_intro_bytes,
+            "bytes_per_sec" => &mut self.bytes_per_sec,
+            REND_OVERUSE => &mut self.rend_overuse,
+            _ => return,
+        };
+        *count += 1;
+    }
+
+    /// Returns the number of decisions across all categories.
+    pub fn total(&self) -> u64 {
+        self.dropped_cells
+            + self.dropped_sent_cells
+            + self.max_bytes
+            + self.hsdir_bytes
+            + self.serv_intro_bytes
+            + self.bytes_per_sec
+            + self.rend_overuse
+    }
+}
+
+impl std::fmt::Display for WouldCloseTally {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Monitoring mode: {} circuit(s) would have been closed.",
+            self.total()
+        )?;
+        for (label, count) in [
+            ("dropped cells", self.dropped_cells),
+            ("dropped sent cells", self.dropped_sent_cells),
+            ("max bytes", self.max_bytes),
+            ("hsdir bytes", self.hsdir_bytes),
+            ("serv intro bytes", self.serv_intro_bytes),
+            ("bytes per sec", self.bytes_per_sec),
+            ("rendguard overuse", self.rend_overuse),
+        ] {
+            writeln!(f, "  {:<20}{}", format!("{}:", label), count)?;
+        }
+        Ok(())
+    }
+}
+
+/// A detection passed to the callback registered with
+/// [`AppState::on_attack`].
+///
+/// Fired where the detection is logged, whether or not `close_circuits` lets
+/// the circuit be closed. Synthetic detections from the IPC `simulate`
+/// command are not passed on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttackEvent {
+    /// More cells arrived or left on a circuit than Tor accounted for.
+    DroppedCells {
+        /// Circuit the cells were dropped on.
+        circ_id: String,
+        /// Fingerprint of the circuit's guard, if known.
+        guard_fp: Option<String>,
+        /// Number of dropped cells.
+        dropped_cells: i64,
+        /// Whether the cells were dropped in the written direction.
+        sent: bool,
+    },
+    /// A circuit went over one of the bandguards byte limits.
+    MaxBytesExceeded {
+        /// Circuit over the limit.
+        circ_id: String,
+        /// Fingerprint of the circuit's guard, if known.
+        guard_fp: Option<String>,
+        /// Limit that was exceeded, from
+        /// [`ATTACK_KINDS`](crate::bandguards::ATTACK_KINDS).
+        kind: &'static str,
+        /// Measured value: bytes, or bytes per second for `bytes_per_sec`.
+        bytes: u64,
+        /// Configured limit, in the same unit as `bytes`.
+        limit: u64,
+    },
+    /// A rendezvous point was used more often than its weight allows.
+    RendOveruse {
+        /// Rendezvous circuit through the overused relay.
+        circ_id: String,
+        /// Fingerprint of the rendezvous point.
+        rp_fp: String,
+        /// Percentage of rendezvous circuits that used it.
+        usage_rate: f64,
+        /// Percentage expected from its consensus weight.
+        expected_weight: f64,
+    },
+    /// A guard connection closed while an in-use circuit was on it.
+    GuardConnectionKill {
+        /// Circuit destroyed with the connection.
+        circ_id: String,
+        /// Fingerprint of the guard, if known.
+        guard_fp: Option<String>,
+        /// Connections to this guard killed so far.
+        killed_conns: u32,
+    },
+    /// Tor has had no working guard connections or circuits for too long.
+    ConnectivityLoss {
+        /// What bandguards saw; never [`ConnectivityStatus::Connected`].
+        status: ConnectivityStatus,
+    },
+}
+
+impl AttackEvent {
+    /// Builds the event for a circuit limit result, or `None` for results
+    /// that are not attacks.
+    fn from_limit_result(
+        circ_id: &str,
+        guard_fp: Option<&str>,
+        result: &CircuitLimitResult,
+    ) -> Option<Self> {
+        let circ_id = circ_id.to_string();
+        let guard_fp = guard_fp.map(str::to_string);
+        let kind = result.attack_kind()?;
+        Some(match *result {
+            CircuitLimitResult::DroppedCells { dropped_cells }
+            | CircuitLimitResult::DroppedSentCells { dropped_cells } => AttackEvent::DroppedCells {
+                circ_id,
+                guard_fp,
+                dropped_cells,
+                sent: matches!(result, CircuitLimitResult::DroppedSentCells { .. }),
+            },
+            CircuitLimitResult::MaxBytesExceeded { bytes, limit }
+            | CircuitLimitResult::HsdirBytesExceeded { bytes, limit }
+            | CircuitLimitResult::ServIntroBytesExceeded { bytes, limit }
+            | CircuitLimitResult::RateExceeded {
+                bytes_per_sec: bytes,
+                limit,
+            } => AttackEvent::MaxBytesExceeded {
+                circ_id,
+                guard_fp,
+                kind,
+                bytes,
+                limit,
+            },
+            CircuitLimitResult::Ok | CircuitLimitResult::TorBug { .. } => return None,
+        })
+    }
+}
+
+/// Callback registered with [`AppState::on_attack`].
+type AttackCallback = Box<dyn Fn(AttackEvent) + Send>;
+
 /// Application state shared across event handlers.
 ///
 /// `AppState` aggregates all the stateful components needed during the main
@@ -1114,6 +2825,45 @@ pub struct AppState {
     pub pathverify: Option<PathVerify>,
     /// Application configuration.
     pub config: Config,
+    /// Shared state for the IPC listener, if `ipc_socket` is configured.
+    pub ipc: Option<Arc<IpcState>>,
+    /// Last bootstrap progress Tor reported, used to spot a bootstrap reset.
+    pub bootstrap_progress: Option<u8>,
+    /// `valid-after` time of the last consensus applied to the guard state.
+    pub consensus_valid_after: Option<DateTime<Utc>>,
+    /// Capabilities of the connected Tor, set once the version is known.
+    pub tor_capabilities: Option<TorCapabilities>,
+    /// Relays and weights from the last consensus applied.
+    pub cached_consensus: Option<CachedConsensus>,
+    /// Unix timestamp of the last periodic guard re-check.
+    pub last_revalidation: f64,
+    /// Unix timestamp of the last timed housekeeping pass.
+    pub last_housekeeping: f64,
+    /// How long each event handler has taken.
+    pub handler_latency: HandlerLatencies,
+    /// Detection export, if `siem_format` is configured.
+    pub siem: Option<SiemWriter>,
+    /// Prometheus exporter, if `metrics_listen` is configured.
+    pub metrics: Option<Arc<MetricsServer>>,
+    /// Rendezvous point overuse detections since startup.
+    pub rend_overuse_total: u64,
+    /// Configuration reloaded on SIGHUP with changes that wait for the next
+    /// reconnect.
+    pub pending_config: Option<Config>,
+    /// Circuits each detector decided to close, whether or not they were.
+    pub would_close: WouldCloseTally,
+    /// Stats shared with a [`Vanguards`](crate::Vanguards) handle, refreshed
+    /// with the other reported status.
+    pub shared_stats: Option<Arc<tokio::sync::Mutex<VanguardsStats>>>,
+    /// Index of the control endpoint the next connection attempt starts at.
+    pub control_rotation: usize,
+    /// Whether the status served over IPC, metrics and
+    /// [`shared_stats`](Self::shared_stats) is due a refresh, after a
+    /// detection, a guard change, a circuit or guard connection coming or
+    /// going, or a housekeeping pass.
+    pub status_stale: bool,
+    /// Called for each detection, if set with [`AppState::on_attack`].
+    attack_callback: Option<AttackCallback>,
 }
 
 impl AppState {
@@ -1150,198 +2900,695 @@ impl AppState {
             logguard: None,
             pathverify: None,
             config,
+            ipc: None,
+            bootstrap_progress: None,
+            consensus_valid_after: None,
+            tor_capabilities: None,
+            cached_consensus: None,
+            last_revalidation: 0.0,
+            last_housekeeping: 0.0,
+            handler_latency: HandlerLatencies::default(),
+            siem: None,
+            metrics: None,
+            rend_overuse_total: 0,
+            pending_config: None,
+            would_close: WouldCloseTally::default(),
+            shared_stats: None,
+            control_rotation: 0,
+            status_stale: true,
+            attack_callback: None,
         }
     }
-}
 
-/// Connects to Tor's control port.
-///
-/// Attempts connection in this order:
-/// 1. Unix socket if configured
-/// 2. TCP port if configured
-/// 3. Default Unix socket /run/tor/control
-/// 4. Default TCP port 127.0.0.1:9051
-async fn connect_to_tor(config: &Config) -> Result<Controller> {
-    // Try configured socket first
-    if let Some(ref socket_path) = config.control_socket {
-        match Controller::from_socket_file(socket_path.as_path()).await {
-            Ok(controller) => {
-                plog(
-                    LogLevel::Notice,
-                    &format!("Connected to Tor via socket {}", socket_path.display()),
-                );
-                return Ok(controller);
-            }
-            Err(e) => {
-                return Err(Error::Control(e));
-            }
-        }
+    /// Registers `callback` to be called with each detected attack.
+    ///
+    /// The callback runs on the event loop, so it should hand slow work
+    /// (paging, HTTP requests) off to another task or thread.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use vanguards_rs::control::{AppState, AttackEvent};
+    /// use vanguards_rs::vanguards::VanguardState;
+    /// use vanguards_rs::config::Config;
+    ///
+    /// let state = VanguardState::new("/tmp/vanguards.state");
+    /// let app_state = AppState::new(state, Config::default()).on_attack(|event| {
+    ///     if let AttackEvent::DroppedCells { circ_id, .. } = event {
+    ///         eprintln!("dropped cells on circuit {}", circ_id);
+    ///     }
+    /// });
+    /// ```
+    pub fn on_attack(mut self, callback: impl Fn(AttackEvent) + Send + 'static) -> Self {
+        self.attack_callback = Some(Box::new(callback));
+        self
     }
 
-    // Try configured port
-    if let Some(port) = config.control_port {
-        let addr = format!("{}:{}", config.control_ip, port);
-        match Controller::from_port(
-            addr.parse()
-                .map_err(|e| Error::Config(format!("Invalid control address: {}", e)))?,
-        )
-        .await
-        {
-            Ok(controller) => {
-                plog(
-                    LogLevel::Notice,
-                    &format!("Connected to Tor via control port {}", addr),
-                );
-                return Ok(controller);
-            }
-            Err(e) => {
-                return Err(Error::Control(e));
-            }
+    /// Passes `event` to the registered attack callback, if any.
+    fn notify_attack(&mut self, event: AttackEvent) {
+        self.status_stale = true;
+        if let Some(callback) = &self.attack_callback {
+            callback(event);
         }
     }
 
-    // Try default socket
-    if let Ok(controller) = Controller::from_socket_file(Path::new("/run/tor/control")).await {
-        plog(
-            LogLevel::Notice,
-            "Connected to Tor via /run/tor/control socket",
-        );
-        return Ok(controller);
+    /// Refreshes the protection score, attack summary and latencies served
+    /// over IPC, the Prometheus values and [`shared_stats`](Self::shared_stats).
+    async fn publish_status(&mut self) {
+        self.status_stale = false;
+        if let Some(ipc) = &self.ipc {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0);
+            ipc.update_protection_score(ProtectionScore::compute(self, get_close_circuits(), now));
+            ipc.update_attacks(self.bandwidth_stats.attack_summary());
+            ipc.update_latencies(self.handler_latency.summaries());
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.update(self.metrics_snapshot());
+        }
+        if let Some(stats) = &self.shared_stats {
+            *stats.lock().await = self.vanguards_stats();
+        }
     }
 
-    // Try default port
-    let addr = format!("{}:9051", config.control_ip);
-    match Controller::from_port(
-        addr.parse()
-            .map_err(|e| Error::Config(format!("Invalid control address: {}", e)))?,
-    )
-    .await
-    {
-        Ok(controller) => {
-            plog(
-                LogLevel::Notice,
-                &format!("Connected to Tor via {} control port", addr),
-            );
-            Ok(controller)
+    /// Collects the values served on the Prometheus endpoint.
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            circuits_closed: self.bandwidth_stats.closed_by_kind.clone(),
+            circs_destroyed: self.bandwidth_stats.circs_destroyed_total,
+            circs_pruned: self.bandwidth_stats.circs_pruned_total,
+            layer2_guards: self.vanguard_state.layer2.len(),
+            layer3_guards: self.vanguard_state.layer3.len(),
+            latencies: self.handler_latency.clone(),
+            rend_overuse: self.rend_overuse_total,
         }
-        Err(e) => Err(Error::Control(e)),
     }
-}
 
-/// Gets the list of event types to subscribe to based on configuration.
-fn get_event_types(config: &Config, tor_version: &Version) -> Vec<EventType> {
-    let mut events = Vec::new();
+    /// Collects the values returned by [`Vanguards::stats`](crate::Vanguards::stats).
+    pub fn vanguards_stats(&self) -> VanguardsStats {
+        VanguardsStats {
+            layer2_guards: self.vanguard_state.layer2.len(),
+            layer3_guards: self.vanguard_state.layer3.len(),
+            tracked_circuits: self.bandwidth_stats.circuit_count(),
+            live_guard_connections: self.bandwidth_stats.live_connection_count(),
+            circs_destroyed: self.bandwidth_stats.circs_destroyed_total,
+            circuits_closed: self.bandwidth_stats.closed_by_kind.clone(),
+            rend_overuse: self.rend_overuse_total,
+            would_close: self.would_close.clone(),
+        }
+    }
 
-    // Always subscribe to these if vanguards or rendguard enabled
-    if config.enable_vanguards || config.enable_rendguard {
-        events.push(EventType::NewConsensus);
-        events.push(EventType::Signal);
+    /// Returns how many seconds ago the last applied consensus became valid.
+    ///
+    /// Tor fetches a fresh consensus hourly, so values well above 3600 mean
+    /// Tor is no longer updating.
+    pub fn consensus_age_secs(&self) -> Option<i64> {
+        self.consensus_valid_after
+            .map(|t| (Utc::now() - t).num_seconds())
     }
 
-    // Rendguard needs CIRC events
-    if config.enable_rendguard {
-        events.push(EventType::Circ);
+    /// Runs `f` and records how long it took under `handler`.
+    pub fn timed<T>(&mut self, handler: Handler, f: impl FnOnce(&mut Self) -> T) -> T {
+        let start = Instant::now();
+        let result = f(self);
+        self.handler_latency.record(handler, start.elapsed());
+        result
     }
 
-    // Bandguards events
-    if config.enable_bandguards {
-        events.push(EventType::Circ);
-        events.push(EventType::Bw);
-        events.push(EventType::OrConn);
-        events.push(EventType::NetworkLiveness);
+    /// Switches to a configuration reloaded on SIGHUP.
+    ///
+    /// The bandguards, rendguard and logguard thresholds and `close_circuits`
+    /// apply at once. Every other change, such as control port settings or
+    /// toggling a component, is logged at NOTICE and held until the next
+    /// reconnect, since the running session was set up for the old values.
+    pub fn apply_reloaded_config(&mut self, config: Config) {
+        // The ASN map was checked with the new configuration; read it again
+        // at the next consensus in case it was edited
+        *ASN_MAP.lock().unwrap_or_else(|e| e.into_inner()) = None;
+
+        let mut live = self.config.clone();
+        live.bandguards = config.bandguards.clone();
+        live.rendguard = config.rendguard.clone();
+        live.logguard = config.logguard.clone();
+        if live.close_circuits != config.close_circuits {
+            live.close_circuits = config.close_circuits;
+            set_close_circuits(config.close_circuits);
+        }
+        if let Some(lg) = self.logguard.as_mut() {
+            lg.log_level = live.logguard.dump_level;
+            lg.log_limit = live.logguard.dump_limit;
+            lg.extra_warn_patterns =
+                crate::logguard::compile_warn_patterns(&live.logguard.extra_warn_patterns)
+                    .unwrap_or_default();
+            lg.warn_coalesce_secs = live.logguard.warn_coalesce_secs;
+        }
 
-        // CIRC_BW and CIRC_MINOR require Tor 0.3.4.10+
-        let min_version = Version::new(0, 3, 4).with_patch(10);
-        if *tor_version >= min_version {
-            events.push(EventType::CircBw);
-            events.push(EventType::CircMinor);
-        } else {
+        let applied = live.changes_from(&self.config).unwrap_or_default();
+        let deferred = config.changes_from(&live).unwrap_or_default();
+        plog(
+            LogLevel::Notice,
+            &format!("Reloaded config: {} setting(s) changed.", applied.len()),
+        );
+        for change in &deferred {
             plog(
                 LogLevel::Notice,
-                "In order for bandwidth-based protections to be enabled, you must use Tor 0.3.4.10 or newer.",
+                &format!("Config change takes effect on reconnect: {}", change),
             );
         }
-    }
 
-    // CBT verify events
-    if config.enable_cbtverify {
-        events.push(EventType::Circ);
-        events.push(EventType::BuildTimeoutSet);
+        self.config = live;
+        self.pending_config = (!deferred.is_empty()).then_some(config);
     }
 
-    // Path verify events
-    if config.enable_pathverify {
-        events.push(EventType::Circ);
-        events.push(EventType::CircMinor);
-        events.push(EventType::OrConn);
-        events.push(EventType::Guard);
-        events.push(EventType::ConfChanged);
+    /// Describes the current protection state, one item per line.
+    ///
+    /// Covers the layer2 and layer3 guards, the circuits and guard
+    /// connections bandguards tracks, and rendguard's use count. This is what
+    /// `SIGUSR2` logs.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::config::Config;
+    /// use vanguards_rs::control::AppState;
+    /// use vanguards_rs::vanguards::VanguardState;
+    ///
+    /// let state = AppState::new(VanguardState::new("/tmp/vanguards.state"), Config::default());
+    /// assert!(state.state_summary().starts_with("Layer2 guards: "));
+    /// ```
+    pub fn state_summary(&self) -> String {
+        let guardset = |set: String| {
+            if set.is_empty() {
+                "(none)".to_string()
+            } else {
+                set
+            }
+        };
+        format!(
+            "Layer2 guards: {}\n\
+             Layer3 guards: {}\n\
+             Tracked circuits: {}\n\
+             Live guard connections: {}\n\
+             Circuits destroyed: {}\n\
+             Rendezvous point uses: {:.0}",
+            guardset(self.vanguard_state.layer2_guardset()),
+            guardset(self.vanguard_state.layer3_guardset()),
+            self.bandwidth_stats.circuit_count(),
+            self.bandwidth_stats.live_connection_count(),
+            self.bandwidth_stats.circs_destroyed_total,
+            self.vanguard_state.rendguard.total_use_counts,
+        )
     }
 
-    // Log guard events
-    if config.enable_logguard {
-        events.push(EventType::Circ);
-        events.push(EventType::Warn);
-
-        // Add log events based on dump level
-        let log_events = LogGuard::get_log_event_types(config.logguard.dump_level);
-        for event_name in log_events {
-            match event_name {
-                "DEBUG" => events.push(EventType::Debug),
-                "INFO" => events.push(EventType::Info),
-                "NOTICE" => events.push(EventType::Notice),
-                "WARN" => events.push(EventType::Warn),
-                "ERR" => events.push(EventType::Err),
-                _ => {}
+    /// Publishes the current guard layers to IPC clients, if IPC is enabled,
+    /// and to pathverify when vanguards-rs manages the layers.
+    fn publish_guards(&mut self) {
+        self.status_stale = true;
+        if self.config.enable_vanguards {
+            if let Some(pv) = self.pathverify.as_mut() {
+                pv.set_vanguard_layers(&self.vanguard_state);
+            }
+        }
+        if let Some(ipc) = &self.ipc {
+            ipc.update_guards(&self.vanguard_state);
+            if let Some(valid_after) = self.consensus_valid_after {
+                ipc.update_consensus(valid_after);
             }
         }
     }
 
-    // Deduplicate
-    events.sort_by_key(|e| format!("{:?}", e));
-    events.dedup();
+    /// Applies the current consensus and records its `valid-after` time.
+    ///
+    /// Falls back to [`cached_consensus`](Self::cached_consensus) while it
+    /// is still valid if Tor cannot serve the consensus.
+    async fn apply_consensus(&mut self, controller: &mut Controller) -> Result<()> {
+        let cached = apply_consensus(
+            controller,
+            &mut self.vanguard_state,
+            &self.config,
+            self.tor_capabilities.as_ref(),
+            self.cached_consensus.as_ref(),
+        )
+        .await?;
+        if cached.valid_after.is_some() {
+            self.consensus_valid_after = cached.valid_after;
+        }
+        self.cached_consensus = Some(cached);
+        self.publish_guards();
+        Ok(())
+    }
 
-    events
-}
+    /// Re-checks the guards against the cached consensus and live `ExcludeNodes`.
+    ///
+    /// Tor is reconfigured and the state file rewritten only if a guard was
+    /// replaced.
+    async fn revalidate_guards(&mut self, controller: &mut Controller) -> Result<()> {
+        let Some(cached) = &self.cached_consensus else {
+            return Ok(());
+        };
+        let exclude = get_exclude_nodes(controller, "ExcludeNodes", &self.config.vanguards).await;
+        if !revalidate_guards(&mut self.vanguard_state, cached, &exclude, &self.config)? {
+            return Ok(());
+        }
 
-/// Handles a circuit event, dispatching to all enabled handlers.
-fn handle_circ_event(state: &mut AppState, event: &stem_rs::events::CircuitEvent, arrived_at: f64) {
+        plog(
+            LogLevel::Notice,
+            "Replaced vanguards that no longer qualify between consensus updates.",
+        );
+        configure_tor_with(
+            controller,
+            &self.vanguard_state,
+            &self.config,
+            self.tor_capabilities.as_ref(),
+        )
+        .await?;
+        self.vanguard_state
+            .write_to_file(Path::new(&self.vanguard_state.state_file))?;
+        self.publish_guards();
+        Ok(())
+    }
+}
+
+/// A control endpoint to try, and whether it is a primary one.
+///
+/// Primary endpoints come from `control_socket`/`control_port`, or the
+/// defaults, and authenticate with the configured password. Fallbacks
+/// carry their own password.
+struct ControlCandidate {
+    endpoint: ControlEndpoint,
+    primary: bool,
+}
+
+/// Lists every control endpoint in the order it is tried.
+///
+/// The primary endpoint comes first: the configured socket, else the
+/// configured port, else the default socket `/run/tor/control` followed by
+/// port 9051 on `control_ip`. Then come the `control_fallbacks`, which also
+/// hold the extra entries of a `control_socket` or `control_port` list.
+///
+/// The list is rotated to begin at `start`. The reconnect loop advances
+/// `start` after each failed session, so a flapping first endpoint does
+/// not delay every attempt.
+fn control_candidates(config: &Config, start: usize) -> Vec<ControlCandidate> {
+    let socket = |path: &Path| ControlEndpoint {
+        socket: Some(path.to_path_buf()),
+        ..ControlEndpoint::default()
+    };
+    let port = |port: u16| ControlEndpoint {
+        address: Some(format!("{}:{}", config.control_ip, port)),
+        ..ControlEndpoint::default()
+    };
+
+    let primary = match (&config.control_socket, config.control_port) {
+        (Some(path), _) => vec![socket(path)],
+        (None, Some(p)) => vec![port(p)],
+        (None, None) => vec![socket(Path::new("/run/tor/control")), port(9051)],
+    };
+    let mut candidates: Vec<ControlCandidate> = primary
+        .into_iter()
+        .map(|endpoint| ControlCandidate {
+            endpoint,
+            primary: true,
+        })
+        .chain(
+            config
+                .control_fallbacks
+                .iter()
+                .map(|endpoint| ControlCandidate {
+                    endpoint: endpoint.clone(),
+                    primary: false,
+                }),
+        )
+        .collect();
+    let len = candidates.len();
+    candidates.rotate_left(start % len);
+    candidates
+}
+
+/// Connects to a control socket path, or an `abstract:` socket on Linux.
+///
+/// A missing socket file is reported as [`Error::Connection`], so it is
+/// retried while Tor starts, with a hint about the abstract socket syntax.
+async fn connect_socket(path: &Path) -> Result<Controller> {
+    if let Some(name) = abstract_socket_name(path) {
+        return connect_abstract_socket(name).await;
+    }
+    if !path.exists() {
+        return Err(Error::Connection(format!(
+            "control socket {} does not exist. If Tor uses an abstract socket, \
+             set it as \"{}<name>\"",
+            path.display(),
+            ABSTRACT_SOCKET_PREFIX
+        )));
+    }
+    Ok(Controller::from_socket_file(path).await?)
+}
+
+/// Connects to the abstract socket `name`.
+#[cfg(target_os = "linux")]
+async fn connect_abstract_socket(name: &str) -> Result<Controller> {
+    // A leading NUL byte puts the address in the abstract namespace
+    let address = format!("\0{}", name);
+    Ok(Controller::from_socket_file(Path::new(&address)).await?)
+}
+
+/// Abstract sockets exist only on Linux; [`Config::validate`] rejects them
+/// elsewhere.
+#[cfg(not(target_os = "linux"))]
+async fn connect_abstract_socket(name: &str) -> Result<Controller> {
+    Err(Error::Config(format!(
+        "abstract control sockets are only supported on Linux: {}{}",
+        ABSTRACT_SOCKET_PREFIX, name
+    )))
+}
+
+/// Connects to a single control endpoint.
+async fn connect_endpoint(endpoint: &ControlEndpoint) -> Result<Controller> {
+    match (&endpoint.socket, &endpoint.address) {
+        (Some(socket), _) => connect_socket(socket).await,
+        (None, Some(address)) => {
+            let addr = address.parse().map_err(|e| {
+                Error::Config(format!("Invalid control address {}: {}", address, e))
+            })?;
+            Ok(Controller::from_port(addr).await?)
+        }
+        (None, None) => Err(Error::Config(
+            "control fallback has neither socket nor address".to_string(),
+        )),
+    }
+}
+
+/// An authenticated control connection and how it was reached.
+struct ControlConnection {
+    controller: Controller,
+    /// The endpoint that answered.
+    endpoint: ControlEndpoint,
+    /// The password that was accepted, if one was used.
+    password: Option<SecurePassword>,
+}
+
+impl ControlConnection {
+    /// Opens a second connection to the same endpoint with the same
+    /// credentials, without prompting again.
+    async fn reopen(&self) -> Result<Controller> {
+        let mut controller = connect_endpoint(&self.endpoint).await?;
+        authenticate_controller(
+            &mut controller,
+            self.password.as_ref().map(SecurePassword::as_str),
+        )
+        .await?;
+        Ok(controller)
+    }
+}
+
+/// Connects and authenticates to the first control endpoint that works.
+///
+/// Endpoints are tried in [`control_candidates`] order, beginning at
+/// `start`. An authentication failure on a primary endpoint is returned as
+/// is, since it points to a misconfiguration rather than an outage. A
+/// fallback that fails to authenticate is skipped.
+///
+/// # Errors
+///
+/// Returns the first primary endpoint's error if no endpoint works.
+async fn open_control_connection(config: &Config, start: usize) -> Result<ControlConnection> {
+    let mut primary_err = None;
+    let mut last_err = None;
+    for candidate in control_candidates(config, start) {
+        let endpoint = &candidate.endpoint;
+        let mut controller = match connect_endpoint(endpoint).await {
+            Ok(controller) => controller,
+            Err(e) => {
+                plog(
+                    LogLevel::Info,
+                    &format!("Could not connect to Tor via {}: {}", endpoint, e),
+                );
+                if candidate.primary && primary_err.is_none() {
+                    primary_err = Some(e);
+                } else {
+                    last_err = Some(e);
+                }
+                continue;
+            }
+        };
+
+        if candidate.primary {
+            let password = configured_password(config)?;
+            let prompted = authenticate_prompting(
+                &mut controller,
+                password.as_ref().map(SecurePassword::as_str),
+                config.no_interactive,
+            )
+            .await?;
+            plog(
+                LogLevel::Notice,
+                &format!("Connected to Tor via {}", endpoint),
+            );
+            return Ok(ControlConnection {
+                controller,
+                endpoint: candidate.endpoint,
+                password: prompted.or(password),
+            });
+        }
+
+        let auth = authenticate_prompting(
+            &mut controller,
+            endpoint.password.as_deref(),
+            config.no_interactive,
+        )
+        .await;
+        let prompted = match auth {
+            Ok(prompted) => prompted,
+            Err(e) => {
+                plog(
+                    LogLevel::Warn,
+                    &format!("Fallback {} failed: {}", endpoint, e),
+                );
+                last_err = Some(e);
+                continue;
+            }
+        };
+        let message = match &primary_err {
+            Some(e) => format!(
+                "Primary Tor unreachable ({}). Using fallback {}.",
+                e, endpoint
+            ),
+            None => format!("Connected to Tor via fallback {}", endpoint),
+        };
+        plog(LogLevel::Notice, &message);
+        let password = prompted.or_else(|| endpoint.password.clone().map(SecurePassword::new));
+        return Ok(ControlConnection {
+            controller,
+            endpoint: candidate.endpoint,
+            password,
+        });
+    }
+
+    Err(primary_err
+        .or(last_err)
+        .unwrap_or_else(|| Error::Config("no control endpoint configured".to_string())))
+}
+
+/// What a successful [`health_check`] found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    /// Version of the connected Tor.
+    pub tor_version: Version,
+    /// Relays listed in the consensus.
+    pub relays: usize,
+    /// Bandwidth weights read from the consensus.
+    pub weights: usize,
+    /// `valid-after` of the consensus, if it could be read.
+    pub valid_after: Option<DateTime<Utc>>,
+}
+
+/// Connects, authenticates and reads the consensus, then disconnects.
+///
+/// This is `--check`: a probe for monitoring that exercises the same steps
+/// as startup without configuring Tor or touching the state file. It never
+/// prompts for a password, whatever `no_interactive` says.
+///
+/// # Errors
+///
+/// Returns the first step's error: connection, authentication, version,
+/// or reading the consensus and its bandwidth weights.
+pub async fn health_check(config: &Config) -> Result<HealthCheck> {
+    // A probe must not hang on a password prompt
+    let config = Config {
+        no_interactive: true,
+        ..config.clone()
+    };
+    let mut controller = open_control_connection(&config, 0).await?.controller;
+    let tor_version = controller.get_version().await?;
+    let consensus = fetch_consensus(&mut controller, &config).await?;
+    Ok(HealthCheck {
+        tor_version,
+        relays: consensus.routers.len(),
+        weights: consensus.weights.len(),
+        valid_after: consensus.valid_after,
+    })
+}
+
+/// Formats the one-line `OK`/`FAIL` summary `--check` prints.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::control::health_check_summary;
+/// use vanguards_rs::Error;
+///
+/// let result = Err(Error::Connection("refused".to_string()));
+/// assert_eq!(health_check_summary(&result), "FAIL: connection error: refused");
+/// ```
+pub fn health_check_summary(result: &Result<HealthCheck>) -> String {
+    match result {
+        Ok(check) => format!(
+            "OK: Tor {}, {} relays, {} bandwidth weights, consensus valid-after {}",
+            check.tor_version,
+            check.relays,
+            check.weights,
+            check
+                .valid_after
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "unknown".to_string())
+        ),
+        // Keep multi-line errors on the one line probes read
+        Err(e) => format!("FAIL: {}", e.to_string().replace('\n', " ")),
+    }
+}
+
+/// Gets the list of event types to subscribe to based on configuration.
+fn get_event_types(config: &Config, caps: &TorCapabilities) -> Vec<EventType> {
+    let mut events = Vec::new();
+
+    // Always subscribe to these if vanguards or rendguard enabled
+    if config.enable_vanguards || config.enable_rendguard {
+        events.push(EventType::NewConsensus);
+        events.push(EventType::Signal);
+    }
+
+    // Rendguard needs CIRC events
+    if config.enable_rendguard {
+        events.push(EventType::Circ);
+    }
+
+    // Bandguards events
+    if config.enable_bandguards {
+        events.push(EventType::Circ);
+        events.push(EventType::Bw);
+        events.push(EventType::OrConn);
+        events.push(EventType::NetworkLiveness);
+
+        if caps.has_circ_bw {
+            events.push(EventType::CircBw);
+            events.push(EventType::CircMinor);
+        } else {
+            plog(
+                LogLevel::Notice,
+                "In order for bandwidth-based protections to be enabled, you must use Tor 0.3.4.10 or newer.",
+            );
+        }
+    }
+
+    // CBT verify events
+    if config.enable_cbtverify {
+        events.push(EventType::Circ);
+        events.push(EventType::BuildTimeoutSet);
+    }
+
+    // Path verify events
+    if config.enable_pathverify {
+        events.push(EventType::Circ);
+        events.push(EventType::CircMinor);
+        events.push(EventType::OrConn);
+        events.push(EventType::Guard);
+        if caps.has_conf_changed {
+            events.push(EventType::ConfChanged);
+        }
+    }
+
+    // Log guard events
+    if config.enable_logguard {
+        events.push(EventType::Circ);
+        events.push(EventType::Warn);
+
+        // Add log events based on dump level
+        let log_events = LogGuard::get_log_event_types(config.logguard.dump_level);
+        for event_name in log_events {
+            match event_name {
+                "DEBUG" => events.push(EventType::Debug),
+                "INFO" => events.push(EventType::Info),
+                "NOTICE" => events.push(EventType::Notice),
+                "WARN" => events.push(EventType::Warn),
+                "ERR" => events.push(EventType::Err),
+                _ => {}
+            }
+        }
+    }
+
+    // Deduplicate
+    events.sort_by_key(|e| format!("{:?}", e));
+    events.dedup();
+
+    events
+}
+
+/// Copies `monitored_purposes` to bandguards when a reload changed it.
+fn sync_monitored_purposes(state: &mut AppState) {
+    let configured = &state.config.bandguards.monitored_purposes;
+    if state.bandwidth_stats.monitored_purposes != *configured {
+        state.bandwidth_stats.monitored_purposes = configured.clone();
+    }
+}
+
+/// Handles a circuit event, dispatching to all enabled handlers.
+///
+/// Returns the circuit to close when rendguard flags its rendezvous point
+/// as overused and `close_circuits_on_overuse` is set. Closing needs the
+/// controller, so it is left to the event loop.
+fn handle_circ_event(
+    state: &mut AppState,
+    event: &stem_rs::events::CircuitEvent,
+    arrived_at: f64,
+) -> Option<String> {
     let circ_id = &event.id.0;
-    let status = format!("{:?}", event.status);
-    let purpose = event.purpose.as_ref().map(|p| format!("{:?}", p));
-    let hs_state = event.hs_state.as_ref().map(|s| format!("{:?}", s));
-    let reason = event.reason.as_ref().map(|r| format!("{:?}", r));
+    // Display gives Tor's names (HS_SERVICE_REND); Debug would not
+    let status = event.status.to_string();
+    let purpose = event.purpose.as_ref().map(|p| p.to_string());
+    let hs_state = event.hs_state.as_ref().map(|s| s.to_string());
+    let reason = event.reason.as_ref().map(|r| r.to_string());
     let path: Vec<String> = event.path.iter().map(|(fp, _)| fp.clone()).collect();
 
     // Rendguard: check for HS_SERVICE_REND in HSSR_CONNECTING
-    if state.config.enable_rendguard {
-        if let (Some(ref p), Some(ref hs)) = (&purpose, &hs_state) {
-            if p == "HS_SERVICE_REND" && hs == "HSSR_CONNECTING" {
-                // Get the rendezvous point (last hop in path)
-                if let Some(rp_fp) = path.last() {
-                    let valid = state
-                        .vanguard_state
-                        .rendguard
-                        .valid_rend_use(rp_fp, &state.config.rendguard);
-                    if !valid {
-                        let usage_rate = state.vanguard_state.rendguard.usage_rate(rp_fp);
-                        let expected = state.vanguard_state.rendguard.expected_weight(rp_fp);
-                        plog(
-                            LogLevel::Warn,
-                            &format!(
-                                "Possible rendezvous point overuse attack: {} used {:.2}% vs expected {:.2}%",
-                                rp_fp, usage_rate, expected
-                            ),
-                        );
-                    }
-                }
+    let mut close = None;
+    if state.config.enable_rendguard
+        && purpose.as_deref() == Some("HS_SERVICE_REND")
+        && hs_state.as_deref() == Some("HSSR_CONNECTING")
+    {
+        // The rendezvous point is the last hop
+        if let Some(rp_fp) = path.last() {
+            if !check_rend_use(state, circ_id, rp_fp, arrived_at)
+                && state.config.rendguard.close_circuits_on_overuse
+            {
+                state.would_close.record(REND_OVERUSE);
+                close = Some(circ_id.clone());
             }
         }
     }
 
     // Bandguards
     if state.config.enable_bandguards {
-        state.bandwidth_stats.circ_event(
+        state.bandwidth_stats.treat_guard_wait_as_built =
+            state.config.bandguards.treat_guard_wait_as_built;
+        sync_monitored_purposes(state);
+        let guard_fp = state
+            .bandwidth_stats
+            .circs
+            .get(circ_id)
+            .and_then(|c| c.guard_fp.clone());
+        let destroyed = state.bandwidth_stats.circ_event(
             circ_id,
             &status,
             purpose.as_deref().unwrap_or("GENERAL"),
@@ -1350,6 +3597,38 @@ fn handle_circ_event(state: &mut AppState, event: &stem_rs::events::CircuitEvent
             reason.as_deref(),
             arrived_at,
         );
+        if destroyed == Some(true) {
+            let killed_conns = guard_fp
+                .as_ref()
+                .and_then(|fp| state.bandwidth_stats.guards.get(fp))
+                .map_or(0, |g| g.killed_conns);
+            state.notify_attack(AttackEvent::GuardConnectionKill {
+                circ_id: circ_id.clone(),
+                guard_fp,
+                killed_conns,
+            });
+            for alert in state
+                .bandwidth_stats
+                .check_guard_kills(&state.config.bandguards, arrived_at)
+            {
+                plog(LogLevel::Warn, &alert.to_string());
+            }
+        }
+        if let Some(service) = event.rend_query.as_ref().or(event.socks_username.as_ref()) {
+            state.bandwidth_stats.tag_service(circ_id, service);
+        }
+        if let Some(count) = state
+            .bandwidth_stats
+            .check_hsdir_rate(&state.config.bandguards, arrived_at)
+        {
+            plog(
+                LogLevel::Warn,
+                &format!(
+                    "{} HSDIR circuits launched in the last minute (limit {}). Possible descriptor flooding.",
+                    count, state.config.bandguards.max_hsdir_rate
+                ),
+            );
+        }
     }
 
     // CBT verify
@@ -1382,6 +3661,8 @@ fn handle_circ_event(state: &mut AppState, event: &stem_rs::events::CircuitEvent
             );
         }
     }
+
+    close
 }
 
 /// Handles a circuit bandwidth event.
@@ -1404,12 +3685,40 @@ fn handle_circbw_event(
     }
 }
 
+/// Records a use of `rp_fp` as rendezvous point for `circ_id` at `now`,
+/// warning if it is overused.
+///
+/// Returns false on overuse.
+fn check_rend_use(state: &mut AppState, circ_id: &str, rp_fp: &str, now: f64) -> bool {
+    let rendguard = &mut state.vanguard_state.rendguard;
+    if rendguard.record_use_at(rp_fp, now, &state.config.rendguard) {
+        return true;
+    }
+    let usage_rate = rendguard.usage_rate(rp_fp);
+    let expected_weight = rendguard.expected_weight(rp_fp);
+    state.rend_overuse_total += 1;
+    plog(
+        LogLevel::Warn,
+        &format!(
+            "Possible rendezvous point overuse attack: {} used {:.2}% vs expected {:.2}%",
+            rp_fp, usage_rate, expected_weight
+        ),
+    );
+    state.notify_attack(AttackEvent::RendOveruse {
+        circ_id: circ_id.to_string(),
+        rp_fp: rp_fp.to_string(),
+        usage_rate,
+        expected_weight,
+    });
+    false
+}
+
 /// Handles a circuit minor event.
 #[allow(dead_code)]
 fn handle_circ_minor_event(state: &mut AppState, event: &stem_rs::events::CircuitEvent) {
     let circ_id = &event.id.0;
-    let purpose = event.purpose.as_ref().map(|p| format!("{:?}", p));
-    let hs_state = event.hs_state.as_ref().map(|s| format!("{:?}", s));
+    let purpose = event.purpose.as_ref().map(ToString::to_string);
+    let hs_state = event.hs_state.as_ref().map(ToString::to_string);
     let path: Vec<String> = event.path.iter().map(|(fp, _)| fp.clone()).collect();
 
     // Bandguards
@@ -1486,6 +3795,7 @@ fn handle_circ_minor_raw(state: &mut AppState, content: &str) {
 
     // Bandguards
     if state.config.enable_bandguards {
+        sync_monitored_purposes(state);
         let path_fps: Vec<String> = path.iter().map(|(fp, _)| fp.clone()).collect();
         state.bandwidth_stats.circ_minor_event(
             circ_id,
@@ -1517,8 +3827,8 @@ fn handle_orconn_event(
     event: &stem_rs::events::OrConnEvent,
     arrived_at: f64,
 ) {
-    let status = format!("{:?}", event.status);
-    let reason = event.reason.as_ref().map(|r| format!("{:?}", r));
+    let status = event.status.to_string();
+    let reason = event.reason.as_ref().map(ToString::to_string);
     let conn_id = event.id.as_deref().unwrap_or("");
 
     // Bandguards
@@ -1553,9 +3863,12 @@ fn handle_bw_event(
     arrived_at: f64,
 ) {
     if state.config.enable_bandguards {
-        state
+        let status = state
             .bandwidth_stats
             .check_connectivity(arrived_at, &state.config.bandguards);
+        if status != ConnectivityStatus::Connected {
+            state.notify_attack(AttackEvent::ConnectivityLoss { status });
+        }
     }
 }
 
@@ -1566,7 +3879,7 @@ fn handle_network_liveness_event(
     arrived_at: f64,
 ) {
     if state.config.enable_bandguards {
-        let status = format!("{:?}", event.status);
+        let status = event.status.to_string();
         state
             .bandwidth_stats
             .network_liveness_event(&status, arrived_at);
@@ -1579,8 +3892,14 @@ fn handle_buildtimeout_set_event(
     event: &stem_rs::events::BuildTimeoutSetEvent,
 ) {
     if state.config.enable_cbtverify {
-        let set_type = format!("{:?}", event.set_type);
+        let set_type = event.set_type.to_string();
         state.timeout_stats.cbt_event(&set_type, event.timeout_rate);
+        if let Some(rate) = state.timeout_stats.is_anomalous(&state.config) {
+            plog(
+                LogLevel::Warn,
+                &state.timeout_stats.anomaly_message(rate, &state.config),
+            );
+        }
     }
 }
 
@@ -1588,7 +3907,7 @@ fn handle_buildtimeout_set_event(
 fn handle_guard_event(state: &mut AppState, event: &stem_rs::events::GuardEvent) {
     if state.config.enable_pathverify {
         if let Some(ref mut pv) = state.pathverify {
-            let status = format!("{:?}", event.status);
+            let status = event.status.to_string();
             // Use endpoint_fingerprint directly from the event
             pv.guard_event(&event.endpoint_fingerprint, &status);
         }
@@ -1608,29 +3927,78 @@ fn handle_conf_changed_event(state: &mut AppState, event: &stem_rs::events::Conf
 fn handle_log_event(state: &mut AppState, event: &stem_rs::events::LogEvent, arrived_at: f64) {
     if state.config.enable_logguard {
         if let Some(ref mut lg) = state.logguard {
-            let runlevel = format!("{:?}", event.runlevel);
+            let runlevel = event.runlevel.to_string();
             lg.log_event_with_timestamp(&runlevel, &event.message, arrived_at);
 
             // Also handle warn events specially
             if matches!(event.runlevel, stem_rs::Runlevel::Warn) {
-                lg.log_warn_event(&event.message);
+                lg.log_warn_event(&event.message, arrived_at);
             }
         }
     }
 }
 
+/// Handles a STATUS_CLIENT event, tracking bootstrap progress.
+///
+/// Returns `true` if the progress went backwards. Tor only re-bootstraps after
+/// a restart or a reset of its network state, either of which can drop the
+/// `HSLayer2Nodes`/`HSLayer3Nodes` settings, so the caller should reapply them.
+fn handle_status_event(state: &mut AppState, event: &stem_rs::events::StatusEvent) -> bool {
+    if event.action != "BOOTSTRAP" {
+        return false;
+    }
+    let Some(progress) = event
+        .arguments
+        .get("PROGRESS")
+        .and_then(|p| p.parse::<u8>().ok())
+    else {
+        return false;
+    };
+
+    let previous = state.bootstrap_progress.replace(progress);
+    match previous {
+        Some(previous) if progress < previous => {
+            plog(
+                LogLevel::Notice,
+                &format!(
+                    "Tor bootstrap reset ({}% after {}%). Reapplying vanguards.",
+                    progress, previous
+                ),
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
 /// Handles a signal event.
 async fn handle_signal_event(
     controller: &mut Controller,
     state: &mut AppState,
     event: &stem_rs::events::SignalEvent,
 ) -> Result<()> {
-    let signal_name = format!("{:?}", event.signal);
-    signal_event(
+    let signal_name = event.signal.to_string();
+    if matches!(event.signal, stem_rs::Signal::Reload | stem_rs::Signal::Hup) {
+        let reloaded = RELOAD_ARGS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(load_config);
+        match reloaded {
+            Some(Ok(config)) => state.apply_reloaded_config(config),
+            Some(Err(e)) => plog(
+                LogLevel::Warn,
+                &format!("Keeping the current config; reload failed: {}", e),
+            ),
+            None => {}
+        }
+    }
+    reload_on_signal(
         controller,
         &state.vanguard_state,
         &state.config,
         &signal_name,
+        state.tor_capabilities.as_ref(),
     )
     .await
 }
@@ -1673,6 +4041,7 @@ async fn handle_signal_event(
 /// | BW | BandGuards (connectivity check) |
 /// | NEWCONSENSUS | VanguardState update |
 /// | SIGNAL | Configuration reload (SIGHUP) |
+/// | STATUS_CLIENT | Reapply vanguards after a bootstrap reset |
 ///
 /// # Example
 ///
@@ -1697,62 +4066,347 @@ async fn handle_signal_event(
 /// - [`authenticate_any`] - Authentication implementation
 /// - [`new_consensus_event`] - Consensus processing
 pub async fn control_loop(state: &mut AppState) -> String {
-    // Connect to Tor
-    let mut controller = match connect_to_tor(&state.config).await {
-        Ok(c) => c,
-        Err(e) => return format!("failed: {}", e),
-    };
-
-    // Authenticate
-    if let Err(e) = authenticate_any(&mut controller, state.config.control_pass.as_deref()).await {
-        return format!("failed: {}", e);
+    match control_session(state).await {
+        Ok(()) => "closed".to_string(),
+        Err(e) => format!("failed: {}", e),
     }
+}
 
-    // Get Tor version for feature detection
-    let tor_version = match controller.get_version().await {
-        Ok(v) => v,
-        Err(e) => return format!("failed: {}", e),
-    };
+/// Returns `true` if an error from `recv_event` means the connection is gone.
+///
+/// Protocol and parse errors come from a single event Tor sent that stem-rs
+/// could not understand; the stream itself is still usable.
+fn is_fatal_event_error(err: &stem_rs::Error) -> bool {
+    !matches!(
+        err,
+        stem_rs::Error::Protocol(_) | stem_rs::Error::Parse { .. }
+    )
+}
 
-    // Initialize vanguard state from consensus
-    if state.config.enable_vanguards || state.config.enable_rendguard {
-        match new_consensus_event(&mut controller, &mut state.vanguard_state, &state.config).await {
-            Ok(()) => {}
-            Err(Error::DescriptorUnavailable(msg)) => {
-                plog(
-                    LogLevel::Notice,
-                    &format!("Tor needs descriptors: {}. Trying again...", msg),
-                );
-                return format!("failed: {}", msg);
+/// Receives the next event, skipping ones that fail to parse.
+///
+/// Returns `None` once the connection has failed.
+async fn recv_next_event(controller: &mut Controller) -> Option<ParsedEvent> {
+    loop {
+        match controller.recv_event().await {
+            Ok(event) => return Some(event),
+            Err(e) if !is_fatal_event_error(&e) => {
+                plog(LogLevel::Warn, &format!("Skipping malformed event: {}", e));
+            }
+            Err(e) => {
+                plog(LogLevel::Debug, &format!("Event receive error: {}", e));
+                return None;
             }
-            Err(e) => return format!("failed: {}", e),
         }
     }
+}
 
-    // Handle one-shot mode
-    if state.config.one_shot_vanguards {
-        // Note: SaveConf is not available in stem-rs Signal enum
-        // We just exit after setting vanguards - user should save config manually if needed
-        plog(
-            LogLevel::Notice,
-            "Updated vanguards. Exiting (one-shot mode).",
-        );
-        std::process::exit(0);
-    }
+/// How many parsed events may wait for the event loop.
+const EVENT_QUEUE_LEN: usize = 1024;
 
-    // Initialize logguard if enabled
-    if state.config.enable_logguard {
-        state.logguard = Some(LogGuard::new(&state.config.logguard));
-    }
+/// Reads events from `controller` into a channel until the connection ends.
+///
+/// [`Controller::recv_event`] is not cancel-safe: dropped part-way through
+/// a message, it loses the rest. Reading in a task of its own lets the
+/// event loop wait on the channel and a timer at once. The channel closes
+/// when the connection does, and the task stops once the receiver is gone.
+fn spawn_event_reader(mut controller: Controller) -> tokio::sync::mpsc::Receiver<ParsedEvent> {
+    let (tx, rx) = tokio::sync::mpsc::channel(EVENT_QUEUE_LEN);
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                event = recv_next_event(&mut controller) => event,
+                () = tx.closed() => break,
+            };
+            let Some(event) = event else {
+                break;
+            };
+            if tx.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// How often the event loop runs timed checks, such as closing circuits
+/// older than `circ_max_age_hours`, whether or not events arrive.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Closes circuits older than `circ_max_age_hours`.
+///
+/// Each one is logged at WARN with its age, once: a circuit left open, as
+/// in monitoring mode, is not reported again on later ticks. Returns the
+/// IDs of the circuits actually closed; with closing disabled, none are.
+async fn close_aged_circuits(state: &mut AppState, controller: &mut Controller) -> Vec<String> {
+    if !state.config.enable_bandguards {
+        return Vec::new();
+    }
+    let mut closed = Vec::new();
+    for circ_id in state
+        .bandwidth_stats
+        .get_aged_circuits(&state.config.bandguards)
+    {
+        let Some(circ) = state.bandwidth_stats.circs.get_mut(&circ_id) else {
+            continue;
+        };
+        if circ.age_reported {
+            continue;
+        }
+        circ.age_reported = true;
+        let age_hours = circ.age_hours();
+        plog(
+            LogLevel::Warn,
+            &format!(
+                "Circuit {} is {:.1} hours old, over circ_max_age_hours ({}). Closing it.",
+                circ_id, age_hours, state.config.bandguards.circ_max_age_hours
+            ),
+        );
+        if try_close_circuit(controller, &circ_id, state.logguard.as_mut()).await {
+            closed.push(circ_id);
+        }
+    }
+    closed
+}
+
+/// Forgets circuits idle past `circ_max_idle_secs`, on the housekeeping timer.
+fn prune_stale_circuits(state: &mut AppState, now: f64) {
+    if !state.config.enable_bandguards {
+        return;
+    }
+    let max_idle_secs = state.config.bandguards.circ_max_idle_secs;
+    let pruned = state
+        .bandwidth_stats
+        .prune_stale(now, f64::from(max_idle_secs));
+    if pruned > 0 {
+        plog(
+            LogLevel::Info,
+            &format!(
+                "Stopped tracking {} circuit(s) with no events for over {} seconds.",
+                pruned, max_idle_secs
+            ),
+        );
+    }
+}
+
+/// How long `on_no_guards = "wait_and_retry"` waits between attempts.
+const NO_GUARDS_RETRY: Duration = Duration::from_secs(60);
+
+/// Applies the first consensus of a session, following `on_no_guards` if no
+/// vanguards can be chosen from it.
+///
+/// With vanguards disabled, or for any error other than
+/// [`Error::NoNodesRemain`], this is just [`AppState::apply_consensus`].
+async fn apply_startup_consensus(
+    state: &mut AppState,
+    controller: &mut Controller,
+    retry_delay: Duration,
+) -> Result<()> {
+    loop {
+        match state.apply_consensus(controller).await {
+            Err(Error::NoNodesRemain) if state.config.enable_vanguards => {}
+            result => return result,
+        }
+        match state.config.on_no_guards {
+            NoGuardsAction::Fail => {
+                plog(
+                    LogLevel::Error,
+                    "No relays qualify as vanguards. Exiting (on_no_guards = fail).",
+                );
+                return Err(Error::NoNodesRemain);
+            }
+            NoGuardsAction::WarnAndContinue => {
+                plog(
+                    LogLevel::Warn,
+                    "No relays qualify as vanguards! Hidden service circuits are NOT \
+                     protected until a later consensus yields guards.",
+                );
+                return Ok(());
+            }
+            NoGuardsAction::WaitAndRetry => {
+                plog(
+                    LogLevel::Notice,
+                    &format!(
+                        "No relays qualify as vanguards. Trying again in {}s...",
+                        retry_delay.as_secs()
+                    ),
+                );
+                tokio::time::sleep(retry_delay).await;
+            }
+        }
+    }
+}
+
+/// Writes the vanguard state to its file on the way out of [`run_main`].
+///
+/// The state is otherwise only written after consensus updates and guard
+/// revalidation, so rendguard counts and guards changed since then would be
+/// lost. Returns whether the write succeeded; either way it is logged.
+fn flush_state_on_shutdown(state: &AppState) -> bool {
+    let path = Path::new(&state.vanguard_state.state_file);
+    match state.vanguard_state.write_to_file(path) {
+        Ok(()) => {
+            plog(
+                LogLevel::Notice,
+                &format!("Saved vanguard state to {}.", path.display()),
+            );
+            true
+        }
+        Err(e) => {
+            plog(
+                LogLevel::Notice,
+                &format!("Could not save vanguard state to {}: {}", path.display(), e),
+            );
+            false
+        }
+    }
+}
+
+/// Consecutive cookie authentication failures [`run_main`] tolerates before
+/// giving up. Tor rewrites the cookie file when it restarts, so reading it
+/// can briefly fail or return a stale value.
+const MAX_COOKIE_AUTH_FAILURES: u32 = 3;
+
+/// First delay before reconnecting to Tor.
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A session that lasted this long resets the reconnect delay.
+const RECONNECT_BACKOFF_RESET_AFTER: Duration = Duration::from_secs(30);
+
+/// Largest fraction shaved off a reconnect delay at random, so several
+/// instances do not retry in lockstep.
+const RECONNECT_JITTER_FRACTION: f64 = 0.1;
+
+/// Reconnect delays for [`run_main`]: 1s, doubling after each attempt up to
+/// `reconnect_max_backoff_secs`, with jitter.
+#[derive(Debug)]
+struct ReconnectBackoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl ReconnectBackoff {
+    fn new(max: Duration) -> Self {
+        Self {
+            next: RECONNECT_INITIAL_BACKOFF.min(max),
+            max,
+        }
+    }
+
+    /// Returns how long to wait after a session that lasted `session_lasted`.
+    fn next_delay(&mut self, session_lasted: Duration) -> Duration {
+        if session_lasted >= RECONNECT_BACKOFF_RESET_AFTER {
+            self.next = RECONNECT_INITIAL_BACKOFF.min(self.max);
+        }
+        let base = self.next;
+        self.next = (self.next * 2).min(self.max);
+        base.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..=RECONNECT_JITTER_FRACTION))
+    }
+}
+
+/// How a failed control session affects reconnection in [`run_main`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionFailure {
+    /// Reconnecting cannot help: the password is wrong or missing, or the
+    /// configuration is invalid. Exits immediately.
+    Fatal,
+    /// Cookie authentication failed. Retried up to
+    /// [`MAX_COOKIE_AUTH_FAILURES`] times in a row.
+    CookieAuth,
+    /// Connection or descriptor trouble. Counts against `retry_limit`.
+    Retryable,
+}
+
+impl SessionFailure {
+    fn classify(e: &Error) -> Self {
+        use stem_rs::AuthError;
+
+        match e {
+            Error::Config(_) => SessionFailure::Fatal,
+            Error::Control(stem_rs::Error::Authentication(auth)) => match auth {
+                AuthError::CookieUnreadable(_)
+                | AuthError::IncorrectCookie
+                | AuthError::IncorrectCookieSize
+                | AuthError::ChallengeFailed => SessionFailure::CookieAuth,
+                _ => SessionFailure::Fatal,
+            },
+            _ => SessionFailure::Retryable,
+        }
+    }
+}
+
+/// Runs one control connection, returning `Ok(())` when Tor closes it.
+///
+/// This is the body of [`control_loop`]; keeping the error typed lets
+/// [`run_main`] report why it could never connect.
+async fn control_session(state: &mut AppState) -> Result<()> {
+    // Connect to Tor, falling back to backup endpoints, and authenticate
+    let connection = open_control_connection(&state.config, state.control_rotation).await?;
+    // Events get a connection of their own; see spawn_event_reader
+    let mut event_controller = connection.reopen().await?;
+    let mut controller = connection.controller;
+
+    // Get Tor version for feature detection
+    let tor_version = controller.get_version().await?;
+    let caps = TorCapabilities::from_version(&tor_version);
+    state.tor_capabilities = Some(caps);
+
+    // Initialize vanguard state from consensus
+    if state.config.enable_vanguards || state.config.enable_rendguard {
+        match apply_startup_consensus(state, &mut controller, NO_GUARDS_RETRY).await {
+            Ok(()) => {}
+            Err(Error::DescriptorUnavailable(msg)) => {
+                plog(
+                    LogLevel::Notice,
+                    &format!("Tor needs descriptors: {}. Trying again...", msg),
+                );
+                return Err(Error::DescriptorUnavailable(msg));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Handle one-shot mode
+    if state.config.one_shot_vanguards {
+        // Note: SaveConf is not available in stem-rs Signal enum
+        // We just exit after setting vanguards - user should save config manually if needed
+        plog(
+            LogLevel::Notice,
+            "Updated vanguards. Exiting (one-shot mode).",
+        );
+        std::process::exit(0);
+    }
+
+    // Initialize logguard if enabled
+    if state.config.enable_logguard {
+        state.logguard = Some(LogGuard::new(&state.config.logguard));
+    }
 
     // Initialize pathverify if enabled
     if state.config.enable_pathverify {
-        state.pathverify = Some(PathVerify::new(
+        let mut pv = PathVerify::new(
             state.config.enable_vanguards,
             state.config.vanguards.num_layer1_guards,
-            state.config.vanguards.num_layer2_guards,
-            state.config.vanguards.num_layer3_guards,
-        ));
+            state.config.vanguards.layer2_guard_count(),
+            state.config.vanguards.layer3_guard_count(),
+        );
+        load_pathverify_layers(&mut controller, &mut pv).await;
+        pv.policies = state.config.path_policies.clone();
+        pv.close_on = state.config.pathverify_close_on.clone();
+        if state.config.enable_vanguards {
+            pv.set_vanguard_layers(&state.vanguard_state);
+        }
+        // Hops without relay details are not checked, so a failure here
+        // only defers policy checks to the next consensus
+        if !pv.policies.is_empty() {
+            if let Err(err) = refresh_policy_relays(&mut controller, &mut pv).await {
+                plog(
+                    LogLevel::Warn,
+                    &format!("Cannot load path policy relays: {}", err),
+                );
+            }
+        }
+        state.pathverify = Some(pv);
 
         // Send NEWNYM to get fresh circuits
         if let Err(e) = controller.signal(stem_rs::Signal::Newnym).await {
@@ -1760,186 +4414,461 @@ pub async fn control_loop(state: &mut AppState) -> String {
         }
     }
 
-    // Subscribe to events
-    let event_types = get_event_types(&state.config, &tor_version);
-    if let Err(e) = controller.set_events(&event_types).await {
-        return format!("failed: {}", e);
+    // Subscribe to events, which a separate task reads. EventType::Status only covers STATUS_GENERAL, so STATUS_CLIENT
+    // (which carries bootstrap progress) is added by name.
+    let mut event_names: Vec<String> = get_event_types(&state.config, &caps)
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+    if state.config.enable_vanguards {
+        event_names.push("STATUS_CLIENT".to_string());
     }
+    event_controller
+        .msg(&format!("SETEVENTS {}", event_names.join(" ")))
+        .await?;
+    let mut events = spawn_event_reader(event_controller);
+    let mut housekeeping = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+    housekeeping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    // Vanguards are applied and events flowing, so the service is up
+    #[cfg(all(unix, feature = "systemd"))]
+    crate::notify::ready();
 
     // Main event loop
     loop {
-        match controller.recv_event().await {
-            Ok(event) => {
-                let arrived_at = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .map(|d| d.as_secs_f64())
-                    .unwrap_or(0.0);
+        // Recomputing the reported status is too costly for every event on
+        // a busy service; catch up with whatever the last pass changed
+        if state.status_stale {
+            state.publish_status().await;
+        }
 
-                match event {
-                    ParsedEvent::Circuit(ref e) => {
-                        handle_circ_event(state, e, arrived_at);
-                    }
-                    ParsedEvent::CircuitBandwidth(ref e) => {
-                        handle_circbw_event(state, e, arrived_at);
-                    }
-                    ParsedEvent::OrConn(ref e) => {
-                        handle_orconn_event(state, e, arrived_at);
-                    }
-                    ParsedEvent::Bandwidth(ref e) => {
-                        handle_bw_event(state, e, arrived_at);
-                    }
-                    ParsedEvent::NetworkLiveness(ref e) => {
-                        handle_network_liveness_event(state, e, arrived_at);
-                    }
-                    ParsedEvent::BuildTimeoutSet(ref e) => {
-                        handle_buildtimeout_set_event(state, e);
-                    }
-                    ParsedEvent::Guard(ref e) => {
-                        handle_guard_event(state, e);
-                    }
-                    ParsedEvent::ConfChanged(ref e) => {
-                        handle_conf_changed_event(state, e);
-                    }
-                    ParsedEvent::Log(ref e) => {
-                        handle_log_event(state, e, arrived_at);
-                    }
-                    ParsedEvent::Signal(ref e) => {
-                        if let Err(err) = handle_signal_event(&mut controller, state, e).await {
-                            plog(LogLevel::Warn, &format!("Signal event error: {}", err));
-                        }
+        // Wake on the housekeeping tick too, so timed checks still run when
+        // Tor is quiet. Both branches are cancel-safe.
+        let event = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => Some(event),
+                None => return Ok(()),
+            },
+            _ = housekeeping.tick() => None,
+        };
+
+        let arrived_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        #[cfg(all(unix, feature = "systemd"))]
+        crate::notify::watchdog();
+
+        if DUMP_REQUESTED.swap(false, Ordering::SeqCst) {
+            plog(LogLevel::Notice, "Got SIGUSR2. Current protection state:");
+            for line in state.state_summary().lines() {
+                plog(LogLevel::Notice, line);
+            }
+        }
+
+        if arrived_at - state.last_housekeeping >= HOUSEKEEPING_INTERVAL.as_secs_f64() {
+            state.last_housekeeping = arrived_at;
+            state.status_stale = true;
+            close_aged_circuits(state, &mut controller).await;
+            prune_stale_circuits(state, arrived_at);
+        }
+        if let Some(lg) = state.logguard.as_mut() {
+            lg.flush_warns(arrived_at);
+        }
+
+        let Some(event) = event else {
+            continue;
+        };
+
+        let tracked = (
+            state.bandwidth_stats.circuit_count(),
+            state.bandwidth_stats.live_connection_count(),
+        );
+        match event {
+            ParsedEvent::Circuit(ref e) => {
+                let overused = state.timed(Handler::Circ, |s| handle_circ_event(s, e, arrived_at));
+                if let Some(circ_id) = overused {
+                    try_close_circuit(&mut controller, &circ_id, state.logguard.as_mut()).await;
+                }
+            }
+            ParsedEvent::CircuitBandwidth(ref e) => {
+                state.timed(Handler::CircBw, |s| handle_circbw_event(s, e, arrived_at));
+            }
+            ParsedEvent::OrConn(ref e) => {
+                state.timed(Handler::OrConn, |s| handle_orconn_event(s, e, arrived_at));
+            }
+            ParsedEvent::Bandwidth(ref e) => {
+                state.timed(Handler::Bw, |s| handle_bw_event(s, e, arrived_at));
+            }
+            ParsedEvent::NetworkLiveness(ref e) => {
+                handle_network_liveness_event(state, e, arrived_at);
+            }
+            ParsedEvent::BuildTimeoutSet(ref e) => {
+                handle_buildtimeout_set_event(state, e);
+            }
+            ParsedEvent::Guard(ref e) => {
+                handle_guard_event(state, e);
+            }
+            ParsedEvent::ConfChanged(ref e) => {
+                handle_conf_changed_event(state, e);
+            }
+            ParsedEvent::Log(ref e) => {
+                handle_log_event(state, e, arrived_at);
+            }
+            ParsedEvent::Signal(ref e) => {
+                if let Err(err) = handle_signal_event(&mut controller, state, e).await {
+                    plog(LogLevel::Warn, &format!("Signal event error: {}", err));
+                }
+            }
+            ParsedEvent::Status(ref e) if handle_status_event(state, e) => {
+                if let Err(err) = configure_tor_with(
+                    &mut controller,
+                    &state.vanguard_state,
+                    &state.config,
+                    state.tor_capabilities.as_ref(),
+                )
+                .await
+                {
+                    plog(
+                        LogLevel::Warn,
+                        &format!("Failed to reapply vanguards: {}", err),
+                    );
+                }
+            }
+            ParsedEvent::Unknown {
+                ref event_type,
+                ref content,
+            } => {
+                // Handle NEWCONSENSUS specially since it may not be in ParsedEvent
+                if event_type == "NEWCONSENSUS" {
+                    let start = Instant::now();
+                    if let Err(err) = state.apply_consensus(&mut controller).await {
+                        plog(LogLevel::Warn, &format!("Consensus event error: {}", err))
                     }
-                    ParsedEvent::Unknown {
-                        ref event_type,
-                        ref content,
-                    } => {
-                        // Handle NEWCONSENSUS specially since it may not be in ParsedEvent
-                        if event_type == "NEWCONSENSUS" {
-                            if let Err(err) = new_consensus_event(
-                                &mut controller,
-                                &mut state.vanguard_state,
-                                &state.config,
-                            )
-                            .await
-                            {
-                                plog(LogLevel::Warn, &format!("Consensus event error: {}", err));
-                            }
-                        } else if event_type == "CIRC_MINOR" {
-                            // Parse CIRC_MINOR event manually
-                            // Format: CircuitID EVENT [Path] [PURPOSE=...] [HS_STATE=...] [OLD_PURPOSE=...] [OLD_HS_STATE=...]
-                            handle_circ_minor_raw(state, content);
+                    state
+                        .handler_latency
+                        .record(Handler::Consensus, start.elapsed());
+                    if let Some(pv) = state
+                        .pathverify
+                        .as_mut()
+                        .filter(|pv| !pv.policies.is_empty())
+                    {
+                        if let Err(err) = refresh_policy_relays(&mut controller, pv).await {
+                            plog(
+                                LogLevel::Warn,
+                                &format!("Cannot refresh path policy relays: {}", err),
+                            );
                         }
                     }
-                    _ => {
-                        // Ignore other events
-                    }
+                } else if event_type == "CIRC_MINOR" {
+                    // Parse CIRC_MINOR event manually
+                    // Format: CircuitID EVENT [Path] [PURPOSE=...] [HS_STATE=...] [OLD_PURPOSE=...] [OLD_HS_STATE=...]
+                    handle_circ_minor_raw(state, content);
                 }
+            }
+            _ => {
+                // Ignore other events
+            }
+        }
 
-                // Check circuit limits after bandwidth events
-                if state.config.enable_bandguards {
-                    let circs_to_check: Vec<String> =
-                        state.bandwidth_stats.circs.keys().cloned().collect();
-                    for circ_id in circs_to_check {
-                        let limit_result = state
-                            .bandwidth_stats
-                            .check_circuit_limits(&circ_id, &state.config.bandguards);
-                        match limit_result {
-                            crate::bandguards::CircuitLimitResult::Ok => {}
-                            crate::bandguards::CircuitLimitResult::TorBug {
-                                bug_id,
-                                dropped_cells,
-                            } => {
-                                plog(
-                                    LogLevel::Info,
-                                    &format!(
-                                        "Tor bug {} (dropped {} cells): {}",
-                                        bug_id, dropped_cells, circ_id
-                                    ),
-                                );
-                            }
-                            crate::bandguards::CircuitLimitResult::DroppedCells {
-                                dropped_cells,
-                            } => {
-                                plog(
-                                    LogLevel::Warn,
-                                    &format!(
-                                        "Dropped cells attack ({} cells): {}",
-                                        dropped_cells, circ_id
-                                    ),
-                                );
-                                try_close_circuit(
-                                    &mut controller,
-                                    &circ_id,
-                                    state.logguard.as_mut(),
-                                )
-                                .await;
-                            }
-                            crate::bandguards::CircuitLimitResult::MaxBytesExceeded {
-                                bytes,
-                                limit,
-                            } => {
-                                plog(
-                                    LogLevel::Warn,
-                                    &format!(
-                                        "Circuit {} exceeded max bytes ({} > {})",
-                                        circ_id, bytes, limit
-                                    ),
-                                );
-                                try_close_circuit(
-                                    &mut controller,
-                                    &circ_id,
-                                    state.logguard.as_mut(),
-                                )
-                                .await;
-                            }
-                            crate::bandguards::CircuitLimitResult::HsdirBytesExceeded {
-                                bytes,
-                                limit,
-                            } => {
-                                plog(
-                                    LogLevel::Warn,
-                                    &format!(
-                                        "HSDIR circuit {} exceeded max bytes ({} > {})",
-                                        circ_id, bytes, limit
-                                    ),
-                                );
-                                try_close_circuit(
-                                    &mut controller,
-                                    &circ_id,
-                                    state.logguard.as_mut(),
-                                )
-                                .await;
-                            }
-                            crate::bandguards::CircuitLimitResult::ServIntroBytesExceeded {
-                                bytes,
-                                limit,
-                            } => {
-                                plog(
-                                    LogLevel::Warn,
-                                    &format!(
-                                        "Service intro circuit {} exceeded max bytes ({} > {})",
-                                        circ_id, bytes, limit
-                                    ),
-                                );
-                                try_close_circuit(
-                                    &mut controller,
-                                    &circ_id,
-                                    state.logguard.as_mut(),
-                                )
-                                .await;
-                            }
-                        }
-                    }
+        // A circuit or guard connection coming or going changes the counts
+        // reported
+        if tracked
+            != (
+                state.bandwidth_stats.circuit_count(),
+                state.bandwidth_stats.live_connection_count(),
+            )
+        {
+            state.status_stale = true;
+        }
+
+        // Re-check guards between consensus updates
+        let interval = f64::from(state.config.vanguards.revalidate_interval_secs);
+        if interval > 0.0
+            && state.vanguard_state.enable_vanguards
+            && arrived_at - state.last_revalidation >= interval
+        {
+            state.last_revalidation = arrived_at;
+            if let Err(err) = state.revalidate_guards(&mut controller).await {
+                plog(LogLevel::Warn, &format!("Guard re-check failed: {}", err));
+            }
+        }
+
+        // Rotate guards if an IPC client asked for it
+        if state
+            .ipc
+            .as_ref()
+            .is_some_and(|ipc| ipc.take_rotate_request())
+        {
+            let vs = &mut state.vanguard_state;
+            for guard in vs.layer2.drain(..).chain(vs.layer3.drain(..)) {
+                vs.rotated_out.insert(guard.idhex, arrived_at);
+            }
+            match state.apply_consensus(&mut controller).await {
+                Ok(()) => plog(LogLevel::Notice, "Rotated vanguards on IPC request."),
+                Err(err) => plog(LogLevel::Warn, &format!("Guard rotation failed: {}", err)),
+            }
+        }
+
+        // Expire individual guards IPC clients asked about
+        let expire_requests = state
+            .ipc
+            .as_ref()
+            .map(|ipc| ipc.take_expire_requests())
+            .unwrap_or_default();
+        if !expire_requests.is_empty() {
+            let mut expired = false;
+            for fp in &expire_requests {
+                if state.vanguard_state.expire_guard(fp) {
+                    plog(
+                        LogLevel::Notice,
+                        &format!("Expired guard {} on IPC request.", fp),
+                    );
+                    expired = true;
+                } else {
+                    plog(
+                        LogLevel::Notice,
+                        &format!("Guard {} not in use; nothing to expire.", fp),
+                    );
                 }
             }
-            Err(e) => {
-                // Connection closed or error
-                plog(LogLevel::Debug, &format!("Event receive error: {}", e));
-                return "closed".to_string();
+            if expired {
+                if let Err(err) = state.apply_consensus(&mut controller).await {
+                    plog(
+                        LogLevel::Warn,
+                        &format!("Guard replacement failed: {}", err),
+                    );
+                }
+            }
+        }
+
+        // Close circuits pathverify or the path policies queued
+        let policy_closes = state
+            .pathverify
+            .as_mut()
+            .map(|pv| pv.take_circs_to_close())
+            .unwrap_or_default();
+        for circ_id in policy_closes {
+            try_close_circuit(&mut controller, &circ_id, state.logguard.as_mut()).await;
+        }
+
+        // Report synthetic attacks IPC clients asked for
+        let simulate_requests = state
+            .ipc
+            .as_ref()
+            .map(|ipc| ipc.take_simulate_requests())
+            .unwrap_or_default();
+        for kind in simulate_requests {
+            if let Some(result) =
+                crate::bandguards::CircuitLimitResult::synthetic(&kind, &state.config.bandguards)
+            {
+                report_limit_result(state, SYNTHETIC_CIRC_ID, &result, true, arrived_at);
+            }
+        }
+
+        // Check circuit limits after bandwidth events
+        if state.config.enable_bandguards
+            && state
+                .bandwidth_stats
+                .limit_sweep_due(&state.config.bandguards, arrived_at)
+        {
+            let circs_to_check: Vec<String> = state.bandwidth_stats.circs.keys().cloned().collect();
+            for circ_id in circs_to_check {
+                let limit_result = state
+                    .bandwidth_stats
+                    .check_circuit_limits(&circ_id, &state.config.bandguards);
+                if !report_limit_result(state, &circ_id, &limit_result, false, arrived_at) {
+                    continue;
+                }
+                // A circuit left open is checked again; count it once
+                if let Some(kind) = limit_result.attack_kind() {
+                    if state.bandwidth_stats.flag_circuit(&circ_id, kind) {
+                        state.would_close.record(kind);
+                    }
+                }
+                if try_close_circuit(&mut controller, &circ_id, state.logguard.as_mut()).await {
+                    state.bandwidth_stats.record_attack(
+                        &circ_id,
+                        AttackOutcome::Closed,
+                        arrived_at,
+                    );
+                }
             }
         }
     }
 }
 
+/// Circuit ID reported for attacks injected with the IPC `simulate` command.
+const SYNTHETIC_CIRC_ID: &str = "SYNTHETIC";
+
+/// Returns the log level and message for a circuit limit result, or `None`
+/// when the circuit is within limits.
+fn limit_result_message(circ_id: &str, result: &CircuitLimitResult) -> Option<(LogLevel, String)> {
+    match result {
+        CircuitLimitResult::Ok => None,
+        CircuitLimitResult::TorBug {
+            bug_id,
+            dropped_cells,
+        } => Some((
+            LogLevel::Info,
+            format!(
+                "Tor bug {} (dropped {} cells): {}",
+                bug_id, dropped_cells, circ_id
+            ),
+        )),
+        CircuitLimitResult::DroppedCells { dropped_cells } => Some((
+            LogLevel::Warn,
+            format!(
+                "Dropped cells attack ({} cells): {}",
+                dropped_cells, circ_id
+            ),
+        )),
+        CircuitLimitResult::DroppedSentCells { dropped_cells } => Some((
+            LogLevel::Warn,
+            format!(
+                "Dropped sent cells attack ({} cells): {}",
+                dropped_cells, circ_id
+            ),
+        )),
+        CircuitLimitResult::MaxBytesExceeded { bytes, limit } => Some((
+            LogLevel::Warn,
+            format!(
+                "Circuit {} exceeded max bytes ({} > {})",
+                circ_id, bytes, limit
+            ),
+        )),
+        CircuitLimitResult::HsdirBytesExceeded { bytes, limit } => Some((
+            LogLevel::Warn,
+            format!(
+                "HSDIR circuit {} exceeded max bytes ({} > {})",
+                circ_id, bytes, limit
+            ),
+        )),
+        CircuitLimitResult::ServIntroBytesExceeded { bytes, limit } => Some((
+            LogLevel::Warn,
+            format!(
+                "Service intro circuit {} exceeded max bytes ({} > {})",
+                circ_id, bytes, limit
+            ),
+        )),
+        CircuitLimitResult::RateExceeded {
+            bytes_per_sec,
+            limit,
+        } => Some((
+            LogLevel::Warn,
+            format!(
+                "Circuit {} exceeded max rate ({} > {} bytes/sec)",
+                circ_id, bytes_per_sec, limit
+            ),
+        )),
+    }
+}
+
+/// Logs a circuit limit result, broadcasts attacks as `attack_detected` and
+/// exports them to the SIEM output.
+///
+/// Synthetic results are prefixed with `[SYNTHETIC]` in the log, flagged in
+/// the IPC event, and never close a circuit.
+///
+/// # Returns
+///
+/// `true` if the circuit should be closed.
+fn report_limit_result(
+    state: &mut AppState,
+    circ_id: &str,
+    result: &CircuitLimitResult,
+    synthetic: bool,
+    at: f64,
+) -> bool {
+    let Some((level, message)) = limit_result_message(circ_id, result) else {
+        return false;
+    };
+    let message = if synthetic {
+        format!("[SYNTHETIC] {}", message)
+    } else {
+        message
+    };
+    let circ = state.bandwidth_stats.circs.get(circ_id);
+    let guard_fp = circ.and_then(|c| c.guard_fp.clone());
+    let service = circ.and_then(|c| c.service.clone());
+    plog_with(
+        level,
+        &message,
+        &LogContext::circuit(circ_id).with_guard(guard_fp.as_deref()),
+    );
+
+    let Some(kind) = result.attack_kind() else {
+        return false;
+    };
+    if !synthetic {
+        if let Some(event) = AttackEvent::from_limit_result(circ_id, guard_fp.as_deref(), result) {
+            state.notify_attack(event);
+        }
+    }
+    if let Some(siem) = &state.siem {
+        siem.write(&Detection {
+            circ_id,
+            kind,
+            guard_fp: guard_fp.as_deref(),
+            service: service.as_deref(),
+            at,
+            synthetic,
+        });
+    }
+    if let Some(ipc) = &state.ipc {
+        ipc.publish(VanguardEvent::AttackDetected {
+            kind: kind.to_string(),
+            circ_id: circ_id.to_string(),
+            message,
+            synthetic,
+        });
+    }
+    if synthetic {
+        plog(
+            LogLevel::Notice,
+            &suppressed_close_message(circ_id, CloseSuppression::Synthetic),
+        );
+    }
+    !synthetic
+}
+
+/// Verifies that the state file's directory exists and accepts new files.
+///
+/// Creates and removes the same temporary file that
+/// [`VanguardState::write_to_file`] uses for its atomic rename, so a failure
+/// here means the first state write would fail too.
+fn check_state_file_writable(state_file: &Path) -> Result<()> {
+    let dir = match state_file.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    if !dir.is_dir() {
+        return Err(Error::State(format!(
+            "state file directory {} does not exist",
+            dir.display()
+        )));
+    }
+
+    let probe = state_file.with_extension("tmp");
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&probe)
+        .map_err(|e| {
+            Error::State(format!(
+                "state file directory {} is not writable: {}",
+                dir.display(),
+                e
+            ))
+        })?;
+    let _ = std::fs::remove_file(&probe);
+
+    Ok(())
+}
+
 /// Runs the main application loop with reconnection support.
 ///
 /// This is the primary entry point for the vanguards application. It manages
@@ -1951,18 +4880,19 @@ pub async fn control_loop(state: &mut AppState) -> String {
 /// ┌─────────────────────────────────────────────────────────────┐
 /// │                      run_main()                             │
 /// │                                                             │
-/// │  1. Set up CTRL+C handler                                   │
-/// │  2. Load/create vanguard state                              │
-/// │  3. Enter reconnection loop:                                │
+/// │  1. Check the state file directory is writable              │
+/// │  2. Set up CTRL+C handler                                   │
+/// │  3. Load/create vanguard state                              │
+/// │  4. Enter reconnection loop:                                │
 /// │     ┌─────────────────────────────────────────────────────┐ │
 /// │     │  • Check shutdown flag                              │ │
 /// │     │  • Check retry limit                                │ │
 /// │     │  • Run control_loop()                               │ │
 /// │     │  • Log disconnection                                │ │
-/// │     │  • Wait 1 second                                    │ │
+/// │     │  • Back off (1s, doubling up to the cap, jittered)  │ │
 /// │     │  • Increment reconnect counter                      │ │
 /// │     └─────────────────────────────────────────────────────┘ │
-/// │  4. Exit when shutdown or retry limit reached               │
+/// │  5. Exit when shutdown or retry limit reached               │
 /// └─────────────────────────────────────────────────────────────┘
 /// ```
 ///
@@ -1977,16 +4907,36 @@ pub async fn control_loop(state: &mut AppState) -> String {
 ///
 /// # Errors
 ///
-/// Returns [`Error::Config`] if:
-/// - Failed to connect to Tor after all retry attempts
-/// - Invalid configuration values
+/// If Tor was never reached before shutdown or the retry limit, returns the
+/// last connection error when it names a specific cause:
+/// - [`Error::Control`] with an authentication failure
+/// - [`Error::UnsupportedTor`] if Tor is too old for vanguards
+/// - [`Error::Config`] for invalid configuration values
+///
+/// Any other cause is reported as [`Error::Connection`].
+///
+/// Returns [`Error::State`] before any connection attempt if the state
+/// file's directory does not exist or is not writable.
 ///
 /// # Shutdown Behavior
 ///
 /// The function handles graceful shutdown via:
 /// - CTRL+C signal (sets shutdown flag)
+/// - [`ShutdownHandle::shutdown`], when run through [`crate::Vanguards`]
 /// - Retry limit reached (configurable via `config.retry_limit`)
 ///
+/// Only connection and descriptor failures count against `retry_limit`.
+/// A wrong or missing password or an invalid configuration returns its
+/// error at once, and cookie authentication gives up after a few
+/// consecutive failures, since reconnecting will not fix them.
+///
+/// On Unix, `SIGUSR1` steps the log level via [`crate::logger::cycle_level`]
+/// so verbosity can be raised on a running daemon without losing state, and
+/// `SIGUSR2` logs [`AppState::state_summary`]. The summary is written by the
+/// event loop when it next wakes: within a second while Tor sends `BW`
+/// events, and at most a minute later otherwise. Neither signal is handled
+/// on other platforms.
+///
 /// # Example
 ///
 /// ```rust,no_run
@@ -2009,23 +4959,92 @@ pub async fn control_loop(state: &mut AppState) -> String {
 /// - [`Config`] - Configuration options
 /// - [`VanguardState`] - State persistence
 pub async fn run_main(config: Config) -> Result<()> {
+    run_main_with(config, RunHooks::default()).await
+}
+
+/// Extra wiring for a [`run_main`] driven from the library API.
+pub(crate) struct RunHooks {
+    /// Refreshed with [`AppState::vanguards_stats`] after detections, guard
+    /// and circuit changes, and each housekeeping pass.
+    pub(crate) stats: Option<Arc<tokio::sync::Mutex<VanguardsStats>>>,
+    /// Stops the loop when fired; a private one is used if `None`.
+    pub(crate) shutdown: Option<ShutdownHandle>,
+    /// Whether CTRL+C stops the loop.
+    pub(crate) ctrl_c: bool,
+    /// Registered with [`AppState::on_attack`] if set.
+    pub(crate) on_attack: Option<Arc<dyn Fn(AttackEvent) + Send + Sync>>,
+}
+
+impl Default for RunHooks {
+    fn default() -> Self {
+        Self {
+            stats: None,
+            shutdown: None,
+            ctrl_c: true,
+            on_attack: None,
+        }
+    }
+}
+
+/// Runs [`run_main`] with `hooks` attached to the event loop.
+pub(crate) async fn run_main_with(config: Config, hooks: RunHooks) -> Result<()> {
+    // Fail fast rather than after the first consensus if the state can't be saved
+    check_state_file_writable(&config.state_file)?;
+
     // Set up CTRL+C handler
-    let shutdown = Arc::new(AtomicBool::new(false));
-    let shutdown_clone = shutdown.clone();
+    let shutdown = hooks.shutdown.unwrap_or_default();
+    if hooks.ctrl_c {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Ok(()) = tokio::signal::ctrl_c().await {
+                plog(LogLevel::Notice, "Got CTRL+C. Exiting.");
+                shutdown.shutdown();
+            }
+        });
+    }
 
-    tokio::spawn(async move {
-        if let Ok(()) = tokio::signal::ctrl_c().await {
-            plog(LogLevel::Notice, "Got CTRL+C. Exiting.");
-            shutdown_clone.store(true, Ordering::SeqCst);
+    // SIGUSR1 cycles the log level without touching any other state
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut usr1) = signal(SignalKind::user_defined1()) else {
+            return;
+        };
+        while usr1.recv().await.is_some() {
+            match crate::logger::cycle_level() {
+                Ok(level) => plog(
+                    LogLevel::Notice,
+                    &format!("Got SIGUSR1. Log level is now {}.", level),
+                ),
+                Err(e) => plog(
+                    LogLevel::Warn,
+                    &format!("Got SIGUSR1 but could not change log level: {}", e),
+                ),
+            }
         }
     });
 
-    // Set close circuits flag from config
-    set_close_circuits(config.close_circuits);
+    // SIGUSR2 asks the event loop to log a summary of the protection state
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut usr2) = signal(SignalKind::user_defined2()) else {
+            return;
+        };
+        while usr2.recv().await.is_some() {
+            DUMP_REQUESTED.store(true, Ordering::SeqCst);
+        }
+    });
+
+    // Set close circuits flag from config
+    set_close_circuits(config.close_circuits);
+    crate::logger::set_anonymize_fingerprints(config.anonymize_fingerprints_in_logs);
 
     // Load or create vanguard state
     let state_path = &config.state_file;
-    let vanguard_state = match VanguardState::read_from_file(state_path) {
+    let vanguard_state = match VanguardState::read_with_backups(state_path) {
         Ok(mut state) => {
             plog(
                 LogLevel::Info,
@@ -2035,7 +5054,7 @@ pub async fn run_main(config: Config) -> Result<()> {
                 LogLevel::Info,
                 &format!("Current layer3 guards: {}", state.layer3_guardset()),
             );
-            state.enable_vanguards = config.enable_vanguards;
+            state.apply_config(&config);
             state
         }
         Err(_) => {
@@ -2047,20 +5066,47 @@ pub async fn run_main(config: Config) -> Result<()> {
                 ),
             );
             let mut state = VanguardState::new(&state_path.to_string_lossy());
-            state.enable_vanguards = config.enable_vanguards;
+            state.apply_config(&config);
             state
         }
     };
 
     let mut app_state = AppState::new(vanguard_state, config.clone());
+    app_state.shared_stats = hooks.stats;
+    if let Some(callback) = hooks.on_attack {
+        app_state = app_state.on_attack(move |event| callback(event));
+    }
+
+    if config.siem_format != SiemFormat::None {
+        if let Some(path) = &config.siem_output {
+            app_state.siem = Some(SiemWriter::open(config.siem_format, path)?);
+        }
+    }
+
+    // Serve local IPC clients if configured
+    if let Some(path) = &config.ipc_socket {
+        let ipc = Arc::new(IpcState::new());
+        crate::ipc::spawn(path, ipc.clone())?;
+        app_state.ipc = Some(ipc);
+    }
+
+    // Serve Prometheus scrapes if configured
+    if let Some(addr) = config.metrics_listen {
+        let metrics = Arc::new(MetricsServer::new());
+        metrics.spawn(addr)?;
+        app_state.metrics = Some(metrics);
+    }
 
     let mut reconnects = 0u32;
     let mut last_connected_at: Option<f64> = None;
     let mut connected = false;
+    let mut last_error: Option<Error> = None;
+    let mut cookie_failures = 0u32;
+    let mut backoff = ReconnectBackoff::new(Duration::from_secs(config.reconnect_max_backoff_secs));
 
     loop {
         // Check for shutdown
-        if shutdown.load(Ordering::SeqCst) {
+        if shutdown.is_shutdown() {
             break;
         }
 
@@ -2071,7 +5117,21 @@ pub async fn run_main(config: Config) -> Result<()> {
             }
         }
 
-        let result = control_loop(&mut app_state).await;
+        if let Some(reloaded) = app_state.pending_config.take() {
+            app_state.vanguard_state.apply_config(&reloaded);
+            app_state.config = reloaded;
+        }
+
+        let session_start = Instant::now();
+        let session = tokio::select! {
+            session = control_session(&mut app_state) => session,
+            _ = shutdown.wait() => break,
+        };
+        let session_lasted = session_start.elapsed();
+        let result = match &session {
+            Ok(()) => "closed".to_string(),
+            Err(e) => format!("failed: {}", e),
+        };
 
         if last_connected_at.is_none() {
             last_connected_at = Some(
@@ -2082,8 +5142,40 @@ pub async fn run_main(config: Config) -> Result<()> {
             );
         }
 
-        if result == "closed" {
-            connected = true;
+        match session {
+            Ok(()) => {
+                connected = true;
+                cookie_failures = 0;
+            }
+            Err(Error::NoNodesRemain) if config.on_no_guards == NoGuardsAction::Fail => {
+                return Err(Error::NoNodesRemain);
+            }
+            Err(e) => {
+                let give_up = match SessionFailure::classify(&e) {
+                    SessionFailure::Fatal => true,
+                    SessionFailure::CookieAuth => {
+                        cookie_failures += 1;
+                        cookie_failures >= MAX_COOKIE_AUTH_FAILURES
+                    }
+                    SessionFailure::Retryable => {
+                        cookie_failures = 0;
+                        false
+                    }
+                };
+                if give_up {
+                    plog(
+                        LogLevel::Error,
+                        &format!(
+                            "Tor connection {}. Retrying will not help; exiting.",
+                            result
+                        ),
+                    );
+                    return Err(e);
+                }
+                // Start the next attempt at the following control endpoint
+                app_state.control_rotation = app_state.control_rotation.wrapping_add(1);
+                last_error = Some(e);
+            }
         }
 
         // Log reconnection attempts (every 10 seconds or on first close)
@@ -2112,11 +5204,33 @@ pub async fn run_main(config: Config) -> Result<()> {
         reconnects += 1;
 
         // Wait before reconnecting
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(backoff.next_delay(session_lasted)) => {}
+            _ = shutdown.wait() => break,
+        }
     }
 
-    if !connected {
-        return Err(Error::Config("Failed to connect to Tor".to_string()));
+    if shutdown.is_shutdown() {
+        if !get_close_circuits() {
+            for line in app_state.would_close.to_string().lines() {
+                plog(LogLevel::Notice, line);
+            }
+        }
+        flush_state_on_shutdown(&app_state);
+    }
+
+    // CTRL+C is a clean exit even if it cut the first session short
+    if !connected && !shutdown.is_shutdown() {
+        // Keep errors that identify a specific cause; anything else is a
+        // generic failure to reach Tor.
+        return Err(match last_error {
+            Some(e @ Error::Control(stem_rs::Error::Authentication(_)))
+            | Some(e @ Error::UnsupportedTor(_))
+            | Some(e @ Error::State(_))
+            | Some(e @ Error::Config(_)) => e,
+            Some(e) => Error::Connection(format!("failed to connect to Tor: {}", e)),
+            None => Error::Connection("failed to connect to Tor".to_string()),
+        });
     }
 
     Ok(())
@@ -2126,8 +5240,11 @@ pub async fn run_main(config: Config) -> Result<()> {
 mod tests {
     use super::*;
     use std::io::Write;
+    use std::sync::Mutex;
     use tempfile::NamedTempFile;
 
+    use crate::bandguards::{CELL_PAYLOAD_SIZE, RELAY_PAYLOAD_SIZE};
+
     #[test]
     fn test_get_consensus_weights() {
         let mut file = NamedTempFile::new().unwrap();
@@ -2145,6 +5262,46 @@ mod tests {
         assert_eq!(weights.get("Wbd"), Some(&0));
     }
 
+    #[test]
+    fn test_get_consensus_valid_after() {
+        let valid_after = Utc::now() - chrono::Duration::minutes(90);
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "network-status-version 3 microdesc\n\
+             vote-status consensus\n\
+             valid-after {}\n\
+             fresh-until 2026-10-17 13:00:00",
+            valid_after.format("%Y-%m-%d %H:%M:%S")
+        )
+        .unwrap();
+
+        let parsed = get_consensus_valid_after(file.path()).unwrap();
+        assert_eq!(parsed.timestamp(), valid_after.timestamp());
+
+        let mut state = AppState::new(VanguardState::new("test.state"), Config::default());
+        assert_eq!(state.consensus_age_secs(), None);
+        state.consensus_valid_after = Some(parsed);
+        let age = state.consensus_age_secs().unwrap();
+        assert!((5400..5460).contains(&age), "age was {}", age);
+    }
+
+    #[test]
+    fn test_duplicate_weight_keys() {
+        let (weights, duplicates) =
+            parse_weight_line("bandwidth-weights Wbd=0 Wmm=10000 Wbd=5 Wmm=10000").unwrap();
+        assert_eq!(weights.get("Wbd"), Some(&5));
+        assert_eq!(weights.get("Wmm"), Some(&10000));
+        assert_eq!(duplicates.len(), 2);
+        assert!(duplicates[0].contains("Wbd"));
+
+        let (_, duplicates) = parse_weight_line("bandwidth-weights Wbd=0 Wmm=10000").unwrap();
+        assert!(duplicates.is_empty());
+
+        let err = parse_weight_line("bandwidth-weights Wmg=4194 Wmm=10000 Wmg=0").unwrap_err();
+        assert!(matches!(err, Error::Consensus(ref msg) if msg.contains("Wmg")));
+    }
+
     #[test]
     fn test_get_consensus_weights_missing() {
         let mut file = NamedTempFile::new().unwrap();
@@ -2154,6 +5311,142 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_layer_conf_settings_clear_disabled_layer3() {
+        let mut state = VanguardState::new("test.state");
+        state.layer2.push(crate::vanguards::GuardNode::new(
+            "A".repeat(40),
+            0.0,
+            f64::MAX,
+        ));
+
+        let config = VanguardsConfig::default();
+        let options: Vec<&str> = layer_conf_settings(&state, &config)
+            .into_iter()
+            .map(|(option, _)| option)
+            .collect();
+        assert_eq!(options, vec!["HSLayer2Nodes", "HSLayer3Nodes"]);
+
+        let config = VanguardsConfig {
+            enable_layer3: false,
+            ..VanguardsConfig::default()
+        };
+        assert_eq!(
+            layer_conf_settings(&state, &config),
+            vec![
+                ("HSLayer2Nodes", "A".repeat(40)),
+                ("HSLayer3Nodes", String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_guards_applies_external_selection() {
+        use crate::vanguards::GuardNode;
+
+        let guard = |fp: String| GuardNode::new(fp, 1000.0, 90000.0);
+        let mut state = VanguardState::new("test.state");
+        state.layer2.push(guard("F".repeat(40)));
+
+        // One bad fingerprint rejects the whole swap
+        let err = state
+            .set_guards(
+                vec![guard("A".repeat(40))],
+                vec![guard("B".repeat(40)), guard("XYZ".to_string())],
+            )
+            .unwrap_err();
+        assert!(matches!(err, Error::Validation(_)));
+        assert_eq!(state.layer2_guardset(), "F".repeat(40));
+        assert!(state
+            .set_guards(vec![guard("A".repeat(40)), guard("A".repeat(40))], vec![])
+            .is_err());
+
+        state
+            .set_guards(
+                vec![guard(format!("${}", "a".repeat(40))), guard("C".repeat(40))],
+                vec![guard("B".repeat(40))],
+            )
+            .unwrap();
+        assert_eq!(
+            layer_conf_settings(&state, &VanguardsConfig::default()),
+            vec![
+                (
+                    "HSLayer2Nodes",
+                    format!("{},{}", "A".repeat(40), "C".repeat(40))
+                ),
+                ("HSLayer3Nodes", "B".repeat(40)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_suppressed_close_reasons() {
+        assert_eq!(close_suppression(false, true), None);
+        assert_eq!(
+            close_suppression(false, false),
+            Some(CloseSuppression::MonitoringMode)
+        );
+        assert_eq!(
+            close_suppression(true, true),
+            Some(CloseSuppression::Synthetic)
+        );
+        assert_eq!(
+            close_suppression(true, false),
+            Some(CloseSuppression::Synthetic)
+        );
+
+        let monitoring = suppressed_close_message("7", CloseSuppression::MonitoringMode);
+        assert!(monitoring.contains("circuit 7"));
+        assert!(monitoring.contains("NOT acted on"));
+        assert!(monitoring.contains("monitoring mode"));
+        let synthetic = suppressed_close_message("SYNTHETIC", CloseSuppression::Synthetic);
+        assert!(synthetic.contains("synthetic detection"));
+        assert_ne!(monitoring, synthetic);
+    }
+
+    #[test]
+    fn test_bootstrap_reset_triggers_reapply() {
+        let mut vanguard_state = VanguardState::new("test.state");
+        vanguard_state.layer2.push(crate::vanguards::GuardNode::new(
+            "A".repeat(40),
+            0.0,
+            f64::MAX,
+        ));
+        let mut state = AppState::new(vanguard_state, Config::default());
+
+        let bootstrap = |progress: u8| match ParsedEvent::parse(
+            "STATUS_CLIENT",
+            &format!("NOTICE BOOTSTRAP PROGRESS={} TAG=x", progress),
+            None,
+        )
+        .unwrap()
+        {
+            ParsedEvent::Status(e) => e,
+            _ => panic!("expected status event"),
+        };
+
+        assert!(!handle_status_event(&mut state, &bootstrap(50)));
+        assert!(!handle_status_event(&mut state, &bootstrap(100)));
+        assert!(handle_status_event(&mut state, &bootstrap(5)));
+        assert_eq!(state.bootstrap_progress, Some(5));
+
+        let settings = layer_conf_settings(&state.vanguard_state, &state.config.vanguards);
+        assert_eq!(settings[0], ("HSLayer2Nodes", "A".repeat(40)));
+    }
+
+    #[test]
+    fn test_parse_ip_to_country() {
+        let response = "250-ip-to-country/1.2.3.4=DE\r\n\
+                        250-ip-to-country/[2001:db8::1]=us\r\n\
+                        250-ip-to-country/10.0.0.1=??\r\n\
+                        250 OK";
+        let countries = parse_ip_to_country(response);
+
+        assert_eq!(countries.len(), 2);
+        assert_eq!(countries[&"1.2.3.4".parse::<IpAddr>().unwrap()], "de");
+        assert_eq!(countries[&"2001:db8::1".parse::<IpAddr>().unwrap()], "us");
+    }
+
     #[test]
     fn test_base64_decode() {
         // Test standard base64 decoding
@@ -2170,19 +5463,105 @@ mod tests {
         // A typical Tor fingerprint in base64 (27 chars without padding)
         // 20 bytes = 160 bits, which is 27 base64 chars (ceil(160/6) = 27)
         let b64 = "AAAAAAAAAAAAAAAAAAAAAAAAAAA";
-        let hex = decode_base64_fingerprint(b64);
+        let hex = decode_base64_fingerprint(b64).unwrap();
         // Should produce 40 hex characters (20 bytes)
         assert_eq!(hex.len(), 40);
         assert!(hex.chars().all(|c| c.is_ascii_hexdigit()));
     }
 
+    #[test]
+    fn test_decode_base64_fingerprint_rejects_wrong_length() {
+        // 19 bytes encode to 26 characters, 21 bytes to 28
+        let short = "AAAAAAAAAAAAAAAAAAAAAAAAAA";
+        let long = "AAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+        assert!(matches!(
+            decode_base64_fingerprint(short),
+            Err(Error::Consensus(_))
+        ));
+        assert!(matches!(
+            decode_base64_fingerprint(long),
+            Err(Error::Consensus(_))
+        ));
+        // Right length, but bits past the 20th byte are set
+        assert!(decode_base64_fingerprint("AAAAAAAAAAAAAAAAAAAAAAAAAAB").is_err());
+        assert!(decode_base64_fingerprint("AAAAAAAAAAAAAAAAAAAAAAAAA!A").is_err());
+        assert!(decode_base64_fingerprint("AAAAAAAAAAAAAAAAAAAAAAAAAAA=").is_ok());
+
+        assert_eq!(base64_decode("SGVsbG8h").unwrap(), b"Hello!");
+        assert_eq!(base64_decode("SGVsbA=="), Some(b"Hell".to_vec()));
+        assert_eq!(base64_decode("SGVsbA="), None);
+        assert_eq!(base64_decode("SGVsbG8=="), None);
+        assert_eq!(base64_decode("SGVsb"), None);
+
+        let response = "\
+r short AAAAAAAAAAAAAAAAAAAAAAAAAA 2024-01-01 00:00:00 1.1.1.1 9001 0
+s Fast Running Valid
+w Bandwidth=1000
+r good AQEBAQEBAQEBAQEBAQEBAQEBAQE 2024-01-01 00:00:00 2.2.2.2 9001 0
+s Fast Guard Running Stable Valid
+w Bandwidth=2000
+r long AAAAAAAAAAAAAAAAAAAAAAAAAAAA 2024-01-01 00:00:00 3.3.3.3 9001 0
+s Fast Running Valid
+w Bandwidth=3000
+";
+        let routers = parse_network_statuses(response).unwrap();
+        assert_eq!(routers.len(), 1);
+        assert_eq!(routers[0].nickname, "good");
+        assert_eq!(routers[0].fingerprint, "01".repeat(20));
+        assert_eq!(routers[0].bandwidth, Some(2000));
+        assert_eq!(routers[0].flags.len(), 5);
+    }
+
+    #[test]
+    fn test_parse_network_statuses_ipv6() {
+        let response = "\
+r dual AQEBAQEBAQEBAQEBAQEBAQEBAQE 2024-01-01 00:00:00 192.0.2.1 9001 0
+a [2001:db8::1]:9001
+s Fast Running Stable Valid
+w Bandwidth=1000
+r v4only AgICAgICAgICAgICAgICAgICAgI BBBBBBBBBBBBBBBBBBBBBBBBBBB 2024-01-01 00:00:00 198.51.100.1 443 0
+s Fast Running Stable Valid
+w Bandwidth=1000
+r v6addr AwMDAwMDAwMDAwMDAwMDAwMDAwM 2024-01-01 00:00:00 [2001:db8:1::2] 9002 0
+s Fast Running Stable Valid
+w Bandwidth=1000
+";
+        let routers = parse_network_statuses(response).unwrap();
+        assert_eq!(routers.len(), 3);
+
+        let dual = &routers[0];
+        assert_eq!(dual.address, "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            dual.or_addresses,
+            vec![("2001:db8::1".parse().unwrap(), 9001, true)]
+        );
+        // ns layout: the digest comes before the published time
+        assert_eq!(
+            routers[1].address,
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(routers[1].or_port, 443);
+        assert!(routers[1].or_addresses.is_empty());
+        assert_eq!(
+            routers[2].address,
+            "2001:db8:1::2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(routers[2].or_port, 9002);
+
+        let mut exclude = ExcludeNodes::new();
+        exclude.networks.push("2001:db8::/32".parse().unwrap());
+        assert!(exclude.router_is_excluded(dual));
+        assert!(!exclude.router_is_excluded(&routers[1]));
+        assert!(exclude.router_is_excluded(&routers[2]));
+    }
+
     #[test]
     fn test_parse_network_statuses() {
         let response = "\
-r relay1 AAAAAAAAAAAAAAAAAAAAAAAAAAAA BBBBBBBBBBBBBBBBBBBBBBBBBBBB 2024-01-01 00:00:00 192.168.1.1 9001 0
+r relay1 AAAAAAAAAAAAAAAAAAAAAAAAAAA BBBBBBBBBBBBBBBBBBBBBBBBBBBB 2024-01-01 00:00:00 192.168.1.1 9001 0
 s Fast Guard Running Stable Valid
 w Bandwidth=1000 Measured=900
-r relay2 CCCCCCCCCCCCCCCCCCCCCCCCCCCC DDDDDDDDDDDDDDDDDDDDDDDDDDDD 2024-01-01 00:00:00 192.168.1.2 9002 0
+r relay2 CCCCCCCCCCCCCCCCCCCCCCCCCCA DDDDDDDDDDDDDDDDDDDDDDDDDDDD 2024-01-01 00:00:00 192.168.1.2 9002 0
 s Fast Running Stable Valid Exit
 w Bandwidth=2000";
 
@@ -2193,6 +5572,10 @@ w Bandwidth=2000";
         assert!(routers[0].flags.contains(&"Guard".to_string()));
         assert_eq!(routers[0].bandwidth, Some(1000));
         assert_eq!(routers[0].measured, Some(900));
+        assert_eq!(
+            routers[0].published.to_rfc3339(),
+            "2024-01-01T00:00:00+00:00"
+        );
 
         assert_eq!(routers[1].nickname, "relay2");
         assert!(routers[1].flags.contains(&"Exit".to_string()));
@@ -2200,8 +5583,154 @@ w Bandwidth=2000";
         assert_eq!(routers[1].measured, None);
     }
 
+    #[test]
+    fn test_badexit_relay_never_selected() {
+        // relay1 carries nearly all the bandwidth, so it would almost always
+        // be picked if BadExit relays were eligible.
+        let response = "\
+r relay1 AAAAAAAAAAAAAAAAAAAAAAAAAAA BBBBBBBBBBBBBBBBBBBBBBBBBBBB 2024-01-01 00:00:00 192.168.1.1 9001 0
+s BadExit Fast Running Stable Valid
+w Bandwidth=1000000 Measured=1000000
+r relay2 CCCCCCCCCCCCCCCCCCCCCCCCCCA DDDDDDDDDDDDDDDDDDDDDDDDDDDD 2024-01-01 00:00:00 192.168.1.2 9002 0
+s Fast Running Stable Valid
+w Bandwidth=10 Measured=10";
+        let routers = parse_network_statuses(response).unwrap();
+        let badexit = routers[0].fingerprint.clone();
+        let config = Config {
+            vanguards: crate::config::VanguardsConfig {
+                num_layer2_guards: 1,
+                num_layer3_guards: 1,
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+
+        for _ in 0..20 {
+            let mut state = VanguardState::new("test.state");
+            state.enable_vanguards = true;
+            consensus_update(
+                &mut state,
+                &routers,
+                &HashMap::new(),
+                &ExcludeNodes::new(),
+                &config,
+            )
+            .unwrap();
+
+            assert!(state
+                .layer2
+                .iter()
+                .chain(&state.layer3)
+                .all(|g| g.idhex != badexit));
+        }
+    }
+
+    #[test]
+    fn test_revalidation_drops_newly_flagged_guard() {
+        let response = "\
+r relay1 AAAAAAAAAAAAAAAAAAAAAAAAAAA BBBBBBBBBBBBBBBBBBBBBBBBBBBB 2024-01-01 00:00:00 192.168.1.1 9001 0
+s Fast Running Stable Valid
+w Bandwidth=1000 Measured=1000
+r relay2 CCCCCCCCCCCCCCCCCCCCCCCCCCA DDDDDDDDDDDDDDDDDDDDDDDDDDDD 2024-01-01 00:00:00 192.168.1.2 9002 0
+s Fast Running Stable Valid
+w Bandwidth=1000 Measured=1000
+r relay3 EEEEEEEEEEEEEEEEEEEEEEEEEEE FFFFFFFFFFFFFFFFFFFFFFFFFFFF 2024-01-01 00:00:00 192.168.1.3 9003 0
+s Fast Running Stable Valid
+w Bandwidth=1000 Measured=1000";
+        let mut cached = CachedConsensus {
+            routers: parse_network_statuses(response).unwrap(),
+            ..CachedConsensus::default()
+        };
+        let config = Config {
+            vanguards: crate::config::VanguardsConfig {
+                num_layer2_guards: 1,
+                num_layer3_guards: 1,
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let flagged = cached.routers[0].fingerprint.clone();
+        let far_future = 4_000_000_000.0;
+
+        let mut state = VanguardState::new("test.state");
+        state.enable_vanguards = true;
+        state.layer2.push(crate::vanguards::GuardNode::new(
+            flagged.clone(),
+            0.0,
+            far_future,
+        ));
+        state.layer3.push(crate::vanguards::GuardNode::new(
+            cached.routers[1].fingerprint.clone(),
+            0.0,
+            far_future,
+        ));
+
+        // Nothing changed, so nothing is replaced
+        assert!(!revalidate_guards(&mut state, &cached, &ExcludeNodes::new(), &config).unwrap());
+        assert_eq!(state.layer2[0].idhex, flagged);
+
+        // Selection-only filters do not evict a guard that became light
+        let mut lighter = cached.clone();
+        lighter.routers[0].measured = Some(1);
+        let mut percentile = config.clone();
+        percentile.vanguards.min_weight_percentile = 0.5;
+        assert!(
+            !revalidate_guards(&mut state, &lighter, &ExcludeNodes::new(), &percentile).unwrap()
+        );
+        assert_eq!(state.layer2[0].idhex, flagged);
+
+        cached.routers[0].flags.push("BadExit".to_string());
+        assert!(revalidate_guards(&mut state, &cached, &ExcludeNodes::new(), &config).unwrap());
+        assert_eq!(state.layer2.len(), 1);
+        assert_ne!(state.layer2[0].idhex, flagged);
+        assert_eq!(state.layer3[0].idhex, cached.routers[1].fingerprint);
+    }
+
+    #[test]
+    fn test_min_weight_percentile_must_leave_enough_relays() {
+        let response = "\
+r relay1 AAAAAAAAAAAAAAAAAAAAAAAAAAA BBBBBBBBBBBBBBBBBBBBBBBBBBBB 2024-01-01 00:00:00 192.168.1.1 9001 0
+s Fast Running Stable Valid
+w Bandwidth=1000 Measured=1000
+r relay2 CCCCCCCCCCCCCCCCCCCCCCCCCCA DDDDDDDDDDDDDDDDDDDDDDDDDDDD 2024-01-01 00:00:00 192.168.1.2 9002 0
+s Fast Running Stable Valid
+w Bandwidth=10 Measured=10
+r relay3 EEEEEEEEEEEEEEEEEEEEEEEEEEE FFFFFFFFFFFFFFFFFFFFFFFFFFFF 2024-01-01 00:00:00 192.168.1.3 9003 0
+s Fast Running Stable Valid
+w Bandwidth=10 Measured=10";
+        let routers = parse_network_statuses(response).unwrap();
+        let update = |num_guards| {
+            let config = Config {
+                vanguards: crate::config::VanguardsConfig {
+                    num_layer2_guards: num_guards,
+                    num_layer3_guards: num_guards,
+                    min_weight_percentile: 0.5,
+                    ..Default::default()
+                },
+                ..Config::default()
+            };
+            let mut state = VanguardState::new("test.state");
+            state.enable_vanguards = true;
+            consensus_update(
+                &mut state,
+                &routers,
+                &HashMap::new(),
+                &ExcludeNodes::new(),
+                &config,
+            )
+        };
+
+        // Only relay1 is left, which is enough for one guard but not two
+        assert!(update(1).is_ok());
+        let err = update(2).unwrap_err().to_string();
+        assert!(err.contains("leaves 1 of 3 relays"), "{}", err);
+    }
+
     #[test]
     fn test_close_circuits_flag() {
+        let _guard = CLOSE_CIRCUITS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
         set_close_circuits(true);
         assert!(get_close_circuits());
 
@@ -2211,4 +5740,2051 @@ w Bandwidth=2000";
         // Reset to default
         set_close_circuits(true);
     }
+
+    #[test]
+    fn test_check_state_file_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("vanguards.state");
+
+        assert!(check_state_file_writable(&state_file).is_ok());
+        assert!(!dir.path().join("vanguards.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_main_missing_state_dir_fails_at_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            state_file: dir.path().join("missing").join("vanguards.state"),
+            control_port: Some(1),
+            retry_limit: Some(0),
+            ..Config::default()
+        };
+
+        let result = tokio::time::timeout(Duration::from_secs(5), run_main(config))
+            .await
+            .expect("startup check should not wait on a Tor connection");
+
+        match result {
+            Err(Error::State(msg)) => assert!(msg.contains("does not exist")),
+            other => panic!("expected state error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tor_capabilities_from_version() {
+        let caps = |major, minor, micro, patch| {
+            TorCapabilities::from_version(&Version::new(major, minor, micro).with_patch(patch))
+        };
+
+        let old = caps(0, 3, 2, 10);
+        assert!(old.has_conf_changed);
+        assert!(!old.has_hslayer);
+        assert!(!old.has_circ_bw);
+        assert_eq!(old.max_hslayer_nodes, 0);
+
+        let hslayer_only = caps(0, 3, 4, 9);
+        assert!(hslayer_only.has_hslayer);
+        assert!(!hslayer_only.has_circ_bw);
+
+        let modern = caps(0, 4, 8, 12);
+        assert_eq!(
+            modern,
+            TorCapabilities {
+                has_circ_bw: true,
+                has_hslayer: true,
+                has_conf_changed: true,
+                max_hslayer_nodes: usize::MAX,
+            }
+        );
+        assert!(!caps(0, 2, 2, 39).has_conf_changed);
+
+        let config = Config::default();
+        assert!(matches!(
+            old.check_layers(&config.vanguards),
+            Err(Error::UnsupportedTor(_))
+        ));
+        assert!(modern.check_layers(&config.vanguards).is_ok());
+
+        let events = get_event_types(&config, &hslayer_only);
+        assert!(!events.contains(&EventType::CircBw));
+        assert!(get_event_types(&config, &modern).contains(&EventType::CircBw));
+    }
+
+    #[test]
+    fn test_synthetic_attack_matches_real_report() {
+        let mut state = AppState::new(VanguardState::new("test.state"), Config::default());
+        let ipc = Arc::new(IpcState::new());
+        state.ipc = Some(ipc.clone());
+        let mut events = ipc.subscribe();
+
+        let real = CircuitLimitResult::DroppedCells { dropped_cells: 1 };
+        let synthetic =
+            CircuitLimitResult::synthetic("dropped_cells", &state.config.bandguards).unwrap();
+        assert_eq!(synthetic, real);
+
+        assert!(report_limit_result(&mut state, "42", &real, false, 1000.0));
+        assert!(!report_limit_result(
+            &mut state,
+            SYNTHETIC_CIRC_ID,
+            &synthetic,
+            true,
+            1000.0
+        ));
+
+        let (real_level, real_message) = limit_result_message("42", &real).unwrap();
+        let (synthetic_level, synthetic_message) =
+            limit_result_message(SYNTHETIC_CIRC_ID, &synthetic).unwrap();
+        assert_eq!(real_level, synthetic_level);
+        assert_eq!(
+            real_message.replace("42", SYNTHETIC_CIRC_ID),
+            synthetic_message
+        );
+
+        assert_eq!(
+            events.try_recv().unwrap(),
+            VanguardEvent::AttackDetected {
+                kind: "dropped_cells".to_string(),
+                circ_id: "42".to_string(),
+                message: real_message,
+                synthetic: false,
+            }
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            VanguardEvent::AttackDetected {
+                kind: "dropped_cells".to_string(),
+                circ_id: SYNTHETIC_CIRC_ID.to_string(),
+                message: format!("[SYNTHETIC] {}", synthetic_message),
+                synthetic: true,
+            }
+        );
+
+        for kind in crate::bandguards::ATTACK_KINDS {
+            let result = CircuitLimitResult::synthetic(kind, &state.config.bandguards).unwrap();
+            assert_eq!(result.attack_kind(), Some(kind));
+        }
+    }
+
+    #[test]
+    fn test_status_published_only_when_stale() {
+        let mut state = AppState::new(VanguardState::new("test.state"), Config::default());
+        let ipc = Arc::new(IpcState::new());
+        state.ipc = Some(ipc.clone());
+        let score = || match ipc.status() {
+            VanguardEvent::Status {
+                protection_score, ..
+            } => protection_score,
+            _ => panic!("expected status"),
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // The first pass of the event loop publishes
+        assert!(state.status_stale);
+        runtime.block_on(state.publish_status());
+        assert!(!state.status_stale);
+        assert!(score().is_some());
+
+        // A synthetic report changes nothing the status covers
+        let synthetic =
+            CircuitLimitResult::synthetic("dropped_cells", &state.config.bandguards).unwrap();
+        report_limit_result(&mut state, SYNTHETIC_CIRC_ID, &synthetic, true, 1000.0);
+        assert!(!state.status_stale);
+        report_limit_result(&mut state, "42", &synthetic, false, 1000.0);
+        assert!(state.status_stale);
+
+        state.status_stale = false;
+        state.publish_guards();
+        assert!(state.status_stale);
+    }
+
+    #[test]
+    fn test_weight_override_changes_selection_probabilities() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "\
+network-status-version 3 microdesc
+bandwidth-weights Wmg=10000 Wmm=10000
+r guard AAAAAAAAAAAAAAAAAAAAAAAAAAA 2024-01-01 00:00:00 192.168.1.1 9001 0
+s Fast Guard Running Stable Valid
+w Bandwidth=1000
+r middle CCCCCCCCCCCCCCCCCCCCCCCCCCA 2024-01-01 00:00:00 192.168.1.2 9001 0
+s Fast Running Stable Valid
+w Bandwidth=1000"
+        )
+        .unwrap();
+
+        let middle_probability = |config: &Config| {
+            let probabilities = analyze_consensus(file.path(), config).unwrap();
+            probabilities
+                .iter()
+                .find(|(fp, _)| fp.starts_with("0820"))
+                .map(|(_, p)| *p)
+                .unwrap()
+        };
+
+        let config = Config::default();
+        assert!((middle_probability(&config) - 0.5).abs() < 1e-9);
+
+        let config = Config {
+            bw_weight_overrides: crate::config::parse_weight_overrides("Wmm=5000").unwrap(),
+            ..Config::default()
+        };
+        assert!((middle_probability(&config) - 1.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_malformed_event_is_skipped() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                use tokio::io::AsyncWriteExt;
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream
+                    .write_all(b"650 CIRC\r\n650 SIGNAL RELOAD\r\n")
+                    .await
+                    .unwrap();
+            });
+
+            let mut controller = Controller::from_port(addr).await.unwrap();
+            let event = recv_next_event(&mut controller).await;
+            assert!(matches!(event, Some(ParsedEvent::Signal(_))));
+
+            server.await.unwrap();
+            assert!(recv_next_event(&mut controller).await.is_none());
+        });
+
+        assert!(!is_fatal_event_error(&stem_rs::Error::Protocol(
+            "missing ID".to_string()
+        )));
+        assert!(is_fatal_event_error(&stem_rs::Error::SocketClosed));
+    }
+
+    /// Answers PROTOCOLINFO, AUTHENTICATE and GETINFO version like an open Tor.
+    async fn serve_mock_tor(listener: tokio::net::TcpListener) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let reply = match line.split_whitespace().next().unwrap_or_default() {
+                "PROTOCOLINFO" => {
+                    "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n\
+                     250-VERSION Tor=\"0.4.8.12\"\r\n250 OK\r\n"
+                }
+                "AUTHENTICATE" => "250 OK\r\n",
+                "GETINFO" => "250-version=0.4.8.12\r\n250 OK\r\n",
+                _ => "510 Unrecognized command\r\n",
+            };
+            if writer.write_all(reply.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn test_unreachable_primary_uses_fallback() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            // Bind and drop a listener to get a port nothing is listening on
+            let closed_port = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let fallback = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let fallback_addr = fallback.local_addr().unwrap();
+            let server = tokio::spawn(serve_mock_tor(fallback));
+
+            let mut config = Config {
+                control_port: Some(closed_port),
+                ..Config::default()
+            };
+            assert!(open_control_connection(&config, 0).await.is_err());
+
+            config.control_fallbacks = vec![
+                ControlEndpoint {
+                    socket: Some(std::path::PathBuf::from("/nonexistent/control")),
+                    ..ControlEndpoint::default()
+                },
+                ControlEndpoint {
+                    address: Some(fallback_addr.to_string()),
+                    ..ControlEndpoint::default()
+                },
+            ];
+            config.validate().unwrap();
+            let mut controller = open_control_connection(&config, 0)
+                .await
+                .unwrap()
+                .controller;
+            assert_eq!(
+                controller.get_version().await.unwrap(),
+                Version::new(0, 4, 8).with_patch(12)
+            );
+
+            drop(controller);
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_pathverify_audits_against_tor_configured_layers() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let l2a = "A".repeat(40);
+        let l2b = "B".repeat(40);
+        let l3 = "C".repeat(40);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let layer2_reply = format!("250 HSLayer2Nodes=${}~manual,${}\r\n", l2a, l2b);
+            let layer3_reply = format!("250 HSLayer3Nodes={}\r\n", l3.to_lowercase());
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = if line.contains("HSLayer2Nodes") {
+                        &layer2_reply
+                    } else {
+                        &layer3_reply
+                    };
+                    writer.write_all(reply.as_bytes()).await.unwrap();
+                }
+            });
+
+            let mut controller = Controller::from_port(addr).await.unwrap();
+            // Not managing vanguards; configured counts differ from Tor's
+            let mut pv = PathVerify::new(false, 2, 4, 8);
+            load_pathverify_layers(&mut controller, &mut pv).await;
+
+            assert!(pv.follow_tor_layers);
+            assert_eq!((pv.num_layer2, pv.num_layer3), (2, 1));
+            assert!(pv.check_layer_counts());
+
+            let hop = |fp: &str| (fp.to_string(), None);
+            let good = [
+                hop(&"D".repeat(40)),
+                hop(&l2b),
+                hop(&l3),
+                hop(&"E".repeat(40)),
+            ];
+            assert!(pv.layer_violations(&good).is_empty());
+
+            let bad = [hop(&"D".repeat(40)), hop(&"F".repeat(40)), hop(&l3)];
+            assert_eq!(pv.layer_violations(&bad).len(), 1);
+
+            drop(controller);
+            server.await.unwrap();
+        });
+    }
+
+    /// Serves the control commands [`apply_consensus`] sends.
+    ///
+    /// Each `GETINFO ns/all` is answered with the next entry of `ns_replies`,
+    /// the last one repeating. `None` entries are answered with an error.
+    async fn serve_consensus_tor(
+        listener: tokio::net::TcpListener,
+        mut ns_replies: Vec<Option<String>>,
+        data_dir: String,
+    ) -> usize {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut ns_requests = 0;
+        while let Ok(Some(line)) = lines.next_line().await {
+            let mut words = line.split_whitespace();
+            let reply = match (words.next(), words.next()) {
+                (Some("GETINFO"), Some("ns/all")) => {
+                    ns_requests += 1;
+                    let body = if ns_replies.len() > 1 {
+                        ns_replies.remove(0)
+                    } else {
+                        ns_replies[0].clone()
+                    };
+                    match body {
+                        Some(body) => format!("250+ns/all=\r\n{}.\r\n250 OK\r\n", body),
+                        None => "551 Internal error\r\n".to_string(),
+                    }
+                }
+                (Some("GETCONF"), Some("DataDirectory")) => {
+                    format!("250 DataDirectory={}\r\n", data_dir)
+                }
+                (Some("GETCONF"), Some(key)) => format!("250 {}\r\n", key),
+                _ => "250 OK\r\n".to_string(),
+            };
+            if writer.write_all(reply.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+        ns_requests
+    }
+
+    /// Runs [`apply_startup_consensus`] against a mock Tor whose successive
+    /// `ns/all` replies list `relay_counts[i]` usable relays.
+    /// Writes a `cached-microdesc-consensus` with weights into `dir` and
+    /// returns its path.
+    fn write_mock_consensus(dir: &Path) -> std::path::PathBuf {
+        let path = dir.join("cached-microdesc-consensus");
+        std::fs::write(
+            &path,
+            "network-status-version 3 microdesc\n\
+             valid-after 2024-01-01 00:00:00\n\
+             bandwidth-weights Wbd=0 Wbe=0 Wbg=4194 Wbm=10000 Wdb=10000 Wed=10000 Wee=10000 Weg=10000 Wem=10000 Wgb=10000 Wgd=0 Wgg=5806 Wgm=5806 Wmb=10000 Wmd=0 Wme=0 Wmg=4194 Wmm=10000\n",
+        )
+        .unwrap();
+        path
+    }
+
+    /// `ns/all` body listing `count` usable relays.
+    fn mock_relays(count: usize) -> String {
+        (0..count)
+            .map(|i| {
+                format!(
+                    "r relay{} {}{} 2024-01-01 00:00:00 192.0.2.{} 9001 0\r\n\
+                     s Fast Running Stable Valid\r\n\
+                     w Bandwidth=1000\r\n",
+                    i,
+                    "A".repeat(26),
+                    (b'A' + i as u8) as char,
+                    i + 1
+                )
+            })
+            .collect()
+    }
+
+    /// App state managing two guards per layer, with its state file in `dir`.
+    fn mock_app_state(dir: &Path) -> AppState {
+        let mut config = Config {
+            state_file: dir.join("vanguards.state"),
+            ..Config::default()
+        };
+        config.vanguards.num_layer2_guards = 2;
+        config.vanguards.num_layer3_guards = 2;
+        let mut vanguard_state = VanguardState::new(&config.state_file.to_string_lossy());
+        vanguard_state.enable_vanguards = true;
+        AppState::new(vanguard_state, config)
+    }
+
+    fn run_startup_consensus(
+        action: NoGuardsAction,
+        relay_counts: &[usize],
+    ) -> (Result<()>, AppState, usize) {
+        let dir = tempfile::tempdir().unwrap();
+        write_mock_consensus(dir.path());
+        let ns_replies = relay_counts
+            .iter()
+            .map(|&count| Some(mock_relays(count)))
+            .collect();
+
+        let mut state = mock_app_state(dir.path());
+        state.config.on_no_guards = action;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (result, ns_requests) = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let data_dir = dir.path().to_string_lossy().into_owned();
+            let server = tokio::spawn(serve_consensus_tor(listener, ns_replies, data_dir));
+
+            let mut controller = Controller::from_port(addr).await.unwrap();
+            let result =
+                apply_startup_consensus(&mut state, &mut controller, Duration::from_millis(10))
+                    .await;
+            drop(controller);
+            (result, server.await.unwrap())
+        });
+        (result, state, ns_requests)
+    }
+
+    #[test]
+    fn test_no_guards_fail() {
+        let (result, state, ns_requests) = run_startup_consensus(NoGuardsAction::Fail, &[0, 20]);
+        assert!(matches!(result, Err(Error::NoNodesRemain)));
+        assert_eq!(ns_requests, 1);
+        assert!(state.vanguard_state.layer2.is_empty());
+    }
+
+    #[test]
+    fn test_no_guards_warn_and_continue() {
+        let (result, state, ns_requests) =
+            run_startup_consensus(NoGuardsAction::WarnAndContinue, &[0, 20]);
+        assert!(result.is_ok());
+        assert_eq!(ns_requests, 1);
+        assert!(state.vanguard_state.layer2.is_empty());
+        assert!(state.vanguard_state.layer3.is_empty());
+    }
+
+    #[test]
+    fn test_no_guards_wait_and_retry() {
+        let (result, state, ns_requests) =
+            run_startup_consensus(NoGuardsAction::WaitAndRetry, &[0, 0, 20]);
+        assert!(result.is_ok());
+        assert_eq!(ns_requests, 3);
+        assert_eq!(state.vanguard_state.layer2.len(), 2);
+        assert_eq!(state.vanguard_state.layer3.len(), 2);
+    }
+
+    #[test]
+    fn test_consensus_fetch_failure_uses_fresh_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let consensus_file = write_mock_consensus(dir.path());
+        let cache = |age_mins: i64| CachedConsensus {
+            routers: parse_network_statuses(&mock_relays(20)).unwrap(),
+            weights: get_consensus_weights(&consensus_file).unwrap(),
+            valid_after: Some(Utc::now() - chrono::Duration::minutes(age_mins)),
+            ..CachedConsensus::default()
+        };
+        assert!(cache(30).is_valid_at(Utc::now()));
+        assert!(!cache(181).is_valid_at(Utc::now()));
+        assert!(!CachedConsensus::default().is_valid_at(Utc::now()));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let apply_with_cache = |cached: CachedConsensus| {
+            let mut state = mock_app_state(dir.path());
+            state.cached_consensus = Some(cached);
+            let data_dir = dir.path().to_string_lossy().into_owned();
+            let result = runtime.block_on(async {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let server = tokio::spawn(serve_consensus_tor(listener, vec![None], data_dir));
+                let mut controller = Controller::from_port(addr).await.unwrap();
+                let result = state.apply_consensus(&mut controller).await;
+                drop(controller);
+                server.await.unwrap();
+                result
+            });
+            (result, state)
+        };
+
+        let fresh = cache(30);
+        let (result, state) = apply_with_cache(fresh.clone());
+        assert!(result.is_ok(), "{:?}", result);
+        let cached_fps: HashSet<&str> = fresh
+            .routers
+            .iter()
+            .map(|r| r.fingerprint.as_str())
+            .collect();
+        assert_eq!(state.vanguard_state.layer2.len(), 2);
+        assert!(state
+            .vanguard_state
+            .layer2
+            .iter()
+            .chain(&state.vanguard_state.layer3)
+            .all(|g| cached_fps.contains(g.idhex.as_str())));
+        assert_eq!(state.consensus_valid_after, fresh.valid_after);
+
+        let (result, state) = apply_with_cache(cache(240));
+        assert!(matches!(result, Err(Error::DescriptorUnavailable(_))));
+        assert!(state.vanguard_state.layer2.is_empty());
+    }
+
+    #[test]
+    fn test_handler_timing_is_recorded() {
+        let mut state = AppState::new(VanguardState::new("test.state"), Config::default());
+        let handled = state.timed(Handler::Circ, |s| {
+            std::thread::sleep(Duration::from_millis(2));
+            s.bandwidth_stats
+                .circ_event("1", "LAUNCHED", "GENERAL", None, &[], None, 1000.0)
+        });
+        assert_eq!(handled, None);
+        assert!(state.bandwidth_stats.circs.contains_key("1"));
+
+        let circ = state.handler_latency.histogram(Handler::Circ);
+        assert_eq!(circ.count, 1);
+        assert!(circ.max_us >= 2000);
+        assert_eq!(state.handler_latency.histogram(Handler::CircBw).count, 0);
+
+        let summaries = state.handler_latency.summaries();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].handler, Handler::Circ);
+        assert_eq!(summaries[0].max_us, circ.max_us);
+    }
+
+    #[test]
+    fn test_detection_is_exported_to_siem() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("siem.log");
+        let mut state = AppState::new(VanguardState::new("test.state"), Config::default());
+        state.siem = Some(SiemWriter::open(SiemFormat::Cef, &path).unwrap());
+        state
+            .bandwidth_stats
+            .circ_event("42", "LAUNCHED", "GENERAL", None, &[], None, 1000.0);
+
+        let result = CircuitLimitResult::DroppedCells { dropped_cells: 1 };
+        assert!(report_limit_result(
+            &mut state, "42", &result, false, 1001.0
+        ));
+        assert!(!report_limit_result(
+            &mut state,
+            "42",
+            &CircuitLimitResult::Ok,
+            false,
+            1002.0
+        ));
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("CEF:0|tn3w|vanguards-rs|"));
+        assert!(lines[0].contains("|dropped_cells|"));
+        assert!(lines[0].contains("rt=1001000 cat=detection cs1Label=circuitId cs1=42"));
+    }
+
+    #[test]
+    fn test_no_interactive_fails_on_missing_password() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            // A Tor that only accepts a password; any AUTHENTICATE succeeds,
+            // so reaching it would mean a password was prompted for
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                let mut authenticated = false;
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = match line.split_whitespace().next().unwrap_or_default() {
+                        "PROTOCOLINFO" => {
+                            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=HASHEDPASSWORD\r\n\
+                             250-VERSION Tor=\"0.4.8.12\"\r\n250 OK\r\n"
+                        }
+                        "AUTHENTICATE" => {
+                            authenticated = true;
+                            "250 OK\r\n"
+                        }
+                        _ => "510 Unrecognized command\r\n",
+                    };
+                    if writer.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+                authenticated
+            });
+
+            let mut controller = Controller::from_port(addr).await.unwrap();
+            let err = authenticate_any_with(&mut controller, None, true)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Config(ref msg) if msg.contains("no_interactive")));
+            drop(controller);
+            assert!(!server.await.unwrap());
+        });
+    }
+
+    /// Serves connections until the test ends, answering like a Tor that
+    /// rejects every password, and counts the connections.
+    async fn serve_auth_rejecting_tor(
+        listener: tokio::net::TcpListener,
+        connections: Arc<std::sync::atomic::AtomicUsize>,
+    ) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.fetch_add(1, Ordering::SeqCst);
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = match line.split_whitespace().next().unwrap_or_default() {
+                    "PROTOCOLINFO" => {
+                        "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=HASHEDPASSWORD\r\n\
+                         250-VERSION Tor=\"0.4.8.12\"\r\n250 OK\r\n"
+                    }
+                    "AUTHENTICATE" => "515 Authentication failed: Password did not match\r\n",
+                    _ => "514 Authentication required.\r\n",
+                };
+                if writer.write_all(reply.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check_never_prompts() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = tokio::spawn(serve_auth_rejecting_tor(listener, connections));
+
+        let config = Config {
+            control_port: Some(port),
+            no_interactive: false,
+            ..Config::default()
+        };
+        let err = health_check(&config).await.unwrap_err();
+        server.abort();
+        assert!(err.to_string().contains("no_interactive is set"), "{}", err);
+    }
+
+    #[test]
+    fn test_auth_failure_exits_before_retry_limit() {
+        // run_main sets the global close-circuits flag
+        let _guard = CLOSE_CIRCUITS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let server = tokio::spawn(serve_auth_rejecting_tor(listener, connections.clone()));
+
+            let config = Config {
+                state_file: dir.path().join("vanguards.state"),
+                control_port: Some(port),
+                control_pass: Some("wrong".to_string()),
+                retry_limit: Some(5),
+                ..Config::default()
+            };
+            let result = tokio::time::timeout(Duration::from_secs(5), run_main(config))
+                .await
+                .expect("an auth failure should not wait out the retry budget");
+            server.abort();
+
+            assert!(matches!(
+                result,
+                Err(Error::Control(stem_rs::Error::Authentication(_)))
+            ));
+            assert_eq!(connections.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn test_connection_failure_retries_up_to_limit() {
+        let _guard = CLOSE_CIRCUITS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let counter = connections.clone();
+            // Accept and hang up at once, like a Tor that is shutting down
+            let server = tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    drop(stream);
+                }
+            });
+
+            let config = Config {
+                state_file: dir.path().join("vanguards.state"),
+                control_port: Some(port),
+                retry_limit: Some(2),
+                ..Config::default()
+            };
+            let result = run_main(config).await;
+            server.abort();
+
+            assert!(matches!(result, Err(Error::Connection(_))), "{:?}", result);
+            assert_eq!(connections.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn test_session_failure_classification() {
+        use stem_rs::AuthError;
+
+        let auth = |e| Error::Control(stem_rs::Error::Authentication(e));
+        assert_eq!(
+            SessionFailure::classify(&auth(AuthError::IncorrectPassword)),
+            SessionFailure::Fatal
+        );
+        assert_eq!(
+            SessionFailure::classify(&auth(AuthError::MissingPassword)),
+            SessionFailure::Fatal
+        );
+        assert_eq!(
+            SessionFailure::classify(&auth(AuthError::CookieUnreadable("gone".to_string()))),
+            SessionFailure::CookieAuth
+        );
+        assert_eq!(
+            SessionFailure::classify(&Error::Config("bad".to_string())),
+            SessionFailure::Fatal
+        );
+        assert_eq!(
+            SessionFailure::classify(&Error::DescriptorUnavailable("md".to_string())),
+            SessionFailure::Retryable
+        );
+        assert_eq!(
+            SessionFailure::classify(&Error::Connection("refused".to_string())),
+            SessionFailure::Retryable
+        );
+    }
+
+    #[test]
+    fn test_aged_circuits_are_closed() {
+        let _guard = CLOSE_CIRCUITS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        set_close_circuits(true);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = mock_app_state(dir.path());
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        for circ_id in ["old", "new"] {
+            state.bandwidth_stats.circ_event(
+                circ_id,
+                "BUILT",
+                "HS_SERVICE_REND",
+                None,
+                &[],
+                None,
+                now,
+            );
+        }
+        state
+            .bandwidth_stats
+            .circs
+            .get_mut("old")
+            .unwrap()
+            .created_at = now - 25.0 * 3600.0;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let closed = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let data_dir = dir.path().to_string_lossy().into_owned();
+            let server = tokio::spawn(serve_consensus_tor(listener, vec![None], data_dir));
+
+            let mut controller = Controller::from_port(addr).await.unwrap();
+            let mut closed = close_aged_circuits(&mut state, &mut controller).await;
+
+            // An aged circuit is handled once, not on every tick
+            closed.extend(close_aged_circuits(&mut state, &mut controller).await);
+            state
+                .bandwidth_stats
+                .circs
+                .get_mut("old")
+                .unwrap()
+                .age_reported = false;
+            state.config.bandguards.circ_max_age_hours = 0;
+            closed.extend(close_aged_circuits(&mut state, &mut controller).await);
+            drop(controller);
+            server.await.unwrap();
+            closed
+        });
+        assert_eq!(closed, vec!["old".to_string()]);
+    }
+
+    #[test]
+    fn test_reconnect_backoff_grows_and_resets() {
+        let mut backoff = ReconnectBackoff::new(Duration::from_secs(10));
+        let short = Duration::from_secs(1);
+        let jittered = |delay: Duration, secs: u64| {
+            let base = Duration::from_secs(secs);
+            delay <= base && delay >= base.mul_f64(1.0 - RECONNECT_JITTER_FRACTION)
+        };
+
+        for secs in [1, 2, 4, 8, 10, 10] {
+            let delay = backoff.next_delay(short);
+            assert!(jittered(delay, secs), "{:?} vs {}s", delay, secs);
+        }
+
+        // A long session starts the sequence over
+        assert!(jittered(
+            backoff.next_delay(RECONNECT_BACKOFF_RESET_AFTER),
+            1
+        ));
+        assert!(jittered(backoff.next_delay(short), 2));
+    }
+
+    async fn serve_safecookie_tor(
+        listener: tokio::net::TcpListener,
+        cookie_path: std::path::PathBuf,
+        cookie: Vec<u8>,
+    ) -> Vec<String> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let server_nonce = [7u8; 32];
+        let mut client_nonce = Vec::new();
+        let mut commands = Vec::new();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let reply = match words[0] {
+                "PROTOCOLINFO" => format!(
+                    "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=SAFECOOKIE COOKIEFILE=\"{}\"\r\n\
+                     250-VERSION Tor=\"0.4.8.12\"\r\n250 OK\r\n",
+                    cookie_path.display()
+                ),
+                "AUTHCHALLENGE" => {
+                    client_nonce = hex_decode(words[2]).unwrap();
+                    let hash = safecookie_hmac(
+                        SAFECOOKIE_SERVER_KEY,
+                        &cookie,
+                        &client_nonce,
+                        &server_nonce,
+                    );
+                    format!(
+                        "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}\r\n",
+                        hex_encode(&hash),
+                        hex_encode(&server_nonce)
+                    )
+                }
+                "AUTHENTICATE" => {
+                    let expected = safecookie_hmac(
+                        SAFECOOKIE_CLIENT_KEY,
+                        &cookie,
+                        &client_nonce,
+                        &server_nonce,
+                    );
+                    if words.get(1) == Some(&hex_encode(&expected).as_str()) {
+                        "250 OK\r\n".to_string()
+                    } else {
+                        "515 Authentication failed\r\n".to_string()
+                    }
+                }
+                "GETINFO" => "250-version=0.4.8.12\r\n250 OK\r\n".to_string(),
+                _ => "510 Unrecognized command\r\n".to_string(),
+            };
+            commands.push(line);
+            if writer.write_all(reply.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+        commands
+    }
+
+    #[tokio::test]
+    async fn test_safecookie_only_authentication() {
+        let dir = tempfile::tempdir().unwrap();
+        let cookie_path = dir.path().join("control_auth_cookie");
+        let cookie: Vec<u8> = (0..32).collect();
+        std::fs::write(&cookie_path, &cookie).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_safecookie_tor(listener, cookie_path, cookie.clone()));
+
+        let mut controller = Controller::from_port(addr).await.unwrap();
+        authenticate_any_with(&mut controller, None, true)
+            .await
+            .unwrap();
+        drop(controller);
+
+        let commands = server.await.unwrap();
+        assert_eq!(commands[0], "PROTOCOLINFO 1");
+        assert!(commands[1].starts_with("AUTHCHALLENGE SAFECOOKIE "));
+        assert!(commands[2].starts_with("AUTHENTICATE "));
+        // Only one PROTOCOLINFO, and the cookie itself is never sent
+        assert_eq!(
+            commands
+                .iter()
+                .filter(|c| c.starts_with("PROTOCOLINFO"))
+                .count(),
+            1
+        );
+        assert!(!commands.iter().any(|c| c.contains(&hex_encode(&cookie))));
+    }
+
+    #[tokio::test]
+    async fn test_safecookie_rejects_wrong_server_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let cookie_path = dir.path().join("control_auth_cookie");
+        std::fs::write(&cookie_path, [1u8; 32]).unwrap();
+
+        // Tor holds a different cookie, so it cannot prove knowledge of ours
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_safecookie_tor(listener, cookie_path, vec![2u8; 32]));
+
+        let mut controller = Controller::from_port(addr).await.unwrap();
+        let err = authenticate_any_with(&mut controller, None, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Control(stem_rs::Error::Authentication(
+                stem_rs::AuthError::ChallengeFailed
+            ))
+        ));
+        drop(controller);
+
+        let commands = server.await.unwrap();
+        assert!(!commands.iter().any(|c| c.starts_with("AUTHENTICATE")));
+    }
+
+    #[test]
+    fn test_parse_entry_guards() {
+        let a = "A".repeat(40);
+        let b = "b".repeat(40);
+        let c = "C".repeat(40);
+        let info = format!(
+            "${}~alpha up\n${}~beta never-connected\n${}~gamma unusable\n$XYZ~bad up\n${}=old down\n",
+            a, b, c, "D".repeat(40)
+        );
+        assert_eq!(
+            parse_entry_guards(&info),
+            vec![a, "B".repeat(40), "D".repeat(40)]
+        );
+        assert!(parse_entry_guards("").is_empty());
+    }
+
+    #[test]
+    fn test_read_relay_families() {
+        use sha2::{Digest, Sha256};
+
+        fn base64(bytes: &[u8]) -> String {
+            const ALPHABET: &[u8] =
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+            let mut out = String::new();
+            for chunk in bytes.chunks(3) {
+                let n = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+                for i in 0..=chunk.len() {
+                    out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                }
+            }
+            out
+        }
+
+        let ids = [[0x11u8; 20], [0x22; 20], [0x33; 20]];
+        let hex = |id: &[u8; 20]| -> String { id.iter().map(|b| format!("{:02X}", b)).collect() };
+        let md = |family: &str| {
+            format!(
+                "onion-key\nntor-onion-key AAAA\nfamily {}\nid ed25519 AAAA\n",
+                family
+            )
+        };
+        // 1 and 2 list each other; 3 claims 1, which does not list it back
+        let mds = [
+            md(&format!("${} $FFFF nick", hex(&ids[1]))),
+            md(&format!("${}~relay2", hex(&ids[0]))),
+            md(&format!("${}", hex(&ids[0]))),
+        ];
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut consensus = String::new();
+        for (id, md) in ids.iter().zip(&mds) {
+            consensus.push_str(&format!(
+                "r relay {} 2024-01-01 00:00:00 192.0.2.1 9001 0\nm {}\n",
+                base64(id),
+                base64(&Sha256::digest(md.as_bytes()))
+            ));
+        }
+        std::fs::write(dir.path().join("cached-microdesc-consensus"), consensus).unwrap();
+        std::fs::write(
+            dir.path().join("cached-microdescs"),
+            format!("@last-listed 2024-01-01 00:00:00\n{}{}", mds[0], mds[1]),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("cached-microdescs.new"),
+            format!("@last-listed 2024-01-01 00:00:00\n{}", mds[2]),
+        )
+        .unwrap();
+
+        let families = read_relay_families(dir.path()).unwrap();
+        assert!(families.same_family(&hex(&ids[0]), &hex(&ids[1])));
+        assert!(families.same_family(&hex(&ids[1]), &hex(&ids[0])));
+        assert!(!families.same_family(&hex(&ids[2]), &hex(&ids[0])));
+        assert_eq!(families.len(), 2);
+
+        assert!(read_relay_families(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_rend_overuse_requests_close() {
+        use crate::vanguards::RendUseCount;
+        use stem_rs::events::CircuitEvent;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = mock_app_state(dir.path());
+        state.config.enable_rendguard = true;
+        state.config.rendguard.use_global_start_count = 10;
+        state.config.rendguard.use_relay_start_count = 5;
+        let (busy, quiet) = ("A".repeat(40), "B".repeat(40));
+        for (fp, weight) in [(&busy, 0.01), (&quiet, 0.99)] {
+            state
+                .vanguard_state
+                .rendguard
+                .use_counts
+                .insert(fp.clone(), RendUseCount::new(fp.clone(), weight));
+        }
+        let rend_event = |circ_id: u32, rp: &str| {
+            CircuitEvent::parse(&format!(
+                "{} EXTENDED ${}~g,${}~m,${}~rp PURPOSE=HS_SERVICE_REND HS_STATE=HSSR_CONNECTING",
+                circ_id,
+                "C".repeat(40),
+                "D".repeat(40),
+                rp
+            ))
+            .unwrap()
+        };
+
+        // Balanced use is left alone
+        for i in 0..10 {
+            let rp = if i % 10 == 0 { &busy } else { &quiet };
+            assert_eq!(handle_circ_event(&mut state, &rend_event(i, rp), 0.0), None);
+        }
+        assert_eq!(state.rend_overuse_total, 0);
+
+        // busy carries 1% of the weight; by its fifth use it is far past 5x
+        let closes: Vec<_> = (10..15)
+            .filter_map(|i| handle_circ_event(&mut state, &rend_event(i, &busy), 0.0))
+            .collect();
+        assert!(!closes.is_empty());
+        assert_eq!(closes.last().map(String::as_str), Some("14"));
+        assert_eq!(state.rend_overuse_total, closes.len() as u64);
+
+        // With closing off, overuse is only counted
+        state.config.rendguard.close_circuits_on_overuse = false;
+        assert_eq!(
+            handle_circ_event(&mut state, &rend_event(15, &busy), 0.0),
+            None
+        );
+        assert_eq!(state.rend_overuse_total, closes.len() as u64 + 1);
+    }
+
+    #[test]
+    fn test_sighup_reload_applies_thresholds() {
+        use clap::Parser;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = mock_app_state(dir.path());
+        state.logguard = Some(LogGuard::new(&state.config.logguard));
+
+        let mut edited = state.config.clone();
+        edited.bandguards.circ_max_megabytes = 42;
+        edited.logguard.dump_limit = 7;
+        edited.enable_pathverify = !state.config.enable_pathverify;
+        let path = dir.path().join("vanguards.conf");
+        std::fs::write(&path, edited.to_toml().unwrap()).unwrap();
+
+        let args = CliArgs::parse_from([
+            "vanguards-rs".as_ref(),
+            "--config".as_ref(),
+            path.as_os_str(),
+        ]);
+        state.apply_reloaded_config(load_config(&args).unwrap());
+
+        assert_eq!(state.config.bandguards.circ_max_megabytes, 42);
+        assert_eq!(state.logguard.as_ref().unwrap().log_limit, 7);
+        // Toggling a component waits for the next session
+        assert_ne!(state.config.enable_pathverify, edited.enable_pathverify);
+        let pending = state.pending_config.as_ref().unwrap();
+        assert_eq!(pending.enable_pathverify, edited.enable_pathverify);
+
+        // Reloading an unchanged file leaves nothing pending
+        let mut state = mock_app_state(dir.path());
+        std::fs::write(&path, state.config.to_toml().unwrap()).unwrap();
+        state.apply_reloaded_config(load_config(&args).unwrap());
+        assert!(state.pending_config.is_none());
+    }
+
+    #[test]
+    fn test_would_close_tally() {
+        let mut tally = WouldCloseTally::default();
+        for kind in [
+            "dropped_cells",
+            "max_bytes",
+            "dropped_cells",
+            REND_OVERUSE,
+            "bogus",
+        ] {
+            tally.record(kind);
+        }
+        assert_eq!(
+            tally,
+            WouldCloseTally {
+                dropped_cells: 2,
+                max_bytes: 1,
+                rend_overuse: 1,
+                ..WouldCloseTally::default()
+            }
+        );
+        assert_eq!(tally.total(), 4);
+
+        let report = tally.to_string();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Monitoring mode: 4 circuit(s) would have been closed.",
+                "  dropped cells:      2",
+                "  dropped sent cells: 0",
+                "  max bytes:          1",
+                "  hsdir bytes:        0",
+                "  serv intro bytes:   0",
+                "  bytes per sec:      0",
+                "  rendguard overuse:  1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_attack_callback_fires_for_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        let mut state =
+            mock_app_state(dir.path()).on_attack(move |event| seen.lock().unwrap().push(event));
+        state.config.bandguards.circ_max_megabytes = 1;
+
+        let guard = "A".repeat(40);
+        let stats = &mut state.bandwidth_stats;
+        stats.circ_event("7", "LAUNCHED", "HS_SERVICE_REND", None, &[], None, 1000.0);
+        stats.circ_event(
+            "7",
+            "BUILT",
+            "HS_SERVICE_REND",
+            None,
+            std::slice::from_ref(&guard),
+            None,
+            1001.0,
+        );
+        let cells = 2100;
+        stats.circbw_event(
+            "7",
+            cells * CELL_PAYLOAD_SIZE,
+            0,
+            cells * RELAY_PAYLOAD_SIZE,
+            0,
+            0,
+            0,
+            1002.0,
+        );
+
+        let result = state
+            .bandwidth_stats
+            .check_circuit_limits("7", &state.config.bandguards);
+        assert!(report_limit_result(&mut state, "7", &result, false, 1002.0));
+        assert_eq!(
+            *events.lock().unwrap(),
+            [AttackEvent::MaxBytesExceeded {
+                circ_id: "7".to_string(),
+                guard_fp: Some(guard),
+                kind: "max_bytes",
+                bytes: cells * CELL_PAYLOAD_SIZE,
+                limit: 1024 * 1024,
+            }]
+        );
+
+        // Synthetic detections stay out of the callback
+        let synthetic =
+            CircuitLimitResult::synthetic("max_bytes", &state.config.bandguards).unwrap();
+        report_limit_result(&mut state, SYNTHETIC_CIRC_ID, &synthetic, true, 1003.0);
+        assert_eq!(events.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_find_consensus_file_falls_back_to_full_flavor() {
+        let cache = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let dirs = vec![
+            cache.path().display().to_string(),
+            data.path().display().to_string(),
+        ];
+
+        let err = find_consensus_file(&dirs).unwrap_err().to_string();
+        for dir in [cache.path(), data.path()] {
+            for name in CONSENSUS_FILENAMES {
+                assert!(
+                    err.contains(&dir.join(name).display().to_string()),
+                    "{}",
+                    err
+                );
+            }
+        }
+
+        let full = data.path().join("cached-consensus");
+        std::fs::write(&full, "network-status-version 3\n").unwrap();
+        assert_eq!(find_consensus_file(&dirs).unwrap(), full);
+
+        let microdesc = cache.path().join("cached-microdesc-consensus");
+        std::fs::write(&microdesc, "network-status-version 3 microdesc\n").unwrap();
+        assert_eq!(find_consensus_file(&dirs).unwrap(), microdesc);
+    }
+
+    #[test]
+    fn test_parse_bandwidth_weights_from_string() {
+        let consensus = "network-status-version 3\n\
+                         valid-after 2024-01-01 00:00:00\n\
+                         r relay1 AAAA BBBB 2024-01-01 00:00:00 1.2.3.4 9001 0\n\
+                         directory-footer\n\
+                         bandwidth-weights Wbd=0 Wmd=0 Wme=0 Wmg=4194 Wmm=10000\n\
+                         directory-signature AAAA BBBB\n";
+
+        let weights = parse_bandwidth_weights(consensus).unwrap();
+        assert_eq!(weights.len(), 5);
+        assert_eq!(weights["Wmg"], 4194);
+        assert_eq!(weights["Wmm"], 10000);
+        assert_eq!(
+            parse_consensus_validity(consensus).0.unwrap(),
+            NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap()
+                .and_utc()
+        );
+
+        let err = parse_bandwidth_weights("network-status-version 3\n").unwrap_err();
+        assert!(matches!(err, Error::Consensus(ref msg) if msg.contains("no bandwidth-weights")));
+    }
+
+    #[test]
+    fn test_flush_state_on_shutdown() {
+        use crate::vanguards::GuardNode;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.state");
+        let mut vanguard_state = VanguardState::new(&path.to_string_lossy());
+        vanguard_state
+            .layer2
+            .push(GuardNode::new("A".repeat(40), 1000.0, 2000.0));
+        let state = AppState::new(vanguard_state, Config::default());
+
+        assert!(flush_state_on_shutdown(&state));
+        let saved = VanguardState::read_from_file(&path).unwrap();
+        assert_eq!(saved.layer2_guardset(), "A".repeat(40));
+
+        let unwritable = AppState::new(
+            VanguardState::new(&dir.path().join("missing/vanguards.state").to_string_lossy()),
+            Config::default(),
+        );
+        assert!(!flush_state_on_shutdown(&unwritable));
+    }
+
+    #[test]
+    fn test_state_summary() {
+        let mut vanguard_state = VanguardState::new("/tmp/unused.state");
+        vanguard_state.layer2 = vec![
+            crate::vanguards::GuardNode::new("A".repeat(40), 0.0, 0.0),
+            crate::vanguards::GuardNode::new("B".repeat(40), 0.0, 0.0),
+        ];
+        vanguard_state.rendguard.total_use_counts = 12.0;
+        let mut state = AppState::new(vanguard_state, Config::default());
+        state.bandwidth_stats.circs_destroyed_total = 3;
+
+        let summary = state.state_summary();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                format!("Layer2 guards: {},{}", "A".repeat(40), "B".repeat(40)).as_str(),
+                "Layer3 guards: (none)",
+                "Tracked circuits: 0",
+                "Live guard connections: 0",
+                "Circuits destroyed: 3",
+                "Rendezvous point uses: 12",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_asn_map_is_read_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("asn.map");
+        std::fs::write(&path, format!("{} AS64496\n", "A".repeat(40))).unwrap();
+
+        assert_eq!(asn_map(&path).unwrap().asn(&"A".repeat(40)), Some(64496));
+        // Served from the cache once read, even if the file goes away
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(asn_map(&path).unwrap().asn(&"A".repeat(40)), Some(64496));
+        // A file that was never read is an error, not a map-less selection
+        let missing = dir.path().join("missing.map");
+        assert!(matches!(asn_map(&missing), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_read_password_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control_pass");
+
+        std::fs::write(&path, "correct horse \n").unwrap();
+        assert_eq!(
+            read_password_file(&path).unwrap().as_str(),
+            "correct horse "
+        );
+
+        std::fs::write(&path, "battery\r\n").unwrap();
+        assert_eq!(read_password_file(&path).unwrap().as_str(), "battery");
+
+        let config = Config {
+            control_pass: Some("from-cli".to_string()),
+            control_pass_file: Some(path.clone()),
+            ..Config::default()
+        };
+        assert_eq!(
+            configured_password(&config).unwrap().unwrap().as_str(),
+            "from-cli"
+        );
+        let config = Config {
+            control_pass: None,
+            ..config
+        };
+        assert_eq!(
+            configured_password(&config).unwrap().unwrap().as_str(),
+            "battery"
+        );
+        assert!(configured_password(&Config::default()).unwrap().is_none());
+
+        let missing = dir.path().join("missing");
+        assert!(matches!(
+            read_password_file(&missing),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_password_file_warning() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control_pass");
+        std::fs::write(&path, "secret\n").unwrap();
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(password_file_warning(&path), None);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let warning = password_file_warning(&path).unwrap();
+        assert!(warning.contains("mode 0644"), "{}", warning);
+    }
+
+    #[test]
+    fn test_exclude_exit_nodes_keep_rendguard_entries() {
+        let response = "\
+r exit1 AAAAAAAAAAAAAAAAAAAAAAAAAAA BBBBBBBBBBBBBBBBBBBBBBBBBBBB 2024-01-01 00:00:00 192.168.1.1 9001 0
+s Exit Fast Running Stable Valid
+w Bandwidth=1000 Measured=1000
+r exit2 CCCCCCCCCCCCCCCCCCCCCCCCCCA DDDDDDDDDDDDDDDDDDDDDDDDDDDD 2024-01-01 00:00:00 192.168.1.2 9002 0
+s Exit Fast Running Stable Valid
+w Bandwidth=1000 Measured=1000
+r middle1 EEEEEEEEEEEEEEEEEEEEEEEEEEA FFFFFFFFFFFFFFFFFFFFFFFFFFFF 2024-01-01 00:00:00 192.168.1.3 9003 0
+s Fast Running Stable Valid
+w Bandwidth=1000 Measured=1000";
+        let routers = parse_network_statuses(response).unwrap();
+        let excluded = routers[0].fingerprint.clone();
+        let other_exit = routers[1].fingerprint.clone();
+        let consensus = CachedConsensus {
+            routers,
+            ..CachedConsensus::default()
+        };
+        let exclude_exits = ExcludeNodes::parse(&format!("${}", excluded), None);
+
+        let mut state = VanguardState::new("test.state");
+        state.enable_vanguards = false;
+        update_from_consensus(
+            &mut state,
+            &consensus,
+            &ExcludeNodes::new(),
+            &ExcludeNodes::new(),
+            &Config::default(),
+        )
+        .unwrap();
+        let use_counts = &state.rendguard.use_counts;
+        assert!((use_counts[&excluded].weight - 0.5).abs() < 1e-9);
+        assert!((use_counts[&other_exit].weight - 0.5).abs() < 1e-9);
+
+        update_from_consensus(
+            &mut state,
+            &consensus,
+            &ExcludeNodes::new(),
+            &exclude_exits,
+            &Config::default(),
+        )
+        .unwrap();
+        let use_counts = &state.rendguard.use_counts;
+        assert!((use_counts[&excluded].weight - 0.5).abs() < 1e-9);
+        assert!((use_counts[&other_exit].weight - 1.0).abs() < 1e-9);
+
+        // Uses of the excluded relay are counted under it, within its share
+        let rendguard_config = crate::config::RendguardConfig {
+            use_global_start_count: 2,
+            use_relay_start_count: 1,
+            ..Default::default()
+        };
+        let rendguard = &mut state.rendguard;
+        assert!(rendguard.valid_rend_use(&other_exit, &rendguard_config));
+        assert!(rendguard.valid_rend_use(&excluded, &rendguard_config));
+        assert_eq!(rendguard.use_counts[&excluded].used, 1.0);
+        assert!(!rendguard.is_overused(&excluded, &rendguard_config));
+    }
+
+    /// Events [`serve_session_tor`] usually sends: one circuit launch.
+    const SESSION_LAUNCH_EVENT: &str = "650 CIRC 7 LAUNCHED BUILD_FLAGS=NEED_CAPACITY \
+         PURPOSE=HS_SERVICE_REND TIME_CREATED=2024-01-01T00:00:00.000000\r\n";
+
+    /// Answers a full bandguards-only session and sends `events` after
+    /// SETEVENTS, then hangs up once `hang_up` fires.
+    ///
+    /// The first connection carries commands and the second events.
+    async fn serve_session_tor(
+        listener: tokio::net::TcpListener,
+        hang_up: tokio::sync::oneshot::Receiver<()>,
+        events: &'static str,
+    ) {
+        let (commands, _) = listener.accept().await.unwrap();
+        let commands = tokio::spawn(serve_session_connection(commands, events));
+        let (event_stream, _) = listener.accept().await.unwrap();
+        let event_stream = serve_session_connection(event_stream, events).await;
+        let _ = hang_up.await;
+        drop(event_stream);
+        commands.abort();
+    }
+
+    /// Answers one connection of [`serve_session_tor`] until SETEVENTS or
+    /// the client hangs up, and returns the still open writing half.
+    async fn serve_session_connection(
+        stream: tokio::net::TcpStream,
+        events: &'static str,
+    ) -> tokio::net::tcp::OwnedWriteHalf {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let reply = match line.split_whitespace().next().unwrap_or_default() {
+                "PROTOCOLINFO" => {
+                    "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n\
+                     250-VERSION Tor=\"0.4.8.12\"\r\n250 OK\r\n"
+                }
+                "GETINFO" => "250-version=0.4.8.12\r\n250 OK\r\n",
+                _ => "250 OK\r\n",
+            };
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            if line.starts_with("SETEVENTS") {
+                writer.write_all(events.as_bytes()).await.unwrap();
+                break;
+            }
+        }
+        writer
+    }
+
+    #[test]
+    fn test_vanguards_stats_follow_run() {
+        let _guard = CLOSE_CIRCUITS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (hang_up, hung_up) = tokio::sync::oneshot::channel();
+            let server = tokio::spawn(serve_session_tor(listener, hung_up, SESSION_LAUNCH_EVENT));
+
+            let config = Config {
+                state_file: dir.path().join("vanguards.state"),
+                control_port: Some(port),
+                enable_vanguards: false,
+                enable_rendguard: false,
+                enable_logguard: false,
+                enable_pathverify: false,
+                retry_limit: Some(1),
+                ..Config::default()
+            };
+            let vanguards = crate::Vanguards::from_config(config).await.unwrap();
+            assert_eq!(vanguards.stats().await.tracked_circuits, 0);
+
+            let watch = async {
+                loop {
+                    let stats = vanguards.stats().await;
+                    if stats.tracked_circuits == 1 {
+                        hang_up.send(()).unwrap();
+                        return stats;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            let (result, stats) = tokio::time::timeout(Duration::from_secs(10), async {
+                tokio::join!(vanguards.run(), watch)
+            })
+            .await
+            .expect("the run should end after the mock Tor hangs up");
+            server.abort();
+
+            result.unwrap();
+            assert_eq!(stats.layer2_guards, 0);
+            assert_eq!(stats.circs_destroyed, 0);
+            assert!(stats.circuits_closed.is_empty());
+            assert_eq!(vanguards.stats().await, stats);
+        });
+    }
+
+    #[test]
+    fn test_shutdown_handle_stops_run() {
+        let _guard = CLOSE_CIRCUITS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let state_file = dir.path().join("vanguards.state");
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            // Never hang up: only the handle can end the run
+            let (_hang_up, hung_up) = tokio::sync::oneshot::channel();
+            let server = tokio::spawn(serve_session_tor(listener, hung_up, SESSION_LAUNCH_EVENT));
+
+            let config = Config {
+                state_file: state_file.clone(),
+                control_port: Some(port),
+                enable_vanguards: false,
+                enable_rendguard: false,
+                enable_logguard: false,
+                enable_pathverify: false,
+                ..Config::default()
+            };
+            let vanguards = crate::Vanguards::from_config(config)
+                .await
+                .unwrap()
+                .handle_ctrl_c(false);
+            let handle = vanguards.shutdown_handle();
+
+            let stop = async {
+                while vanguards.stats().await.tracked_circuits == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                handle.shutdown();
+            };
+            let (result, ()) = tokio::time::timeout(Duration::from_secs(10), async {
+                tokio::join!(vanguards.run(), stop)
+            })
+            .await
+            .expect("the handle should stop the run");
+            server.abort();
+
+            result.unwrap();
+            assert!(handle.is_shutdown());
+            assert!(state_file.exists(), "state should be flushed on shutdown");
+        });
+    }
+
+    #[test]
+    fn test_attack_callback_fires_through_run() {
+        const EVENTS: &str = "650 CIRC 7 LAUNCHED PURPOSE=HS_SERVICE_REND \
+             TIME_CREATED=2024-01-01T00:00:00.000000\r\n\
+             650 CIRC 7 BUILT $AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA~guard \
+             PURPOSE=HS_SERVICE_REND TIME_CREATED=2024-01-01T00:00:00.000000\r\n\
+             650 CIRC_BW ID=7 READ=1068900 WRITTEN=0 TIME=2024-01-01T00:00:02.000000 \
+             DELIVERED_READ=1045800 OVERHEAD_READ=0 DELIVERED_WRITTEN=0 \
+             OVERHEAD_WRITTEN=0\r\n";
+
+        let _guard = CLOSE_CIRCUITS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (_hang_up, hung_up) = tokio::sync::oneshot::channel();
+            let server = tokio::spawn(serve_session_tor(listener, hung_up, EVENTS));
+
+            let mut config = Config {
+                state_file: dir.path().join("vanguards.state"),
+                control_port: Some(port),
+                enable_vanguards: false,
+                enable_rendguard: false,
+                enable_logguard: false,
+                enable_pathverify: false,
+                ..Config::default()
+            };
+            config.bandguards.circ_max_megabytes = 1;
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let seen = Arc::clone(&events);
+            let vanguards = crate::Vanguards::from_config(config)
+                .await
+                .unwrap()
+                .handle_ctrl_c(false)
+                .on_attack(move |event| seen.lock().unwrap().push(event));
+            let handle = vanguards.shutdown_handle();
+
+            let stop = async {
+                while events.lock().unwrap().is_empty() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                handle.shutdown();
+            };
+            let (result, ()) = tokio::time::timeout(Duration::from_secs(10), async {
+                tokio::join!(vanguards.run(), stop)
+            })
+            .await
+            .expect("the run should report the attack");
+            server.abort();
+
+            result.unwrap();
+            let events = events.lock().unwrap();
+            assert!(matches!(
+                &events[0],
+                AttackEvent::MaxBytesExceeded { circ_id, guard_fp: Some(guard), .. }
+                    if circ_id == "7" && *guard == "A".repeat(40)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_missing_socket_suggests_abstract_syntax() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let err = connect_socket(Path::new("/nonexistent/control"))
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(err, Error::Connection(_)));
+            assert!(err.to_string().contains("abstract:<name>"));
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_connect_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let name = format!("vanguards-rs-test-{}", std::process::id());
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+            let listener = std::os::unix::net::UnixListener::bind_addr(&addr).unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let listener = tokio::net::UnixListener::from_std(listener).unwrap();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(_)) = lines.next_line().await {
+                    writer
+                        .write_all(b"250-version=0.4.8.12\r\n250 OK\r\n")
+                        .await
+                        .unwrap();
+                }
+            });
+
+            let socket = std::path::PathBuf::from(format!("{}{}", ABSTRACT_SOCKET_PREFIX, name));
+            let mut controller = connect_socket(&socket).await.unwrap();
+            assert_eq!(
+                controller.get_version().await.unwrap(),
+                Version::new(0, 4, 8).with_patch(12)
+            );
+            server.abort();
+        });
+    }
+
+    #[test]
+    fn test_consensus_staleness() {
+        let dir = tempfile::tempdir().unwrap();
+        let weights = get_consensus_weights(&write_mock_consensus(dir.path())).unwrap();
+        let now = Utc::now();
+        let header = |valid_after: DateTime<Utc>| {
+            let fmt = |t: DateTime<Utc>| t.format("%Y-%m-%d %H:%M:%S").to_string();
+            format!(
+                "network-status-version 3 microdesc\nvalid-after {}\nfresh-until {}\nvalid-until {}\n",
+                fmt(valid_after),
+                fmt(valid_after + chrono::Duration::hours(1)),
+                fmt(valid_after + chrono::Duration::hours(3)),
+            )
+        };
+        let consensus = |doc: &str| CachedConsensus {
+            routers: parse_network_statuses(&mock_relays(20)).unwrap(),
+            weights: weights.clone(),
+            valid_after: parse_consensus_validity(doc).0.ok(),
+            valid_until: parse_consensus_valid_until(doc).ok(),
+            ..CachedConsensus::default()
+        };
+
+        let fresh = consensus(&header(now - chrono::Duration::minutes(30)));
+        assert!(!fresh.is_expired_at(now));
+        assert!(!fresh.is_older_than(now, 2));
+
+        let expired = consensus(&header(now - chrono::Duration::hours(5)));
+        assert!(expired.is_expired_at(now));
+        assert!(expired.is_older_than(now, 2));
+        assert!(!expired.is_older_than(now, 0));
+        assert!(parse_consensus_valid_until("valid-after 2024-01-01 00:00:00").is_err());
+
+        let config = Config {
+            vanguards: crate::config::VanguardsConfig {
+                num_layer2_guards: 2,
+                num_layer3_guards: 2,
+                max_consensus_age_hours: 2,
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let listed = fresh.routers[0].fingerprint.clone();
+        let replenish = |consensus: &CachedConsensus| {
+            let mut state = VanguardState::new("test.state");
+            state.enable_vanguards = true;
+            for fp in [listed.clone(), "F".repeat(40)] {
+                let guard = crate::vanguards::GuardNode::new(fp, 0.0, 4_000_000_000.0);
+                state.layer2.push(guard);
+            }
+            let none = ExcludeNodes::new();
+            update_from_consensus(&mut state, consensus, &none, &none, &config).unwrap();
+            state
+                .layer2
+                .iter()
+                .map(|g| g.idhex.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(replenish(&fresh).len(), 2);
+        assert!(replenish(&fresh).contains(&listed));
+
+        // A stale consensus still drops the guard that left it, but does not
+        // pick a replacement
+        assert_eq!(replenish(&expired), [listed]);
+    }
+
+    #[test]
+    fn test_health_check_summary() {
+        let ok = Ok(HealthCheck {
+            tor_version: Version::new(0, 4, 8).with_patch(12),
+            relays: 7000,
+            weights: 21,
+            valid_after: NaiveDateTime::parse_from_str("2024-01-01 12:00:00", "%Y-%m-%d %H:%M:%S")
+                .ok()
+                .map(|t| t.and_utc()),
+        });
+        assert_eq!(
+            health_check_summary(&ok),
+            "OK: Tor 0.4.8.12, 7000 relays, 21 bandwidth weights, \
+             consensus valid-after 2024-01-01 12:00:00"
+        );
+
+        let unknown_age = Ok(HealthCheck {
+            tor_version: Version::new(0, 4, 8),
+            relays: 1,
+            weights: 0,
+            valid_after: None,
+        });
+        assert!(health_check_summary(&unknown_age).ends_with("valid-after unknown"));
+
+        let failed = Err(Error::Consensus("no cached consensus".to_string()));
+        let summary = health_check_summary(&failed);
+        assert!(summary.starts_with("FAIL: "), "{}", summary);
+        assert!(summary.contains("no cached consensus"));
+        assert_eq!(summary.lines().count(), 1);
+    }
+
+    /// Records each SETCONF line and answers 250 OK, or the reply given with
+    /// the `reject`ed option.
+    async fn serve_setconf_tor(
+        listener: tokio::net::TcpListener,
+        reject: Option<(&'static str, &'static str)>,
+    ) -> Vec<String> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut setconfs = Vec::new();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let reply = match reject {
+                Some((option, reply)) if line.contains(option) => reply,
+                _ => "250 OK\r\n",
+            };
+            if line.starts_with("SETCONF") {
+                setconfs.push(line);
+            }
+            writer.write_all(reply.as_bytes()).await.unwrap();
+        }
+        setconfs
+    }
+
+    #[test]
+    fn test_configure_tor_conf_sequence() {
+        let mut state = VanguardState::new("test.state");
+        state
+            .layer2
+            .push(crate::vanguards::GuardNode::new("A".repeat(40), 0.0, 1.0));
+        state
+            .layer3
+            .push(crate::vanguards::GuardNode::new("B".repeat(40), 0.0, 1.0));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let configure = |vanguards: VanguardsConfig, reject| {
+            let config = Config {
+                vanguards,
+                ..Config::default()
+            };
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let server = tokio::spawn(serve_setconf_tor(listener, reject));
+                let mut controller = Controller::from_port(addr).await.unwrap();
+                let result = configure_tor_with(&mut controller, &state, &config, None).await;
+                drop(controller);
+                (result, server.await.unwrap())
+            })
+        };
+        let setconfs = |vanguards| {
+            let (result, sent) = configure(vanguards, None);
+            result.unwrap();
+            sent
+        };
+        let vanguards = VanguardsConfig {
+            num_layer1_guards: 0,
+            ..VanguardsConfig::default()
+        };
+
+        let layers = vec![
+            format!("SETCONF HSLayer2Nodes={}", "A".repeat(40)),
+            format!("SETCONF HSLayer3Nodes={}", "B".repeat(40)),
+        ];
+
+        // Loose: only the layer options are set; StrictNodes is left to the torrc
+        assert_eq!(setconfs(vanguards.clone()), layers);
+
+        // Strict: StrictNodes follows the layer options
+        let strict_layers = VanguardsConfig {
+            strict_layers: true,
+            ..vanguards.clone()
+        };
+        let mut strict = layers.clone();
+        strict.push("SETCONF StrictNodes=1".to_string());
+        assert_eq!(setconfs(strict_layers.clone()), strict);
+
+        // A Tor without the option still gets its layers
+        let (result, sent) = configure(
+            strict_layers.clone(),
+            Some(("StrictNodes", "552 Unrecognized option\r\n")),
+        );
+        result.unwrap();
+        assert_eq!(sent, strict);
+        let (result, _) = configure(
+            strict_layers,
+            Some(("StrictNodes", "553 Unable to set option\r\n")),
+        );
+        assert!(matches!(result, Err(Error::Control(_))));
+
+        // A disabled layer is cleared, not left pinned from before a reload
+        let layer3_off = VanguardsConfig {
+            num_layer1_guards: 0,
+            enable_layer3: false,
+            ..VanguardsConfig::default()
+        };
+        assert_eq!(
+            setconfs(layer3_off),
+            [
+                format!("SETCONF HSLayer2Nodes={}", "A".repeat(40)),
+                "SETCONF HSLayer3Nodes=".to_string(),
+            ]
+        );
+
+        // Only an unrecognized option means the Tor is too old
+        let (result, _) = configure(
+            vanguards.clone(),
+            Some(("HSLayer3Nodes", "552 Unrecognized option\r\n")),
+        );
+        assert!(matches!(result, Err(Error::UnsupportedTor(_))));
+        let (result, _) = configure(
+            vanguards,
+            Some(("HSLayer2Nodes", "552 Invalid router set\r\n")),
+        );
+        assert!(matches!(result, Err(Error::Control(_))));
+    }
+
+    #[test]
+    fn test_control_endpoint_order_and_rotation() {
+        let fallback = |address: &str| ControlEndpoint {
+            address: Some(address.to_string()),
+            ..ControlEndpoint::default()
+        };
+        let config = Config {
+            control_ip: "127.0.0.1".to_string(),
+            control_port: Some(9051),
+            control_fallbacks: vec![fallback("127.0.0.1:9151"), fallback("127.0.0.1:9251")],
+            ..Config::default()
+        };
+        let order = |config: &Config, start| {
+            control_candidates(config, start)
+                .iter()
+                .map(|c| format!("{}{}", c.endpoint, if c.primary { " *" } else { "" }))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            order(&config, 0),
+            [
+                "control port 127.0.0.1:9051 *",
+                "control port 127.0.0.1:9151",
+                "control port 127.0.0.1:9251"
+            ]
+        );
+        assert_eq!(
+            order(&config, 1),
+            [
+                "control port 127.0.0.1:9151",
+                "control port 127.0.0.1:9251",
+                "control port 127.0.0.1:9051 *"
+            ]
+        );
+        assert_eq!(order(&config, 3), order(&config, 0));
+
+        // The socket takes precedence, and the defaults apply when nothing is set
+        let socket = Config {
+            control_socket: Some(std::path::PathBuf::from("/a")),
+            ..config.clone()
+        };
+        assert_eq!(order(&socket, 0)[0], "socket /a *");
+        let defaults = Config {
+            control_port: None,
+            control_fallbacks: Vec::new(),
+            ..config
+        };
+        assert_eq!(
+            order(&defaults, 0),
+            ["socket /run/tor/control *", "control port 127.0.0.1:9051 *"]
+        );
+    }
+
+    #[test]
+    fn test_signal_event_reloads_config() {
+        use clap::Parser;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = mock_app_state(dir.path());
+        state.config.vanguards.num_layer1_guards = 0;
+
+        let mut edited = state.config.clone();
+        edited.bandguards.circ_max_megabytes = 42;
+        let path = dir.path().join("vanguards.conf");
+        std::fs::write(&path, edited.to_toml().unwrap()).unwrap();
+        set_reload_args(CliArgs::parse_from([
+            "vanguards-rs".as_ref(),
+            "--config".as_ref(),
+            path.as_os_str(),
+        ]));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let setconfs = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(serve_setconf_tor(listener, None));
+            let mut controller = Controller::from_port(addr).await.unwrap();
+            let event = stem_rs::events::SignalEvent::parse("RELOAD").unwrap();
+            handle_signal_event(&mut controller, &mut state, &event)
+                .await
+                .unwrap();
+            drop(controller);
+            server.await.unwrap()
+        });
+        *RELOAD_ARGS.lock().unwrap_or_else(|e| e.into_inner()) = None;
+
+        assert_eq!(state.config.bandguards.circ_max_megabytes, 42);
+        assert!(setconfs
+            .iter()
+            .any(|l| l.starts_with("SETCONF HSLayer2Nodes=")));
+    }
 }
@@ -24,7 +24,9 @@
 //!   ├── Consensus              ◄── Consensus parsing failures
 //!   ├── NoNodesRemain          ◄── All relays filtered out
 //!   ├── Validation             ◄── Invalid input data
-//!   └── DescriptorUnavailable  ◄── Missing descriptors
+//!   ├── DescriptorUnavailable  ◄── Missing descriptors
+//!   ├── Connection             ◄── Could not reach Tor's control port
+//!   └── UnsupportedTor         ◄── Tor too old for vanguards
 //! ```
 //!
 //! # Recovery Guide
@@ -39,6 +41,8 @@
 //! | [`NoNodesRemain`](Error::NoNodesRemain) | No | No | Adjust ExcludeNodes |
 //! | [`Validation`](Error::Validation) | No | No | Fix input data |
 //! | [`DescriptorUnavailable`](Error::DescriptorUnavailable) | Yes | Yes | Wait for bootstrap |
+//! | [`Connection`](Error::Connection) | Sometimes | Yes | Check Tor is running and reachable |
+//! | [`UnsupportedTor`](Error::UnsupportedTor) | No | No | Upgrade Tor |
 //!
 //! # Example
 //!
@@ -142,6 +146,14 @@ use thiserror::Error;
 ///             eprintln!("Descriptor unavailable: {}", msg);
 ///             // Wait for Tor to finish bootstrapping
 ///         }
+///         Error::Connection(msg) => {
+///             eprintln!("Connection error: {}", msg);
+///             // Check that Tor is running and the control port is reachable
+///         }
+///         Error::UnsupportedTor(msg) => {
+///             eprintln!("Unsupported Tor: {}", msg);
+///             // Upgrade Tor
+///         }
 ///     }
 /// }
 /// ```
@@ -156,7 +168,8 @@ use thiserror::Error;
 ///         Error::Io(_) |
 ///         Error::Control(_) |
 ///         Error::Consensus(_) |
-///         Error::DescriptorUnavailable(_)
+///         Error::DescriptorUnavailable(_) |
+///         Error::Connection(_)
 ///     )
 /// }
 /// ```
@@ -265,6 +278,29 @@ pub enum Error {
     /// - Retry after a short delay
     #[error("descriptor unavailable: {0}")]
     DescriptorUnavailable(String),
+
+    /// Could not establish a control connection to Tor.
+    ///
+    /// This error is returned when every connection attempt failed before
+    /// the retry limit or shutdown was reached.
+    ///
+    /// # Recovery
+    ///
+    /// - Check that Tor is running
+    /// - Verify the control port or socket path
+    /// - Retry; Tor may still be starting
+    #[error("connection error: {0}")]
+    Connection(String),
+
+    /// The connected Tor is too old to support vanguards.
+    ///
+    /// Tor rejected the `HSLayer2Nodes` option, which was added in 0.3.3.x.
+    ///
+    /// # Recovery
+    ///
+    /// Upgrade Tor to 0.3.4.x or newer. Retrying will not help.
+    #[error("unsupported Tor version: {0}")]
+    UnsupportedTor(String),
 }
 
 /// Result type alias for vanguards-rs operations.
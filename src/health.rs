@@ -0,0 +1,273 @@
+//! A single 0–100 score summarizing how well the service is protected.
+//!
+//! Operators who do not follow every log line still want to know at a glance
+//! whether their setup is healthy. [`ProtectionScore::compute`] starts from
+//! 100 and subtracts points for each problem it finds, keeping the list of
+//! deductions so the number can be explained.
+//!
+//! # Scoring Rules
+//!
+//! | Check | Deduction |
+//! |-------|-----------|
+//! | Vanguards disabled | 40 (layer checks are then skipped) |
+//! | Layer2 or layer3 short of live guards | up to 20 per layer, in proportion to the guards missing |
+//! | Bandguards disabled | 10 |
+//! | Rendguard disabled | 5 |
+//! | Circuit closing paused | 10 |
+//! | Tor version not known yet | 5 |
+//! | Tor lacks `HSLayer2Nodes`/`HSLayer3Nodes` | 20 |
+//! | Tor lacks `CIRC_BW` fields (with bandguards on) | 10 |
+//! | Attacks detected in the last 24 hours | 5 each, at most 15 |
+//!
+//! A guard counts as live until its `expires_at`. The score never drops
+//! below 0.
+//!
+//! # What This Module Does NOT Do
+//!
+//! - **Relay quality**: Guards are counted, not judged on bandwidth or flags
+//! - **History**: Each score is computed from the current state only
+//!
+//! # See Also
+//!
+//! - [`crate::ipc`] - Reports the score in the `status` reply
+//! - [`crate::control::AppState`] - The state the score is computed from
+
+use serde::{Deserialize, Serialize};
+
+use crate::control::AppState;
+
+/// Deduction when vanguards are disabled altogether.
+const VANGUARDS_DISABLED_POINTS: u8 = 40;
+
+/// Largest deduction for one guard layer with no live guards.
+const MISSING_LAYER_POINTS: u8 = 20;
+
+/// Deduction when bandguards are disabled.
+const BANDGUARDS_DISABLED_POINTS: u8 = 10;
+
+/// Deduction when rendguard is disabled.
+const RENDGUARD_DISABLED_POINTS: u8 = 5;
+
+/// Deduction when circuits are not closed on detected attacks.
+const ENFORCEMENT_PAUSED_POINTS: u8 = 10;
+
+/// Deduction before the Tor version has been checked.
+const TOR_UNKNOWN_POINTS: u8 = 5;
+
+/// Deduction when Tor cannot pin the vanguard layers.
+const NO_HSLAYER_POINTS: u8 = 20;
+
+/// Deduction when Tor cannot feed bandguards per-circuit bandwidth.
+const NO_CIRC_BW_POINTS: u8 = 10;
+
+/// Deduction per recent attack.
+const ATTACK_POINTS: u8 = 5;
+
+/// Cap on the total deduction for recent attacks.
+const MAX_ATTACK_POINTS: u8 = 15;
+
+/// How far back detected attacks count against the score.
+const ATTACK_WINDOW_SECS: f64 = 24.0 * 3600.0;
+
+/// One reason the score is below 100.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deduction {
+    /// Points subtracted.
+    pub points: u8,
+    /// What was found, e.g. `"rendguard disabled"`.
+    pub reason: String,
+}
+
+/// Overall protection posture with its breakdown.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::health::ProtectionScore;
+/// use vanguards_rs::{AppState, Config, VanguardState};
+///
+/// let state = AppState::new(VanguardState::new("vanguards.state"), Config::default());
+/// let score = ProtectionScore::compute(&state, true, 0.0);
+/// // No guards chosen yet and Tor not contacted
+/// assert!(score.score < 100);
+/// for d in &score.deductions {
+///     println!("-{} {}", d.points, d.reason);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtectionScore {
+    /// Score from 0 (unprotected) to 100 (nothing to report).
+    pub score: u8,
+    /// Why points were subtracted, in the order checked.
+    pub deductions: Vec<Deduction>,
+}
+
+impl ProtectionScore {
+    /// Scores `state` using the rules in the [module docs](self).
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - Current application state
+    /// * `enforcing` - Whether circuits are closed on detected attacks
+    /// * `now` - Current Unix timestamp
+    pub fn compute(state: &AppState, enforcing: bool, now: f64) -> Self {
+        let config = &state.config;
+        let mut deductions = Vec::new();
+        let mut deduct = |points: u8, reason: String| {
+            if points > 0 {
+                deductions.push(Deduction { points, reason });
+            }
+        };
+
+        if config.enable_vanguards {
+            let vs = &state.vanguard_state;
+            let layers = [
+                (
+                    "layer2",
+                    &vs.layer2,
+                    config.vanguards.enable_layer2,
+                    config.vanguards.num_layer2_guards,
+                ),
+                (
+                    "layer3",
+                    &vs.layer3,
+                    config.vanguards.enable_layer3,
+                    config.vanguards.num_layer3_guards,
+                ),
+            ];
+            for (name, guards, enabled, wanted) in layers {
+                if !enabled || wanted == 0 {
+                    continue;
+                }
+                let live = guards.iter().filter(|g| g.expires_at > now).count();
+                let missing = usize::from(wanted).saturating_sub(live);
+                let points =
+                    (usize::from(MISSING_LAYER_POINTS) * missing).div_ceil(usize::from(wanted));
+                deduct(
+                    points as u8,
+                    format!("{} has {} of {} live guards", name, live, wanted),
+                );
+            }
+        } else {
+            deduct(VANGUARDS_DISABLED_POINTS, "vanguards disabled".to_string());
+        }
+
+        if !config.enable_bandguards {
+            deduct(
+                BANDGUARDS_DISABLED_POINTS,
+                "bandguards disabled".to_string(),
+            );
+        }
+        if !config.enable_rendguard {
+            deduct(RENDGUARD_DISABLED_POINTS, "rendguard disabled".to_string());
+        }
+        if !enforcing {
+            deduct(
+                ENFORCEMENT_PAUSED_POINTS,
+                "circuit closing paused".to_string(),
+            );
+        }
+
+        match &state.tor_capabilities {
+            None => deduct(TOR_UNKNOWN_POINTS, "Tor version not checked".to_string()),
+            Some(caps) => {
+                if !caps.has_hslayer && config.enable_vanguards {
+                    deduct(
+                        NO_HSLAYER_POINTS,
+                        "Tor cannot pin vanguard layers".to_string(),
+                    );
+                }
+                if !caps.has_circ_bw && config.enable_bandguards {
+                    deduct(
+                        NO_CIRC_BW_POINTS,
+                        "Tor lacks per-circuit bandwidth events".to_string(),
+                    );
+                }
+            }
+        }
+
+        let recent_attacks = state
+            .bandwidth_stats
+            .attack_records
+            .iter()
+            .filter(|r| now - r.at < ATTACK_WINDOW_SECS)
+            .count();
+        if recent_attacks > 0 {
+            let points =
+                (usize::from(ATTACK_POINTS) * recent_attacks).min(usize::from(MAX_ATTACK_POINTS));
+            deduct(
+                points as u8,
+                format!("{} attacks detected in the last 24 hours", recent_attacks),
+            );
+        }
+
+        let total: u32 = deductions.iter().map(|d| u32::from(d.points)).sum();
+        Self {
+            score: 100u32.saturating_sub(total) as u8,
+            deductions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bandguards::{AttackOutcome, AttackRecord};
+    use crate::config::Config;
+    use crate::control::TorCapabilities;
+    use crate::vanguards::{GuardNode, VanguardState};
+    use stem_rs::version::Version;
+
+    fn healthy_state(now: f64) -> AppState {
+        let config = Config::default();
+        let mut vs = VanguardState::new("test.state");
+        for i in 0..config.vanguards.num_layer2_guards {
+            vs.layer2
+                .push(GuardNode::new(format!("{:040X}", i), now, now + 86400.0));
+        }
+        for i in 0..config.vanguards.num_layer3_guards {
+            vs.layer3.push(GuardNode::new(
+                format!("{:040X}", 100 + i),
+                now,
+                now + 3600.0,
+            ));
+        }
+        let mut state = AppState::new(vs, config);
+        state.tor_capabilities = Some(TorCapabilities::from_version(
+            &Version::new(0, 4, 8).with_patch(12),
+        ));
+        state
+    }
+
+    #[test]
+    fn test_healthy_state_scores_higher_than_degraded() {
+        let now = 1_700_000_000.0;
+        let healthy = ProtectionScore::compute(&healthy_state(now), true, now);
+        assert_eq!(healthy.score, 100, "{:?}", healthy.deductions);
+        assert!(healthy.deductions.is_empty());
+
+        let mut degraded = healthy_state(now);
+        // Half the layer2 guards have expired and rendguard is off
+        for guard in degraded.vanguard_state.layer2.iter_mut().step_by(2) {
+            guard.expires_at = now - 1.0;
+        }
+        degraded.config.enable_rendguard = false;
+        degraded
+            .bandwidth_stats
+            .attack_records
+            .push_back(AttackRecord {
+                circ_id: "1".to_string(),
+                kind: "dropped_cells",
+                outcome: AttackOutcome::Closed,
+                at: now - 60.0,
+                service: None,
+            });
+        let score = ProtectionScore::compute(&degraded, false, now);
+
+        assert!(score.score < healthy.score);
+        let total: u32 = score.deductions.iter().map(|d| u32::from(d.points)).sum();
+        assert_eq!(u32::from(score.score), 100 - total);
+        assert_eq!(score.deductions.len(), 4);
+        assert!(score.deductions[0].reason.starts_with("layer2 has"));
+    }
+}
@@ -0,0 +1,732 @@
+//! Local IPC over a Unix domain socket.
+//!
+//! This module lets local tools such as a management UI observe and steer a
+//! running vanguards-rs instance. When `ipc_socket` is configured, a listener
+//! task accepts clients on that path, streams [`VanguardEvent`]s to them as
+//! newline-delimited JSON, and accepts simple line-based commands.
+//!
+//! # Protocol
+//!
+//! Clients send one command per line. Responses and events are JSON objects,
+//! one per line, tagged by an `event` field.
+//!
+//! | Command | Effect | Reply |
+//! |---------|--------|-------|
+//! | `status` | None | `status` event to the requesting client |
+//! | `pause` | Stop closing circuits on detected attacks | `enforcement_changed` broadcast |
+//! | `resume` | Resume closing circuits | `enforcement_changed` broadcast |
+//! | `rotate` | Discard current layer2/layer3 guards and pick new ones | `rotate_requested` broadcast |
+//! | `expire <fingerprint>` | Replace just that layer2/layer3 guard | `expire_requested` broadcast |
+//! | `simulate <attack>` | Report a synthetic bandguards attack | `attack_detected` broadcast with `"synthetic":true` |
+//! | `metrics` | None | `metrics` event with event handler processing times |
+//!
+//! Unknown commands get an `error` event back. Guard updates after each
+//! consensus are broadcast to every client as `guards_updated`, and each
+//! bandguards detection as `attack_detected`.
+//!
+//! `simulate` lets operators check that their alerting fires end to end. The
+//! attack name is one of `dropped_cells`, `dropped_sent_cells`, `max_bytes`,
+//! `hsdir_bytes`, `serv_intro_bytes` or `bytes_per_sec`. The report goes through the same logging and event
+//! path as a real detection, but the log line starts with `[SYNTHETIC]`, the
+//! event carries `"synthetic":true`, and no circuit is closed.
+//!
+//! The `status` reply also carries a `protection_score` from 0 to 100 with
+//! the deductions behind it; see [`crate::health`] for the rules. Its
+//! `attacks` list counts recent detections per service (onion address or
+//! SOCKS username), so operators running several services on one Tor can
+//! tell which one is targeted.
+//!
+//! The `metrics` reply lists, for each event handler that has run, how many
+//! events it handled and its 50th, 90th and 99th percentile and maximum
+//! processing time in microseconds; see [`crate::metrics`].
+//!
+//! Per layer, `status` also reports the remaining lifetimes of the current
+//! guards under `lifetimes.layer2` and `lifetimes.layer3`: the shortest,
+//! median and longest in seconds, and a histogram of guard counts with
+//! buckets ending at one hour, six hours, one day, one week, 30 days and
+//! beyond. Operators tuning the lifetime settings can see how rotation is
+//! spread out.
+//!
+//! The `status` reply includes the `valid-after` time of the consensus the
+//! current guards were chosen from and its age in seconds. Tor fetches a new
+//! consensus every hour, so an age well beyond that means Tor has stopped
+//! updating and guard decisions are going stale.
+//!
+//! ```text
+//! client → server:  status
+//! server → client:  {"event":"status","layer2":["AAAA..."],"layer3":["BBBB..."],"enforcing":true,
+//!                    "consensus_valid_after":"2026-10-17T12:00:00Z","consensus_age_secs":1520}
+//! client → server:  pause
+//! server → all:     {"event":"enforcement_changed","enforcing":false}
+//! ```
+//!
+//! # What This Module Does NOT Do
+//!
+//! - **Authentication**: Access is controlled only by the socket's file permissions
+//! - **Remote access**: Only Unix domain sockets are supported
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::sync::Arc;
+//! use vanguards_rs::ipc::{self, IpcState};
+//!
+//! # async fn example() -> vanguards_rs::Result<()> {
+//! let state = Arc::new(IpcState::new());
+//! ipc::spawn("/run/vanguards/ipc.sock", state.clone())?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Security Considerations
+//!
+//! - The socket is created with mode 0600 so only the daemon's user can connect
+//! - A client that can connect can disable circuit closing, so keep the socket
+//!   in a directory other users cannot reach
+//!
+//! # See Also
+//!
+//! - [`crate::control::run_main`] - Starts the listener when configured
+//! - [`crate::config::Config`] - The `ipc_socket` option
+
+use std::collections::BTreeMap;
+use std::fs::DirBuilder;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::bandguards::{ServiceAttacks, ATTACK_KINDS};
+use crate::config::LogLevel;
+use crate::control::{get_close_circuits, set_close_circuits};
+use crate::error::{Error, Result};
+use crate::health::ProtectionScore;
+use crate::logger::plog;
+use crate::metrics::LatencySummary;
+use crate::node_selection::is_valid_fingerprint;
+use crate::vanguards::{GuardNode, LifetimeStats, VanguardState};
+
+/// Number of events buffered per client before slow clients start losing them.
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// An event sent to IPC clients.
+///
+/// Serialized as a JSON object with an `event` tag, e.g.
+/// `{"event":"enforcement_changed","enforcing":false}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum VanguardEvent {
+    /// Current protection status, sent in reply to `status`.
+    Status {
+        /// Current layer2 guard fingerprints.
+        layer2: Vec<String>,
+        /// Current layer3 guard fingerprints.
+        layer3: Vec<String>,
+        /// Whether circuits are closed on detected attacks.
+        enforcing: bool,
+        /// `valid-after` of the last applied consensus (RFC 3339), if any.
+        consensus_valid_after: Option<String>,
+        /// Seconds since `consensus_valid_after`.
+        consensus_age_secs: Option<i64>,
+        /// Overall protection score, once the control loop has computed one.
+        protection_score: Option<ProtectionScore>,
+        /// Recent detections grouped by service.
+        #[serde(default)]
+        attacks: Vec<ServiceAttacks>,
+        /// Remaining guard lifetimes, keyed by `layer2` and `layer3`.
+        #[serde(default)]
+        lifetimes: BTreeMap<String, LifetimeStats>,
+    },
+    /// Guard layers changed after a consensus update.
+    GuardsUpdated {
+        /// New layer2 guard fingerprints.
+        layer2: Vec<String>,
+        /// New layer3 guard fingerprints.
+        layer3: Vec<String>,
+    },
+    /// Circuit closing was paused or resumed.
+    EnforcementChanged {
+        /// Whether circuits are now closed on detected attacks.
+        enforcing: bool,
+    },
+    /// A guard rotation was requested and will run after the next Tor event.
+    RotateRequested,
+    /// Expiry of a single guard was requested and will run after the next Tor event.
+    ExpireRequested {
+        /// Fingerprint of the guard to expire.
+        fingerprint: String,
+    },
+    /// Bandguards detected an attack on a circuit.
+    AttackDetected {
+        /// Attack name, e.g. `dropped_cells`.
+        kind: String,
+        /// Circuit the attack was seen on.
+        circ_id: String,
+        /// The warning that was logged.
+        message: String,
+        /// Whether this was injected with the `simulate` command.
+        synthetic: bool,
+    },
+    /// Event handler processing times, sent in reply to `metrics`.
+    Metrics {
+        /// One entry per handler that has run.
+        latencies: Vec<LatencySummary>,
+    },
+    /// A command could not be handled.
+    Error {
+        /// Description of the problem.
+        message: String,
+    },
+}
+
+impl VanguardEvent {
+    /// Serializes the event as a single JSON line, including the trailing newline.
+    pub fn to_json_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap_or_default();
+        line.push('\n');
+        line
+    }
+}
+
+/// A command received from an IPC client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpcCommand {
+    /// Report current status.
+    Status,
+    /// Stop closing circuits on detected attacks.
+    Pause,
+    /// Resume closing circuits on detected attacks.
+    Resume,
+    /// Replace the current layer2/layer3 guards.
+    Rotate,
+    /// Replace a single guard, given by fingerprint.
+    Expire(String),
+    /// Report a synthetic attack of the given kind.
+    Simulate(String),
+    /// Report event handler processing times.
+    Metrics,
+}
+
+impl FromStr for IpcCommand {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let command = words.next().unwrap_or_default().to_lowercase();
+        let argument = words.next();
+        match (command.as_str(), argument) {
+            ("status", None) => Ok(IpcCommand::Status),
+            ("pause", None) => Ok(IpcCommand::Pause),
+            ("resume", None) => Ok(IpcCommand::Resume),
+            ("rotate", None) => Ok(IpcCommand::Rotate),
+            ("metrics", None) => Ok(IpcCommand::Metrics),
+            ("expire", Some(fp)) => {
+                let fp = fp.trim_start_matches('$');
+                if !is_valid_fingerprint(fp) {
+                    return Err(Error::Validation(format!("invalid fingerprint: {}", fp)));
+                }
+                Ok(IpcCommand::Expire(fp.to_uppercase()))
+            }
+            ("simulate", Some(kind)) => {
+                let kind = kind.to_lowercase();
+                if !ATTACK_KINDS.contains(&kind.as_str()) {
+                    return Err(Error::Validation(format!(
+                        "unknown attack: {} (expected one of {})",
+                        kind,
+                        ATTACK_KINDS.join(", ")
+                    )));
+                }
+                Ok(IpcCommand::Simulate(kind))
+            }
+            _ => Err(Error::Validation(format!(
+                "unknown IPC command: {}",
+                s.trim()
+            ))),
+        }
+    }
+}
+
+/// State shared between the control loop and the IPC listener.
+///
+/// The control loop publishes guard updates through [`IpcState::update_guards`]
+/// and polls [`IpcState::take_rotate_request`]; the listener answers client
+/// commands from it.
+#[derive(Debug)]
+pub struct IpcState {
+    events: broadcast::Sender<VanguardEvent>,
+    guards: Mutex<(Vec<GuardNode>, Vec<GuardNode>)>,
+    consensus_valid_after: Mutex<Option<DateTime<Utc>>>,
+    protection_score: Mutex<Option<ProtectionScore>>,
+    attacks: Mutex<Vec<ServiceAttacks>>,
+    latencies: Mutex<Vec<LatencySummary>>,
+    rotate_requested: AtomicBool,
+    expire_requests: Mutex<Vec<String>>,
+    simulate_requests: Mutex<Vec<String>>,
+}
+
+impl Default for IpcState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IpcState {
+    /// Creates an empty IPC state with no known guards.
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            events,
+            guards: Mutex::new((Vec::new(), Vec::new())),
+            consensus_valid_after: Mutex::new(None),
+            protection_score: Mutex::new(None),
+            attacks: Mutex::new(Vec::new()),
+            latencies: Mutex::new(Vec::new()),
+            rotate_requested: AtomicBool::new(false),
+            expire_requests: Mutex::new(Vec::new()),
+            simulate_requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sends an event to every connected client.
+    pub fn publish(&self, event: VanguardEvent) {
+        // No receivers just means no clients are connected
+        let _ = self.events.send(event);
+    }
+
+    /// Returns a receiver for every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<VanguardEvent> {
+        self.events.subscribe()
+    }
+
+    /// Records the current guard layers and broadcasts `guards_updated`.
+    pub fn update_guards(&self, state: &VanguardState) {
+        let layer2: Vec<String> = state.layer2.iter().map(|g| g.idhex.clone()).collect();
+        let layer3: Vec<String> = state.layer3.iter().map(|g| g.idhex.clone()).collect();
+        *self.guards.lock().unwrap_or_else(|e| e.into_inner()) =
+            (state.layer2.clone(), state.layer3.clone());
+        self.publish(VanguardEvent::GuardsUpdated { layer2, layer3 });
+    }
+
+    /// Records the `valid-after` time of the consensus just applied.
+    pub fn update_consensus(&self, valid_after: DateTime<Utc>) {
+        *self
+            .consensus_valid_after
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(valid_after);
+    }
+
+    /// Records the latest protection score for `status` replies.
+    pub fn update_protection_score(&self, score: ProtectionScore) {
+        *self
+            .protection_score
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(score);
+    }
+
+    /// Records the per-service attack summary for `status` replies.
+    pub fn update_attacks(&self, attacks: Vec<ServiceAttacks>) {
+        *self.attacks.lock().unwrap_or_else(|e| e.into_inner()) = attacks;
+    }
+
+    /// Records the latest event handler latencies for `metrics` replies.
+    pub fn update_latencies(&self, latencies: Vec<LatencySummary>) {
+        *self.latencies.lock().unwrap_or_else(|e| e.into_inner()) = latencies;
+    }
+
+    /// Returns a `status` event describing the current state.
+    pub fn status(&self) -> VanguardEvent {
+        let (layer2, layer3) = self
+            .guards
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let valid_after = *self
+            .consensus_valid_after
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let now = Utc::now().timestamp_millis() as f64 / 1000.0;
+        VanguardEvent::Status {
+            lifetimes: BTreeMap::from([
+                (
+                    "layer2".to_string(),
+                    LifetimeStats::from_guards(&layer2, now),
+                ),
+                (
+                    "layer3".to_string(),
+                    LifetimeStats::from_guards(&layer3, now),
+                ),
+            ]),
+            layer2: layer2.into_iter().map(|g| g.idhex).collect(),
+            layer3: layer3.into_iter().map(|g| g.idhex).collect(),
+            enforcing: get_close_circuits(),
+            consensus_valid_after: valid_after
+                .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            consensus_age_secs: valid_after.map(|t| (Utc::now() - t).num_seconds()),
+            protection_score: self
+                .protection_score
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+            attacks: self
+                .attacks
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        }
+    }
+
+    /// Returns true once per rotation requested by a client.
+    pub fn take_rotate_request(&self) -> bool {
+        self.rotate_requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Returns and clears the fingerprints clients asked to expire.
+    pub fn take_expire_requests(&self) -> Vec<String> {
+        std::mem::take(
+            &mut *self
+                .expire_requests
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()),
+        )
+    }
+
+    /// Returns and clears the attack kinds clients asked to simulate.
+    pub fn take_simulate_requests(&self) -> Vec<String> {
+        std::mem::take(
+            &mut *self
+                .simulate_requests
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()),
+        )
+    }
+
+    /// Applies a client command.
+    ///
+    /// # Returns
+    ///
+    /// An event to send only to the requesting client, if any. Commands that
+    /// change state broadcast their result to all clients instead.
+    pub fn handle_command(&self, command: IpcCommand) -> Option<VanguardEvent> {
+        match command {
+            IpcCommand::Status => Some(self.status()),
+            IpcCommand::Metrics => Some(VanguardEvent::Metrics {
+                latencies: self
+                    .latencies
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .clone(),
+            }),
+            IpcCommand::Pause | IpcCommand::Resume => {
+                let enforcing = command == IpcCommand::Resume;
+                set_close_circuits(enforcing);
+                plog(
+                    LogLevel::Notice,
+                    &format!(
+                        "Circuit closing {} via IPC.",
+                        if enforcing { "resumed" } else { "paused" }
+                    ),
+                );
+                self.publish(VanguardEvent::EnforcementChanged { enforcing });
+                None
+            }
+            IpcCommand::Rotate => {
+                self.rotate_requested.store(true, Ordering::SeqCst);
+                plog(LogLevel::Notice, "Guard rotation requested via IPC.");
+                self.publish(VanguardEvent::RotateRequested);
+                None
+            }
+            IpcCommand::Expire(fingerprint) => {
+                self.expire_requests
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(fingerprint.clone());
+                plog(
+                    LogLevel::Notice,
+                    &format!("Expiry of guard {} requested via IPC.", fingerprint),
+                );
+                self.publish(VanguardEvent::ExpireRequested { fingerprint });
+                None
+            }
+            IpcCommand::Simulate(kind) => {
+                plog(
+                    LogLevel::Notice,
+                    &format!("Synthetic {} attack requested via IPC.", kind),
+                );
+                self.simulate_requests
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(kind);
+                None
+            }
+        }
+    }
+}
+
+/// Binds the IPC socket and serves clients on a background task.
+///
+/// A stale socket left at `path` by a previous run is replaced; a socket
+/// that still accepts connections, and any other file there, is left alone.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] if `path` exists and is not a socket or
+/// another process is serving it, and
+/// [`Error::Io`] if the socket cannot be bound or its permissions cannot
+/// be restricted.
+pub fn spawn(path: impl AsRef<Path>, state: Arc<IpcState>) -> Result<JoinHandle<()>> {
+    let path = path.as_ref();
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(Error::Config(format!(
+                "ipc_socket {} exists and is not a socket",
+                path.display()
+            )));
+        }
+        // Only a socket nothing answers on is stale
+        Ok(_) if std::os::unix::net::UnixStream::connect(path).is_ok() => {
+            return Err(Error::Config(format!(
+                "ipc_socket {} is in use by another process",
+                path.display()
+            )));
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = bind_private(path)?;
+
+    plog(
+        LogLevel::Notice,
+        &format!("Listening for IPC clients on {}", path.display()),
+    );
+
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_client(stream, state.clone()));
+                }
+                Err(e) => {
+                    plog(LogLevel::Warn, &format!("IPC accept failed: {}", e));
+                    return;
+                }
+            }
+        }
+    }))
+}
+
+/// Binds a listener at `path` that only the current user can connect to.
+///
+/// The socket is bound inside a fresh 0700 directory beside `path`,
+/// restricted to 0600, and only then renamed into place, so it is never
+/// reachable with the default umask's permissions. The rename replaces
+/// whatever socket is at `path`, so [`spawn`] first checks it is stale.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let private_dir = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+
+    let bound = private_dir.join("sock");
+    let result = UnixListener::bind(&bound)
+        .and_then(|listener| {
+            std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&bound, path)?;
+            Ok(listener)
+        })
+        .map_err(Error::from);
+    let _ = std::fs::remove_file(&bound);
+    let _ = std::fs::remove_dir(&private_dir);
+    result
+}
+
+/// Forwards broadcast events to one client and answers its commands.
+async fn serve_client(stream: UnixStream, state: Arc<IpcState>) {
+    let mut events = state.subscribe();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    loop {
+        let outgoing = tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) if line.trim().is_empty() => continue,
+                Ok(Some(line)) => match line.parse::<IpcCommand>() {
+                    Ok(command) => state.handle_command(command),
+                    Err(e) => Some(VanguardEvent::Error { message: e.to_string() }),
+                },
+                _ => return,
+            },
+            event = events.recv() => match event {
+                Ok(event) => Some(event),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+        };
+
+        if let Some(event) = outgoing {
+            if writer
+                .write_all(event.to_json_line().as_bytes())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vanguards::GuardNode;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!("status".parse::<IpcCommand>().unwrap(), IpcCommand::Status);
+        assert_eq!(
+            " PAUSE \n".parse::<IpcCommand>().unwrap(),
+            IpcCommand::Pause
+        );
+        assert_eq!("resume".parse::<IpcCommand>().unwrap(), IpcCommand::Resume);
+        assert_eq!("rotate".parse::<IpcCommand>().unwrap(), IpcCommand::Rotate);
+        assert_eq!(
+            "metrics".parse::<IpcCommand>().unwrap(),
+            IpcCommand::Metrics
+        );
+        assert!("shutdown".parse::<IpcCommand>().is_err());
+        assert_eq!(
+            format!("expire ${}", "a".repeat(40))
+                .parse::<IpcCommand>()
+                .unwrap(),
+            IpcCommand::Expire("A".repeat(40))
+        );
+        assert!("expire".parse::<IpcCommand>().is_err());
+        assert!("expire nothex".parse::<IpcCommand>().is_err());
+        assert!("status extra".parse::<IpcCommand>().is_err());
+        assert_eq!(
+            "simulate Dropped_Cells".parse::<IpcCommand>().unwrap(),
+            IpcCommand::Simulate("dropped_cells".to_string())
+        );
+        assert!("simulate".parse::<IpcCommand>().is_err());
+        assert!("simulate everything".parse::<IpcCommand>().is_err());
+    }
+
+    #[test]
+    fn test_event_json_line() {
+        let line = VanguardEvent::EnforcementChanged { enforcing: false }.to_json_line();
+        assert_eq!(
+            line,
+            "{\"event\":\"enforcement_changed\",\"enforcing\":false}\n"
+        );
+    }
+
+    #[test]
+    fn test_client_status_and_pause() {
+        let _guard = crate::control::CLOSE_CIRCUITS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        set_close_circuits(true);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(client_status_and_pause());
+        set_close_circuits(true);
+    }
+
+    async fn client_status_and_pause() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ipc.sock");
+        let state = Arc::new(IpcState::new());
+
+        let mut vanguard_state = VanguardState::new("test.state");
+        vanguard_state
+            .layer2
+            .push(GuardNode::new("A".repeat(40), 0.0, 1.0));
+        state.update_guards(&vanguard_state);
+        let valid_after = Utc::now() - chrono::Duration::minutes(30);
+        state.update_consensus(valid_after);
+
+        let server = spawn(&path, state.clone()).unwrap();
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer.write_all(b"status\n").await.unwrap();
+        let reply: VanguardEvent =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        let VanguardEvent::Status {
+            layer2,
+            layer3,
+            enforcing,
+            consensus_valid_after,
+            consensus_age_secs,
+            protection_score,
+            attacks,
+            lifetimes,
+        } = reply
+        else {
+            panic!("expected status event, got {:?}", reply);
+        };
+        assert_eq!(layer2, vec!["A".repeat(40)]);
+        assert!(layer3.is_empty());
+        assert!(enforcing);
+        assert_eq!(
+            consensus_valid_after,
+            Some(valid_after.to_rfc3339_opts(SecondsFormat::Secs, true))
+        );
+        assert!((1800..1900).contains(&consensus_age_secs.unwrap()));
+        assert_eq!(protection_score, None);
+        assert!(attacks.is_empty());
+        // The guard expired long ago
+        assert_eq!(lifetimes["layer2"].count, 1);
+        assert_eq!(lifetimes["layer2"].max_secs, Some(0));
+        assert_eq!(lifetimes["layer3"].count, 0);
+
+        writer.write_all(b"pause\n").await.unwrap();
+        let reply: VanguardEvent =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(
+            reply,
+            VanguardEvent::EnforcementChanged { enforcing: false }
+        );
+        assert!(!get_close_circuits());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_spawn_replaces_only_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ipc.sock");
+        let state = Arc::new(IpcState::new());
+
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(matches!(spawn(&path, state.clone()), Err(Error::Config(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+        std::fs::remove_file(&path).unwrap();
+
+        // A stale socket from an earlier run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let server = spawn(&path, state).unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        UnixStream::connect(&path).await.unwrap();
+        // Only the socket is left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // A socket another instance is serving is not taken over
+        let err = spawn(&path, Arc::new(IpcState::new())).unwrap_err();
+        assert!(err.to_string().contains("in use"), "{}", err);
+        UnixStream::connect(&path).await.unwrap();
+
+        server.abort();
+    }
+}
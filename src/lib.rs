@@ -30,6 +30,11 @@
 //! | [`pathverify`] | Circuit path verification |
 //! | [`node_selection`] | Bandwidth-weighted relay selection |
 //! | [`logger`] | Logging infrastructure using tracing |
+//! | [`ipc`] | Local event streaming and commands over a Unix socket |
+//! | [`health`] | Overall protection score for status output |
+//! | [`metrics`] | Processing-time histograms for event handlers |
+//! | [`siem`] | CEF and LEEF export of detections |
+//! | `notify` | systemd readiness and watchdog notifications (Unix, `systemd` feature) |
 //!
 //! # What This Library Does NOT Do
 //!
@@ -52,7 +57,7 @@
 //!     let config = Config::default();
 //!     
 //!     // Create and run vanguards protection
-//!     let mut vanguards = Vanguards::from_config(config).await?;
+//!     let vanguards = Vanguards::from_config(config).await?;
 //!     vanguards.run().await
 //! }
 //! ```
@@ -132,36 +137,59 @@ pub mod cbtverify;
 pub mod config;
 pub mod control;
 pub mod error;
+pub mod health;
+pub mod ipc;
 pub mod logger;
 pub mod logguard;
+pub mod metrics;
 pub mod node_selection;
+#[cfg(all(unix, feature = "systemd"))]
+pub mod notify;
 pub mod pathverify;
 pub mod rendguard;
+pub mod siem;
 pub mod vanguards;
 
-pub use api::{SecurePassword, Vanguards};
+pub use api::{SecurePassword, ShutdownHandle, Vanguards, VanguardsStats};
 pub use bandguards::{
-    BandwidthStats, BwCircuitStat, BwGuardStat, CircuitLimitResult, ConnectivityStatus,
-    CELL_PAYLOAD_SIZE, MAX_CIRC_DESTROY_LAG_SECS, RELAY_HEADER_SIZE, RELAY_PAYLOAD_SIZE,
+    AttackOutcome, AttackRecord, BandwidthSnapshot, BandwidthStats, BwCircuitStat, BwGuardStat,
+    CircuitLimitResult, CircuitSummary, ConnectivityStatus, GuardKillAlert, GuardSummary,
+    ServiceAttacks, CELL_PAYLOAD_SIZE, MAX_CIRC_DESTROY_LAG_SECS, RELAY_HEADER_SIZE,
+    RELAY_PAYLOAD_SIZE,
 };
-pub use cbtverify::{CircuitStat, TimeoutStats};
+pub use cbtverify::{CircuitStat, TimeoutReport, TimeoutStats};
 pub use config::{
-    BandguardsConfig, CliArgs, Config, LogLevel, LogguardConfig, RendguardConfig, VanguardsConfig,
+    BandguardsConfig, CliArgs, Config, ControlEndpoint, LogFormat, LogLevel, LogguardConfig,
+    NoGuardsAction, PathPolicy, PathPosition, PathViolationKind, RendguardConfig, SiemFormat,
+    StateFormat, VanguardsConfig,
 };
 pub use error::{Error, Result};
+pub use health::{Deduction, ProtectionScore};
+pub use ipc::{IpcCommand, IpcState, VanguardEvent};
 pub use logguard::{LogEntry, LogGuard};
+pub use metrics::{
+    Handler, HandlerLatencies, LatencyHistogram, LatencySummary, MetricsServer, MetricsSnapshot,
+};
 pub use node_selection::{
-    is_valid_country_code, is_valid_fingerprint, is_valid_ip_or_network, BwWeightedGenerator,
-    FlagsRestriction, NodeRestriction, NodeRestrictionList, Position,
+    is_valid_country_code, is_valid_fingerprint, is_valid_ip_or_network, AsRestriction,
+    BwWeightedGenerator, FlagsRestriction, MinAgeRestriction, MinBandwidthRestriction,
+    MinUptimeRestriction, NodeRestriction, NodeRestrictionList, Position, RelayFamilies,
 };
 pub use pathverify::{
-    Layer1Guards, Layer1Stats, PathVerify, ROUTELEN_FOR_PURPOSE, ROUTELEN_FOR_PURPOSE_LITE,
+    Layer1Guards, Layer1Stats, PathVerify, PathViolation, PolicyRelay, PolicyViolation,
+    ROUTELEN_FOR_PURPOSE, ROUTELEN_FOR_PURPOSE_LITE,
 };
 pub use rendguard::{RendCheckResult, NOT_IN_CONSENSUS_ID};
-pub use vanguards::{ExcludeNodes, GuardNode, RendGuard, RendUseCount, VanguardState};
+pub use siem::{Detection, SiemWriter};
+pub use vanguards::{
+    DiversityConstraint, DiversityReport, ExcludeNodes, GuardNode, LifetimeStats,
+    RelaxedConstraint, RendGuard, RendUseCount, VanguardState,
+};
 
 pub use control::{
-    authenticate_any, configure_tor, control_loop, get_close_circuits, get_consensus_weights,
-    new_consensus_event, run_main, set_close_circuits, signal_event, try_close_circuit, AppState,
-    VERSION,
+    analyze_consensus, authenticate_any, authenticate_any_with, configure_tor, consensus_update,
+    control_loop, get_close_circuits, get_consensus_valid_after, get_consensus_weights,
+    new_consensus_event, parse_bandwidth_weights, parse_network_statuses, run_main,
+    set_close_circuits, set_reload_args, signal_event, try_close_circuit, AppState, AttackEvent,
+    TorCapabilities, WouldCloseTally, VERSION,
 };
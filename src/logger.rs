@@ -8,9 +8,12 @@
 //! The logging system provides:
 //!
 //! - **Multiple output destinations**: stdout, file, or syslog
+//! - **Plain text or JSON**: One JSON object per line for log shippers
+//! - **Size-based rotation**: Log files are rolled to `.1`, `.2`, ... past a size limit
 //! - **Configurable log levels**: From DEBUG to ERROR
 //! - **Python vanguards compatibility**: `plog` function matches Python API
 //! - **Environment variable override**: `RUST_LOG` can override configured level
+//! - **Runtime level changes**: [`set_level`] adjusts verbosity without a restart
 //!
 //! # Log Levels
 //!
@@ -53,31 +56,116 @@
 //! logger::init(LogLevel::Notice, Some(":syslog:")).unwrap();
 //! ```
 //!
+//! # Changing the Level at Runtime
+//!
+//! The active filter is installed behind a reload handle, so the level can be
+//! changed while the daemon keeps its guard and circuit state. On Unix,
+//! sending `SIGUSR1` to the process calls [`cycle_level`], which steps one
+//! level more verbose each time and wraps from DEBUG back to ERROR.
+//! (`SIGUSR2` logs a summary of the protection state instead; see
+//! [`run_main`](crate::control::run_main).)
+//!
+//! ```rust,no_run
+//! use vanguards_rs::{LogLevel, logger};
+//!
+//! logger::init(LogLevel::Notice, None).unwrap();
+//! logger::set_level(LogLevel::Debug).unwrap();
+//! ```
+//!
+//! # Anonymizing Fingerprints
+//!
+//! Guard fingerprints in logs reveal which vanguards a service uses. After
+//! [`set_anonymize_fingerprints`] is enabled, [`plog`] rewrites every
+//! 40-character hex fingerprint as an HMAC-SHA256 token keyed with a random
+//! per-process secret, e.g. `$~5f1e09c2a1b3c4d5`. No part of the fingerprint
+//! is kept. The same relay maps to the same token for the life of the
+//! process, so lines can still be correlated.
+//!
+//! # JSON Output
+//!
+//! With [`LogFormat::Json`], every line is an object with `timestamp`
+//! (RFC 3339, UTC), `level` and `message`. Lines logged through
+//! [`plog_with`] also carry `circ_id` and `guard_fp` when the
+//! [`LogContext`] sets them:
+//!
+//! ```text
+//! {"circ_id":"42","level":"INFO","message":"We force-closed circuit","timestamp":"2025-10-17T12:00:00.000Z"}
+//! ```
+//!
+//! JSON output is chosen with [`init_with`]:
+//!
+//! ```rust,no_run
+//! use vanguards_rs::{LogFormat, LogLevel, logger};
+//! use vanguards_rs::logger::LogOptions;
+//!
+//! let options = LogOptions { format: LogFormat::Json, ..LogOptions::default() };
+//! logger::init_with(LogLevel::Notice, None, options).unwrap();
+//! ```
+//!
 //! # What This Module Does NOT Do
 //!
-//! - **Log rotation**: Use external tools like logrotate
+//! - **Time-based rotation**: Use external tools like logrotate
 //! - **Log aggregation**: Use external services for centralized logging
-//! - **Structured logging**: Currently outputs plain text only
 //!
 //! # See Also
 //!
 //! - [`crate::config::LogLevel`] - Log level enumeration
+//! - [`crate::config::LogFormat`] - Text or JSON output
 //! - [`crate::logguard`] - Log buffering for circuit debugging
 //! - [tracing crate](https://docs.rs/tracing) - Underlying logging framework
 
+use chrono::{SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use std::fs::{self, File, OpenOptions};
 use std::io::Write;
 use std::os::unix::net::UnixDatagram;
-use std::path::Path;
-use std::sync::OnceLock;
-use tracing::{debug, error, info, warn};
-use tracing_subscriber::fmt::format::FmtSpan;
-use tracing_subscriber::EnvFilter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tracing::field::{Field, Visit};
+use tracing::{debug, error, info, warn, Event, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, Layered, SubscriberExt};
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
 
-use crate::config::LogLevel;
+use crate::config::{LogFormat, LogLevel};
 use crate::error::{Error, Result};
 
 static LOGGER_INITIALIZED: OnceLock<()> = OnceLock::new();
 
+/// Handle used to swap the active filter after initialization.
+static FILTER_HANDLE: OnceLock<FilterHandle> = OnceLock::new();
+
+/// Level most recently applied through [`init`] or [`set_level`].
+static CURRENT_LEVEL: Mutex<LogLevel> = Mutex::new(LogLevel::Notice);
+
+/// Whether [`plog`] replaces fingerprints with anonymized tokens.
+static ANONYMIZE_FINGERPRINTS: AtomicBool = AtomicBool::new(false);
+
+/// Per-process HMAC key for anonymized fingerprint tokens.
+static FINGERPRINT_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+/// Length of a hex relay fingerprint.
+const FINGERPRINT_LEN: usize = 40;
+
+/// Number of HMAC bytes shown, as hex, in an anonymized fingerprint token.
+const FINGERPRINT_TOKEN_BYTES: usize = 8;
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+type FilteredRegistry = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type OutputLayer = Box<dyn Layer<FilteredRegistry> + Send + Sync>;
+
+/// Returns the tracing filter directive for a log level.
+fn level_filter(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::Debug => "debug",
+        LogLevel::Info => "info",
+        LogLevel::Notice => "info",
+        LogLevel::Warn => "warn",
+        LogLevel::Error => "error",
+    }
+}
+
 /// Initialize the logging system.
 ///
 /// This function sets up the tracing subscriber with the specified log level
@@ -128,48 +216,230 @@ static LOGGER_INITIALIZED: OnceLock<()> = OnceLock::new();
 ///
 /// # See Also
 ///
+/// - [`init_with`] - The same, with output options
 /// - [`plog`] - Log messages after initialization
 /// - [`crate::config::LogLevel`] - Available log levels
 pub fn init(level: LogLevel, logfile: Option<&str>) -> Result<()> {
+    init_with(level, logfile, LogOptions::default())
+}
+
+/// Output options for [`init_with`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogOptions {
+    /// Plain text, or one JSON object per line.
+    pub format: LogFormat,
+    /// When to roll a file log over; ignored for stdout and syslog.
+    pub rotation: LogRotation,
+}
+
+/// Initialize the logging system like [`init`], with output `options`.
+///
+/// # Errors
+///
+/// As for [`init`].
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use vanguards_rs::{LogFormat, LogLevel, logger};
+/// use vanguards_rs::logger::{LogOptions, LogRotation};
+///
+/// // Log JSON to a file, keeping five rotated 10 MB files
+/// let options = LogOptions {
+///     format: LogFormat::Json,
+///     rotation: LogRotation { max_bytes: 10 * 1024 * 1024, max_files: 5 },
+/// };
+/// logger::init_with(LogLevel::Debug, Some("/var/log/vanguards.log"), options).unwrap();
+/// ```
+///
+/// # Notes
+///
+/// - A line is never split across a rotation
+/// - JSON lines are never colored
+pub fn init_with(level: LogLevel, logfile: Option<&str>, options: LogOptions) -> Result<()> {
+    let LogOptions { format, rotation } = options;
     if LOGGER_INITIALIZED.get().is_some() {
         return Ok(());
     }
 
-    let filter = match level {
-        LogLevel::Debug => "debug",
-        LogLevel::Info => "info",
-        LogLevel::Notice => "info",
-        LogLevel::Warn => "warn",
-        LogLevel::Error => "error",
-    };
-
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(filter));
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level_filter(level)));
 
     match logfile {
         None => {
-            let subscriber = tracing_subscriber::fmt()
-                .with_env_filter(env_filter)
-                .with_target(false)
-                .with_thread_ids(false)
-                .with_span_events(FmtSpan::NONE)
-                .with_ansi(true)
-                .finish();
-            tracing::subscriber::set_global_default(subscriber)
-                .map_err(|e| Error::Config(format!("failed to set logger: {}", e)))?;
+            install(env_filter, output_layer(format, std::io::stdout, true))?;
         }
         Some(":syslog:") => {
-            init_syslog(env_filter)?;
+            init_syslog(env_filter, format)?;
         }
         Some(path) => {
-            init_file_logger(path, env_filter)?;
+            init_file_logger(path, env_filter, format, rotation)?;
         }
     }
 
+    *CURRENT_LEVEL.lock().unwrap_or_else(|e| e.into_inner()) = level;
     LOGGER_INITIALIZED.get_or_init(|| ());
     Ok(())
 }
 
-fn init_syslog(env_filter: EnvFilter) -> Result<()> {
+/// Installs the global subscriber with a reloadable filter in front of `fmt_layer`.
+fn install<L>(env_filter: EnvFilter, fmt_layer: L) -> Result<()>
+where
+    L: Layer<FilteredRegistry> + Send + Sync + 'static,
+{
+    let (filter_layer, handle) = reload::Layer::new(env_filter);
+    let subscriber = tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(fmt_layer);
+
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| Error::Config(format!("failed to set logger: {}", e)))?;
+
+    let _ = FILTER_HANDLE.set(handle);
+    Ok(())
+}
+
+/// Change the active log level without restarting.
+///
+/// Replaces the filter installed by [`init`], so the new level takes effect
+/// for every subsequent message while all other daemon state is preserved.
+/// The new level also replaces any filter that came from `RUST_LOG`.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] if logging has not been initialized or the
+/// subscriber backing the filter has been dropped.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use vanguards_rs::{LogLevel, logger};
+///
+/// logger::init(LogLevel::Notice, None).unwrap();
+/// logger::set_level(LogLevel::Debug).unwrap();
+/// ```
+///
+/// # See Also
+///
+/// - [`cycle_level`] - Step through levels one at a time
+pub fn set_level(level: LogLevel) -> Result<()> {
+    let handle = FILTER_HANDLE
+        .get()
+        .ok_or_else(|| Error::Config("logger is not initialized".to_string()))?;
+    apply_level(handle, level)?;
+    *CURRENT_LEVEL.lock().unwrap_or_else(|e| e.into_inner()) = level;
+    Ok(())
+}
+
+/// Switch to the next more verbose log level, wrapping from DEBUG to ERROR.
+///
+/// This is what the `SIGUSR1` handler installed by
+/// [`run_main`](crate::control::run_main) calls.
+///
+/// # Returns
+///
+/// The level now in effect.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] if logging has not been initialized.
+pub fn cycle_level() -> Result<LogLevel> {
+    let next = match current_level() {
+        LogLevel::Error => LogLevel::Warn,
+        LogLevel::Warn => LogLevel::Notice,
+        LogLevel::Notice => LogLevel::Info,
+        LogLevel::Info => LogLevel::Debug,
+        LogLevel::Debug => LogLevel::Error,
+    };
+    set_level(next)?;
+    Ok(next)
+}
+
+/// Returns the level most recently set through [`init`] or [`set_level`].
+pub fn current_level() -> LogLevel {
+    *CURRENT_LEVEL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Builds the layer that formats events for `writer`.
+fn output_layer<W>(format: LogFormat, writer: W, ansi: bool) -> OutputLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_target(false)
+            .with_thread_ids(false)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => JsonLayer { writer }.boxed(),
+    }
+}
+
+/// Writes each event as one JSON object per line.
+struct JsonLayer<W> {
+    writer: W,
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = JsonFields(Map::new());
+        event.record(&mut fields);
+        let mut object = fields.0;
+        object.insert(
+            "timestamp".to_string(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .into(),
+        );
+        object.insert(
+            "level".to_string(),
+            event.metadata().level().as_str().into(),
+        );
+
+        let mut line = Value::Object(object).to_string();
+        line.push('\n');
+        let _ = self.writer.make_writer().write_all(line.as_bytes());
+    }
+}
+
+/// Collects an event's fields into a JSON object.
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+fn apply_level(handle: &FilterHandle, level: LogLevel) -> Result<()> {
+    handle
+        .reload(EnvFilter::new(level_filter(level)))
+        .map_err(|e| Error::Config(format!("failed to change log level: {}", e)))
+}
+
+fn init_syslog(env_filter: EnvFilter, format: LogFormat) -> Result<()> {
     let syslog_path = if Path::new("/dev/log").exists() {
         "/dev/log"
     } else if Path::new("/var/run/syslog").exists() {
@@ -178,27 +448,18 @@ fn init_syslog(env_filter: EnvFilter) -> Result<()> {
         return Err(Error::Config("no syslog socket found".to_string()));
     };
 
-    let subscriber = tracing_subscriber::fmt()
-        .with_env_filter(env_filter)
-        .with_target(false)
-        .with_thread_ids(false)
-        .with_ansi(false)
-        .with_writer(move || {
-            UnixDatagram::unbound()
-                .and_then(|sock| {
-                    sock.connect(syslog_path)?;
-                    Ok(SyslogWriter { socket: sock })
-                })
-                .unwrap_or_else(|_| SyslogWriter {
-                    socket: UnixDatagram::unbound().unwrap(),
-                })
-        })
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber)
-        .map_err(|e| Error::Config(format!("failed to set logger: {}", e)))?;
+    let writer = move || {
+        UnixDatagram::unbound()
+            .and_then(|sock| {
+                sock.connect(syslog_path)?;
+                Ok(SyslogWriter { socket: sock })
+            })
+            .unwrap_or_else(|_| SyslogWriter {
+                socket: UnixDatagram::unbound().unwrap(),
+            })
+    };
 
-    Ok(())
+    install(env_filter, output_layer(format, writer, false))
 }
 
 struct SyslogWriter {
@@ -217,24 +478,154 @@ impl Write for SyslogWriter {
     }
 }
 
-fn init_file_logger(path: &str, env_filter: EnvFilter) -> Result<()> {
-    let file = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(path)?;
+fn init_file_logger(
+    path: &str,
+    env_filter: EnvFilter,
+    format: LogFormat,
+    rotation: LogRotation,
+) -> Result<()> {
+    let file = RotatingFile::open(Path::new(path), rotation)?;
+    install(env_filter, output_layer(format, Mutex::new(file), false))
+}
+
+/// Size-based rotation for a file log.
+///
+/// Once writing a line would take the log past `max_bytes`, the file is
+/// renamed to `<logfile>.1`, older rotations move up one number, anything
+/// past `max_files` is deleted, and a fresh file is started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogRotation {
+    /// Size in bytes that triggers rotation. 0 disables rotation.
+    pub max_bytes: u64,
+    /// Number of rotated files to keep.
+    pub max_files: u32,
+}
 
-    let subscriber = tracing_subscriber::fmt()
-        .with_env_filter(env_filter)
-        .with_target(false)
-        .with_thread_ids(false)
-        .with_ansi(false)
-        .with_writer(std::sync::Mutex::new(file))
-        .finish();
+/// Log file writer that rotates according to a [`LogRotation`].
+///
+/// Each formatted line reaches [`Write::write`] as one buffer, so rotating
+/// before a write, never during one, keeps lines whole.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    rotation: LogRotation,
+}
 
-    tracing::subscriber::set_global_default(subscriber)
-        .map_err(|e| Error::Config(format!("failed to set logger: {}", e)))?;
+impl RotatingFile {
+    fn open(path: &Path, rotation: LogRotation) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            len,
+            rotation,
+        })
+    }
 
-    Ok(())
+    /// Path of the `n`th rotated file.
+    fn rotated_path(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        let _ = fs::remove_file(self.rotated_path(self.rotation.max_files));
+        for n in (1..self.rotation.max_files).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let max_bytes = self.rotation.max_bytes;
+        if max_bytes > 0 && self.len > 0 && self.len + buf.len() as u64 > max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.len += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Enables or disables fingerprint anonymization in [`plog`].
+pub fn set_anonymize_fingerprints(enabled: bool) {
+    ANONYMIZE_FINGERPRINTS.store(enabled, Ordering::SeqCst);
+}
+
+/// Replaces every 40-character hex fingerprint in `message` with a short token.
+///
+/// A token is `~` and 16 hex digits of the HMAC-SHA256 of the fingerprint
+/// under a random per-process key. Matching ignores case, and hex runs of
+/// any other length are left alone.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::logger::anonymize_fingerprints;
+///
+/// let fp = "0123456789ABCDEF0123456789ABCDEF01234567";
+/// let line = anonymize_fingerprints(&format!("Layer2 guard ${} is down", fp));
+/// assert!(line.starts_with("Layer2 guard $~"));
+/// assert!(!line.contains(&fp[..8]));
+/// ```
+pub fn anonymize_fingerprints(message: &str) -> String {
+    use hmac::{Hmac, Mac};
+
+    let key = FINGERPRINT_KEY.get_or_init(rand::random);
+    let bytes = message.as_bytes();
+    let mut out = String::with_capacity(message.len());
+    let mut copied = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_hexdigit() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < bytes.len() && bytes[i].is_ascii_hexdigit() {
+            i += 1;
+        }
+        let bounded = |b: Option<&u8>| b.is_none_or(|b| !b.is_ascii_alphanumeric());
+        if i - start == FINGERPRINT_LEN
+            && bounded(start.checked_sub(1).and_then(|p| bytes.get(p)))
+            && bounded(bytes.get(i))
+        {
+            let fingerprint = message[start..i].to_ascii_uppercase();
+            let mut mac =
+                Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+            mac.update(fingerprint.as_bytes());
+            let digest = mac.finalize().into_bytes();
+
+            out.push_str(&message[copied..start]);
+            out.push('~');
+            for byte in &digest[..FINGERPRINT_TOKEN_BYTES] {
+                out.push_str(&format!("{:02x}", byte));
+            }
+            copied = i;
+        }
+    }
+    out.push_str(&message[copied..]);
+    out
 }
 
 /// Log a message at the specified level.
@@ -271,18 +662,82 @@ fn init_file_logger(path: &str, env_filter: EnvFilter) -> Result<()> {
 ///
 /// - Messages are only output if the level meets the configured minimum
 /// - Notice maps to `info!` since tracing doesn't have a notice level
+/// - Fingerprints are anonymized first if [`set_anonymize_fingerprints`] is on
 ///
 /// # See Also
 ///
 /// - [`init`] - Initialize logging before calling plog
 /// - [`plog_fmt`](crate::plog_fmt) - Formatted logging macro
 pub fn plog(level: LogLevel, message: &str) {
+    plog_with(level, message, &LogContext::default());
+}
+
+/// Structured fields attached to a log line by [`plog_with`].
+///
+/// Text output appends them as `key="value"`; JSON output makes them
+/// top-level keys. Unset fields are left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogContext<'a> {
+    /// Circuit the message is about.
+    pub circ_id: Option<&'a str>,
+    /// Guard the message is about.
+    pub guard_fp: Option<&'a str>,
+}
+
+impl<'a> LogContext<'a> {
+    /// Context for a message about `circ_id`.
+    pub fn circuit(circ_id: &'a str) -> Self {
+        Self {
+            circ_id: Some(circ_id),
+            guard_fp: None,
+        }
+    }
+
+    /// Adds the guard the message is about.
+    pub fn with_guard(mut self, guard_fp: Option<&'a str>) -> Self {
+        self.guard_fp = guard_fp;
+        self
+    }
+}
+
+/// Log a message at the specified level with structured context.
+///
+/// Like [`plog`], but the circuit and guard in `context` are recorded as
+/// fields rather than interpolated into `message`. The guard fingerprint is
+/// anonymized along with the message.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::{LogLevel, logger};
+/// use vanguards_rs::logger::LogContext;
+///
+/// logger::plog_with(LogLevel::Info, "We force-closed circuit", &LogContext::circuit("42"));
+/// ```
+pub fn plog_with(level: LogLevel, message: &str, context: &LogContext<'_>) {
+    let anonymize = ANONYMIZE_FINGERPRINTS.load(Ordering::SeqCst);
+    let anonymized;
+    let message = if anonymize {
+        anonymized = anonymize_fingerprints(message);
+        anonymized.as_str()
+    } else {
+        message
+    };
+    let guard_fp = context.guard_fp.map(|fp| {
+        if anonymize {
+            anonymize_fingerprints(fp)
+        } else {
+            fp.to_string()
+        }
+    });
+    let circ_id = context.circ_id;
+    let guard_fp = guard_fp.as_deref();
     match level {
-        LogLevel::Debug => debug!("{}", message),
-        LogLevel::Info => info!("{}", message),
-        LogLevel::Notice => info!("{}", message),
-        LogLevel::Warn => warn!("{}", message),
-        LogLevel::Error => error!("{}", message),
+        LogLevel::Debug => debug!(circ_id, guard_fp, "{}", message),
+        LogLevel::Info => info!(circ_id, guard_fp, "{}", message),
+        LogLevel::Notice => info!(circ_id, guard_fp, "{}", message),
+        LogLevel::Warn => warn!(circ_id, guard_fp, "{}", message),
+        LogLevel::Error => error!(circ_id, guard_fp, "{}", message),
     }
 }
 
@@ -304,3 +759,181 @@ macro_rules! plog_fmt {
         $crate::logger::plog($level, &format!($($arg)*))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[test]
+    fn test_apply_level_changes_emitted_messages() {
+        let buf = SharedBuf::default();
+        let writer = buf.clone();
+        let (filter_layer, handle) =
+            reload::Layer::new(EnvFilter::new(level_filter(LogLevel::Notice)));
+        let subscriber = tracing_subscriber::registry().with(filter_layer).with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone()),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            plog(LogLevel::Debug, "hidden debug message");
+            plog(LogLevel::Notice, "visible notice message");
+
+            apply_level(&handle, LogLevel::Debug).unwrap();
+            plog(LogLevel::Debug, "visible debug message");
+
+            apply_level(&handle, LogLevel::Error).unwrap();
+            plog(LogLevel::Notice, "hidden notice message");
+        });
+
+        let output = buf.contents();
+        assert!(!output.contains("hidden debug message"));
+        assert!(output.contains("visible notice message"));
+        assert!(output.contains("visible debug message"));
+        assert!(!output.contains("hidden notice message"));
+    }
+
+    #[test]
+    fn test_anonymized_log_line_hides_fingerprint() {
+        let fp = "AABBCCDDEEFF00112233445566778899AABBCCDD";
+        let buf = SharedBuf::default();
+        let writer = buf.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone()),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            set_anonymize_fingerprints(true);
+            plog(
+                LogLevel::Notice,
+                &format!("Layer2 guards: ${},{}", fp, fp.to_lowercase()),
+            );
+            set_anonymize_fingerprints(false);
+        });
+
+        let output = buf.contents();
+        assert!(!output.to_uppercase().contains(fp));
+        assert!(!output.contains("AABBCCDD"));
+        assert!(output.contains("$~"));
+
+        let token = anonymize_fingerprints(fp);
+        assert_eq!(token.len(), 1 + 2 * FINGERPRINT_TOKEN_BYTES);
+        assert_eq!(anonymize_fingerprints(&fp.to_lowercase()), token);
+        assert_eq!(output.matches(token.as_str()).count(), 2);
+
+        // Hex runs of other lengths are not fingerprints
+        let short = "circuit 1234 digest DEADBEEF";
+        assert_eq!(anonymize_fingerprints(short), short);
+    }
+
+    #[test]
+    fn test_json_lines_parse() {
+        let buf = SharedBuf::default();
+        let writer = buf.clone();
+        let subscriber = tracing_subscriber::registry().with(JsonLayer {
+            writer: move || writer.clone(),
+        });
+
+        let fp = "AABBCCDDEEFF00112233445566778899AABBCCDD";
+        tracing::subscriber::with_default(subscriber, || {
+            plog(LogLevel::Warn, "Tor says \"hello\"\nagain");
+            plog_with(
+                LogLevel::Info,
+                "We force-closed circuit",
+                &LogContext::circuit("42").with_guard(Some(fp)),
+            );
+        });
+
+        let output = buf.contents();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+
+        assert_eq!(lines[0]["level"], "WARN");
+        assert_eq!(lines[0]["message"], "Tor says \"hello\"\nagain");
+        assert!(lines[0].get("circ_id").is_none());
+        assert!(lines[0].get("guard_fp").is_none());
+        let timestamp = lines[0]["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+
+        assert_eq!(lines[1]["level"], "INFO");
+        assert_eq!(lines[1]["message"], "We force-closed circuit");
+        assert_eq!(lines[1]["circ_id"], "42");
+        assert_eq!(lines[1]["guard_fp"], fp);
+    }
+
+    #[test]
+    fn test_rotating_file_rolls_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.log");
+        let rotation = LogRotation {
+            max_bytes: 100,
+            max_files: 2,
+        };
+        let mut file = RotatingFile::open(&path, rotation).unwrap();
+        let line = |marker: &str, len: usize| format!("{:x<1$}\n", marker, len - 1);
+
+        // Two 40-byte lines fit; the third would not, so it starts a new file
+        file.write_all(line("first", 40).as_bytes()).unwrap();
+        file.write_all(line("second", 40).as_bytes()).unwrap();
+        assert!(!file.rotated_path(1).exists());
+        file.write_all(line("third", 40).as_bytes()).unwrap();
+        assert_eq!(
+            fs::read_to_string(file.rotated_path(1)).unwrap(),
+            line("first", 40) + &line("second", 40)
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), line("third", 40));
+
+        // Older files move up and the oldest beyond max_files is dropped
+        file.write_all(line("fourth", 90).as_bytes()).unwrap();
+        file.write_all(line("fifth", 90).as_bytes()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), line("fifth", 90));
+        assert_eq!(
+            fs::read_to_string(file.rotated_path(1)).unwrap(),
+            line("fourth", 90)
+        );
+        assert_eq!(
+            fs::read_to_string(file.rotated_path(2)).unwrap(),
+            line("third", 40)
+        );
+        assert!(!file.rotated_path(3).exists());
+
+        // Reopening picks up the existing size
+        drop(file);
+        let reopened = RotatingFile::open(&path, rotation).unwrap();
+        assert_eq!(reopened.len, 90);
+    }
+
+    #[test]
+    fn test_level_filter() {
+        assert_eq!(level_filter(LogLevel::Debug), "debug");
+        assert_eq!(level_filter(LogLevel::Notice), "info");
+        assert_eq!(level_filter(LogLevel::Warn), "warn");
+    }
+}
@@ -10,7 +10,8 @@
 //!
 //! - **Buffers log messages**: Keeps recent log entries up to a configurable limit
 //! - **Dumps on circuit close**: Outputs buffered logs before and after circuit closure
-//! - **Monitors warnings**: Logs Tor WARN-level messages at NOTICE level
+//! - **Monitors warnings**: Logs Tor WARN-level messages, raising configured
+//!   protocol warnings to WARN
 //! - **Enables ProtocolWarnings**: Optionally enables Tor's ProtocolWarnings setting
 //!
 //! # Configuration
@@ -22,6 +23,23 @@
 //! | `protocol_warns` | true | Enable ProtocolWarnings in Tor |
 //! | `dump_limit` | 25 | Maximum log entries to buffer |
 //! | `dump_level` | NOTICE | Minimum log level to buffer |
+//! | `extra_warn_patterns` | `[]` | Regexes for warnings to treat as protocol warnings |
+//! | `warn_coalesce_secs` | 10 | Window for folding repeated warnings (0 = off) |
+//!
+//! # Protocol Warnings
+//!
+//! A Tor warning matching one of `extra_warn_patterns` is logged at WARN as
+//! a protocol warning; other warnings are logged at NOTICE, as in Python
+//! vanguards. There are no built-in patterns. Patterns use the [`regex`]
+//! crate's syntax and are checked when the configuration is loaded.
+//!
+//! # Repeated Warnings
+//!
+//! A misbehaving peer can make Tor emit the same warning hundreds of times a
+//! second. The first occurrence is logged straight away; identical repeats
+//! (compared after collapsing whitespace) within `warn_coalesce_secs` are
+//! counted instead, and when the window closes a single line with a
+//! `(suppressed N duplicates)` suffix is logged.
 //!
 //! # What This Module Does NOT Do
 //!
@@ -35,12 +53,64 @@
 //! - [`crate::logger`] - Main logging infrastructure
 //! - [Python vanguards logguard](https://github.com/mikeperry-tor/vanguards)
 
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use regex::Regex;
+
 use crate::config::{LogLevel, LogguardConfig};
+use crate::error::{Error, Result};
 use crate::logger::plog;
 
+/// Compiles `extra_warn_patterns`.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] naming the first pattern that is not a valid
+/// regex.
+pub(crate) fn compile_warn_patterns(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|p| {
+            Regex::new(p).map_err(|e| {
+                Error::Config(format!(
+                    "invalid logguard extra_warn_patterns entry {:?}: {}",
+                    p, e
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Repeats of one warning seen since it was first logged.
+#[derive(Debug, Clone)]
+struct WarnWindow {
+    /// The warning as first seen.
+    message: String,
+    /// Whether it is a protocol warning.
+    protocol_warn: bool,
+    /// Unix timestamp of the first occurrence.
+    opened_at: f64,
+    /// Repeats not logged yet.
+    suppressed: u64,
+}
+
+/// Key for coalescing: the message with runs of whitespace collapsed.
+fn normalize_warn(message: &str) -> String {
+    message.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn log_warn(protocol_warn: bool, message: &str) {
+    if protocol_warn {
+        plog(
+            LogLevel::Warn,
+            &format!("Tor protocol warning: {}", message),
+        );
+    } else {
+        plog(LogLevel::Notice, &format!("Tor log warn: {}", message));
+    }
+}
+
 /// A buffered log entry from Tor.
 ///
 /// Contains the log level, message content, and arrival timestamp.
@@ -131,7 +201,10 @@ fn format_timestamp(timestamp: f64) -> String {
 ///
 /// The log buffer operates as a ring buffer with a configurable maximum size.
 /// When the buffer is full, the oldest entries are discarded to make room
-/// for new ones.
+/// for new ones. A dump for a circuit that was launched before the newest
+/// lost entry notes at INFO how many were lost. There is one
+/// buffer for all circuits, so `dump_limit` bounds its memory no matter how
+/// long a circuit lives or how much Tor logs.
 ///
 /// # Example
 ///
@@ -172,15 +245,42 @@ pub struct LogGuard {
     pub log_level: LogLevel,
     /// Maximum number of entries to buffer.
     pub log_limit: usize,
+    /// Entries discarded to stay within `log_limit` since the last dump.
+    pub dropped_entries: u64,
+    /// Arrival time of the newest discarded entry since the last dump.
+    last_dropped_at: Option<f64>,
+    /// Launch times of open circuits, by circuit ID.
+    circ_launched_at: HashMap<String, f64>,
+    /// Compiled `extra_warn_patterns`.
+    pub extra_warn_patterns: Vec<Regex>,
+    /// Seconds to coalesce identical warnings over (0 = disabled).
+    pub warn_coalesce_secs: u64,
+    /// Open coalescing windows, keyed by normalized message.
+    warn_windows: HashMap<String, WarnWindow>,
 }
 
 impl LogGuard {
     /// Creates a new LogGuard with the specified configuration.
+    ///
+    /// Invalid `extra_warn_patterns` are dropped; [`Config::validate`]
+    /// rejects them before this point.
+    ///
+    /// [`Config::validate`]: crate::config::Config::validate
     pub fn new(config: &LogguardConfig) -> Self {
         Self {
             log_buffer: VecDeque::new(),
             log_level: config.dump_level,
             log_limit: config.dump_limit,
+            dropped_entries: 0,
+            last_dropped_at: None,
+            circ_launched_at: HashMap::new(),
+            extra_warn_patterns: config
+                .extra_warn_patterns
+                .iter()
+                .filter_map(|p| Regex::new(p).ok())
+                .collect(),
+            warn_coalesce_secs: config.warn_coalesce_secs,
+            warn_windows: HashMap::new(),
         }
     }
 
@@ -194,29 +294,104 @@ impl LogGuard {
     /// * `runlevel` - The log level (DEBUG, INFO, NOTICE, WARN, ERR)
     /// * `message` - The log message content
     pub fn log_event(&mut self, runlevel: &str, message: &str) {
-        let entry = LogEntry::new(runlevel, message);
-        self.log_buffer.push_back(entry);
-
-        while self.log_buffer.len() > self.log_limit {
-            self.log_buffer.pop_front();
-        }
+        self.push_entry(LogEntry::new(runlevel, message));
     }
 
     /// Handles a log event with a specific timestamp.
     pub fn log_event_with_timestamp(&mut self, runlevel: &str, message: &str, arrived_at: f64) {
-        let entry = LogEntry::with_timestamp(runlevel, message, arrived_at);
-        self.log_buffer.push_back(entry);
+        self.push_entry(LogEntry::with_timestamp(runlevel, message, arrived_at));
+    }
 
+    /// Buffers `entry`, evicting the oldest entries past `log_limit`.
+    fn push_entry(&mut self, entry: LogEntry) {
+        self.log_buffer.push_back(entry);
         while self.log_buffer.len() > self.log_limit {
-            self.log_buffer.pop_front();
+            if let Some(dropped) = self.log_buffer.pop_front() {
+                self.last_dropped_at = Some(dropped.arrived_at);
+            }
+            self.dropped_entries += 1;
         }
     }
 
     /// Handles a WARN-level log event.
     ///
-    /// Logs the warning at NOTICE level for visibility.
-    pub fn log_warn_event(&self, message: &str) {
-        plog(LogLevel::Notice, &format!("Tor log warn: {}", message));
+    /// Protocol warnings, those matching `extra_warn_patterns`, are logged
+    /// at WARN; anything else at NOTICE.
+    /// Repeats of a warning within `warn_coalesce_secs` of its first
+    /// occurrence are counted rather than logged; see [`Self::flush_warns`].
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The warning text
+    /// * `arrived_at` - Unix timestamp when the warning arrived
+    ///
+    /// # Returns
+    ///
+    /// `true` if the message was a protocol warning.
+    pub fn log_warn_event(&mut self, message: &str, arrived_at: f64) -> bool {
+        self.flush_warns(arrived_at);
+        let protocol_warn = self.is_protocol_warn(message);
+
+        if self.warn_coalesce_secs > 0 {
+            let key = normalize_warn(message);
+            if let Some(window) = self.warn_windows.get_mut(&key) {
+                window.suppressed += 1;
+                return protocol_warn;
+            }
+            self.warn_windows.insert(
+                key,
+                WarnWindow {
+                    message: message.to_string(),
+                    protocol_warn,
+                    opened_at: arrived_at,
+                    suppressed: 0,
+                },
+            );
+        }
+        log_warn(protocol_warn, message);
+        protocol_warn
+    }
+
+    /// Closes coalescing windows older than `warn_coalesce_secs`, logging one
+    /// line for each that suppressed repeats.
+    ///
+    /// Called for every incoming event so a burst is reported soon after it
+    /// ends, not only when the same warning comes back.
+    pub fn flush_warns(&mut self, now: f64) {
+        for (protocol_warn, message) in self.close_warn_windows(now) {
+            log_warn(protocol_warn, &message);
+        }
+    }
+
+    /// Removes expired windows and returns the coalesced lines to log.
+    fn close_warn_windows(&mut self, now: f64) -> Vec<(bool, String)> {
+        if self.warn_windows.is_empty() {
+            return Vec::new();
+        }
+        let window_secs = self.warn_coalesce_secs as f64;
+        let mut lines = Vec::new();
+        self.warn_windows.retain(|_, window| {
+            if now - window.opened_at < window_secs {
+                return true;
+            }
+            if window.suppressed > 0 {
+                lines.push((
+                    window.protocol_warn,
+                    format!(
+                        "{} (suppressed {} duplicates)",
+                        window.message, window.suppressed
+                    ),
+                ));
+            }
+            false
+        });
+        lines
+    }
+
+    fn is_protocol_warn(&self, message: &str) -> bool {
+        self.extra_warn_patterns
+            .iter()
+            .any(|re| re.is_match(message))
     }
 
     /// Dumps the log buffer for a circuit close event.
@@ -229,6 +404,18 @@ impl LogGuard {
     /// * `circ_id` - The circuit ID being closed
     /// * `when` - "Pre" for before close, "Post" for after close
     pub fn dump_log_queue(&mut self, circ_id: &str, when: &str) {
+        if let Some(dropped) = self.dropped_during(circ_id) {
+            plog(
+                LogLevel::Info,
+                &format!(
+                    "{}-close CIRC ID={} Tor log: ({} older entries dropped; \
+                     raise dump_limit to keep more)",
+                    when, circ_id, dropped
+                ),
+            );
+        }
+        self.dropped_entries = 0;
+        self.last_dropped_at = None;
         while let Some(entry) = self.log_buffer.pop_front() {
             plog(
                 LogLevel::Notice,
@@ -242,9 +429,21 @@ impl LogGuard {
         }
     }
 
+    /// Returns how many entries were dropped since the last dump, if any of
+    /// them arrived after `circ_id` was launched.
+    ///
+    /// Circuits launched before this guard started count as old enough.
+    fn dropped_during(&self, circ_id: &str) -> Option<u64> {
+        let last_dropped_at = self.last_dropped_at?;
+        let launched_at = self.circ_launched_at.get(circ_id);
+        (!self.log_buffer.is_empty() && launched_at.is_none_or(|t| last_dropped_at >= *t))
+            .then_some(self.dropped_entries)
+    }
+
     /// Handles a circuit event for post-close log dumping.
     ///
-    /// Dumps buffered logs after a circuit is closed with REQUESTED reason.
+    /// Notes when each circuit was launched, and dumps buffered logs after a
+    /// circuit is closed with REQUESTED reason.
     ///
     /// # Arguments
     ///
@@ -252,8 +451,18 @@ impl LogGuard {
     /// * `status` - The circuit status (CLOSED, FAILED, etc.)
     /// * `reason` - The close reason
     pub fn circ_event(&mut self, circ_id: &str, status: &str, reason: Option<&str>) {
-        if (status == "CLOSED" || status == "FAILED") && reason == Some("REQUESTED") {
-            self.dump_log_queue(circ_id, "Post");
+        if status == "LAUNCHED" {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            self.circ_launched_at.insert(circ_id.to_string(), now);
+        }
+        if status == "CLOSED" || status == "FAILED" {
+            if reason == Some("REQUESTED") {
+                self.dump_log_queue(circ_id, "Post");
+            }
+            self.circ_launched_at.remove(circ_id);
         }
     }
 
@@ -265,6 +474,8 @@ impl LogGuard {
     /// Clears the log buffer.
     pub fn clear(&mut self) {
         self.log_buffer.clear();
+        self.dropped_entries = 0;
+        self.last_dropped_at = None;
     }
 
     /// Returns the log levels that should be subscribed to based on dump_level.
@@ -358,6 +569,62 @@ mod tests {
         assert_eq!(first.message, "Message 2");
     }
 
+    #[test]
+    fn test_logguard_counts_dropped_entries() {
+        let config = LogguardConfig {
+            dump_limit: 10,
+            ..Default::default()
+        };
+        let mut guard = LogGuard::new(&config);
+
+        for i in 0..1000 {
+            guard.log_event_with_timestamp("DEBUG", &format!("Message {}", i), i as f64);
+        }
+
+        assert_eq!(guard.buffer_len(), 10);
+        assert_eq!(guard.dropped_entries, 990);
+        let kept: Vec<&str> = guard
+            .log_buffer
+            .iter()
+            .map(|e| e.message.as_str())
+            .collect();
+        let expected: Vec<String> = (990..1000).map(|i| format!("Message {}", i)).collect();
+        assert_eq!(kept, expected);
+
+        guard.dump_log_queue("1", "Pre");
+        assert_eq!(guard.buffer_len(), 0);
+        assert_eq!(guard.dropped_entries, 0);
+    }
+
+    #[test]
+    fn test_logguard_drops_noted_within_circuit_lifetime() {
+        let config = LogguardConfig {
+            dump_limit: 2,
+            ..Default::default()
+        };
+        let mut guard = LogGuard::new(&config);
+        guard.circ_event("1", "LAUNCHED", None);
+        let launched_at = guard.circ_launched_at["1"];
+
+        for i in 0..5 {
+            guard.log_event_with_timestamp("NOTICE", "before", launched_at - 10.0 + f64::from(i));
+        }
+        assert_eq!(guard.dropped_entries, 3);
+        // Everything lost predates circuit 1, but not a circuit we never saw
+        assert_eq!(guard.dropped_during("1"), None);
+        assert_eq!(guard.dropped_during("2"), Some(3));
+
+        // The oldest go first, so the third newer entry pushes out one of its own
+        for i in 1..=3 {
+            guard.log_event_with_timestamp("NOTICE", "after", launched_at + f64::from(i));
+        }
+        assert_eq!(guard.dropped_during("1"), Some(6));
+
+        guard.circ_event("1", "CLOSED", Some("REQUESTED"));
+        assert_eq!(guard.dropped_during("2"), None);
+        assert!(guard.circ_launched_at.is_empty());
+    }
+
     #[test]
     fn test_logguard_clear() {
         let config = LogguardConfig::default();
@@ -370,6 +637,57 @@ mod tests {
         assert_eq!(guard.buffer_len(), 0);
     }
 
+    #[test]
+    fn test_extra_warn_patterns() {
+        let config = LogguardConfig {
+            extra_warn_patterns: vec![r"^Rejecting INTRODUCE2 .* replay".to_string()],
+            ..Default::default()
+        };
+        let mut guard = LogGuard::new(&config);
+
+        assert!(guard.log_warn_event("Rejecting INTRODUCE2 cell: possible replay", 0.0));
+        // Nothing is a protocol warning unless configured
+        assert!(!guard.log_warn_event("Bug: tor_assertion_failed_(): Bug", 0.0));
+        assert!(!guard.log_warn_event("Rejecting INTRODUCE2 cell: bad signature", 0.0));
+        assert!(!LogGuard::new(&LogguardConfig::default())
+            .log_warn_event("Rejecting INTRODUCE2 cell: possible replay", 0.0));
+
+        let err = compile_warn_patterns(&["(unclosed".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("(unclosed"));
+    }
+
+    #[test]
+    fn test_warn_coalescing() {
+        let config = LogguardConfig {
+            extra_warn_patterns: vec!["^Bug: ".to_string()],
+            ..Default::default()
+        };
+        let mut guard = LogGuard::new(&config);
+        let message = "Bug: circuit_receive_relay_cell(): dropping cell";
+
+        for i in 0..100 {
+            guard.log_warn_event(message, 1000.0 + i as f64 * 0.05);
+        }
+        guard.log_warn_event("Unrelated  warning", 1004.0);
+        assert!(guard.close_warn_windows(1009.9).is_empty());
+
+        let lines = guard.close_warn_windows(1010.0);
+        assert_eq!(
+            lines,
+            vec![(true, format!("{} (suppressed 99 duplicates)", message))]
+        );
+        assert_eq!(guard.warn_windows.len(), 1);
+
+        // A warning seen once closes without a second line
+        assert!(guard.close_warn_windows(1014.0).is_empty());
+        assert!(guard.warn_windows.is_empty());
+
+        guard.warn_coalesce_secs = 0;
+        guard.log_warn_event(message, 1020.0);
+        guard.log_warn_event(message, 1020.0);
+        assert!(guard.warn_windows.is_empty());
+    }
+
     #[test]
     fn test_get_log_event_types_debug() {
         let events = LogGuard::get_log_event_types(LogLevel::Debug);
@@ -406,6 +724,8 @@ mod tests {
             dump_level: LogLevel::Debug,
             dump_limit: 25,
             protocol_warns: true,
+            extra_warn_patterns: Vec::new(),
+            warn_coalesce_secs: 10,
         };
         let lg = LogGuard::new(&config);
 
@@ -77,6 +77,12 @@
 //! # Generate default configuration file
 //! vanguards-rs --generate_config vanguards.conf
 //!
+//! # Check that a config file loads back unchanged after re-saving
+//! vanguards-rs --check-config vanguards.conf
+//!
+//! # Show which relays a consensus favours, with Wmm halved
+//! vanguards-rs --analyze-consensus cached-microdesc-consensus --weights Wmm=5000
+//!
 //! # Use custom configuration file
 //! vanguards-rs --config /etc/vanguards/vanguards.conf
 //!
@@ -92,6 +98,9 @@
 //!
 //! # One-shot mode: set vanguards and exit
 //! vanguards-rs --one-shot-vanguards
+//!
+//! # Health check: print OK or FAIL and exit 0 or 1, changing nothing
+//! vanguards-rs --check
 //! ```
 //!
 //! ## Component Control
@@ -119,10 +128,24 @@
 //!
 //! # Exit Codes
 //!
-//! | Code | Meaning |
-//! |------|---------|
-//! | 0 | Success |
-//! | 1 | Error (see stderr for details) |
+//! Failures map to distinct codes so supervisors can tell a misconfiguration
+//! (don't restart) from a transient connection problem (do restart). Details
+//! are always printed to stderr.
+//!
+//! | Code | Meaning | Source |
+//! |------|---------|--------|
+//! | 0 | Success | |
+//! | 1 | Unclassified error | Consensus, validation, and other errors |
+//! | 2 | Invalid command-line arguments | Reported by clap before startup |
+//! | 3 | Configuration error | [`Error::Config`](vanguards_rs::Error::Config) |
+//! | 4 | Could not connect to Tor | [`Error::Connection`](vanguards_rs::Error::Connection), other control errors |
+//! | 5 | Authentication failed | [`Error::Control`](vanguards_rs::Error::Control) with an auth failure |
+//! | 6 | Tor too old for vanguards | [`Error::UnsupportedTor`](vanguards_rs::Error::UnsupportedTor) |
+//! | 7 | State file error | [`Error::State`](vanguards_rs::Error::State) |
+//! | 8 | I/O error | [`Error::Io`](vanguards_rs::Error::Io) |
+//!
+//! `--check` reports its own failures on stdout, a config that fails to load
+//! included, and always exits 1 for them, so probes need only test for zero.
 //!
 //! # Environment Variables
 //!
@@ -140,20 +163,40 @@
 use clap::Parser;
 use std::process::ExitCode;
 
-use vanguards_rs::{config, control, logger, CliArgs, Config, LogLevel};
+use vanguards_rs::logger::{LogOptions, LogRotation};
+use vanguards_rs::{config, control, logger, CliArgs, Config, Error, LogLevel};
+
+/// Number of relays `--analyze-consensus` lists.
+const ANALYZE_TOP_RELAYS: usize = 20;
 
 #[tokio::main]
 async fn main() -> ExitCode {
     match run().await {
-        Ok(()) => ExitCode::SUCCESS,
+        Ok(code) => code,
         Err(e) => {
             eprintln!("Error: {}", e);
-            ExitCode::FAILURE
+            ExitCode::from(exit_code(&e))
         }
     }
 }
 
-async fn run() -> vanguards_rs::Result<()> {
+/// Maps an error to its process exit code (see the module docs).
+fn exit_code(err: &Error) -> u8 {
+    match err {
+        Error::Config(_) => 3,
+        Error::Control(stem_rs::Error::Authentication(_)) => 5,
+        Error::Connection(_) | Error::Control(_) => 4,
+        Error::UnsupportedTor(_) => 6,
+        Error::State(_) => 7,
+        Error::Io(_) => 8,
+        Error::Consensus(_)
+        | Error::NoNodesRemain
+        | Error::Validation(_)
+        | Error::DescriptorUnavailable(_) => 1,
+    }
+}
+
+async fn run() -> vanguards_rs::Result<ExitCode> {
     let args = CliArgs::parse();
 
     // Handle --generate_config
@@ -162,14 +205,69 @@ async fn run() -> vanguards_rs::Result<()> {
         let toml = config.to_toml()?;
         std::fs::write(output_path, toml)?;
         println!("Wrote default config to {}", output_path.display());
-        return Ok(());
+        return Ok(ExitCode::SUCCESS);
     }
 
-    // Load configuration
-    let config = config::load_config(&args)?;
+    // Handle --check-config
+    if let Some(ref path) = args.check_config {
+        let changes = Config::from_file(path)?.round_trip_changes()?;
+        if changes.is_empty() {
+            println!("{} round-trips without changes", path.display());
+            return Ok(ExitCode::SUCCESS);
+        }
+        for change in &changes {
+            println!("{}", change);
+        }
+        return Err(Error::Config(format!(
+            "{} setting(s) in {} changed after re-serialization",
+            changes.len(),
+            path.display()
+        )));
+    }
+
+    // Load configuration. With --check, a bad config is a failed probe
+    let config = match config::load_config(&args) {
+        Ok(config) => config,
+        Err(e) if args.check => {
+            println!("{}", control::health_check_summary(&Err(e)));
+            return Ok(ExitCode::FAILURE);
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Handle --analyze-consensus
+    if let Some(ref path) = args.analyze_consensus {
+        let probabilities = control::analyze_consensus(path, &config)?;
+        println!("{} eligible relays", probabilities.len());
+        for (fingerprint, probability) in probabilities.iter().take(ANALYZE_TOP_RELAYS) {
+            println!("{} {:.4}%", fingerprint, probability * 100.0);
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Handle --check
+    if args.check {
+        let result = control::health_check(&config).await;
+        println!("{}", control::health_check_summary(&result));
+        return Ok(if result.is_ok() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
 
     // Initialize logging
-    logger::init(config.loglevel, config.logfile.as_deref())?;
+    logger::init_with(
+        config.loglevel,
+        config.logfile.as_deref(),
+        LogOptions {
+            format: config.log_format,
+            rotation: LogRotation {
+                max_bytes: config.logfile_max_bytes,
+                max_files: config.logfile_max_files,
+            },
+        },
+    )?;
 
     logger::plog(
         LogLevel::Notice,
@@ -206,5 +304,32 @@ async fn run() -> vanguards_rs::Result<()> {
     logger::plog(LogLevel::Notice, "Configuration loaded successfully");
 
     // Run the main control loop
-    control::run_main(config).await
+    control::set_reload_args(args);
+    control::run_main(config).await?;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(exit_code(&Error::Config("bad".to_string())), 3);
+        assert_eq!(exit_code(&Error::Connection("refused".to_string())), 4);
+        assert_eq!(
+            exit_code(&Error::Control(stem_rs::Error::Authentication(
+                stem_rs::AuthError::IncorrectPassword
+            ))),
+            5
+        );
+        assert_eq!(
+            exit_code(&Error::Control(stem_rs::Error::Protocol("x".to_string()))),
+            4
+        );
+        assert_eq!(exit_code(&Error::UnsupportedTor("0.3.2".to_string())), 6);
+        assert_eq!(exit_code(&Error::State("corrupt".to_string())), 7);
+        assert_eq!(exit_code(&Error::Io(std::io::Error::other("disk full"))), 8);
+        assert_eq!(exit_code(&Error::NoNodesRemain), 1);
+    }
 }
@@ -0,0 +1,569 @@
+//! Processing-time metrics for the control loop's event handlers.
+//!
+//! Every Tor event is handled inline on the control loop, so a slow handler
+//! delays everything queued behind it. On a weak CPU, applying a new
+//! consensus is the usual suspect. [`HandlerLatencies`] keeps one
+//! [`LatencyHistogram`] per handler so operators can see where the time goes.
+//! IPC clients get the percentiles with the `metrics` command.
+//!
+//! With `metrics_listen` set, a [`MetricsServer`] also serves the handler
+//! percentiles, detection counters and guard counts over HTTP in the
+//! Prometheus text format, so a daemon can be scraped without parsing its
+//! logs:
+//!
+//! ```text
+//! vanguards_circuits_closed_total{reason="dropped_cells"} 2
+//! vanguards_circs_destroyed_total 14
+//! vanguards_circs_pruned_total 0
+//! vanguards_guards{layer="layer2"} 4
+//! vanguards_handler_duration_seconds{handler="circ",quantile="0.5"} 0.0001
+//! vanguards_handler_duration_seconds_sum{handler="circ"} 0.5
+//! vanguards_handler_duration_seconds_count{handler="circ"} 4200
+//! vanguards_rend_overuse_total 0
+//! ```
+//!
+//! # Buckets
+//!
+//! Histograms use fixed buckets from 50µs to 5s, with an overflow bucket
+//! above that. A percentile is reported as the upper bound of the bucket it
+//! falls in, so it overstates the true value by at most one bucket width.
+//! For the overflow bucket the largest recorded duration is reported.
+//!
+//! # What This Module Does NOT Do
+//!
+//! - **Windowing**: Histograms cover the whole process lifetime
+//! - **HTTP features**: `/metrics` answers plain `GET`s; there is no TLS or authentication
+//!
+//! # See Also
+//!
+//! - [`crate::control::AppState`] - Owns the histograms
+//! - [`crate::ipc`] - The `metrics` command
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::bandguards::ATTACK_KINDS;
+use crate::config::LogLevel;
+use crate::error::Result;
+use crate::logger::plog;
+
+/// Longest a scrape may take to send its request headers.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest request accepted on the metrics endpoint.
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Pause after a failed accept, so that running out of file descriptors
+/// does not spin the listener.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Upper bounds of the histogram buckets, in microseconds.
+pub const BUCKET_BOUNDS_US: [u64; 16] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000,
+];
+
+/// An event handler whose processing time is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Handler {
+    /// `CIRC` events.
+    Circ,
+    /// `CIRC_BW` events.
+    CircBw,
+    /// `ORCONN` events.
+    OrConn,
+    /// `BW` events.
+    Bw,
+    /// Applying a new consensus after `NEWCONSENSUS`.
+    Consensus,
+}
+
+impl Handler {
+    /// Every measured handler, in reporting order.
+    pub const ALL: [Handler; 5] = [
+        Handler::Circ,
+        Handler::CircBw,
+        Handler::OrConn,
+        Handler::Bw,
+        Handler::Consensus,
+    ];
+
+    /// Returns the handler's name, as used over IPC and in metric labels.
+    pub fn name(self) -> &'static str {
+        match self {
+            Handler::Circ => "circ",
+            Handler::CircBw => "circ_bw",
+            Handler::OrConn => "or_conn",
+            Handler::Bw => "bw",
+            Handler::Consensus => "consensus",
+        }
+    }
+}
+
+/// Counts of durations in the [`BUCKET_BOUNDS_US`] buckets.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// One count per bucket, plus the overflow bucket at the end.
+    buckets: [u64; BUCKET_BOUNDS_US.len() + 1],
+    /// Number of recorded durations.
+    pub count: u64,
+    /// Sum of recorded durations, in microseconds.
+    pub sum_us: u64,
+    /// Largest recorded duration, in microseconds.
+    pub max_us: u64,
+}
+
+impl LatencyHistogram {
+    /// Records one duration.
+    pub fn record(&mut self, elapsed: Duration) {
+        let us = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let bucket = BUCKET_BOUNDS_US
+            .iter()
+            .position(|&bound| us <= bound)
+            .unwrap_or(BUCKET_BOUNDS_US.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+        self.max_us = self.max_us.max(us);
+    }
+
+    /// Returns the `p`th percentile (0–100) in microseconds, or `None` if
+    /// nothing was recorded.
+    pub fn percentile_us(&self, p: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let bound = BUCKET_BOUNDS_US.get(i).copied().unwrap_or(self.max_us);
+                return Some(bound.min(self.max_us));
+            }
+        }
+        Some(self.max_us)
+    }
+}
+
+/// Percentiles of one handler's processing time, as reported over IPC.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencySummary {
+    /// The measured handler.
+    pub handler: Handler,
+    /// Number of events handled.
+    pub count: u64,
+    /// Median processing time, in microseconds.
+    pub p50_us: u64,
+    /// 90th percentile, in microseconds.
+    pub p90_us: u64,
+    /// 99th percentile, in microseconds.
+    pub p99_us: u64,
+    /// Slowest event, in microseconds.
+    pub max_us: u64,
+}
+
+/// One [`LatencyHistogram`] per [`Handler`].
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::Duration;
+/// use vanguards_rs::metrics::{Handler, HandlerLatencies};
+///
+/// let mut latencies = HandlerLatencies::default();
+/// latencies.record(Handler::Circ, Duration::from_micros(80));
+/// let summary = latencies.summaries();
+/// assert_eq!(summary.len(), 1);
+/// assert_eq!(summary[0].p50_us, 80);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandlerLatencies {
+    histograms: [LatencyHistogram; Handler::ALL.len()],
+}
+
+impl HandlerLatencies {
+    /// Records how long one run of `handler` took.
+    pub fn record(&mut self, handler: Handler, elapsed: Duration) {
+        self.histograms[handler as usize].record(elapsed);
+    }
+
+    /// Returns the histogram for `handler`.
+    pub fn histogram(&self, handler: Handler) -> &LatencyHistogram {
+        &self.histograms[handler as usize]
+    }
+
+    /// Returns percentiles for every handler that has run at least once.
+    pub fn summaries(&self) -> Vec<LatencySummary> {
+        Handler::ALL
+            .iter()
+            .filter_map(|&handler| {
+                let h = self.histogram(handler);
+                Some(LatencySummary {
+                    handler,
+                    count: h.count,
+                    p50_us: h.percentile_us(50.0)?,
+                    p90_us: h.percentile_us(90.0)?,
+                    p99_us: h.percentile_us(99.0)?,
+                    max_us: h.max_us,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Quantiles reported for each handler on the Prometheus endpoint.
+const SUMMARY_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Values served on the Prometheus endpoint, refreshed by the control loop.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Circuits closed after a detection, by attack name from
+    /// [`ATTACK_KINDS`].
+    pub circuits_closed: BTreeMap<&'static str, u64>,
+    /// Circuits destroyed while carrying traffic, from
+    /// [`BandwidthStats::circs_destroyed_total`](crate::bandguards::BandwidthStats::circs_destroyed_total).
+    pub circs_destroyed: u64,
+    /// Circuits forgotten after going idle, from
+    /// [`BandwidthStats::circs_pruned_total`](crate::bandguards::BandwidthStats::circs_pruned_total).
+    pub circs_pruned: u64,
+    /// Current layer 2 guard count.
+    pub layer2_guards: usize,
+    /// Current layer 3 guard count.
+    pub layer3_guards: usize,
+    /// Event handler processing times.
+    pub latencies: HandlerLatencies,
+    /// Rendezvous point overuse detections.
+    pub rend_overuse: u64,
+}
+
+impl MetricsSnapshot {
+    /// Renders the snapshot in the Prometheus text exposition format.
+    ///
+    /// Every attack name gets a `circuits_closed` sample, zero if nothing
+    /// was closed for it, so rate queries work from the first scrape.
+    /// Handler latencies are a `summary` per handler; quantiles are left out
+    /// until the handler has run.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::metrics::MetricsSnapshot;
+    ///
+    /// let snapshot = MetricsSnapshot {
+    ///     layer2_guards: 4,
+    ///     ..MetricsSnapshot::default()
+    /// };
+    /// let text = snapshot.render();
+    /// assert!(text.contains("vanguards_guards{layer=\"layer2\"} 4\n"));
+    /// assert!(text.contains("vanguards_circuits_closed_total{reason=\"max_bytes\"} 0\n"));
+    /// ```
+    pub fn render(&self) -> String {
+        let mut out = String::from(
+            "# HELP vanguards_circuits_closed_total Circuits closed after an attack detection, \
+             by attack kind.\n\
+             # TYPE vanguards_circuits_closed_total counter\n",
+        );
+        for kind in ATTACK_KINDS {
+            let count = self.circuits_closed.get(kind).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "vanguards_circuits_closed_total{{reason=\"{}\"}} {}",
+                kind, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP vanguards_circs_destroyed_total Circuits destroyed while carrying traffic.\n\
+             # TYPE vanguards_circs_destroyed_total counter\n\
+             vanguards_circs_destroyed_total {}",
+            self.circs_destroyed
+        );
+        let _ = writeln!(
+            out,
+            "# HELP vanguards_circs_pruned_total Circuits no longer tracked after going idle \
+             without a CLOSED event.\n\
+             # TYPE vanguards_circs_pruned_total counter\n\
+             vanguards_circs_pruned_total {}",
+            self.circs_pruned
+        );
+        let _ = writeln!(
+            out,
+            "# HELP vanguards_guards Current vanguards per layer.\n\
+             # TYPE vanguards_guards gauge\n\
+             vanguards_guards{{layer=\"layer2\"}} {}\n\
+             vanguards_guards{{layer=\"layer3\"}} {}",
+            self.layer2_guards, self.layer3_guards
+        );
+        out.push_str(
+            "# HELP vanguards_handler_duration_seconds Time spent handling Tor events, \
+             by handler.\n\
+             # TYPE vanguards_handler_duration_seconds summary\n",
+        );
+        for handler in Handler::ALL {
+            let histogram = self.latencies.histogram(handler);
+            for quantile in SUMMARY_QUANTILES {
+                if let Some(us) = histogram.percentile_us(quantile * 100.0) {
+                    let _ = writeln!(
+                        out,
+                        "vanguards_handler_duration_seconds{{handler=\"{}\",quantile=\"{}\"}} {}",
+                        handler.name(),
+                        quantile,
+                        us as f64 / 1e6
+                    );
+                }
+            }
+            let _ = writeln!(
+                out,
+                "vanguards_handler_duration_seconds_sum{{handler=\"{}\"}} {}\n\
+                 vanguards_handler_duration_seconds_count{{handler=\"{}\"}} {}",
+                handler.name(),
+                histogram.sum_us as f64 / 1e6,
+                handler.name(),
+                histogram.count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP vanguards_rend_overuse_total Rendezvous point overuse detections.\n\
+             # TYPE vanguards_rend_overuse_total counter\n\
+             vanguards_rend_overuse_total {}",
+            self.rend_overuse
+        );
+        out
+    }
+}
+
+/// Serves the latest [`MetricsSnapshot`] on `/metrics`.
+///
+/// Shared between the control loop, which calls [`MetricsServer::update`],
+/// and the listener started with [`MetricsServer::spawn`].
+#[derive(Debug, Default)]
+pub struct MetricsServer {
+    snapshot: Mutex<MetricsSnapshot>,
+}
+
+impl MetricsServer {
+    /// Creates a server with an all-zero snapshot.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the snapshot served to scrapers.
+    pub fn update(&self, snapshot: MetricsSnapshot) {
+        *self.snapshot.lock().unwrap_or_else(|e| e.into_inner()) = snapshot;
+    }
+
+    /// Returns the snapshot currently served.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.snapshot
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Binds `addr` and serves scrapes on a background task.
+    ///
+    /// Returns the bound address, which tells the actual port when `addr`
+    /// uses port 0. Must be called from within a tokio runtime.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`](crate::Error::Io) if the address cannot be bound.
+    pub fn spawn(self: &Arc<Self>, addr: SocketAddr) -> Result<SocketAddr> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let local = listener.local_addr()?;
+
+        plog(
+            LogLevel::Notice,
+            &format!("Serving Prometheus metrics on http://{}/metrics", local),
+        );
+
+        let server = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_scrape(stream, server.clone()));
+                    }
+                    Err(e) => {
+                        plog(LogLevel::Warn, &format!("Metrics accept failed: {}", e));
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                }
+            }
+        });
+        Ok(local)
+    }
+}
+
+/// Answers one HTTP request and closes the connection.
+async fn serve_scrape(mut stream: TcpStream, server: Arc<MetricsServer>) {
+    let Ok(Some(request_line)) = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map(|r| r.ok().flatten())
+    else {
+        return;
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = server.snapshot().render();
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Reads request headers and returns the request line, or `None` if the
+/// client sent too much or hung up early.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let text = String::from_utf8_lossy(&buf);
+    Ok(text.lines().next().map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut h = LatencyHistogram::default();
+        assert_eq!(h.percentile_us(50.0), None);
+
+        // 90 fast events and 10 slow ones
+        for _ in 0..90 {
+            h.record(Duration::from_micros(40));
+        }
+        for _ in 0..10 {
+            h.record(Duration::from_millis(30));
+        }
+        assert_eq!(h.count, 100);
+        assert_eq!(h.percentile_us(50.0), Some(50));
+        assert_eq!(h.percentile_us(90.0), Some(50));
+        assert_eq!(h.percentile_us(99.0), Some(30_000));
+        assert_eq!(h.max_us, 30_000);
+
+        // Beyond the last bucket the maximum is reported
+        h.record(Duration::from_secs(7));
+        assert_eq!(h.percentile_us(100.0), Some(7_000_000));
+    }
+
+    async fn scrape(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let server = Arc::new(MetricsServer::new());
+        let addr = server.spawn("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let mut snapshot = MetricsSnapshot {
+            circs_destroyed: 14,
+            circs_pruned: 3,
+            layer2_guards: 4,
+            layer3_guards: 8,
+            rend_overuse: 1,
+            ..MetricsSnapshot::default()
+        };
+        snapshot.circuits_closed.insert("dropped_cells", 2);
+        server.update(snapshot);
+
+        let response = scrape(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        for line in [
+            "# TYPE vanguards_circuits_closed_total counter",
+            "vanguards_circuits_closed_total{reason=\"dropped_cells\"} 2",
+            "vanguards_circuits_closed_total{reason=\"serv_intro_bytes\"} 0",
+            "vanguards_circs_destroyed_total 14",
+            "vanguards_circs_pruned_total 3",
+            "vanguards_guards{layer=\"layer2\"} 4",
+            "vanguards_guards{layer=\"layer3\"} 8",
+            "vanguards_rend_overuse_total 1",
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                body
+            );
+        }
+
+        assert!(scrape(addr, "/")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_render_handler_latencies() {
+        let mut snapshot = MetricsSnapshot::default();
+        for _ in 0..9 {
+            snapshot
+                .latencies
+                .record(Handler::Circ, Duration::from_micros(80));
+        }
+        snapshot
+            .latencies
+            .record(Handler::Circ, Duration::from_millis(30));
+        let text = snapshot.render();
+
+        assert!(text.contains("# TYPE vanguards_handler_duration_seconds summary\n"));
+        for line in [
+            "vanguards_handler_duration_seconds{handler=\"circ\",quantile=\"0.5\"} 0.0001",
+            "vanguards_handler_duration_seconds{handler=\"circ\",quantile=\"0.9\"} 0.0001",
+            "vanguards_handler_duration_seconds{handler=\"circ\",quantile=\"0.99\"} 0.03",
+            "vanguards_handler_duration_seconds_sum{handler=\"circ\"} 0.03072",
+            "vanguards_handler_duration_seconds_count{handler=\"circ\"} 10",
+            "vanguards_handler_duration_seconds_count{handler=\"consensus\"} 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+        // No quantiles for a handler that never ran
+        assert!(!text.contains("handler=\"consensus\",quantile"));
+    }
+}
@@ -62,12 +62,16 @@
 //! - [`crate::config`] - Configuration for node selection parameters
 //! - [Python vanguards NodeSelection](https://github.com/mikeperry-tor/vanguards)
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use ipnetwork::IpNetwork;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use stem_rs::descriptor::router_status::RouterStatusEntry;
+use stem_rs::descriptor::ServerDescriptor;
 
 use crate::error::{Error, Result};
 
@@ -261,6 +265,254 @@ impl NodeRestriction for FlagsRestriction {
     }
 }
 
+/// Restriction rejecting relays that have not been in the consensus long
+/// enough.
+///
+/// Ages come from first-seen times the caller tracks, such as
+/// [`VanguardState::first_seen`](crate::vanguards::VanguardState::first_seen).
+/// A consensus entry's own `published` time cannot be used: it only says
+/// when the relay last uploaded a descriptor. Relays with no recorded time
+/// are rejected.
+///
+/// # Example
+///
+/// ```rust
+/// use std::collections::HashMap;
+/// use vanguards_rs::node_selection::MinAgeRestriction;
+///
+/// let first_seen = HashMap::from([("A".repeat(40), 0.0)]);
+/// let restriction = MinAgeRestriction::new(24, first_seen, 1_700_000_000.0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct MinAgeRestriction {
+    /// Unix time each relay was first seen, by fingerprint.
+    pub first_seen: HashMap<String, f64>,
+    /// Latest first-seen time that still passes.
+    pub seen_before: f64,
+}
+
+impl MinAgeRestriction {
+    /// Creates a restriction requiring `min_age_hours` in the consensus as
+    /// of `now`, a Unix timestamp.
+    pub fn new(min_age_hours: u32, first_seen: HashMap<String, f64>, now: f64) -> Self {
+        Self {
+            first_seen,
+            seen_before: now - f64::from(min_age_hours) * 3600.0,
+        }
+    }
+}
+
+impl NodeRestriction for MinAgeRestriction {
+    fn r_is_ok(&self, router: &RouterStatusEntry) -> bool {
+        self.first_seen
+            .get(&router.fingerprint)
+            .is_some_and(|&seen| seen <= self.seen_before)
+    }
+}
+
+/// Restriction rejecting relays with too little bandwidth.
+///
+/// Uses the measured bandwidth where the authorities have one and the
+/// self-reported value otherwise. Consensus bandwidths are in kilobytes per
+/// second and are scaled up before comparing; a relay with no bandwidth at
+/// all only passes a zero minimum.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::node_selection::MinBandwidthRestriction;
+///
+/// // At least 1 MB/s
+/// let restriction = MinBandwidthRestriction::new(1_000_000);
+/// ```
+#[derive(Debug, Clone)]
+pub struct MinBandwidthRestriction {
+    /// Minimum bandwidth in bytes per second.
+    pub min_bytes: u64,
+}
+
+impl MinBandwidthRestriction {
+    /// Creates a restriction requiring `min_bytes` per second.
+    pub fn new(min_bytes: u64) -> Self {
+        Self { min_bytes }
+    }
+}
+
+impl NodeRestriction for MinBandwidthRestriction {
+    fn r_is_ok(&self, router: &RouterStatusEntry) -> bool {
+        let kilobytes = router.measured.or(router.bandwidth).unwrap_or(0);
+        kilobytes.saturating_mul(1000) >= self.min_bytes
+    }
+}
+
+/// Restriction rejecting relays that have not been up for long enough.
+///
+/// Consensus entries carry no uptime, so it is taken from the `uptime` line
+/// of server descriptors. Relays with no descriptor, or one without an
+/// uptime, pass, so missing descriptors do not shrink the pool.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::node_selection::MinUptimeRestriction;
+///
+/// // Descriptors as fetched with GETINFO desc/all-recent
+/// let restriction = MinUptimeRestriction::new(86400, &[]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct MinUptimeRestriction {
+    /// Minimum uptime in seconds.
+    pub min_seconds: u64,
+    /// Uptime in seconds each descriptor reported, by fingerprint.
+    pub uptimes: HashMap<String, u64>,
+}
+
+impl MinUptimeRestriction {
+    /// Creates a restriction requiring `min_seconds` of uptime, as reported
+    /// by `descriptors`.
+    pub fn new(min_seconds: u64, descriptors: &[ServerDescriptor]) -> Self {
+        let uptimes = descriptors
+            .iter()
+            .filter_map(|desc| Some((desc.fingerprint.clone()?, desc.uptime?)))
+            .collect();
+        Self {
+            min_seconds,
+            uptimes,
+        }
+    }
+}
+
+impl NodeRestriction for MinUptimeRestriction {
+    fn r_is_ok(&self, router: &RouterStatusEntry) -> bool {
+        self.uptimes
+            .get(&router.fingerprint)
+            .is_none_or(|&uptime| uptime >= self.min_seconds)
+    }
+}
+
+/// Restriction rejecting relays in the same autonomous system as a chosen
+/// guard.
+///
+/// The relay-to-ASN map comes from the operator, usually via
+/// [`Self::from_file`]; relays missing from it pass. Each guard passed to
+/// [`Self::select`] or [`Self::with_selected`] takes its AS out of the
+/// running, so layer2 and layer3 guards end up spread across networks.
+///
+/// # Example
+///
+/// ```rust
+/// use std::collections::HashMap;
+/// use vanguards_rs::node_selection::AsRestriction;
+///
+/// let asns = HashMap::from([("A".repeat(40), 64496), ("B".repeat(40), 64496)]);
+/// let mut restriction = AsRestriction::new(asns);
+/// restriction.select(&"A".repeat(40));
+/// assert_eq!(restriction.asn(&"B".repeat(40)), Some(64496));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AsRestriction {
+    /// AS number of each relay, by upper-case fingerprint.
+    asns: Arc<HashMap<String, u32>>,
+    /// AS numbers of the guards selected so far.
+    selected: HashSet<u32>,
+}
+
+impl AsRestriction {
+    /// Creates a restriction from AS numbers keyed by fingerprint.
+    pub fn new(asns: HashMap<String, u32>) -> Self {
+        Self {
+            asns: Arc::new(
+                asns.into_iter()
+                    .map(|(fp, asn)| (fp.to_uppercase(), asn))
+                    .collect(),
+            ),
+            selected: HashSet::new(),
+        }
+    }
+
+    /// Loads the relay-to-ASN map from `path`.
+    ///
+    /// Each line holds a fingerprint and an AS number, written either as
+    /// `64496` or `AS64496`. Blank lines and text after `#` are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Config`] if the file cannot be read or a line is
+    /// malformed.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("cannot read ASN map {}: {}", path.display(), e)))?;
+        let mut asns = HashMap::new();
+        for (num, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || {
+                Error::Config(format!(
+                    "{}:{}: expected \"FINGERPRINT ASN\", got \"{}\"",
+                    path.display(),
+                    num + 1,
+                    line
+                ))
+            };
+            let mut fields = line.split_whitespace();
+            let (Some(fingerprint), Some(asn), None) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            let asn = asn
+                .strip_prefix("AS")
+                .or_else(|| asn.strip_prefix("as"))
+                .unwrap_or(asn);
+            let asn: u32 = asn.parse().map_err(|_| invalid())?;
+            if !is_valid_fingerprint(fingerprint) {
+                return Err(invalid());
+            }
+            asns.insert(fingerprint.to_string(), asn);
+        }
+        Ok(Self::new(asns))
+    }
+
+    /// Returns the AS number of `fingerprint`, if the map has one.
+    pub fn asn(&self, fingerprint: &str) -> Option<u32> {
+        self.asns.get(&fingerprint.to_uppercase()).copied()
+    }
+
+    /// Marks the AS of `fingerprint` as taken by a guard.
+    pub fn select(&mut self, fingerprint: &str) {
+        if let Some(asn) = self.asn(fingerprint) {
+            self.selected.insert(asn);
+        }
+    }
+
+    /// Marks the AS of each of `fingerprints` as taken.
+    pub fn with_selected<'a>(mut self, fingerprints: impl IntoIterator<Item = &'a str>) -> Self {
+        for fingerprint in fingerprints {
+            self.select(fingerprint);
+        }
+        self
+    }
+
+    /// Returns the number of relays in the map.
+    pub fn len(&self) -> usize {
+        self.asns.len()
+    }
+
+    /// Returns true if the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.asns.is_empty()
+    }
+}
+
+impl NodeRestriction for AsRestriction {
+    fn r_is_ok(&self, router: &RouterStatusEntry) -> bool {
+        self.asn(&router.fingerprint)
+            .is_none_or(|asn| !self.selected.contains(&asn))
+    }
+}
+
 /// A list of node restrictions to apply.
 ///
 /// All restrictions must pass for a router to be accepted. This allows
@@ -335,6 +587,78 @@ impl Position {
     }
 }
 
+/// Relay families, as declared in relay descriptors.
+///
+/// Like Tor, two relays only count as one family when each lists the
+/// other, so a relay cannot claim kinship with relays it does not run.
+///
+/// # Example
+///
+/// ```rust
+/// use std::collections::HashMap;
+/// use vanguards_rs::node_selection::RelayFamilies;
+///
+/// let a = "A".repeat(40);
+/// let b = "B".repeat(40);
+/// let c = "C".repeat(40);
+/// let declared = HashMap::from([
+///     (a.clone(), vec![b.clone(), c.clone()]),
+///     (b.clone(), vec![a.clone()]),
+/// ]);
+/// let families = RelayFamilies::from_declared(&declared);
+/// assert!(families.same_family(&a, &b));
+/// // C never listed A
+/// assert!(!families.same_family(&a, &c));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayFamilies {
+    members: HashMap<String, HashSet<String>>,
+}
+
+impl RelayFamilies {
+    /// Builds families from each relay's declared family members.
+    ///
+    /// Fingerprints are compared case-insensitively.
+    pub fn from_declared(declared: &HashMap<String, Vec<String>>) -> Self {
+        let declared: HashMap<String, HashSet<String>> = declared
+            .iter()
+            .map(|(fp, family)| {
+                (
+                    fp.to_uppercase(),
+                    family.iter().map(|m| m.to_uppercase()).collect(),
+                )
+            })
+            .collect();
+        let mut members = HashMap::new();
+        for (fp, family) in &declared {
+            let mutual: HashSet<String> = family
+                .iter()
+                .filter(|m| *m != fp && declared.get(*m).is_some_and(|f| f.contains(fp)))
+                .cloned()
+                .collect();
+            if !mutual.is_empty() {
+                members.insert(fp.clone(), mutual);
+            }
+        }
+        Self { members }
+    }
+
+    /// Returns true if `a` and `b` declare each other as family.
+    pub fn same_family(&self, a: &str, b: &str) -> bool {
+        self.members.get(a).is_some_and(|m| m.contains(b))
+    }
+
+    /// Returns the number of relays with at least one family member.
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Returns true if no relay has a family member.
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
 /// Bandwidth-weighted node generator.
 ///
 /// Implements bandwidth-weighted random selection of relay nodes.
@@ -360,7 +684,8 @@ impl Position {
 /// # Selection Algorithm
 ///
 /// 1. Filter routers through all restrictions
-/// 2. Calculate weighted bandwidth for each remaining router
+/// 2. Calculate weighted bandwidth for each remaining router, dropping the
+///    lightest if [`Self::with_min_weight_percentile`] was used
 /// 3. Build cumulative weight distribution
 /// 4. Generate random value in [0, total_weight)
 /// 5. Select router where cumulative weight exceeds random value
@@ -393,6 +718,10 @@ pub struct BwWeightedGenerator {
     exit_total: f64,
     position: Position,
     bw_weights: HashMap<String, i64>,
+    families: RelayFamilies,
+    as_restriction: Option<AsRestriction>,
+    /// Seeded RNG from [`Self::with_seed`]; `thread_rng` is used otherwise.
+    rng: Option<Mutex<StdRng>>,
 }
 
 impl BwWeightedGenerator {
@@ -439,12 +768,116 @@ impl BwWeightedGenerator {
             exit_total: 0.0,
             position,
             bw_weights,
+            families: RelayFamilies::default(),
+            as_restriction: None,
+            rng: None,
         };
 
         generator.rebuild_weights();
         Ok(generator)
     }
 
+    /// Creates a generator that draws from a [`StdRng`] seeded with `seed`.
+    ///
+    /// Generators built with the same seed from the same routers select
+    /// the same sequence of relays, which makes guard selection
+    /// reproducible in tests and when replaying a report.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NoNodesRemain`] if all routers are filtered out.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let generator =
+    ///     BwWeightedGenerator::with_seed(routers, restrictions, weights, Position::Middle, 42)?;
+    /// ```
+    pub fn with_seed(
+        sorted_routers: Vec<RouterStatusEntry>,
+        restrictions: NodeRestrictionList,
+        bw_weights: HashMap<String, i64>,
+        position: Position,
+        seed: u64,
+    ) -> Result<Self> {
+        let mut generator = Self::new(sorted_routers, restrictions, bw_weights, position)?;
+        generator.rng = Some(Mutex::new(StdRng::seed_from_u64(seed)));
+        Ok(generator)
+    }
+
+    /// Attaches relay families, so guard selection can keep relays run by
+    /// one operator out of the same layer.
+    pub fn with_families(mut self, families: RelayFamilies) -> Self {
+        self.families = families;
+        self
+    }
+
+    /// Returns the relay families attached with [`Self::with_families`].
+    pub fn families(&self) -> &RelayFamilies {
+        &self.families
+    }
+
+    /// Attaches an AS map, so guard selection can pass over relays sharing
+    /// an AS with a guard already chosen.
+    ///
+    /// Unlike the restrictions given to [`Self::new`], it is not applied
+    /// here: which ASes are taken changes with every guard added, so
+    /// selection checks it per draw.
+    pub fn with_as_restriction(mut self, restriction: AsRestriction) -> Self {
+        self.as_restriction = Some(restriction);
+        self
+    }
+
+    /// Returns the AS map attached with [`Self::with_as_restriction`].
+    pub fn as_restriction(&self) -> Option<&AsRestriction> {
+        self.as_restriction.as_ref()
+    }
+
+    /// Drops the lightest relays, keeping only those at or above the
+    /// `percentile` of cumulative weight.
+    ///
+    /// Relays are ranked by weight, lightest first, and a relay is kept once
+    /// the running total up to and including it reaches `percentile` of
+    /// [`Self::weight_total`]. Relays tied with the cutoff weight are kept
+    /// too, so 0.0 keeps every relay and 1.0 keeps only the heaviest.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// // Choose only from relays in the top half of the network by weight
+    /// let generator = BwWeightedGenerator::new(routers, restrictions, weights, Position::Middle)?
+    ///     .with_min_weight_percentile(0.5);
+    /// ```
+    pub fn with_min_weight_percentile(mut self, percentile: f64) -> Self {
+        if percentile <= 0.0 || self.weight_total <= 0.0 {
+            return self;
+        }
+
+        let mut ascending = self.node_weights.clone();
+        ascending.sort_by(f64::total_cmp);
+        let target = self.weight_total * percentile.min(1.0);
+        let mut cumulative = 0.0;
+        let mut cutoff = ascending.last().copied().unwrap_or(0.0);
+        for weight in ascending {
+            cumulative += weight;
+            if cumulative >= target {
+                cutoff = weight;
+                break;
+            }
+        }
+
+        let kept: Vec<RouterStatusEntry> = self
+            .rstr_routers
+            .drain(..)
+            .zip(&self.node_weights)
+            .filter(|(_, weight)| **weight >= cutoff)
+            .map(|(router, _)| router)
+            .collect();
+        self.rstr_routers = kept;
+        self.rebuild_weights();
+        self
+    }
+
     /// Rebuilds the weight arrays after router list changes.
     fn rebuild_weights(&mut self) {
         self.node_weights.clear();
@@ -526,8 +959,13 @@ impl BwWeightedGenerator {
             return Err(Error::NoNodesRemain);
         }
 
-        let mut rng = rand::thread_rng();
-        let choice_val = rng.gen_range(0.0..self.weight_total);
+        let choice_val = match &self.rng {
+            Some(rng) => rng
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .gen_range(0.0..self.weight_total),
+            None => rand::thread_rng().gen_range(0.0..self.weight_total),
+        };
         let mut cumulative = 0.0;
 
         for (i, weight) in self.node_weights.iter().enumerate() {
@@ -564,11 +1002,68 @@ impl BwWeightedGenerator {
     pub fn node_weights(&self) -> &[f64] {
         &self.node_weights
     }
+
+    /// Returns the share of [`weight_total`](Self::weight_total) carried by
+    /// the given routers.
+    ///
+    /// Fingerprints that are not eligible count as zero. Returns 0 if the
+    /// total weight is zero.
+    pub fn weight_fraction<'a>(&self, fingerprints: impl IntoIterator<Item = &'a str>) -> f64 {
+        if self.weight_total <= 0.0 {
+            return 0.0;
+        }
+        let wanted: HashSet<&str> = fingerprints.into_iter().collect();
+        let weight: f64 = self
+            .rstr_routers
+            .iter()
+            .zip(&self.node_weights)
+            .filter(|(router, _)| wanted.contains(router.fingerprint.as_str()))
+            .map(|(_, weight)| weight)
+            .sum();
+        weight / self.weight_total
+    }
+
+    /// Returns the effective selection probability of each eligible router.
+    ///
+    /// Probabilities are computed over the restricted router set, so relays
+    /// removed by flag restrictions or exclusions do not appear and their
+    /// weight is redistributed across the remaining relays. Each value is the
+    /// chance that a single call to [`generate`](Self::generate) returns that
+    /// router.
+    ///
+    /// # Returns
+    ///
+    /// `(fingerprint, probability)` pairs in the same order as
+    /// [`routers`](Self::routers). All probabilities are zero if the total
+    /// weight is zero.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// for (fp, p) in generator.selection_probabilities() {
+    ///     println!("{} {:.4}%", fp, p * 100.0);
+    /// }
+    /// ```
+    pub fn selection_probabilities(&self) -> Vec<(String, f64)> {
+        self.rstr_routers
+            .iter()
+            .zip(&self.node_weights)
+            .map(|(router, weight)| {
+                let probability = if self.weight_total > 0.0 {
+                    weight / self.weight_total
+                } else {
+                    0.0
+                };
+                (router.fingerprint.clone(), probability)
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::Utc;
 
     #[test]
     fn test_valid_fingerprints() {
@@ -684,6 +1179,67 @@ mod tests {
         assert!(!restriction.r_is_ok(&router));
     }
 
+    #[test]
+    fn test_min_age_restriction() {
+        use stem_rs::descriptor::router_status::RouterStatusEntryType;
+
+        let now = 1_700_000_000.0;
+        let router = RouterStatusEntry::new(
+            RouterStatusEntryType::V3,
+            "fresh".to_string(),
+            "A".repeat(40),
+            Utc::now(),
+            "192.0.2.1".parse().unwrap(),
+            9001,
+        );
+        let seen = |hours_ago: f64| HashMap::from([("A".repeat(40), now - hours_ago * 3600.0)]);
+
+        // A relay first seen an hour ago is too new, however fresh its entry
+        assert!(!MinAgeRestriction::new(24, seen(1.0), now).r_is_ok(&router));
+        assert!(MinAgeRestriction::new(24, seen(25.0), now).r_is_ok(&router));
+        assert!(MinAgeRestriction::new(0, seen(1.0), now).r_is_ok(&router));
+
+        // Never seen counts as too new
+        assert!(!MinAgeRestriction::new(24, HashMap::new(), now).r_is_ok(&router));
+    }
+
+    #[test]
+    fn test_as_restriction() {
+        use std::io::Write;
+        use stem_rs::descriptor::router_status::RouterStatusEntryType;
+
+        let router = |fp: &str| {
+            RouterStatusEntry::new(
+                RouterStatusEntryType::V3,
+                "relay".to_string(),
+                fp.to_string(),
+                Utc::now(),
+                "192.0.2.1".parse().unwrap(),
+                9001,
+            )
+        };
+        let (a, b, c) = ("A".repeat(40), "B".repeat(40), "C".repeat(40));
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "# relay ASN").unwrap();
+        writeln!(file, "{} AS64496", a.to_lowercase()).unwrap();
+        writeln!(file, "{} 64496  # same AS as A", b).unwrap();
+        writeln!(file).unwrap();
+        let mut restriction = AsRestriction::from_file(file.path()).unwrap();
+        assert_eq!(restriction.len(), 2);
+        assert!(restriction.r_is_ok(&router(&a)));
+        assert!(restriction.r_is_ok(&router(&b)));
+
+        // Once A is chosen, B shares its AS; C is not in the map at all
+        restriction.select(&a);
+        assert!(!restriction.r_is_ok(&router(&b)));
+        assert!(restriction.r_is_ok(&router(&c)));
+
+        writeln!(file, "{} AS-one", c).unwrap();
+        let err = AsRestriction::from_file(file.path()).unwrap_err();
+        assert!(err.to_string().contains(":5:"), "{}", err);
+    }
+
     #[test]
     fn test_node_restriction_list() {
         use chrono::Utc;
@@ -713,4 +1269,224 @@ mod tests {
         router.flags = vec!["Fast".to_string()];
         assert!(!list.r_is_ok(&router));
     }
+
+    #[test]
+    fn test_selection_probabilities_with_exclusion() {
+        use chrono::Utc;
+        use stem_rs::descriptor::router_status::RouterStatusEntryType;
+
+        struct ExcludeFingerprint(String);
+
+        impl NodeRestriction for ExcludeFingerprint {
+            fn r_is_ok(&self, router: &RouterStatusEntry) -> bool {
+                router.fingerprint != self.0
+            }
+        }
+
+        let routers: Vec<RouterStatusEntry> = [("A", 500), ("B", 300), ("C", 200)]
+            .iter()
+            .map(|(c, bw)| {
+                let mut router = RouterStatusEntry::new(
+                    RouterStatusEntryType::V3,
+                    format!("relay{}", c),
+                    c.repeat(40),
+                    Utc::now(),
+                    "192.0.2.1".parse().unwrap(),
+                    9001,
+                );
+                router.flags = vec!["Fast".to_string(), "Valid".to_string()];
+                router.measured = Some(*bw);
+                router
+            })
+            .collect();
+
+        let unrestricted = BwWeightedGenerator::new(
+            routers.clone(),
+            NodeRestrictionList::new(vec![]),
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap();
+        let probs: HashMap<String, f64> =
+            unrestricted.selection_probabilities().into_iter().collect();
+        assert_eq!(probs.len(), 3);
+        assert!((probs[&"A".repeat(40)] - 0.5).abs() < 1e-9);
+        assert!((probs[&"B".repeat(40)] - 0.3).abs() < 1e-9);
+        assert!((probs[&"C".repeat(40)] - 0.2).abs() < 1e-9);
+
+        let restricted = BwWeightedGenerator::new(
+            routers,
+            NodeRestrictionList::new(vec![Box::new(ExcludeFingerprint("A".repeat(40)))]),
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap();
+        let probs: HashMap<String, f64> =
+            restricted.selection_probabilities().into_iter().collect();
+        assert_eq!(probs.len(), 2);
+        assert!(!probs.contains_key(&"A".repeat(40)));
+        assert!((probs[&"B".repeat(40)] - 0.6).abs() < 1e-9);
+        assert!((probs[&"C".repeat(40)] - 0.4).abs() < 1e-9);
+        assert!((probs.values().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_min_weight_percentile() {
+        use stem_rs::descriptor::router_status::RouterStatusEntryType;
+
+        // Weights 100..400 of 1000: the lightest two make up the bottom 30%
+        let routers: Vec<RouterStatusEntry> = [("D", 400), ("C", 300), ("B", 200), ("A", 100)]
+            .iter()
+            .map(|(c, bw)| {
+                let mut router = RouterStatusEntry::new(
+                    RouterStatusEntryType::V3,
+                    format!("relay{}", c),
+                    c.repeat(40),
+                    Utc::now(),
+                    "192.0.2.1".parse().unwrap(),
+                    9001,
+                );
+                router.flags = vec!["Fast".to_string(), "Valid".to_string()];
+                router.measured = Some(*bw);
+                router
+            })
+            .collect();
+        let generator = |percentile| {
+            BwWeightedGenerator::with_seed(
+                routers.clone(),
+                NodeRestrictionList::new(vec![]),
+                HashMap::new(),
+                Position::Middle,
+                7,
+            )
+            .unwrap()
+            .with_min_weight_percentile(percentile)
+        };
+
+        assert_eq!(generator(0.0).router_count(), 4);
+
+        let top = generator(0.5);
+        assert_eq!(top.router_count(), 2);
+        assert!((top.weight_total() - 700.0).abs() < 1e-9);
+        for _ in 0..1000 {
+            let fp = &top.generate().unwrap().fingerprint;
+            assert!(*fp == "C".repeat(40) || *fp == "D".repeat(40), "{}", fp);
+        }
+
+        let heaviest = generator(1.0);
+        assert_eq!(heaviest.router_count(), 1);
+        assert_eq!(heaviest.generate().unwrap().fingerprint, "D".repeat(40));
+    }
+
+    #[test]
+    fn test_seeded_generator_is_reproducible() {
+        use chrono::Utc;
+        use stem_rs::descriptor::router_status::RouterStatusEntryType;
+
+        let routers: Vec<RouterStatusEntry> = (0..20u64)
+            .map(|i| {
+                let mut router = RouterStatusEntry::new(
+                    RouterStatusEntryType::V3,
+                    format!("relay{}", i),
+                    format!("{:040X}", i),
+                    Utc::now(),
+                    "192.0.2.1".parse().unwrap(),
+                    9001,
+                );
+                router.flags = vec!["Fast".to_string(), "Valid".to_string()];
+                router.measured = Some(100 + i * 50);
+                router
+            })
+            .collect();
+        let sequence = |seed: u64| -> Vec<String> {
+            let generator = BwWeightedGenerator::with_seed(
+                routers.clone(),
+                NodeRestrictionList::new(vec![]),
+                HashMap::new(),
+                Position::Middle,
+                seed,
+            )
+            .unwrap();
+            (0..50)
+                .map(|_| generator.generate().unwrap().fingerprint.clone())
+                .collect()
+        };
+
+        assert_eq!(sequence(7), sequence(7));
+        assert_ne!(sequence(7), sequence(8));
+    }
+
+    #[test]
+    fn test_min_bandwidth_and_uptime_restrictions() {
+        use chrono::Utc;
+        use stem_rs::descriptor::router_status::RouterStatusEntryType;
+
+        // (fingerprint char, kB/s, Fast)
+        let routers: Vec<RouterStatusEntry> =
+            [("A", 5000, true), ("B", 50, true), ("C", 9000, false)]
+                .iter()
+                .map(|(c, bw, fast)| {
+                    let mut router = RouterStatusEntry::new(
+                        RouterStatusEntryType::V3,
+                        format!("relay{}", c),
+                        c.repeat(40),
+                        Utc::now(),
+                        "192.0.2.1".parse().unwrap(),
+                        9001,
+                    );
+                    router.flags = vec!["Valid".to_string()];
+                    if *fast {
+                        router.flags.push("Fast".to_string());
+                    }
+                    router.measured = Some(*bw);
+                    router
+                })
+                .collect();
+
+        let restrictions = NodeRestrictionList::new(vec![
+            Box::new(FlagsRestriction::new(vec!["Fast".to_string()], vec![])),
+            Box::new(MinBandwidthRestriction::new(1_000_000)),
+        ]);
+        let generator = BwWeightedGenerator::new(
+            routers.clone(),
+            restrictions,
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap();
+        assert_eq!(generator.router_count(), 1);
+        for _ in 0..20 {
+            assert_eq!(generator.generate().unwrap().fingerprint, "A".repeat(40));
+        }
+
+        let mut unmeasured = routers[0].clone();
+        unmeasured.measured = None;
+        unmeasured.bandwidth = None;
+        assert!(!MinBandwidthRestriction::new(1).r_is_ok(&unmeasured));
+        assert!(MinBandwidthRestriction::new(0).r_is_ok(&unmeasured));
+
+        let descriptor = |c: &str, uptime: Option<u64>| {
+            let mut desc = ServerDescriptor::new(
+                format!("relay{}", c),
+                "192.0.2.1".parse().unwrap(),
+                9001,
+                Utc::now(),
+                String::new(),
+            );
+            desc.fingerprint = Some(c.repeat(40));
+            desc.uptime = uptime;
+            desc
+        };
+        let uptime = MinUptimeRestriction::new(
+            86400,
+            &[descriptor("A", Some(90000)), descriptor("B", Some(60))],
+        );
+        let passing: Vec<bool> = routers.iter().map(|r| uptime.r_is_ok(r)).collect();
+        // C has no descriptor, so it passes
+        assert_eq!(passing, [true, false, true]);
+
+        // Nor does a descriptor without an uptime line reject its relay
+        let uptime = MinUptimeRestriction::new(86400, &[descriptor("B", None)]);
+        assert!(uptime.r_is_ok(&routers[1]));
+    }
 }
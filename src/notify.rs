@@ -0,0 +1,182 @@
+//! systemd readiness and watchdog notifications.
+//!
+//! Under a `Type=notify` unit, systemd considers the service started only
+//! once it sends `READY=1`, and with `WatchdogSec=` set it restarts the
+//! service if `WATCHDOG=1` stops arriving. vanguards-rs sends `READY=1` the
+//! first time it has connected to Tor, applied the vanguards and subscribed
+//! to events, not again after a reconnect, and `WATCHDOG=1` on every pass of
+//! the event loop.
+//!
+//! The event loop wakes at least once a minute even when Tor is quiet, so
+//! `WatchdogSec=` should be comfortably above that, such as `WatchdogSec=3min`.
+//!
+//! This module is only built on Unix, with the `systemd` cargo feature:
+//!
+//! ```text
+//! cargo build --release --features systemd
+//! ```
+//!
+//! # Protocol
+//!
+//! Notifications are datagrams sent to the Unix socket named by the
+//! `NOTIFY_SOCKET` environment variable, as `sd_notify(3)` does. A leading
+//! `@` names a Linux abstract socket. When the variable is unset, as when
+//! not run by systemd, every call is a no-op.
+//!
+//! # What This Module Does NOT Do
+//!
+//! - **Socket activation**: The control and IPC sockets are opened by vanguards-rs
+//! - **Status text or `STOPPING=1`**: Only readiness and watchdog pings are sent
+//!
+//! # See Also
+//!
+//! - [`crate::control::control_loop`] - Sends the notifications
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use crate::config::LogLevel;
+use crate::logger::plog;
+
+/// Reads `NOTIFY_SOCKET` once; systemd does not change it while we run.
+fn notify_socket() -> Option<&'static str> {
+    static SOCKET: OnceLock<Option<String>> = OnceLock::new();
+    SOCKET
+        .get_or_init(|| {
+            std::env::var("NOTIFY_SOCKET")
+                .ok()
+                .filter(|s| !s.is_empty())
+        })
+        .as_deref()
+}
+
+/// Sends `state` to `socket`.
+///
+/// Returns `Ok(false)` without sending anything when `socket` is `None`.
+fn notify_to(socket: Option<&str>, state: &str) -> io::Result<bool> {
+    let Some(socket) = socket else {
+        return Ok(false);
+    };
+    let sock = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sock.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract NOTIFY_SOCKET requires Linux",
+            ))
+        }
+        None => {
+            sock.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Sends `state`, such as `"READY=1"`, to systemd.
+///
+/// # Returns
+///
+/// `Ok(true)` if the notification was sent and `Ok(false)` if
+/// `NOTIFY_SOCKET` is unset.
+///
+/// # Errors
+///
+/// Returns the I/O error if the socket cannot be reached.
+pub fn notify(state: &str) -> io::Result<bool> {
+    notify_to(notify_socket(), state)
+}
+
+/// Whether `READY=1` has reached systemd.
+static READY_SENT: AtomicBool = AtomicBool::new(false);
+
+/// Tells systemd the service is ready, once per process.
+///
+/// Later calls, such as after reconnecting to Tor, send nothing. Failures
+/// are logged rather than returned and leave the next call to try again;
+/// systemd times the start out if none gets through.
+pub fn ready() {
+    ready_once(&READY_SENT, notify_socket());
+}
+
+/// Sends `READY=1` to `socket` unless `sent` says it already went out.
+fn ready_once(sent: &AtomicBool, socket: Option<&str>) {
+    if sent.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if let Err(e) = notify_to(socket, "READY=1") {
+        sent.store(false, Ordering::SeqCst);
+        plog(
+            LogLevel::Warn,
+            &format!("Failed to notify systemd of readiness: {}", e),
+        );
+    }
+}
+
+/// Pings the systemd watchdog.
+pub fn watchdog() {
+    if let Err(e) = notify("WATCHDOG=1") {
+        plog(
+            LogLevel::Debug,
+            &format!("Failed to ping systemd watchdog: {}", e),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_socket_is_noop() {
+        assert!(!notify_to(None, "READY=1").unwrap());
+        if std::env::var_os("NOTIFY_SOCKET").is_none() {
+            assert!(!notify("WATCHDOG=1").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_notify_sends_datagram() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        assert!(notify_to(path.to_str(), "READY=1").unwrap());
+
+        let mut buf = [0u8; 32];
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn test_ready_sent_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let sent = AtomicBool::new(false);
+
+        // Nobody listening yet, so the next call tries again
+        ready_once(&sent, path.to_str());
+        assert!(!sent.load(Ordering::SeqCst));
+
+        let listener = UnixDatagram::bind(&path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        ready_once(&sent, path.to_str());
+        ready_once(&sent, path.to_str());
+
+        let mut buf = [0u8; 32];
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        assert_eq!(
+            listener.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+}
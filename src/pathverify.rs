@@ -11,6 +11,8 @@
 //! - **Layer 2 guards**: Second-hop relay verification
 //! - **Layer 3 guards**: Third-hop relay verification
 //! - **Path lengths**: Expected hop counts for each circuit purpose
+//! - **Path policies**: Optional user constraints on relay flags, countries,
+//!   and networks per hop (see [`PathPolicy`])
 //!
 //! # Path Length Mappings
 //!
@@ -32,6 +34,11 @@
 //! └─────────────────────┴──────────────────┴───────────────────────────┘
 //! ```
 //!
+//! Tor only adds the third vanguard hop when layer 3 is in use, so the full
+//! lengths apply only with layer 3 guards configured. A built circuit with
+//! another length is logged at WARN, and closed if `pathverify_close_on`
+//! lists `bad_length`. Retries that Tor is known to build short are only logged at INFO.
+//!
 //! # Guard Layer Architecture
 //!
 //! ```text
@@ -55,6 +62,15 @@
 //! └─────────────────────────────────────────────────────────────────────┘
 //! ```
 //!
+//! # Vanguard Membership
+//!
+//! The layer sets above are what Tor reports in `HSLayer2Nodes` and
+//! `HSLayer3Nodes`. When vanguards-rs manages the layers, it also hands
+//! pathverify a snapshot of the guards in its own state (see
+//! [`PathVerify::set_vanguard_layers`]). A built HS circuit whose second or
+//! third hop is outside that snapshot means Tor did not honor the layers it
+//! was given, and is logged at ERROR.
+//!
 //! # What This Module Does NOT Do
 //!
 //! - **Guard selection**: Use [`crate::node_selection`] for selecting guards
@@ -67,10 +83,14 @@
 //! - [`crate::control`] - Event handling that calls path verification
 //! - [Python vanguards pathverify](https://github.com/mikeperry-tor/vanguards)
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
 
-use crate::config::LogLevel;
+use ipnetwork::IpNetwork;
+
+use crate::config::{LogLevel, PathPolicy, PathPosition, PathViolationKind};
 use crate::logger::plog;
+use crate::vanguards::VanguardState;
 
 /// Expected path lengths for full vanguards mode.
 pub const ROUTELEN_FOR_PURPOSE: &[(&str, usize)] = &[
@@ -94,6 +114,48 @@ pub const ROUTELEN_FOR_PURPOSE_LITE: &[(&str, usize)] = &[
     ("HS_SERVICE_REND", 4),
 ];
 
+/// A problem with a circuit's path found by [`PathVerify`].
+///
+/// Returned from [`PathVerify::circ_event`] and
+/// [`PathVerify::circ_minor_event`] so the caller can act on it, for example
+/// by closing the circuit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathViolation {
+    /// The second hop is outside layer 2.
+    WrongLayer2 {
+        /// Fingerprint of the second hop.
+        fingerprint: String,
+    },
+    /// The third hop is outside layer 3.
+    WrongLayer3 {
+        /// Fingerprint of the third hop.
+        fingerprint: String,
+    },
+    /// The path does not have the hop count its purpose calls for.
+    BadLength {
+        /// Hop count expected for the purpose.
+        expected: usize,
+        /// Hop count of the path.
+        actual: usize,
+    },
+    /// The first hop is not one of the layer 1 guards.
+    UnexpectedGuard {
+        /// Fingerprint of the first hop.
+        fingerprint: String,
+    },
+}
+
+impl PathViolation {
+    /// Returns the kind of problem, as listed in `pathverify_close_on`.
+    pub fn kind(&self) -> PathViolationKind {
+        match self {
+            Self::WrongLayer2 { .. } | Self::WrongLayer3 { .. } => PathViolationKind::WrongLayer,
+            Self::BadLength { .. } => PathViolationKind::BadLength,
+            Self::UnexpectedGuard { .. } => PathViolationKind::UnexpectedGuard,
+        }
+    }
+}
+
 /// Per-guard usage statistics.
 ///
 /// Tracks how many times a guard has been used and how many connections
@@ -309,6 +371,30 @@ impl Layer1Guards {
     }
 }
 
+/// Consensus details about a relay that [`PathPolicy`] rules are checked against.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PolicyRelay {
+    /// Consensus flags.
+    pub flags: Vec<String>,
+    /// OR address.
+    pub address: Option<IpAddr>,
+    /// Lowercase country code from Tor's GeoIP database, if known.
+    pub country: Option<String>,
+}
+
+/// A hop that broke a [`PathPolicy`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyViolation {
+    /// Index of the offending hop in the circuit path.
+    pub hop: usize,
+    /// Fingerprint of the offending relay.
+    pub fingerprint: String,
+    /// What the relay did wrong, e.g. `has forbidden flag Exit`.
+    pub reason: String,
+    /// Whether the violated policy asks for the circuit to be closed.
+    pub close: bool,
+}
+
 /// Path verification state.
 ///
 /// Verifies that circuits use the configured vanguard relays and have
@@ -380,6 +466,24 @@ pub struct PathVerify {
     pub num_layer2: u8,
     /// Expected number of layer 3 guards.
     pub num_layer3: u8,
+    /// Whether the layers and their sizes come from Tor's own
+    /// `HSLayer2Nodes`/`HSLayer3Nodes` rather than from vanguards-rs.
+    pub follow_tor_layers: bool,
+    /// User path policies checked on each built HS circuit.
+    pub policies: Vec<PathPolicy>,
+    /// Relay details the policies are evaluated against, by fingerprint.
+    pub policy_relays: HashMap<String, PolicyRelay>,
+    /// Layer 2 guards in the vanguard state, empty unless vanguards-rs
+    /// manages the layers.
+    pub vanguard_layer2: HashSet<String>,
+    /// Layer 3 guards in the vanguard state, empty unless vanguards-rs
+    /// manages the layers.
+    pub vanguard_layer3: HashSet<String>,
+    /// Kinds of [`PathViolation`] that close the circuit they are found on.
+    pub close_on: BTreeSet<PathViolationKind>,
+    /// Circuits that broke a closing policy or had a problem listed in
+    /// [`close_on`](Self::close_on), waiting to be closed.
+    pub circs_to_close: Vec<String>,
 }
 
 impl PathVerify {
@@ -393,30 +497,57 @@ impl PathVerify {
             num_layer1,
             num_layer2,
             num_layer3,
+            follow_tor_layers: false,
+            policies: Vec::new(),
+            policy_relays: HashMap::new(),
+            vanguard_layer2: HashSet::new(),
+            vanguard_layer3: HashSet::new(),
+            close_on: BTreeSet::new(),
+            circs_to_close: Vec::new(),
         }
     }
 
+    /// Snapshots the layer 2 and layer 3 guards of `state`.
+    ///
+    /// Call again whenever the guards change, since built circuits are
+    /// checked against the latest snapshot.
+    pub fn set_vanguard_layers(&mut self, state: &VanguardState) {
+        self.vanguard_layer2 = state.layer2.iter().map(|g| g.idhex.clone()).collect();
+        self.vanguard_layer3 = state.layer3.iter().map(|g| g.idhex.clone()).collect();
+    }
+
     /// Initializes layer 2 and layer 3 from configuration values.
     ///
     /// # Arguments
     ///
     /// * `layer2_nodes` - Comma-separated layer 2 fingerprints (or None)
     /// * `layer3_nodes` - Comma-separated layer 3 fingerprints (or None)
+    ///
+    /// When created without full vanguards, any layers found here were set
+    /// by the operator, so their sizes become the expected counts and later
+    /// `CONF_CHANGED` events keep them in step.
     pub fn init_layers(&mut self, layer2_nodes: Option<&str>, layer3_nodes: Option<&str>) {
+        let managed = self.full_vanguards;
+
         if let Some(nodes) = layer2_nodes {
             if !nodes.is_empty() {
-                self.layer2 = nodes.split(',').map(|s| s.trim().to_string()).collect();
+                self.layer2 = parse_layer_nodes(nodes);
                 self.full_vanguards = true;
             }
         }
 
         if let Some(nodes) = layer3_nodes {
             if !nodes.is_empty() {
-                self.layer3 = nodes.split(',').map(|s| s.trim().to_string()).collect();
+                self.layer3 = parse_layer_nodes(nodes);
                 self.full_vanguards = true;
             }
         }
 
+        if !managed && self.full_vanguards {
+            self.follow_tor_layers = true;
+            self.adopt_layer_counts();
+        }
+
         // If layers are empty and vanguards disabled, we're monitoring vg-lite
         if self.layer2.is_empty() && self.layer3.is_empty() && !self.full_vanguards {
             plog(
@@ -471,21 +602,106 @@ impl PathVerify {
     pub fn conf_changed_event(&mut self, changed: &HashMap<String, Vec<String>>) {
         if let Some(values) = changed.get("HSLayer2Nodes") {
             if let Some(first) = values.first() {
-                self.layer2 = first.split(',').map(|s| s.trim().to_string()).collect();
+                self.layer2 = parse_layer_nodes(first);
                 self.full_vanguards = true;
             }
         }
 
         if let Some(values) = changed.get("HSLayer3Nodes") {
             if let Some(first) = values.first() {
-                self.layer3 = first.split(',').map(|s| s.trim().to_string()).collect();
+                self.layer3 = parse_layer_nodes(first);
                 self.full_vanguards = true;
             }
         }
 
+        if self.follow_tor_layers {
+            self.adopt_layer_counts();
+        }
         self.check_layer_counts();
     }
 
+    /// Expects exactly as many layer2/layer3 guards as Tor is configured with.
+    fn adopt_layer_counts(&mut self) {
+        self.num_layer2 = u8::try_from(self.layer2.len()).unwrap_or(u8::MAX);
+        self.num_layer3 = u8::try_from(self.layer3.len()).unwrap_or(u8::MAX);
+    }
+
+    /// Describes each middle hop of `path` outside its expected layer.
+    ///
+    /// Hop 2 must be in [`layer2`](Self::layer2) and, when layer3 guards are
+    /// expected, hop 3 in [`layer3`](Self::layer3).
+    pub fn layer_violations(&self, path: &[(String, Option<String>)]) -> Vec<String> {
+        self.check_layers(path)
+            .into_iter()
+            .map(|(_, m)| m)
+            .collect()
+    }
+
+    fn check_layers(&self, path: &[(String, Option<String>)]) -> Vec<(PathViolation, String)> {
+        let mut found = Vec::new();
+        if let Some((fp, _)) = path.get(1).filter(|(fp, _)| !self.layer2.contains(fp)) {
+            found.push((
+                PathViolation::WrongLayer2 {
+                    fingerprint: fp.clone(),
+                },
+                format!("Layer2 {} not in {:?}", fp, self.layer2),
+            ));
+        }
+        if self.num_layer3 > 0 {
+            if let Some((fp, _)) = path.get(2).filter(|(fp, _)| !self.layer3.contains(fp)) {
+                found.push((
+                    PathViolation::WrongLayer3 {
+                        fingerprint: fp.clone(),
+                    },
+                    format!("Layer3 {} not in {:?}", fp, self.layer3),
+                ));
+            }
+        }
+        found
+    }
+
+    /// Describes each middle hop of `path` outside the vanguard state's layers.
+    ///
+    /// Unlike [`layer_violations`](Self::layer_violations), this checks
+    /// against [`vanguard_layer2`](Self::vanguard_layer2) and
+    /// [`vanguard_layer3`](Self::vanguard_layer3); an empty snapshot is
+    /// not checked.
+    pub fn vanguard_violations(&self, path: &[(String, Option<String>)]) -> Vec<String> {
+        self.check_vanguard_layers(path)
+            .into_iter()
+            .map(|(_, m)| m)
+            .collect()
+    }
+
+    fn check_vanguard_layers(
+        &self,
+        path: &[(String, Option<String>)],
+    ) -> Vec<(PathViolation, String)> {
+        let layers = [(&self.vanguard_layer2, 2), (&self.vanguard_layer3, 3)];
+        layers
+            .into_iter()
+            .filter(|(layer, _)| !layer.is_empty())
+            .filter_map(|(layer, hop)| {
+                let (fp, _) = path.get(hop - 1)?;
+                if layer.contains(fp) {
+                    return None;
+                }
+                let fingerprint = fp.clone();
+                let violation = if hop == 2 {
+                    PathViolation::WrongLayer2 { fingerprint }
+                } else {
+                    PathViolation::WrongLayer3 { fingerprint }
+                };
+                let message = format!(
+                    "hop {} {} is not one of our layer{} vanguards; \
+                     Tor is not honoring HSLayer{}Nodes",
+                    hop, fp, hop, hop
+                );
+                Some((violation, message))
+            })
+            .collect()
+    }
+
     /// Handles an ORCONN event.
     ///
     /// Tracks guard connection state changes.
@@ -519,8 +735,12 @@ impl PathVerify {
     }
 
     /// Returns the expected path length for a circuit purpose.
+    ///
+    /// Uses [`ROUTELEN_FOR_PURPOSE`] when layer 3 guards are configured and
+    /// [`ROUTELEN_FOR_PURPOSE_LITE`] otherwise.
     pub fn routelen_for_purpose(&self, purpose: &str) -> Option<usize> {
-        let table = if self.full_vanguards {
+        let has_layer3 = self.num_layer3 > 0 || !self.layer3.is_empty();
+        let table = if self.full_vanguards && has_layer3 {
             ROUTELEN_FOR_PURPOSE
         } else {
             ROUTELEN_FOR_PURPOSE_LITE
@@ -532,23 +752,136 @@ impl PathVerify {
             .map(|(_, len)| *len)
     }
 
+    /// Checks a circuit path against the configured [`PathPolicy`] list.
+    ///
+    /// Hops whose relay is missing from [`policy_relays`](Self::policy_relays)
+    /// are skipped, since there is nothing to check them against.
+    ///
+    /// # Returns
+    ///
+    /// One [`PolicyViolation`] per broken rule, in path order.
+    pub fn policy_violations(&self, path: &[(String, Option<String>)]) -> Vec<PolicyViolation> {
+        let mut violations = Vec::new();
+
+        for policy in &self.policies {
+            for (hop, (fp, _)) in path.iter().enumerate() {
+                let applies = match policy.position {
+                    PathPosition::Guard => hop == 0,
+                    PathPosition::Middle => hop > 0 && hop + 1 < path.len(),
+                    PathPosition::Last => hop + 1 == path.len(),
+                    PathPosition::Any => true,
+                };
+                let Some(relay) = self.policy_relays.get(fp).filter(|_| applies) else {
+                    continue;
+                };
+
+                let mut violation = |reason: String| {
+                    violations.push(PolicyViolation {
+                        hop,
+                        fingerprint: fp.clone(),
+                        reason,
+                        close: policy.close_circuits,
+                    })
+                };
+
+                for flag in &policy.require_flags {
+                    if !relay.flags.contains(flag) {
+                        violation(format!("lacks required flag {}", flag));
+                    }
+                }
+                for flag in &policy.forbid_flags {
+                    if relay.flags.contains(flag) {
+                        violation(format!("has forbidden flag {}", flag));
+                    }
+                }
+
+                let in_country = |codes: &[String]| {
+                    relay
+                        .country
+                        .as_ref()
+                        .is_some_and(|cc| codes.iter().any(|c| c.eq_ignore_ascii_case(cc)))
+                };
+                if !policy.require_countries.is_empty() && !in_country(&policy.require_countries) {
+                    violation(format!(
+                        "is in country {} rather than {:?}",
+                        relay.country.as_deref().unwrap_or("??"),
+                        policy.require_countries
+                    ));
+                }
+                if in_country(&policy.forbid_countries) {
+                    violation(format!(
+                        "is in forbidden country {}",
+                        relay.country.as_deref().unwrap_or("??")
+                    ));
+                }
+
+                let in_network = |networks: &[String]| {
+                    relay.address.is_some_and(|addr| {
+                        networks
+                            .iter()
+                            .filter_map(|n| n.parse::<IpNetwork>().ok())
+                            .any(|n| n.contains(addr))
+                    })
+                };
+                if !policy.require_networks.is_empty() && !in_network(&policy.require_networks) {
+                    violation(format!("has address outside {:?}", policy.require_networks));
+                }
+                if in_network(&policy.forbid_networks) {
+                    violation("has address in a forbidden network".to_string());
+                }
+            }
+        }
+
+        violations
+    }
+
+    /// Returns and clears the circuits queued for closing.
+    pub fn take_circs_to_close(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.circs_to_close)
+    }
+
+    /// Queues `circ_id` in [`circs_to_close`](Self::circs_to_close), once.
+    fn queue_close(&mut self, circ_id: &str) {
+        if !self.circs_to_close.iter().any(|c| c == circ_id) {
+            self.circs_to_close.push(circ_id.to_string());
+        }
+    }
+
+    /// Queues `circ_id` for closing if any of `found` is in
+    /// [`close_on`](Self::close_on).
+    fn close_on_violations(&mut self, circ_id: &str, found: &[PathViolation]) {
+        if found.iter().any(|v| self.close_on.contains(&v.kind())) {
+            self.queue_close(circ_id);
+        }
+    }
+
     /// Handles a CIRC event.
     ///
-    /// Verifies circuit paths when circuits are built.
+    /// Verifies circuit paths when circuits are built. Unexpected path
+    /// lengths and path policy violations are logged. Circuits breaking a
+    /// policy with `close_circuits` set, or with a problem listed in
+    /// [`close_on`](Self::close_on), are queued in
+    /// [`circs_to_close`](Self::circs_to_close).
+    ///
+    /// # Returns
+    ///
+    /// The wrong-length and wrong-layer problems found, at most one of each
+    /// kind. Path policy violations are not included.
     pub fn circ_event(
         &mut self,
-        _circ_id: &str,
+        circ_id: &str,
         status: &str,
         purpose: &str,
         hs_state: Option<&str>,
         path: &[(String, Option<String>)],
-    ) {
+    ) -> Vec<PathViolation> {
+        let mut found = Vec::new();
         if !purpose.starts_with("HS_") {
-            return;
+            return found;
         }
 
         if status != "BUILT" && status != "GUARD_WAIT" {
-            return;
+            return found;
         }
 
         // Check path length
@@ -562,19 +895,27 @@ impl PathVerify {
                 let level = if is_expected {
                     LogLevel::Info
                 } else {
-                    LogLevel::Notice
+                    LogLevel::Warn
                 };
 
                 plog(
                     level,
                     &format!(
-                        "Tor made a {}-hop path, but I wanted a {}-hop path for purpose {}:{:?}",
+                        "Tor made a {}-hop path on circuit {}, but I wanted a {}-hop path \
+                         for purpose {}:{:?}",
                         path.len(),
+                        circ_id,
                         expected_len,
                         purpose,
                         hs_state
                     ),
                 );
+                if !is_expected {
+                    found.push(PathViolation::BadLength {
+                        expected: expected_len,
+                        actual: path.len(),
+                    });
+                }
             }
         }
 
@@ -585,20 +926,10 @@ impl PathVerify {
             self.layer1.check_use_counts();
         }
 
-        // Check layer 2 guard
-        if path.len() > 1 && !self.layer2.contains(&path[1].0) {
-            plog(
-                LogLevel::Warn,
-                &format!("Layer2 {} not in {:?}", path[1].0, self.layer2),
-            );
-        }
-
-        // Check layer 3 guard
-        if self.num_layer3 > 0 && path.len() > 2 && !self.layer3.contains(&path[2].0) {
-            plog(
-                LogLevel::Warn,
-                &format!("Layer3 {} not in {:?}", path[2].0, self.layer3),
-            );
+        // Check layer 2 and layer 3 guards
+        for (violation, message) in self.check_layers(path) {
+            plog(LogLevel::Warn, &message);
+            push_unique(&mut found, violation);
         }
 
         // Check layer counts
@@ -623,18 +954,49 @@ impl PathVerify {
                 ),
             );
         }
+
+        // Check the hops against our own guards and user path policies once,
+        // when the circuit is built
+        if status == "BUILT" {
+            for (violation, message) in self.check_vanguard_layers(path) {
+                plog(LogLevel::Error, &format!("Circuit {} {}", circ_id, message));
+                push_unique(&mut found, violation);
+            }
+
+            let violations = self.policy_violations(path);
+            for v in &violations {
+                plog(
+                    LogLevel::Warn,
+                    &format!(
+                        "Circuit {} violates path policy: hop {} ({}) {}",
+                        circ_id, v.hop, v.fingerprint, v.reason
+                    ),
+                );
+            }
+            if violations.iter().any(|v| v.close) {
+                self.queue_close(circ_id);
+            }
+        }
+        self.close_on_violations(circ_id, &found);
+        found
     }
 
     /// Handles a CIRC_MINOR event (purpose changes).
     ///
-    /// Warns on suspicious purpose changes.
+    /// Warns on suspicious purpose changes, and queues HS circuits with a
+    /// problem listed in [`close_on`](Self::close_on) for closing.
+    ///
+    /// # Returns
+    ///
+    /// The unexpected-guard and wrong-layer problems found on an HS circuit.
     pub fn circ_minor_event(
         &mut self,
-        _circ_id: &str,
+        circ_id: &str,
         purpose: &str,
         old_purpose: Option<&str>,
         path: &[(String, Option<String>)],
-    ) {
+    ) -> Vec<PathViolation> {
+        let mut found = Vec::new();
         let is_hs = purpose.starts_with("HS_");
         let was_hs = old_purpose.map(|p| p.starts_with("HS_")).unwrap_or(false);
 
@@ -674,25 +1036,42 @@ impl PathVerify {
                         self.layer1.guards.keys().collect::<Vec<_>>()
                     ),
                 );
+                found.push(PathViolation::UnexpectedGuard {
+                    fingerprint: path[0].0.clone(),
+                });
             }
 
-            if path.len() > 1 && !self.layer2.contains(&path[1].0) {
-                plog(
-                    LogLevel::Warn,
-                    &format!("Layer2 {} not in {:?}", path[1].0, self.layer2),
-                );
-            }
-
-            if self.num_layer3 > 0 && path.len() > 2 && !self.layer3.contains(&path[2].0) {
-                plog(
-                    LogLevel::Warn,
-                    &format!("Layer3 {} not in {:?}", path[2].0, self.layer3),
-                );
+            for (violation, message) in self.check_layers(path) {
+                plog(LogLevel::Warn, &message);
+                push_unique(&mut found, violation);
             }
         }
+        self.close_on_violations(circ_id, &found);
+        found
     }
 }
 
+fn push_unique(found: &mut Vec<PathViolation>, violation: PathViolation) {
+    if !found.contains(&violation) {
+        found.push(violation);
+    }
+}
+
+/// Parses an `HSLayer2Nodes`/`HSLayer3Nodes` value into bare fingerprints.
+///
+/// Tor echoes the option as written, so entries may carry a `$` prefix or a
+/// `~nickname`/`=nickname` suffix.
+fn parse_layer_nodes(nodes: &str) -> HashSet<String> {
+    nodes
+        .split(',')
+        .map(|s| {
+            let s = s.trim().trim_start_matches('$');
+            s.split(['~', '=']).next().unwrap_or(s).to_uppercase()
+        })
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -752,6 +1131,46 @@ mod tests {
         assert_eq!(guards.guards.get(&fp).unwrap().use_count, 2);
     }
 
+    #[test]
+    fn test_policy_forbidding_flag_in_middle() {
+        let mut pv = PathVerify::new(true, 2, 4, 8);
+        pv.policies.push(PathPolicy {
+            position: PathPosition::Middle,
+            forbid_flags: vec!["Exit".to_string()],
+            close_circuits: true,
+            ..Default::default()
+        });
+
+        let relay = |flags: &[&str]| PolicyRelay {
+            flags: flags.iter().map(|f| f.to_string()).collect(),
+            ..Default::default()
+        };
+        let fps: Vec<String> = ["A", "B", "C", "D"].iter().map(|c| c.repeat(40)).collect();
+        // The guard and last hop have Exit too, but the policy only covers the middle.
+        pv.policy_relays
+            .insert(fps[0].clone(), relay(&["Guard", "Exit"]));
+        pv.policy_relays.insert(fps[1].clone(), relay(&["Fast"]));
+        pv.policy_relays
+            .insert(fps[2].clone(), relay(&["Fast", "Exit"]));
+        pv.policy_relays.insert(fps[3].clone(), relay(&["Exit"]));
+        let path: Vec<(String, Option<String>)> = fps.iter().map(|fp| (fp.clone(), None)).collect();
+
+        let violations = pv.policy_violations(&path);
+        assert_eq!(
+            violations,
+            vec![PolicyViolation {
+                hop: 2,
+                fingerprint: fps[2].clone(),
+                reason: "has forbidden flag Exit".to_string(),
+                close: true,
+            }]
+        );
+
+        pv.circ_event("42", "BUILT", "HS_VANGUARDS", None, &path);
+        assert_eq!(pv.take_circs_to_close(), vec!["42".to_string()]);
+        assert!(pv.circs_to_close.is_empty());
+    }
+
     #[test]
     fn test_path_verify_new() {
         let verifier = PathVerify::new(true, 2, 4, 8);
@@ -806,6 +1225,166 @@ mod tests {
         assert_eq!(verifier.routelen_for_purpose("HS_SERVICE_REND"), Some(4));
     }
 
+    #[test]
+    fn test_routelen_without_layer3() {
+        let mut verifier = PathVerify::new(false, 1, 4, 0);
+        verifier.init_layers(Some(&"A".repeat(40)), None);
+
+        assert!(verifier.full_vanguards);
+        assert_eq!(verifier.routelen_for_purpose("HS_SERVICE_REND"), Some(4));
+    }
+
+    fn hops(n: usize) -> Vec<(String, Option<String>)> {
+        (0..n)
+            .map(|i| (format!("{:X}", i).repeat(40)[..40].to_string(), None))
+            .collect()
+    }
+
+    #[test]
+    fn test_service_rend_path_length_ok() {
+        let mut verifier = PathVerify::new(true, 2, 4, 8);
+        verifier.close_on.insert(PathViolationKind::BadLength);
+
+        verifier.circ_event("7", "BUILT", "HS_SERVICE_REND", None, &hops(5));
+
+        assert!(verifier.take_circs_to_close().is_empty());
+    }
+
+    #[test]
+    fn test_short_path_closed() {
+        let mut verifier = PathVerify::new(true, 2, 4, 8);
+        verifier.circ_event("7", "BUILT", "HS_SERVICE_REND", None, &hops(3));
+        assert!(verifier.take_circs_to_close().is_empty());
+
+        verifier.close_on.insert(PathViolationKind::BadLength);
+        verifier.circ_event("8", "GUARD_WAIT", "HS_SERVICE_REND", None, &hops(3));
+        verifier.circ_event("8", "BUILT", "HS_SERVICE_REND", None, &hops(3));
+        assert_eq!(verifier.take_circs_to_close(), vec!["8".to_string()]);
+
+        // Tor builds these short on purpose when retrying
+        verifier.circ_event(
+            "9",
+            "BUILT",
+            "HS_CLIENT_INTRO",
+            Some("HSCI_CONNECTING"),
+            &hops(3),
+        );
+        assert!(verifier.take_circs_to_close().is_empty());
+    }
+
+    fn vanguard_state(layer2: &str, layer3: &str) -> VanguardState {
+        use crate::vanguards::GuardNode;
+
+        let mut state = VanguardState::new("/tmp/unused.state");
+        state.layer2 = vec![GuardNode::new(layer2.to_string(), 0.0, 0.0)];
+        state.layer3 = vec![GuardNode::new(layer3.to_string(), 0.0, 0.0)];
+        state
+    }
+
+    #[test]
+    fn test_vanguard_violations_in_set() {
+        let mut verifier = PathVerify::new(true, 2, 4, 8);
+        let path = hops(5);
+        verifier.set_vanguard_layers(&vanguard_state(&path[1].0, &path[2].0));
+
+        assert!(verifier.vanguard_violations(&path).is_empty());
+    }
+
+    #[test]
+    fn test_vanguard_violations_out_of_set() {
+        let mut verifier = PathVerify::new(true, 2, 4, 8);
+        let path = hops(5);
+        verifier.set_vanguard_layers(&vanguard_state(&"F".repeat(40), &path[2].0));
+
+        let violations = verifier.vanguard_violations(&path);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].starts_with(&format!("hop 2 {} ", path[1].0)));
+        assert!(violations[0].contains("HSLayer2Nodes"));
+
+        // Nothing to check against when vanguards-rs does not manage layers
+        verifier.set_vanguard_layers(&VanguardState::new("/tmp/unused.state"));
+        assert!(verifier.vanguard_violations(&path).is_empty());
+    }
+
+    #[test]
+    fn test_circ_event_violations() {
+        let mut verifier = PathVerify::new(true, 2, 4, 8);
+        let path = hops(5);
+        verifier.layer2.insert(path[1].0.clone());
+        verifier.layer3.insert(path[2].0.clone());
+
+        let found = verifier.circ_event("1", "BUILT", "HS_SERVICE_REND", None, &path);
+        assert!(found.is_empty());
+
+        let found = verifier.circ_event("2", "BUILT", "HS_SERVICE_REND", None, &path[..4]);
+        assert_eq!(
+            found,
+            vec![PathViolation::BadLength {
+                expected: 5,
+                actual: 4
+            }]
+        );
+
+        verifier.layer2.clear();
+        let found = verifier.circ_event("3", "BUILT", "HS_SERVICE_REND", None, &path);
+        assert_eq!(
+            found,
+            vec![PathViolation::WrongLayer2 {
+                fingerprint: path[1].0.clone()
+            }]
+        );
+
+        // Out of both Tor's layer and ours, reported once
+        verifier.layer2.insert(path[1].0.clone());
+        verifier.layer3.clear();
+        verifier.set_vanguard_layers(&vanguard_state(&path[1].0, &"F".repeat(40)));
+        let found = verifier.circ_event("4", "BUILT", "HS_SERVICE_REND", None, &path);
+        assert_eq!(
+            found,
+            vec![PathViolation::WrongLayer3 {
+                fingerprint: path[2].0.clone()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_circ_minor_event_unexpected_guard() {
+        let mut verifier = PathVerify::new(true, 2, 4, 8);
+        let path = hops(4);
+        verifier.layer2.insert(path[1].0.clone());
+        verifier.layer3.insert(path[2].0.clone());
+
+        let found = verifier.circ_minor_event("1", "HS_CLIENT_REND", Some("GENERAL"), &path);
+        assert_eq!(
+            found,
+            vec![PathViolation::UnexpectedGuard {
+                fingerprint: path[0].0.clone()
+            }]
+        );
+
+        verifier.layer1.add_conn(&path[0].0);
+        let found = verifier.circ_minor_event("1", "HS_CLIENT_REND", Some("GENERAL"), &path);
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn test_close_on_selected_kinds() {
+        let mut verifier = PathVerify::new(true, 2, 4, 8);
+        let path = hops(4);
+        verifier.layer3.insert(path[2].0.clone());
+        verifier.close_on.insert(PathViolationKind::WrongLayer);
+
+        // Unexpected guards are only logged
+        verifier.layer2.insert(path[1].0.clone());
+        verifier.circ_minor_event("1", "HS_CLIENT_REND", Some("GENERAL"), &path);
+        assert!(verifier.take_circs_to_close().is_empty());
+
+        verifier.layer2.clear();
+        verifier.circ_minor_event("2", "HS_CLIENT_REND", Some("GENERAL"), &path);
+        verifier.circ_event("2", "BUILT", "HS_CLIENT_REND", None, &path);
+        assert_eq!(verifier.take_circs_to_close(), vec!["2".to_string()]);
+    }
+
     #[test]
     fn test_orconn_event() {
         let mut verifier = PathVerify::new(true, 2, 4, 8);
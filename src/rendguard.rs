@@ -120,6 +120,7 @@
 //! | `use_max_use_to_bw_ratio` | 5.0 | Maximum ratio of use to bandwidth |
 //! | `use_scale_at_count` | 20000 | Scale counts when reaching this total |
 //! | `use_max_consensus_weight_churn` | 1.0 | Weight for NOT_IN_CONSENSUS relays |
+//! | `max_not_in_consensus_entries` | 1000 | Distinct non-consensus RPs remembered individually |
 //!
 //! # Example
 //!
@@ -147,6 +148,8 @@
 //! - Start counts prevent false positives during initial operation
 //! - Scaling prevents long-running relays from accumulating unfair counts
 //! - NOT_IN_CONSENSUS tracking catches relays that leave the network
+//! - Individual non-consensus RPs are kept in a bounded LRU, so a flood of
+//!   made-up fingerprints cannot grow memory without limit
 //! - Weight churn allowance handles consensus changes gracefully
 //!
 //! # See Also
@@ -157,7 +160,7 @@
 //! - [Python vanguards rendguard](https://github.com/mikeperry-tor/vanguards) - Original implementation
 
 // Re-export types from vanguards module
-pub use crate::vanguards::{RendGuard, RendUseCount};
+pub use crate::vanguards::{NotInConsensusUses, RendGuard, RendUseCount};
 
 /// Identifier used for relays not in the current consensus.
 ///
@@ -250,6 +253,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_not_in_consensus_cap_evicts_least_recent() {
+        let mut rg = RendGuard::new();
+        let config = RendguardConfig {
+            max_not_in_consensus_entries: 3,
+            ..Default::default()
+        };
+        let fps: Vec<String> = ["A", "B", "C", "D"].iter().map(|c| c.repeat(40)).collect();
+
+        rg.valid_rend_use(&fps[0], &config);
+        rg.valid_rend_use(&fps[1], &config);
+        rg.valid_rend_use(&fps[2], &config);
+        // Touch A so B becomes the least recently used.
+        rg.valid_rend_use(&fps[0], &config);
+        rg.valid_rend_use(&fps[3], &config);
+
+        assert_eq!(rg.not_in_consensus.len(), 3);
+        assert_eq!(rg.not_in_consensus.uses(&fps[0]), Some(2.0));
+        assert_eq!(rg.not_in_consensus.uses(&fps[1]), None);
+        assert_eq!(rg.not_in_consensus.uses(&fps[2]), Some(1.0));
+        assert_eq!(rg.not_in_consensus.uses(&fps[3]), Some(1.0));
+
+        // The aggregate entry still sees every use.
+        assert_eq!(rg.use_counts.get(NOT_IN_CONSENSUS_ID).unwrap().used, 5.0);
+
+        // Eviction order survives a save and load: C is now the oldest
+        let json = serde_json::to_string(&rg.not_in_consensus).unwrap();
+        rg.not_in_consensus = serde_json::from_str(&json).unwrap();
+        rg.valid_rend_use(&"E".repeat(40), &config);
+        assert_eq!(rg.not_in_consensus.uses(&fps[2]), None);
+        assert_eq!(rg.not_in_consensus.uses(&fps[0]), Some(2.0));
+        assert_eq!(rg.not_in_consensus.len(), 3);
+    }
+
     #[test]
     fn test_overuse_detection() {
         let mut rg = RendGuard::new();
@@ -0,0 +1,303 @@
+//! Detection export in CEF or LEEF for SIEM ingestion.
+//!
+//! Security teams that collect alerts in a SIEM usually ingest ArcSight
+//! Common Event Format (CEF) or IBM QRadar's Log Event Extended Format
+//! (LEEF) rather than JSON. With `siem_format` set, each bandguards
+//! detection is appended to `siem_output` as one line in that format, ready
+//! for a log shipper to forward.
+//!
+//! # Record Layout
+//!
+//! CEF lines use the standard seven-field header followed by key-value
+//! extensions:
+//!
+//! ```text
+//! CEF:0|tn3w|vanguards-rs|1.0.1|dropped_cells|Dropped cells on circuit|8|rt=1760702400000 cat=detection cs1Label=circuitId cs1=42 cs2Label=attackKind cs2=dropped_cells cs3Label=guardFingerprint cs3=AAAA...
+//! ```
+//!
+//! LEEF lines use the LEEF 1.0 header and tab-separated attributes with the
+//! same meaning: `devTime`, `cat`, `sev`, `circuitId`, `attackKind`,
+//! `guardFingerprint` and, for attributed circuits, `service`.
+//!
+//! Detections injected with the IPC `simulate` command are exported too, with
+//! `cat=synthetic`, so the SIEM side of an alerting pipeline can be tested.
+//!
+//! # What This Module Does NOT Do
+//!
+//! - **Transport**: Lines are written to a file; use syslog or a log shipper to forward them
+//! - **Other events**: Only bandguards detections are exported
+//!
+//! # See Also
+//!
+//! - [`crate::config::SiemFormat`] - Format selection
+//! - [`crate::bandguards::ATTACK_KINDS`] - Attack names used as event IDs
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use chrono::{TimeZone, Utc};
+
+use crate::config::{LogLevel, SiemFormat};
+use crate::error::Result;
+use crate::logger::plog;
+
+/// Vendor name in CEF and LEEF headers.
+const VENDOR: &str = "tn3w";
+
+/// Product name in CEF and LEEF headers.
+const PRODUCT: &str = "vanguards-rs";
+
+/// One bandguards detection to export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection<'a> {
+    /// Circuit the attack was detected on.
+    pub circ_id: &'a str,
+    /// Attack name from [`ATTACK_KINDS`](crate::bandguards::ATTACK_KINDS).
+    pub kind: &'a str,
+    /// Fingerprint of the circuit's guard, if known.
+    pub guard_fp: Option<&'a str>,
+    /// Service the circuit was attributed to, if any.
+    pub service: Option<&'a str>,
+    /// Unix timestamp of the detection.
+    pub at: f64,
+    /// Whether the detection was injected with `simulate`.
+    pub synthetic: bool,
+}
+
+impl Detection<'_> {
+    /// Human-readable event name for the header.
+    fn name(&self) -> &'static str {
+        match self.kind {
+            "dropped_cells" => "Dropped cells on circuit",
+            "dropped_sent_cells" => "Dropped sent cells on circuit",
+            "max_bytes" => "Circuit exceeded byte limit",
+            "hsdir_bytes" => "HSDIR circuit exceeded byte limit",
+            "serv_intro_bytes" => "Intro circuit exceeded byte limit",
+            "bytes_per_sec" => "Circuit exceeded byte rate limit",
+            _ => "Bandguards detection",
+        }
+    }
+
+    /// Severity on the 0-10 scale both formats use. Dropped cells are a
+    /// direct sign of a guard discovery attempt; byte limits may be benign.
+    fn severity(&self) -> u8 {
+        if matches!(self.kind, "dropped_cells" | "dropped_sent_cells") {
+            8
+        } else {
+            5
+        }
+    }
+
+    fn category(&self) -> &'static str {
+        if self.synthetic {
+            "synthetic"
+        } else {
+            "detection"
+        }
+    }
+
+    fn millis(&self) -> i64 {
+        (self.at * 1000.0) as i64
+    }
+}
+
+impl SiemFormat {
+    /// Renders `detection` as one line, without the trailing newline.
+    ///
+    /// Returns `None` for [`SiemFormat::None`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::config::SiemFormat;
+    /// use vanguards_rs::siem::Detection;
+    ///
+    /// let detection = Detection {
+    ///     circ_id: "42",
+    ///     kind: "max_bytes",
+    ///     guard_fp: None,
+    ///     service: None,
+    ///     at: 1760702400.0,
+    ///     synthetic: false,
+    /// };
+    /// let line = SiemFormat::Cef.render(&detection).unwrap();
+    /// assert!(line.starts_with("CEF:0|tn3w|vanguards-rs|"));
+    /// assert_eq!(SiemFormat::None.render(&detection), None);
+    /// ```
+    pub fn render(&self, detection: &Detection<'_>) -> Option<String> {
+        match self {
+            SiemFormat::None => None,
+            SiemFormat::Cef => Some(render_cef(detection)),
+            SiemFormat::Leef => Some(render_leef(detection)),
+        }
+    }
+}
+
+fn render_cef(d: &Detection<'_>) -> String {
+    let mut line = format!(
+        "CEF:0|{}|{}|{}|{}|{}|{}|rt={} cat={} cs1Label=circuitId cs1={} cs2Label=attackKind cs2={}",
+        cef_header(VENDOR),
+        cef_header(PRODUCT),
+        cef_header(env!("CARGO_PKG_VERSION")),
+        cef_header(d.kind),
+        cef_header(d.name()),
+        d.severity(),
+        d.millis(),
+        d.category(),
+        cef_value(d.circ_id),
+        cef_value(d.kind),
+    );
+    if let Some(fp) = d.guard_fp {
+        line.push_str(&format!(" cs3Label=guardFingerprint cs3={}", cef_value(fp)));
+    }
+    if let Some(service) = d.service {
+        line.push_str(&format!(" cs4Label=service cs4={}", cef_value(service)));
+    }
+    line
+}
+
+fn render_leef(d: &Detection<'_>) -> String {
+    let dev_time = Utc
+        .timestamp_millis_opt(d.millis())
+        .single()
+        .unwrap_or_default()
+        .format("%b %d %Y %H:%M:%S");
+    let mut line = format!(
+        "LEEF:1.0|{}|{}|{}|{}|devTime={}\tcat={}\tsev={}\tcircuitId={}\tattackKind={}",
+        leef_header(VENDOR),
+        leef_header(PRODUCT),
+        leef_header(env!("CARGO_PKG_VERSION")),
+        leef_header(d.kind),
+        dev_time,
+        d.category(),
+        d.severity(),
+        leef_value(d.circ_id),
+        leef_value(d.kind),
+    );
+    if let Some(fp) = d.guard_fp {
+        line.push_str(&format!("\tguardFingerprint={}", leef_value(fp)));
+    }
+    if let Some(service) = d.service {
+        line.push_str(&format!("\tservice={}", leef_value(service)));
+    }
+    line
+}
+
+/// Escapes a CEF header field: `\` and `|` are backslash-escaped.
+fn cef_header(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+/// Escapes a CEF extension value: `\` and `=` are backslash-escaped and
+/// line breaks become `\n`.
+fn cef_value(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+/// Strips `|` from a LEEF header field, which has no escape mechanism.
+fn leef_header(s: &str) -> String {
+    s.replace('|', "")
+}
+
+/// Strips the tab delimiter and line breaks from a LEEF attribute value.
+fn leef_value(s: &str) -> String {
+    s.replace(['\t', '\r', '\n'], " ")
+}
+
+/// Appends rendered detections to the configured output file.
+#[derive(Debug)]
+pub struct SiemWriter {
+    format: SiemFormat,
+    file: File,
+}
+
+impl SiemWriter {
+    /// Opens `path` for appending, creating it if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`](crate::Error::Io) if the file cannot be opened.
+    pub fn open(format: SiemFormat, path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { format, file })
+    }
+
+    /// Writes one line for `detection`.
+    ///
+    /// Write errors are logged rather than returned so a full disk does not
+    /// interrupt attack handling.
+    pub fn write(&self, detection: &Detection<'_>) {
+        let Some(line) = self.format.render(detection) else {
+            return;
+        };
+        if let Err(e) = writeln!(&self.file, "{}", line) {
+            plog(
+                LogLevel::Warn,
+                &format!("Failed to write SIEM event: {}", e),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dropped_cells_cef_line() {
+        let fp = "A".repeat(40);
+        let detection = Detection {
+            circ_id: "42",
+            kind: "dropped_cells",
+            guard_fp: Some(&fp),
+            service: Some("svc=a|b"),
+            at: 1760702400.5,
+            synthetic: false,
+        };
+        let line = SiemFormat::Cef.render(&detection).unwrap();
+
+        let header: Vec<&str> = line.splitn(8, '|').collect();
+        assert_eq!(header.len(), 8);
+        assert_eq!(header[0], "CEF:0");
+        assert_eq!(header[1], "tn3w");
+        assert_eq!(header[2], "vanguards-rs");
+        assert_eq!(header[3], env!("CARGO_PKG_VERSION"));
+        assert_eq!(header[4], "dropped_cells");
+        assert_eq!(header[5], "Dropped cells on circuit");
+        assert_eq!(header[6], "8");
+        assert_eq!(
+            header[7],
+            format!(
+                "rt=1760702400500 cat=detection cs1Label=circuitId cs1=42 \
+                 cs2Label=attackKind cs2=dropped_cells cs3Label=guardFingerprint cs3={} \
+                 cs4Label=service cs4=svc\\=a|b",
+                fp
+            )
+        );
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn test_leef_line() {
+        let detection = Detection {
+            circ_id: "7",
+            kind: "max_bytes",
+            guard_fp: None,
+            service: None,
+            at: 1760702400.0,
+            synthetic: true,
+        };
+        assert_eq!(
+            SiemFormat::Leef.render(&detection).unwrap(),
+            format!(
+                "LEEF:1.0|tn3w|vanguards-rs|{}|max_bytes|devTime=Oct 17 2025 12:00:00\t\
+                 cat=synthetic\tsev=5\tcircuitId=7\tattackKind=max_bytes",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+}
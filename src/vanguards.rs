@@ -74,13 +74,16 @@
 //!
 //! State is persisted in Python pickle format for compatibility with the
 //! Python vanguards implementation. This allows seamless migration between
-//! implementations.
+//! implementations. Data Python vanguards does not keep, such as
+//! `rotated_out` or the rendguard's `not_in_consensus`, is saved under extra
+//! keys, left out when empty, that Python vanguards ignores.
 //!
 //! ```text
 //! ┌─────────────────────────────────────────────────────────────────────────┐
 //! │                        State File Format                                │
 //! │                                                                         │
 //! │  VanguardState {                                                        │
+//! │      layer1: [GuardNode, ...],  // entry guards Tor reported            │
 //! │      layer2: [                                                          │
 //! │          GuardNode { idhex, chosen_at, expires_at },                    │
 //! │          ...                                                            │
@@ -144,9 +147,8 @@
 //! - [Python vanguards](https://github.com/mikeperry-tor/vanguards) - Original implementation
 //! - [Vanguards proposal](https://github.com/torproject/torspec/blob/main/proposals/292-mesh-vanguards.txt) - Design specification
 
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{BufWriter, Write};
 use std::net::IpAddr;
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -156,13 +158,31 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use stem_rs::descriptor::router_status::RouterStatusEntry;
 
-use crate::config::VanguardsConfig;
+use crate::config::{Config, LogLevel, StateFormat, VanguardsConfig};
 use crate::error::{Error, Result};
-use crate::node_selection::{is_valid_country_code, is_valid_fingerprint, BwWeightedGenerator};
+use crate::logger::plog;
+use crate::node_selection::{
+    is_valid_country_code, is_valid_fingerprint, BwWeightedGenerator, NodeRestriction,
+};
 
 /// Seconds per hour constant.
 const SEC_PER_HOUR: f64 = 3600.0;
 
+/// Times new layer2 guards are reselected to meet
+/// [`VanguardsConfig::min_layer_bw_fraction`] before settling for the best
+/// attempt.
+const MAX_LAYER_BW_ATTEMPTS: usize = 100;
+
+/// State file pickle revision this version writes and fully understands.
+const STATE_PICKLE_REVISION: u32 = 1;
+
+/// Width of the buckets windowed rendezvous uses are counted in, in seconds.
+const REND_USE_BUCKET_SECS: f64 = 60.0;
+
+/// Least age of the newest state backup before another is taken, in
+/// seconds: one consensus period.
+const STATE_BACKUP_INTERVAL_SECS: u64 = 3600;
+
 /// A guard node selected as a vanguard with lifetime metadata.
 ///
 /// Each guard node tracks when it was selected and when it should expire.
@@ -309,6 +329,93 @@ impl RendUseCount {
     }
 }
 
+/// Per-relay use counts for rendezvous points missing from the consensus.
+///
+/// All such relays share the aggregate `NOT_IN_CONSENSUS` entry for overuse
+/// detection; this keeps the individual counts for diagnostics. Fingerprints
+/// here are attacker-controlled, so the map is a bounded LRU: once it holds
+/// `max_not_in_consensus_entries` relays, recording a new one evicts the one
+/// used least recently.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "SavedNotInConsensusUses")]
+pub struct NotInConsensusUses {
+    entries: HashMap<String, (f64, u64)>,
+    clock: u64,
+    /// Fingerprints by last use, oldest first, so eviction needs no scan.
+    /// Rebuilt from `entries` when loaded.
+    #[serde(skip)]
+    by_last_use: BTreeMap<u64, String>,
+}
+
+/// [`NotInConsensusUses`] as saved in the state file.
+#[derive(Deserialize)]
+struct SavedNotInConsensusUses {
+    entries: HashMap<String, (f64, u64)>,
+    clock: u64,
+}
+
+impl From<SavedNotInConsensusUses> for NotInConsensusUses {
+    fn from(saved: SavedNotInConsensusUses) -> Self {
+        let by_last_use = saved
+            .entries
+            .iter()
+            .map(|(fp, &(_, last_used))| (last_used, fp.clone()))
+            .collect();
+        Self {
+            entries: saved.entries,
+            clock: saved.clock,
+            by_last_use,
+        }
+    }
+}
+
+impl NotInConsensusUses {
+    /// Records one use of `fingerprint`, evicting the least recently used
+    /// relay if the map would exceed `max_entries`.
+    ///
+    /// Does nothing if `max_entries` is 0.
+    pub fn record(&mut self, fingerprint: &str, max_entries: usize) {
+        if max_entries == 0 {
+            return;
+        }
+        self.clock += 1;
+
+        if let Some(entry) = self.entries.get_mut(fingerprint) {
+            if let Some(fp) = self.by_last_use.remove(&entry.1) {
+                self.by_last_use.insert(self.clock, fp);
+            }
+            entry.0 += 1.0;
+            entry.1 = self.clock;
+            return;
+        }
+
+        while self.entries.len() >= max_entries {
+            let Some((_, oldest)) = self.by_last_use.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.entries
+            .insert(fingerprint.to_string(), (1.0, self.clock));
+        self.by_last_use.insert(self.clock, fingerprint.to_string());
+    }
+
+    /// Returns how often `fingerprint` was used, if it is still tracked.
+    pub fn uses(&self, fingerprint: &str) -> Option<f64> {
+        self.entries.get(fingerprint).map(|(used, _)| *used)
+    }
+
+    /// Returns the number of relays currently tracked.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if no relays are tracked.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
 /// Rendezvous point usage tracking for detecting statistical attacks.
 ///
 /// Tracks usage counts for all relays used as rendezvous points and
@@ -322,6 +429,21 @@ pub struct RendGuard {
     pub total_use_counts: f64,
     /// Version number for pickle compatibility.
     pub pickle_revision: f64,
+    /// Individual uses of relays counted under `NOT_IN_CONSENSUS`.
+    ///
+    /// Saved in the state file under an extra key, left out when empty,
+    /// that Python vanguards ignores.
+    #[serde(default, skip_serializing_if = "NotInConsensusUses::is_empty")]
+    pub not_in_consensus: NotInConsensusUses,
+    /// Uses per counted relay in one-minute buckets of (bucket start, uses),
+    /// oldest first, kept only while `use_window_hours` is set.
+    ///
+    /// Each relay holds at most one bucket per minute of the window, and
+    /// relays that leave the consensus lose their buckets with their counts.
+    /// Left out of the state file when empty, so state written without a
+    /// window is unchanged and older state loads with no buckets.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub use_buckets: HashMap<String, VecDeque<(f64, f64)>>,
 }
 
 impl Default for RendGuard {
@@ -337,6 +459,8 @@ impl RendGuard {
             use_counts: HashMap::new(),
             total_use_counts: 0.0,
             pickle_revision: 1.0,
+            not_in_consensus: NotInConsensusUses::default(),
+            use_buckets: HashMap::new(),
         }
     }
 
@@ -369,12 +493,97 @@ impl RendGuard {
         fingerprint: &str,
         config: &crate::config::RendguardConfig,
     ) -> bool {
+        let relay_id = self.count_use(fingerprint, config);
+        !self.over_limit(&relay_id, config)
+    }
+
+    /// Like [`Self::valid_rend_use`], for a use at Unix time `now`.
+    ///
+    /// With `use_window_hours` set, uses older than the window are first
+    /// dropped with [`Self::prune_old`] and this one is added to the
+    /// counted relay's bucket for the minute of `now`.
+    /// Otherwise it is identical to [`Self::valid_rend_use`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::config::RendguardConfig;
+    /// use vanguards_rs::vanguards::RendGuard;
+    ///
+    /// let config = RendguardConfig {
+    ///     use_window_hours: 1,
+    ///     ..RendguardConfig::default()
+    /// };
+    /// let mut rg = RendGuard::new();
+    /// rg.record_use_at(&"A".repeat(40), 0.0, &config);
+    /// assert_eq!(rg.total_use_counts, 1.0);
+    ///
+    /// // Two hours later the first use has left the window
+    /// rg.record_use_at(&"A".repeat(40), 7200.0, &config);
+    /// assert_eq!(rg.total_use_counts, 1.0);
+    /// ```
+    pub fn record_use_at(
+        &mut self,
+        fingerprint: &str,
+        now: f64,
+        config: &crate::config::RendguardConfig,
+    ) -> bool {
+        if config.use_window_hours == 0 {
+            return self.valid_rend_use(fingerprint, config);
+        }
+        self.prune_old(now, config);
+        let relay_id = self.count_use(fingerprint, config);
+        let start = (now / REND_USE_BUCKET_SECS).floor() * REND_USE_BUCKET_SECS;
+        let buckets = self.use_buckets.entry(relay_id.clone()).or_default();
+        match buckets.back_mut() {
+            Some((at, uses)) if *at == start => *uses += 1.0,
+            _ => buckets.push_back((start, 1.0)),
+        }
+        !self.over_limit(&relay_id, config)
+    }
+
+    /// Drops uses recorded before the `use_window_hours` window ending at
+    /// `now` from the counts of the relays they were counted under.
+    ///
+    /// Uses leave a minute at a time, with the bucket they fell in. Does
+    /// nothing when the window is disabled.
+    pub fn prune_old(&mut self, now: f64, config: &crate::config::RendguardConfig) {
+        if config.use_window_hours == 0 {
+            return;
+        }
+        let cutoff = now - f64::from(config.use_window_hours) * 3600.0;
+        let mut pruned = false;
+        for (relay_id, buckets) in &mut self.use_buckets {
+            let mut expired = 0.0;
+            while buckets.front().is_some_and(|(at, _)| *at < cutoff) {
+                if let Some((_, uses)) = buckets.pop_front() {
+                    expired += uses;
+                }
+            }
+            if expired > 0.0 {
+                if let Some(count) = self.use_counts.get_mut(relay_id) {
+                    count.used = (count.used - expired).max(0.0);
+                }
+                pruned = true;
+            }
+        }
+        self.use_buckets.retain(|_, buckets| !buckets.is_empty());
+        if pruned {
+            self.total_use_counts = self.use_counts.values().map(|c| c.used).sum();
+        }
+    }
+
+    /// Counts one use of `fingerprint`, returning the ID it was counted
+    /// under: the fingerprint itself, or `NOT_IN_CONSENSUS`.
+    fn count_use(&mut self, fingerprint: &str, config: &crate::config::RendguardConfig) -> String {
         const NOT_IN_CONSENSUS_ID: &str = "NOT_IN_CONSENSUS";
 
         let relay_id = if self.use_counts.contains_key(fingerprint) {
             fingerprint.to_string()
         } else {
             // Relay not in consensus - track under special ID
+            self.not_in_consensus
+                .record(fingerprint, config.max_not_in_consensus_entries);
             if !self.use_counts.contains_key(NOT_IN_CONSENSUS_ID) {
                 self.use_counts.insert(
                     NOT_IN_CONSENSUS_ID.to_string(),
@@ -389,19 +598,17 @@ impl RendGuard {
             count.used += 1.0;
         }
         self.total_use_counts += 1.0;
+        relay_id
+    }
 
-        // Check for overuse
-        if let Some(count) = self.use_counts.get(&relay_id) {
-            if self.total_use_counts >= config.use_global_start_count as f64
+    /// Returns true if `relay_id` is used beyond its share.
+    fn over_limit(&self, relay_id: &str, config: &crate::config::RendguardConfig) -> bool {
+        self.use_counts.get(relay_id).is_some_and(|count| {
+            self.total_use_counts >= config.use_global_start_count as f64
                 && count.used >= config.use_relay_start_count as f64
                 && count.used / self.total_use_counts
                     > count.weight * config.use_max_use_to_bw_ratio
-            {
-                return false; // Overused
-            }
-        }
-
-        true // Valid usage
+        })
     }
 
     /// Transfers and updates use counts on consensus change.
@@ -411,20 +618,56 @@ impl RendGuard {
         &mut self,
         generator: &BwWeightedGenerator,
         config: &crate::config::RendguardConfig,
+    ) {
+        self.xfer_use_counts_excluding(generator, &ExcludeNodes::new(), config);
+    }
+
+    /// Like [`Self::xfer_use_counts`], leaving `excluded` relays out of the
+    /// totals the other relays' expected shares are computed from.
+    ///
+    /// Clients still pick excluded relays as rendezvous points, so they
+    /// keep their entries, judged against their share of the whole
+    /// network, rather than falling under `NOT_IN_CONSENSUS`.
+    pub fn xfer_use_counts_excluding(
+        &mut self,
+        generator: &BwWeightedGenerator,
+        excluded: &ExcludeNodes,
+        config: &crate::config::RendguardConfig,
     ) {
         const NOT_IN_CONSENSUS_ID: &str = "NOT_IN_CONSENSUS";
 
         let old_counts = std::mem::take(&mut self.use_counts);
-        let should_scale = self.total_use_counts >= config.use_scale_at_count as f64;
+        // A window already bounds the counts; halving would leave them out
+        // of step with use_buckets
+        let should_scale = config.use_window_hours == 0
+            && self.total_use_counts >= config.use_scale_at_count as f64;
 
         // Create entries for all routers in new consensus
         let routers = generator.routers();
         let node_weights = generator.node_weights();
-        let weight_total = generator.weight_total();
-        let exit_total = generator.exit_total();
+        let is_exit = |router: &RouterStatusEntry| router.flags.contains(&"Exit".to_string());
+        let (mut kept_weight_total, mut kept_exit_total) =
+            (generator.weight_total(), generator.exit_total());
+        if excluded.has_exclusions() {
+            for (i, router) in routers.iter().enumerate() {
+                if !excluded.router_is_excluded(router) {
+                    continue;
+                }
+                if is_exit(router) {
+                    kept_exit_total -= node_weights[i];
+                } else {
+                    kept_weight_total -= node_weights[i];
+                }
+            }
+        }
 
         for (i, router) in routers.iter().enumerate() {
-            let weight = if router.flags.contains(&"Exit".to_string()) && exit_total > 0.0 {
+            let (weight_total, exit_total) = if excluded.router_is_excluded(router) {
+                (generator.weight_total(), generator.exit_total())
+            } else {
+                (kept_weight_total, kept_exit_total)
+            };
+            let weight = if is_exit(router) && exit_total > 0.0 {
                 node_weights[i] / exit_total
             } else if weight_total > 0.0 {
                 node_weights[i] / weight_total
@@ -460,6 +703,16 @@ impl RendGuard {
             }
         }
 
+        // Uses of relays that left were dropped above, so their buckets go
+        // too; a relay that returns starts from nothing
+        if config.use_window_hours == 0 {
+            self.use_buckets.clear();
+        } else {
+            let use_counts = &self.use_counts;
+            self.use_buckets
+                .retain(|relay_id, _| use_counts.contains_key(relay_id));
+        }
+
         // Recalculate total
         self.total_use_counts = self.use_counts.values().map(|c| c.used).sum();
     }
@@ -498,6 +751,66 @@ impl RendGuard {
             false
         }
     }
+
+    /// Returns the `n` relays used most out of proportion to their weight.
+    ///
+    /// Relays are ranked by how far their share of uses exceeds the
+    /// overuse threshold, `used / total - weight * use_max_use_to_bw_ratio`,
+    /// highest first. Relays with fewer than `use_relay_start_count` uses
+    /// are left out. The `NOT_IN_CONSENSUS` bucket is ranked like any relay.
+    ///
+    /// Each entry is `(fingerprint, usage_rate_pct, expected_weight_pct)`,
+    /// as from [`Self::usage_rate`] and [`Self::expected_weight`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::config::RendguardConfig;
+    /// use vanguards_rs::vanguards::{RendGuard, RendUseCount};
+    ///
+    /// let mut rg = RendGuard::new();
+    /// for (fp, used, weight) in [("A", 150.0, 0.01), ("B", 850.0, 0.99)] {
+    ///     let mut count = RendUseCount::new(fp.repeat(40), weight);
+    ///     count.used = used;
+    ///     rg.use_counts.insert(fp.repeat(40), count);
+    /// }
+    /// rg.total_use_counts = 1000.0;
+    ///
+    /// let top = rg.top_overused(1, &RendguardConfig::default());
+    /// assert_eq!(top[0].0, "A".repeat(40));
+    /// assert_eq!(top[0].1, 15.0);
+    /// ```
+    pub fn top_overused(
+        &self,
+        n: usize,
+        config: &crate::config::RendguardConfig,
+    ) -> Vec<(String, f64, f64)> {
+        if self.total_use_counts <= 0.0 {
+            return Vec::new();
+        }
+        let mut ranked: Vec<(f64, &RendUseCount)> = self
+            .use_counts
+            .values()
+            .filter(|c| c.used >= f64::from(config.use_relay_start_count))
+            .map(|c| {
+                let margin =
+                    c.used / self.total_use_counts - c.weight * config.use_max_use_to_bw_ratio;
+                (margin, c)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.idhex.cmp(&b.1.idhex)));
+        ranked
+            .into_iter()
+            .take(n)
+            .map(|(_, c)| {
+                (
+                    c.idhex.clone(),
+                    self.usage_rate(&c.idhex),
+                    self.expected_weight(&c.idhex),
+                )
+            })
+            .collect()
+    }
 }
 
 /// Persistent vanguard state containing guard layers and rendguard tracking.
@@ -539,11 +852,14 @@ impl RendGuard {
 ///
 /// ```text
 /// VanguardState {
+///     layer1: [GuardNode, ...],  // optional, entry guards Tor reported
 ///     layer2: [GuardNode, ...],
 ///     layer3: [GuardNode, ...],
 ///     state_file: String,
 ///     rendguard: RendGuard,
 ///     pickle_revision: u32,
+///     rotated_out: {fingerprint: rotated_at, ...},  // optional
+///     first_seen: {fingerprint: first_seen_at, ...},  // optional
 /// }
 /// ```
 ///
@@ -576,6 +892,12 @@ impl RendGuard {
 /// - [`crate::config::VanguardsConfig`] - Configuration options
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VanguardState {
+    /// Entry guards Tor reported after vanguards set `NumEntryGuards`.
+    ///
+    /// Tor picks these itself; they are recorded so the state file shows
+    /// the whole path and entry guard churn is visible across restarts.
+    #[serde(default)]
+    pub layer1: Vec<GuardNode>,
     /// Layer 2 guard nodes (second hop).
     pub layer2: Vec<GuardNode>,
     /// Layer 3 guard nodes (third hop).
@@ -589,6 +911,30 @@ pub struct VanguardState {
     /// Whether vanguards are enabled (runtime flag, not persisted).
     #[serde(skip)]
     pub enable_vanguards: bool,
+    /// Format [`write_to_file`](Self::write_to_file) uses (runtime setting,
+    /// not persisted).
+    ///
+    /// Reading a state file sets it to the format the file was in, but
+    /// [`apply_config`](Self::apply_config) replaces that with the configured
+    /// `state_format`, so after startup it is the format the file is written
+    /// in, not the one it was read in.
+    #[serde(skip)]
+    pub state_format: StateFormat,
+    /// Previous state files [`write_to_file`](Self::write_to_file) keeps
+    /// (runtime setting, not persisted).
+    #[serde(skip)]
+    pub state_backups: u32,
+    /// Fingerprints of guards that recently expired, with the time they were
+    /// rotated out. Used to avoid immediately reselecting the same relay.
+    ///
+    /// Saved in the state file under an extra key, left out when empty,
+    /// that Python vanguards ignores.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub rotated_out: HashMap<String, f64>,
+    /// Unix time each relay was first seen in a consensus, for
+    /// `min_guard_age_hours`. Kept only while that option is set.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub first_seen: HashMap<String, f64>,
 }
 
 impl Default for VanguardState {
@@ -601,17 +947,36 @@ impl VanguardState {
     /// Creates a new empty vanguard state.
     pub fn new(state_file: &str) -> Self {
         Self {
+            layer1: Vec::new(),
             layer2: Vec::new(),
             layer3: Vec::new(),
             state_file: state_file.to_string(),
             rendguard: RendGuard::new(),
             pickle_revision: 1,
             enable_vanguards: true,
+            state_format: StateFormat::default(),
+            state_backups: 0,
+            rotated_out: HashMap::new(),
+            first_seen: HashMap::new(),
         }
     }
 
+    /// Applies the runtime settings that are not persisted in the state file
+    /// from `config`.
+    ///
+    /// Called on every state loaded or created at startup and again when the
+    /// configuration is reloaded.
+    pub fn apply_config(&mut self, config: &Config) {
+        self.enable_vanguards = config.enable_vanguards;
+        self.state_format = config.state_format;
+        self.state_backups = config.state_backups;
+    }
+
     /// Loads state from a file or creates new state if the file doesn't exist.
     ///
+    /// A state file that cannot be read is replaced by its newest readable
+    /// backup, as [`read_with_backups`](Self::read_with_backups) does.
+    ///
     /// # Arguments
     ///
     /// * `path` - Path to the state file
@@ -620,7 +985,7 @@ impl VanguardState {
     ///
     /// The loaded or newly created state.
     pub fn load_or_create(path: &Path) -> Self {
-        match Self::read_from_file(path) {
+        match Self::read_with_backups(path) {
             Ok(mut state) => {
                 state.state_file = path.to_string_lossy().to_string();
                 state
@@ -629,22 +994,74 @@ impl VanguardState {
         }
     }
 
-    /// Reads state from a pickle file with validation.
+    /// Reads state from a pickle or JSON file with validation.
+    ///
+    /// The file is parsed as a pickle first and as JSON if that fails.
+    /// [`state_format`](Self::state_format) is set to the one that matched, so
+    /// the state is written back in the same format until
+    /// [`apply_config`](Self::apply_config) sets the configured one.
     ///
     /// Validates that:
     /// - All fingerprints are valid 40-character hex strings
     /// - No timestamps are in the future (with 1 hour tolerance)
     /// - The file format is valid
     ///
+    /// A state file whose layout does not match, such as one written by a
+    /// newer Python vanguards, is read again leniently: layer2, layer3 and
+    /// rendguard are salvaged wherever they can be found and anything else is
+    /// dropped, with a WARN, so existing guards survive the upgrade.
+    ///
     /// # Errors
     ///
     /// Returns [`Error::State`] if the file cannot be read, parsed, or fails validation.
     pub fn read_from_file(path: &Path) -> Result<Self> {
-        let file =
-            File::open(path).map_err(|e| Error::State(format!("cannot open state file: {}", e)))?;
-        let reader = BufReader::new(file);
-        let state: Self = serde_pickle::from_reader(reader, Default::default())
-            .map_err(|e| Error::State(format!("cannot parse state file: {}", e)))?;
+        let bytes = std::fs::read(path)
+            .map_err(|e| Error::State(format!("cannot open state file: {}", e)))?;
+        let state: Self = match serde_pickle::from_slice(&bytes, Default::default()) {
+            Ok(state) => state,
+            Err(strict_err) => match serde_pickle::value_from_slice(&bytes, Default::default()) {
+                Ok(value) => {
+                    let state = Self::from_pickle_value_lenient(&value).ok_or_else(|| {
+                        Error::State(format!("cannot parse state file: {}", strict_err))
+                    })?;
+                    plog(
+                        LogLevel::Warn,
+                        &format!(
+                            "State file has an unrecognized layout (pickle revision {}, \
+                             expected {}): {}. Kept {} layer2 and {} layer3 guards.",
+                            state.pickle_revision,
+                            STATE_PICKLE_REVISION,
+                            strict_err,
+                            state.layer2.len(),
+                            state.layer3.len()
+                        ),
+                    );
+                    state
+                }
+                // Not a pickle at all; try JSON before giving up
+                Err(pickle_err) => {
+                    let mut state: Self = serde_json::from_slice(&bytes).map_err(|json_err| {
+                        Error::State(format!(
+                            "cannot parse state file as pickle ({}) or JSON ({})",
+                            pickle_err, json_err
+                        ))
+                    })?;
+                    state.state_format = StateFormat::Json;
+                    state
+                }
+            },
+        };
+
+        if state.pickle_revision > STATE_PICKLE_REVISION {
+            plog(
+                LogLevel::Warn,
+                &format!(
+                    "State file pickle revision {} is newer than {}; fields added since \
+                     will be lost when the state is next written.",
+                    state.pickle_revision, STATE_PICKLE_REVISION
+                ),
+            );
+        }
 
         // Validate the loaded state
         state.validate()?;
@@ -652,6 +1069,42 @@ impl VanguardState {
         Ok(state)
     }
 
+    /// Salvages what it can from a state pickle that did not deserialize.
+    ///
+    /// Returns `None` unless the top level is a dict with at least one guard
+    /// layer list in it.
+    fn from_pickle_value_lenient(value: &serde_pickle::Value) -> Option<Self> {
+        let serde_pickle::Value::Dict(dict) = value else {
+            return None;
+        };
+        let field = |name: &str| dict.get(&serde_pickle::HashableValue::String(name.to_string()));
+        if field("layer2").is_none() && field("layer3").is_none() {
+            return None;
+        }
+
+        let mut state = Self::new(
+            &field("state_file")
+                .and_then(pickle_string)
+                .unwrap_or_default(),
+        );
+        state.layer1 = pickle_guard_layer(field("layer1"));
+        state.layer2 = pickle_guard_layer(field("layer2"));
+        state.layer3 = pickle_guard_layer(field("layer3"));
+        state.rendguard = field("rendguard")
+            .and_then(|v| serde_pickle::from_value(v.clone()).ok())
+            .unwrap_or_default();
+        state.rotated_out = field("rotated_out")
+            .and_then(|v| serde_pickle::from_value(v.clone()).ok())
+            .unwrap_or_default();
+        state.first_seen = field("first_seen")
+            .and_then(|v| serde_pickle::from_value(v.clone()).ok())
+            .unwrap_or_default();
+        if let Some(revision) = field("pickle_revision").and_then(pickle_f64) {
+            state.pickle_revision = revision as u32;
+        }
+        Some(state)
+    }
+
     /// Validates the state for integrity.
     ///
     /// Checks:
@@ -670,6 +1123,22 @@ impl VanguardState {
         // Allow 1 hour tolerance for clock skew
         let max_timestamp = now + 3600.0;
 
+        // Validate layer1 guards
+        for guard in &self.layer1 {
+            if !is_valid_fingerprint(&guard.idhex) {
+                return Err(Error::State(format!(
+                    "invalid fingerprint in layer1: {}",
+                    guard.idhex
+                )));
+            }
+            if guard.chosen_at > max_timestamp {
+                return Err(Error::State(format!(
+                    "future timestamp in layer1 guard {}: chosen_at {} > now {}",
+                    guard.idhex, guard.chosen_at, now
+                )));
+            }
+        }
+
         // Validate layer2 guards
         for guard in &self.layer2 {
             if !is_valid_fingerprint(&guard.idhex) {
@@ -732,10 +1201,59 @@ impl VanguardState {
         Ok(())
     }
 
-    /// Writes state to a pickle file with atomic write and secure permissions.
+    /// Reads state from `path`, or from the newest backup that can be read
+    /// if `path` cannot.
+    ///
+    /// Backups are `<path>.1`, `<path>.2`, ..., as left by
+    /// [`write_to_file`](Self::write_to_file) with
+    /// [`state_backups`](Self::state_backups) set. Loading one is logged at
+    /// WARN.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error for `path` if no backup can be read either.
+    pub fn read_with_backups(path: &Path) -> Result<Self> {
+        let err = match Self::read_from_file(path) {
+            Ok(state) => return Ok(state),
+            Err(e) => e,
+        };
+        for n in 1.. {
+            let backup = state_backup_path(path, n);
+            if !backup.exists() {
+                break;
+            }
+            match Self::read_from_file(&backup) {
+                Ok(state) => {
+                    plog(
+                        LogLevel::Warn,
+                        &format!(
+                            "Cannot load state from {} ({}). Using backup {}.",
+                            path.display(),
+                            err,
+                            backup.display()
+                        ),
+                    );
+                    return Ok(state);
+                }
+                Err(e) => plog(
+                    LogLevel::Info,
+                    &format!("Cannot load state backup {}: {}", backup.display(), e),
+                ),
+            }
+        }
+        Err(err)
+    }
+
+    /// Writes state in [`state_format`](Self::state_format) with atomic write
+    /// and secure permissions.
     ///
     /// Uses atomic write (write to temp file, then rename) to prevent corruption.
     /// On Unix systems, sets file permissions to 0600 (owner read/write only).
+    /// With [`state_backups`](Self::state_backups) set, the file being
+    /// replaced is kept as `<path>.1` and older backups move up one number,
+    /// unless `<path>.1` is less than an hour old. The state is saved far
+    /// more often than that, and backups a few minutes apart would push out
+    /// the older states they are there to keep.
     ///
     /// # Errors
     ///
@@ -758,12 +1276,18 @@ impl VanguardState {
         };
 
         #[cfg(not(unix))]
-        let file = File::create(&temp_path)
+        let file = std::fs::File::create(&temp_path)
             .map_err(|e| Error::State(format!("cannot create temp state file: {}", e)))?;
 
         let mut writer = BufWriter::new(file);
-        serde_pickle::to_writer(&mut writer, self, Default::default())
-            .map_err(|e| Error::State(format!("cannot write state file: {}", e)))?;
+        match self.state_format {
+            StateFormat::Pickle => {
+                serde_pickle::to_writer(&mut writer, self, Default::default())
+                    .map_err(|e| Error::State(format!("cannot write state file: {}", e)))?
+            }
+            StateFormat::Json => serde_json::to_writer_pretty(&mut writer, self)
+                .map_err(|e| Error::State(format!("cannot write state file: {}", e)))?,
+        }
 
         // Ensure all data is flushed
         writer
@@ -771,6 +1295,11 @@ impl VanguardState {
             .map_err(|e| Error::State(format!("cannot flush state file: {}", e)))?;
         drop(writer);
 
+        if self.state_backups > 0 && path.exists() && state_backup_due(path) {
+            rotate_state_backups(path, self.state_backups)
+                .map_err(|e| Error::State(format!("cannot rotate state backups: {}", e)))?;
+        }
+
         // Atomic rename
         std::fs::rename(&temp_path, path)
             .map_err(|e| Error::State(format!("cannot rename temp state file: {}", e)))?;
@@ -778,6 +1307,51 @@ impl VanguardState {
         Ok(())
     }
 
+    /// Returns the layer 1 guard fingerprints as a comma-separated string.
+    pub fn layer1_guardset(&self) -> String {
+        self.layer1
+            .iter()
+            .map(|g| g.idhex.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Replaces layer1 with the entry guards Tor reported, in Tor's order.
+    ///
+    /// Guards already recorded keep their `chosen_at` and `expires_at`;
+    /// new ones are stamped with `now` and expire after `lifetime_secs`.
+    /// Returns `true` if the set of guards changed.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::vanguards::VanguardState;
+    ///
+    /// let mut state = VanguardState::new("vanguards.state");
+    /// let guards = vec!["A".repeat(40), "B".repeat(40)];
+    /// assert!(state.update_layer1(&guards, 1000.0, 3600.0));
+    /// assert!(!state.update_layer1(&guards, 2000.0, 3600.0));
+    /// assert_eq!(state.layer1[0].chosen_at, 1000.0);
+    /// ```
+    pub fn update_layer1(&mut self, fingerprints: &[String], now: f64, lifetime_secs: f64) -> bool {
+        let layer1: Vec<GuardNode> = fingerprints
+            .iter()
+            .map(|fp| {
+                self.layer1
+                    .iter()
+                    .find(|g| &g.idhex == fp)
+                    .cloned()
+                    .unwrap_or_else(|| GuardNode::new(fp.clone(), now, now + lifetime_secs))
+            })
+            .collect();
+        let changed = layer1.len() != self.layer1.len()
+            || layer1
+                .iter()
+                .any(|g| !self.layer1.iter().any(|old| old.idhex == g.idhex));
+        self.layer1 = layer1;
+        changed
+    }
+
     /// Returns the layer 2 guard fingerprints as a comma-separated string.
     pub fn layer2_guardset(&self) -> String {
         self.layer2
@@ -818,106 +1392,453 @@ impl VanguardState {
         sample1.max(sample2)
     }
 
+    /// Scales a lifetime by a random factor in `1 ± jitter_fraction`.
+    ///
+    /// The max-of-two draw keeps every expiry inside the configured
+    /// `[min, max]` window, so anyone who knows a fleet's shared config knows
+    /// its guards never rotate outside it. Jitter blurs those edges, making
+    /// rotation times of instances that share a config harder to line up.
+    /// A fraction of 0 returns `lifetime` unchanged.
+    ///
+    /// # Returns
+    ///
+    /// A lifetime in `[lifetime * (1 - f), lifetime * (1 + f)]`.
+    pub fn apply_lifetime_jitter(lifetime: f64, jitter_fraction: f64) -> f64 {
+        if jitter_fraction <= 0.0 {
+            return lifetime;
+        }
+        let factor = rand::thread_rng().gen_range(-jitter_fraction..=jitter_fraction);
+        lifetime * (1.0 + factor)
+    }
+
     /// Adds a new layer 2 guard.
     ///
-    /// Selects a guard using the provided generator, avoiding duplicates
-    /// and excluded nodes.
+    /// Selects a guard using the provided generator, avoiding duplicates,
+    /// excluded nodes, and guards still within their rotation cooldown.
     pub fn add_new_layer2(
         &mut self,
         generator: &BwWeightedGenerator,
         excluded: &ExcludeNodes,
         config: &VanguardsConfig,
     ) -> Result<()> {
-        let existing: HashSet<_> = self.layer2.iter().map(|g| g.idhex.as_str()).collect();
-
-        for _ in 0..1000 {
-            let guard = generator.generate()?;
-            if existing.contains(guard.fingerprint.as_str()) {
-                continue;
-            }
-            if excluded.router_is_excluded(guard) {
-                continue;
-            }
-
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs_f64();
-            let lifetime = Self::calculate_guard_lifetime(
-                config.min_layer2_lifetime_hours,
-                config.max_layer2_lifetime_hours,
-            );
-            let expires = now + lifetime;
-
-            self.layer2
-                .push(GuardNode::new(guard.fingerprint.clone(), now, expires));
-            return Ok(());
-        }
-
-        Err(Error::NoNodesRemain)
+        let mut report = DiversityReport::default();
+        self.add_new_guard(2, generator, excluded, config, &mut report)?;
+        report.log();
+        Ok(())
     }
 
     /// Adds a new layer 3 guard.
     ///
-    /// Selects a guard using the provided generator, avoiding duplicates
-    /// and excluded nodes.
+    /// Selects a guard using the provided generator, avoiding duplicates,
+    /// excluded nodes, and guards still within their rotation cooldown.
     pub fn add_new_layer3(
         &mut self,
         generator: &BwWeightedGenerator,
         excluded: &ExcludeNodes,
         config: &VanguardsConfig,
     ) -> Result<()> {
-        let existing: HashSet<_> = self.layer3.iter().map(|g| g.idhex.as_str()).collect();
+        let mut report = DiversityReport::default();
+        self.add_new_guard(3, generator, excluded, config, &mut report)?;
+        report.log();
+        Ok(())
+    }
 
-        for _ in 0..1000 {
-            let guard = generator.generate()?;
-            if existing.contains(guard.fingerprint.as_str()) {
-                continue;
+    /// Adds layer2 guards up to `num_layer2`, enforcing
+    /// `min_layer_bw_fraction` as described on [`Self::replenish_layers`].
+    fn fill_layer2(
+        &mut self,
+        num_layer2: usize,
+        generator: &BwWeightedGenerator,
+        excluded: &ExcludeNodes,
+        config: &VanguardsConfig,
+        report: &mut DiversityReport,
+    ) -> Result<()> {
+        let kept = self.layer2.len();
+        let kept_relaxed = report.relaxed.len();
+        let min_fraction = config.min_layer_bw_fraction;
+        let mut best: Option<(f64, Vec<GuardNode>, Vec<RelaxedConstraint>)> = None;
+
+        for _ in 0..MAX_LAYER_BW_ATTEMPTS {
+            while self.layer2.len() < num_layer2 {
+                self.add_new_guard(2, generator, excluded, config, report)?;
             }
-            if excluded.router_is_excluded(guard) {
-                continue;
+            if min_fraction <= 0.0 || kept == num_layer2 {
+                return Ok(());
             }
+            let fraction = generator.weight_fraction(self.layer2.iter().map(|g| g.idhex.as_str()));
+            if fraction >= min_fraction {
+                return Ok(());
+            }
+            if best.as_ref().is_none_or(|(f, _, _)| fraction > *f) {
+                best = Some((
+                    fraction,
+                    self.layer2.clone(),
+                    report.relaxed[kept_relaxed..].to_vec(),
+                ));
+            }
+            self.layer2.truncate(kept);
+            report.relaxed.truncate(kept_relaxed);
+        }
 
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs_f64();
-            let lifetime = Self::calculate_guard_lifetime(
-                config.min_layer3_lifetime_hours,
-                config.max_layer3_lifetime_hours,
+        if let Some((fraction, layer2, relaxed)) = best {
+            plog(
+                LogLevel::Notice,
+                &format!(
+                    "Layer2 carries {:.4}% of eligible weight, below min_layer_bw_fraction {:.4}%, after {} attempts. Keeping the best selection.",
+                    fraction * 100.0,
+                    min_fraction * 100.0,
+                    MAX_LAYER_BW_ATTEMPTS
+                ),
             );
-            let expires = now + lifetime;
-
-            self.layer3
-                .push(GuardNode::new(guard.fingerprint.clone(), now, expires));
-            return Ok(());
+            self.layer2 = layer2;
+            report.relaxed.extend(relaxed);
         }
-
-        Err(Error::NoNodesRemain)
+        Ok(())
     }
 
-    /// Removes guards that are no longer in the consensus.
-    pub fn remove_down_from_layer(layer: &mut Vec<GuardNode>, consensus_fps: &HashSet<String>) {
-        layer.retain(|g| consensus_fps.contains(&g.idhex));
-    }
+    /// Selects a guard for layer `layer_num` and gives it a fresh lifetime.
+    fn add_new_guard(
+        &mut self,
+        layer_num: u8,
+        generator: &BwWeightedGenerator,
+        excluded: &ExcludeNodes,
+        config: &VanguardsConfig,
+        report: &mut DiversityReport,
+    ) -> Result<()> {
+        let (layer, min_hours, max_hours) = if layer_num == 2 {
+            (
+                &self.layer2,
+                config.min_layer2_lifetime_hours,
+                config.max_layer2_lifetime_hours,
+            )
+        } else {
+            (
+                &self.layer3,
+                config.min_layer3_lifetime_hours,
+                config.max_layer3_lifetime_hours,
+            )
+        };
+        let fingerprint =
+            self.select_new_guard(layer, generator, excluded, config, layer_num, report)?;
 
-    /// Removes guards whose rotation time has expired.
-    pub fn remove_expired_from_layer(layer: &mut Vec<GuardNode>) {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs_f64();
-        layer.retain(|g| g.expires_at >= now);
+        let lifetime = Self::apply_lifetime_jitter(
+            Self::calculate_guard_lifetime(min_hours, max_hours),
+            config.lifetime_jitter_fraction,
+        );
+        let guard = GuardNode::new(fingerprint, now, now + lifetime);
+
+        if layer_num == 2 {
+            self.layer2.push(guard);
+        } else {
+            self.layer3.push(guard);
+        }
+        Ok(())
     }
 
-    /// Removes guards that match the ExcludeNodes configuration.
-    pub fn remove_excluded_from_layer(
-        layer: &mut Vec<GuardNode>,
-        router_map: &HashMap<String, &RouterStatusEntry>,
+    /// Picks a fingerprint for a new guard in `layer`.
+    ///
+    /// Relays rotated out within `rotation_cooldown_hours` are skipped. If
+    /// they turn out to be the only eligible candidates, one is reused and a
+    /// warning is logged, since rotation then achieves nothing.
+    ///
+    /// With `subnet_diversity`, relays sharing a subnet with a guard already
+    /// in the layer are passed over too, and with `enforce_guard_diversity`
+    /// so are relays in the same declared family as one. With an AS map
+    /// attached to the generator, relays sharing an AS with any layer2 or
+    /// layer3 guard are passed over as well. If the 1000-draw budget finds
+    /// nothing else, a subnet neighbour is taken before a family member and
+    /// a family member before an AS neighbour, and the relaxation is
+    /// recorded in `report`.
+    fn select_new_guard(
+        &self,
+        layer: &[GuardNode],
+        generator: &BwWeightedGenerator,
         excluded: &ExcludeNodes,
-    ) {
-        layer.retain(|g| {
-            if let Some(router) = router_map.get(&g.idhex) {
+        config: &VanguardsConfig,
+        layer_num: u8,
+        report: &mut DiversityReport,
+    ) -> Result<String> {
+        let existing: HashSet<_> = layer.iter().map(|g| g.idhex.as_str()).collect();
+        let enforce_family = config.enforce_guard_diversity;
+        let taken_subnets: HashSet<IpAddr> = if config.subnet_diversity {
+            generator
+                .routers()
+                .iter()
+                .filter(|r| existing.contains(r.fingerprint.as_str()))
+                .map(|r| diversity_subnet(r.address))
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        let taken_asns = generator.as_restriction().map(|r| {
+            r.clone().with_selected(
+                self.layer2
+                    .iter()
+                    .chain(&self.layer3)
+                    .map(|g| g.idhex.as_str()),
+            )
+        });
+        let mut cooling_candidate = None;
+        let mut crowded_candidate = None;
+        let mut family_candidate = None;
+        let mut asn_candidate = None;
+
+        for _ in 0..1000 {
+            let guard = generator.generate()?;
+            if existing.contains(guard.fingerprint.as_str()) {
+                continue;
+            }
+            if excluded.router_is_excluded(guard) {
+                continue;
+            }
+            if self.in_rotation_cooldown(&guard.fingerprint, config) {
+                cooling_candidate.get_or_insert_with(|| guard.fingerprint.clone());
+                continue;
+            }
+            if taken_asns.as_ref().is_some_and(|r| !r.r_is_ok(guard)) {
+                asn_candidate.get_or_insert_with(|| guard.fingerprint.clone());
+                continue;
+            }
+            if enforce_family
+                && existing
+                    .iter()
+                    .any(|fp| generator.families().same_family(fp, &guard.fingerprint))
+            {
+                family_candidate.get_or_insert_with(|| guard.fingerprint.clone());
+                continue;
+            }
+            if taken_subnets.contains(&diversity_subnet(guard.address)) {
+                crowded_candidate.get_or_insert_with(|| guard.fingerprint.clone());
+                continue;
+            }
+            return Ok(guard.fingerprint.clone());
+        }
+
+        let relaxed = crowded_candidate
+            .map(|fp| (fp, DiversityConstraint::Subnet))
+            .or_else(|| family_candidate.map(|fp| (fp, DiversityConstraint::Family)))
+            .or_else(|| asn_candidate.map(|fp| (fp, DiversityConstraint::Asn)));
+        if let Some((fingerprint, constraint)) = relaxed {
+            report.relaxed.push(RelaxedConstraint {
+                layer: layer_num,
+                constraint,
+                fingerprint: fingerprint.clone(),
+            });
+            return Ok(fingerprint);
+        }
+
+        match cooling_candidate {
+            Some(fingerprint) => {
+                plog(
+                    LogLevel::Warn,
+                    &format!(
+                        "Layer{} guard {} was rotated out less than {} hours ago but is the \
+                         only eligible candidate. Reselecting it.",
+                        layer_num, fingerprint, config.rotation_cooldown_hours
+                    ),
+                );
+                Ok(fingerprint)
+            }
+            None => Err(Error::NoNodesRemain),
+        }
+    }
+
+    /// Removes the guard with the given fingerprint from whichever layer holds it.
+    ///
+    /// Used to drop a single suspected guard without rotating the whole layer.
+    /// The guard is remembered for the rotation cooldown, and the next call to
+    /// [`replenish_layers`](Self::replenish_layers) picks a replacement.
+    ///
+    /// # Arguments
+    ///
+    /// * `fingerprint` - Relay fingerprint, with or without a leading `$`
+    ///
+    /// # Returns
+    ///
+    /// `true` if a guard was removed, `false` if neither layer had it.
+    pub fn expire_guard(&mut self, fingerprint: &str) -> bool {
+        let fingerprint = fingerprint.trim().trim_start_matches('$').to_uppercase();
+        let before = self.layer2.len() + self.layer3.len();
+        self.layer2.retain(|g| g.idhex != fingerprint);
+        self.layer3.retain(|g| g.idhex != fingerprint);
+        if self.layer2.len() + self.layer3.len() == before {
+            return false;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.rotated_out.insert(fingerprint, now);
+        true
+    }
+
+    /// Replaces both guard layers with guards chosen by an external selector.
+    ///
+    /// Fingerprints may carry a leading `$` and are stored uppercase. Both
+    /// layers are checked before either is touched, so on error the current
+    /// guards stay in place. Call [`configure_tor`](crate::configure_tor) and
+    /// [`write_to_file`](Self::write_to_file) afterwards to apply and persist
+    /// them.
+    ///
+    /// The guards are otherwise treated like selected ones: they are dropped
+    /// once `expires_at` passes or they leave the consensus, and the next
+    /// [`replenish_layers`](Self::replenish_layers) trims or tops up each
+    /// layer to the configured count.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Validation`] if a fingerprint is not 40 hex digits,
+    /// appears twice in one layer, or a guard expires before it was chosen.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::{GuardNode, VanguardState};
+    ///
+    /// let mut state = VanguardState::new("vanguards.state");
+    /// let guard = |fp: &str| GuardNode::new(fp.to_string(), 0.0, 86400.0);
+    /// state
+    ///     .set_guards(vec![guard(&"A".repeat(40))], vec![guard(&"B".repeat(40))])
+    ///     .unwrap();
+    /// assert!(state.set_guards(vec![guard("not-a-fingerprint")], vec![]).is_err());
+    /// assert_eq!(state.layer2_guardset(), "A".repeat(40));
+    /// ```
+    pub fn set_guards(&mut self, layer2: Vec<GuardNode>, layer3: Vec<GuardNode>) -> Result<()> {
+        let layer2 = Self::checked_layer("layer2", layer2)?;
+        let layer3 = Self::checked_layer("layer3", layer3)?;
+        self.layer2 = layer2;
+        self.layer3 = layer3;
+        Ok(())
+    }
+
+    /// Normalizes and validates an externally chosen layer for [`set_guards`](Self::set_guards).
+    fn checked_layer(name: &str, mut layer: Vec<GuardNode>) -> Result<Vec<GuardNode>> {
+        let mut seen = HashSet::new();
+        for guard in &mut layer {
+            guard.idhex = guard.idhex.trim().trim_start_matches('$').to_uppercase();
+            if !is_valid_fingerprint(&guard.idhex) {
+                return Err(Error::Validation(format!(
+                    "invalid fingerprint in {}: {}",
+                    name, guard.idhex
+                )));
+            }
+            if !seen.insert(guard.idhex.clone()) {
+                return Err(Error::Validation(format!(
+                    "duplicate guard in {}: {}",
+                    name, guard.idhex
+                )));
+            }
+            if guard.expires_at < guard.chosen_at {
+                return Err(Error::Validation(format!(
+                    "{} guard {} expires before it was chosen",
+                    name, guard.idhex
+                )));
+            }
+        }
+        Ok(layer)
+    }
+
+    /// Returns true if `fingerprint` was rotated out within the cooldown window.
+    pub fn in_rotation_cooldown(&self, fingerprint: &str, config: &VanguardsConfig) -> bool {
+        if config.rotation_cooldown_hours == 0 {
+            return false;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let cooldown_secs = config.rotation_cooldown_hours as f64 * SEC_PER_HOUR;
+        self.rotated_out
+            .get(fingerprint)
+            .is_some_and(|rotated_at| now - rotated_at < cooldown_secs)
+    }
+
+    /// Returns the spread of remaining lifetimes in `layer` (2 or 3).
+    ///
+    /// Any other layer number gives empty stats.
+    pub fn lifetime_stats(&self, layer: u8) -> LifetimeStats {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let guards: &[GuardNode] = match layer {
+            2 => &self.layer2,
+            3 => &self.layer3,
+            _ => &[],
+        };
+        LifetimeStats::from_guards(guards, now)
+    }
+
+    /// Removes expired guards from both layers and remembers them for the
+    /// rotation cooldown.
+    ///
+    /// Entries older than `rotation_cooldown_hours` are forgotten.
+    pub fn remove_expired_guards(&mut self, config: &VanguardsConfig) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let cooldown_secs = config.rotation_cooldown_hours as f64 * SEC_PER_HOUR;
+        self.rotated_out
+            .retain(|_, rotated_at| now - *rotated_at < cooldown_secs);
+
+        if config.rotation_cooldown_hours > 0 {
+            for guard in self.layer2.iter().chain(self.layer3.iter()) {
+                if guard.expires_at < now {
+                    self.rotated_out.insert(guard.idhex.clone(), now);
+                }
+            }
+        }
+
+        Self::remove_expired_from_layer(&mut self.layer2);
+        Self::remove_expired_from_layer(&mut self.layer3);
+    }
+
+    /// Records when each relay in `routers` was first seen in a consensus,
+    /// and forgets relays no longer listed.
+    ///
+    /// Relays in the first consensus tracked are recorded at 0: how long
+    /// they had been around is unknown, and counting them all as new would
+    /// leave no candidates for `min_guard_age_hours` on a fresh install.
+    pub fn track_first_seen(&mut self, routers: &[RouterStatusEntry], now: f64) {
+        let seen_at = if self.first_seen.is_empty() { 0.0 } else { now };
+        let listed: HashSet<&str> = routers.iter().map(|r| r.fingerprint.as_str()).collect();
+        self.first_seen.retain(|fp, _| listed.contains(fp.as_str()));
+        for router in routers {
+            self.first_seen
+                .entry(router.fingerprint.clone())
+                .or_insert(seen_at);
+        }
+    }
+
+    /// Removes guards that are no longer in the consensus.
+    pub fn remove_down_from_layer(layer: &mut Vec<GuardNode>, consensus_fps: &HashSet<String>) {
+        layer.retain(|g| consensus_fps.contains(&g.idhex));
+    }
+
+    /// Removes guards whose rotation time has expired.
+    pub fn remove_expired_from_layer(layer: &mut Vec<GuardNode>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        layer.retain(|g| g.expires_at >= now);
+    }
+
+    /// Removes guards that match the ExcludeNodes configuration.
+    pub fn remove_excluded_from_layer(
+        layer: &mut Vec<GuardNode>,
+        router_map: &HashMap<String, &RouterStatusEntry>,
+        excluded: &ExcludeNodes,
+    ) {
+        layer.retain(|g| {
+            if let Some(router) = router_map.get(&g.idhex) {
                 !excluded.router_is_excluded(router)
             } else {
                 true
@@ -928,28 +1849,287 @@ impl VanguardState {
     /// Replenishes guard layers to configured counts.
     ///
     /// First trims layers if they exceed configured counts, then adds
-    /// new guards until the configured count is reached.
+    /// new guards until the configured count is reached. A layer disabled
+    /// via `enable_layer2`/`enable_layer3` is emptied and never selected for.
+    ///
+    /// With `min_layer_bw_fraction` set, the guards added to layer2 are
+    /// drawn again until the whole layer carries that share of the
+    /// generator's weight. Guards kept from before are never replaced. If no
+    /// attempt meets the fraction, the best one is kept with a NOTICE.
+    ///
+    /// # Returns
+    ///
+    /// The diversity constraints that had to be relaxed, which are also
+    /// logged at NOTICE.
     pub fn replenish_layers(
         &mut self,
         generator: &BwWeightedGenerator,
         excluded: &ExcludeNodes,
         config: &VanguardsConfig,
-    ) -> Result<()> {
-        self.layer2.truncate(config.num_layer2_guards as usize);
-        self.layer3.truncate(config.num_layer3_guards as usize);
+    ) -> Result<DiversityReport> {
+        let num_layer2 = config.layer2_guard_count() as usize;
+        let num_layer3 = config.layer3_guard_count() as usize;
+        let mut report = DiversityReport::default();
+
+        self.layer2.truncate(num_layer2);
+        self.layer3.truncate(num_layer3);
+
+        self.fill_layer2(num_layer2, generator, excluded, config, &mut report)?;
+
+        while self.layer3.len() < num_layer3 {
+            self.add_new_guard(3, generator, excluded, config, &mut report)?;
+        }
+
+        report.log();
+        Ok(report)
+    }
+}
+
+/// Upper bounds of the [`LifetimeStats`] histogram buckets, in seconds: one
+/// hour, six hours, one day, one week and 30 days. A final bucket holds
+/// everything longer.
+pub const LIFETIME_BUCKET_BOUNDS_SECS: [u64; 5] = [3_600, 21_600, 86_400, 604_800, 2_592_000];
+
+/// Remaining lifetimes of the guards in one layer.
+///
+/// Guards past their expiry count as zero seconds remaining.
+///
+/// # Example
+///
+/// ```rust
+/// use vanguards_rs::vanguards::{GuardNode, LifetimeStats};
+///
+/// let guards = vec![
+///     GuardNode::new("A".repeat(40), 0.0, 1_000.0 + 600.0),
+///     GuardNode::new("B".repeat(40), 0.0, 1_000.0 + 7_200.0),
+/// ];
+/// let stats = LifetimeStats::from_guards(&guards, 1_000.0);
+/// assert_eq!(stats.min_secs, Some(600));
+/// assert_eq!(stats.median_secs, Some(3_900));
+/// assert_eq!(stats.histogram, vec![1, 1, 0, 0, 0, 0]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LifetimeStats {
+    /// Number of guards in the layer.
+    pub count: usize,
+    /// Shortest remaining lifetime, in seconds.
+    pub min_secs: Option<u64>,
+    /// Median remaining lifetime, in seconds.
+    pub median_secs: Option<u64>,
+    /// Longest remaining lifetime, in seconds.
+    pub max_secs: Option<u64>,
+    /// Guard counts per [`LIFETIME_BUCKET_BOUNDS_SECS`] bucket, plus one
+    /// for longer lifetimes.
+    pub histogram: Vec<usize>,
+}
 
-        while self.layer2.len() < config.num_layer2_guards as usize {
-            self.add_new_layer2(generator, excluded, config)?;
+impl LifetimeStats {
+    /// Computes stats for `guards` as of the Unix timestamp `now`.
+    pub fn from_guards(guards: &[GuardNode], now: f64) -> Self {
+        let mut remaining: Vec<u64> = guards
+            .iter()
+            .map(|g| (g.expires_at - now).max(0.0) as u64)
+            .collect();
+        remaining.sort_unstable();
+
+        let mut histogram = vec![0; LIFETIME_BUCKET_BOUNDS_SECS.len() + 1];
+        for secs in &remaining {
+            let bucket = LIFETIME_BUCKET_BOUNDS_SECS
+                .iter()
+                .position(|&bound| *secs <= bound)
+                .unwrap_or(LIFETIME_BUCKET_BOUNDS_SECS.len());
+            histogram[bucket] += 1;
         }
 
-        while self.layer3.len() < config.num_layer3_guards as usize {
-            self.add_new_layer3(generator, excluded, config)?;
+        let mid = remaining.len() / 2;
+        let median_secs = match remaining.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(remaining[mid]),
+            _ => Some((remaining[mid - 1] + remaining[mid]) / 2),
+        };
+        Self {
+            count: remaining.len(),
+            min_secs: remaining.first().copied(),
+            median_secs,
+            max_secs: remaining.last().copied(),
+            histogram,
         }
+    }
+}
 
-        Ok(())
+/// A guard-layer diversity rule that selection may have to relax.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiversityConstraint {
+    /// No two guards in a layer share an IPv4 /16 or IPv6 /32.
+    Subnet,
+    /// No two guards in a layer declare each other as family.
+    Family,
+    /// No two layer2 or layer3 guards are in the same autonomous system.
+    Asn,
+}
+
+impl std::fmt::Display for DiversityConstraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiversityConstraint::Subnet => write!(f, "subnet"),
+            DiversityConstraint::Family => write!(f, "family"),
+            DiversityConstraint::Asn => write!(f, "AS"),
+        }
+    }
+}
+
+/// A guard chosen in spite of a diversity constraint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelaxedConstraint {
+    /// Layer the guard was added to (2 or 3).
+    pub layer: u8,
+    /// The constraint the guard breaks.
+    pub constraint: DiversityConstraint,
+    /// Fingerprint of the guard.
+    pub fingerprint: String,
+}
+
+/// Diversity constraints relaxed while filling guard layers.
+///
+/// Returned by [`VanguardState::replenish_layers`]. An empty report means
+/// every configured diversity goal was met.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiversityReport {
+    /// Each guard that was added in spite of a constraint.
+    pub relaxed: Vec<RelaxedConstraint>,
+}
+
+impl DiversityReport {
+    /// Returns true if no constraint was relaxed.
+    pub fn is_empty(&self) -> bool {
+        self.relaxed.is_empty()
+    }
+
+    /// Logs each relaxation at NOTICE.
+    fn log(&self) {
+        for r in &self.relaxed {
+            plog(
+                LogLevel::Notice,
+                &format!(
+                    "Not enough relays to keep layer{} {}-diverse; added guard {} anyway.",
+                    r.layer, r.constraint, r.fingerprint
+                ),
+            );
+        }
+    }
+}
+
+/// Returns the subnet used for [`DiversityConstraint::Subnet`]: the /16 of
+/// an IPv4 address or the /32 of an IPv6 one.
+fn diversity_subnet(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let [a, b, _, _] = v4.octets();
+            IpAddr::from([a, b, 0, 0])
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            IpAddr::from([s[0], s[1], 0, 0, 0, 0, 0, 0])
+        }
+    }
+}
+
+/// Reads a pickled `str` (or Python 2 byte string).
+fn pickle_string(value: &serde_pickle::Value) -> Option<String> {
+    match value {
+        serde_pickle::Value::String(s) => Some(s.clone()),
+        serde_pickle::Value::Bytes(b) => String::from_utf8(b.clone()).ok(),
+        _ => None,
+    }
+}
+
+/// Reads a pickled number as `f64`.
+fn pickle_f64(value: &serde_pickle::Value) -> Option<f64> {
+    match value {
+        serde_pickle::Value::F64(f) => Some(*f),
+        serde_pickle::Value::I64(i) => Some(*i as f64),
+        _ => None,
+    }
+}
+
+/// Reads the guards of one layer, skipping entries that do not look like one.
+///
+/// A guard may be a dict with `idhex`, `chosen_at` and `expires_at` (extra
+/// keys ignored) or a list or tuple holding those three in order.
+fn pickle_guard_layer(value: Option<&serde_pickle::Value>) -> Vec<GuardNode> {
+    use serde_pickle::{HashableValue, Value};
+
+    let entries = match value {
+        Some(Value::List(entries)) | Some(Value::Tuple(entries)) => entries,
+        _ => return Vec::new(),
+    };
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (idhex, chosen_at, expires_at) = match entry {
+                Value::Dict(d) => {
+                    let get = |k: &str| d.get(&HashableValue::String(k.to_string()));
+                    (get("idhex")?, get("chosen_at")?, get("expires_at")?)
+                }
+                Value::List(items) | Value::Tuple(items) if items.len() >= 3 => {
+                    (&items[0], &items[1], &items[2])
+                }
+                _ => return None,
+            };
+            Some(GuardNode::new(
+                pickle_string(idhex)?,
+                pickle_f64(chosen_at)?,
+                pickle_f64(expires_at)?,
+            ))
+        })
+        .collect()
+}
+
+/// Path of the `n`th backup of the state file at `path`.
+fn state_backup_path(path: &Path, n: u32) -> std::path::PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+/// Whether the state file at `path` should become a new backup: the newest
+/// backup is missing or was written [`STATE_BACKUP_INTERVAL_SECS`] or more
+/// ago.
+fn state_backup_due(path: &Path) -> bool {
+    std::fs::metadata(state_backup_path(path, 1))
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|written| written.elapsed().ok())
+        .is_none_or(|age| age.as_secs() >= STATE_BACKUP_INTERVAL_SECS)
+}
+
+/// Moves the state file at `path` to `<path>.1`, shifting older backups up
+/// and dropping any past `keep`.
+///
+/// The primary is briefly missing until the new state is renamed into
+/// place; [`VanguardState::read_with_backups`] covers a crash in between.
+fn rotate_state_backups(path: &Path, keep: u32) -> std::io::Result<()> {
+    let _ = std::fs::remove_file(state_backup_path(path, keep));
+    for n in (1..keep).rev() {
+        let from = state_backup_path(path, n);
+        if from.exists() {
+            std::fs::rename(&from, state_backup_path(path, n + 1))?;
+        }
     }
+    std::fs::rename(path, state_backup_path(path, 1))
 }
 
+/// Shortest IPv4 prefix [`ExcludeNodes::parse`] accepts; a `/8` is already
+/// 1/256 of the address space.
+///
+/// The daemon uses `vanguards.exclude_min_ipv4_prefix`, which defaults to it.
+pub const DEFAULT_MIN_IPV4_PREFIX: u8 = 8;
+
+/// Shortest IPv6 prefix [`ExcludeNodes::parse`] accepts.
+///
+/// The daemon uses `vanguards.exclude_min_ipv6_prefix`, which defaults to it.
+pub const DEFAULT_MIN_IPV6_PREFIX: u8 = 16;
+
 /// Parsed ExcludeNodes configuration for relay filtering.
 ///
 /// Parses Tor's ExcludeNodes configuration option to filter out unwanted
@@ -1043,9 +2223,43 @@ impl ExcludeNodes {
     /// - `$FINGERPRINT` or `FINGERPRINT` - 40 hex character fingerprint
     /// - `$FINGERPRINT~nickname` or `$FINGERPRINT=nickname` - Fingerprint with suffix (suffix stripped)
     /// - `{cc}` - Country code (2 characters)
-    /// - `192.168.0.0/24` or `2001:db8::/32` - IP network
+    /// - `192.168.0.0/24` or `2001:db8::/32` - IP network, IPv6 optionally in
+    ///   brackets
+    /// - `10.0.0.1` or `2001:db8::1` - Single address, as a `/32` or `/128`
     /// - `nickname` - Relay nickname
+    ///
+    /// Networks broader than [`DEFAULT_MIN_IPV4_PREFIX`] or
+    /// [`DEFAULT_MIN_IPV6_PREFIX`], such as `0.0.0.0/0`, are skipped with a
+    /// warning: they would exclude so much of the network that no guards
+    /// could be chosen. See [`Self::parse_with_min_prefixes`] to change the
+    /// limits.
     pub fn parse(conf_line: &str, exclude_unknowns: Option<&str>) -> Self {
+        Self::parse_with_min_prefixes(
+            conf_line,
+            exclude_unknowns,
+            DEFAULT_MIN_IPV4_PREFIX,
+            DEFAULT_MIN_IPV6_PREFIX,
+        )
+    }
+
+    /// Parses an ExcludeNodes configuration line, skipping IPv4 networks
+    /// shorter than `/min_ipv4_prefix` and IPv6 networks shorter than
+    /// `/min_ipv6_prefix`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::vanguards::ExcludeNodes;
+    ///
+    /// let exclude = ExcludeNodes::parse_with_min_prefixes("10.0.0.0/8,2001:db8::/32", None, 16, 48);
+    /// assert!(exclude.networks.is_empty());
+    /// ```
+    pub fn parse_with_min_prefixes(
+        conf_line: &str,
+        exclude_unknowns: Option<&str>,
+        min_ipv4_prefix: u8,
+        min_ipv6_prefix: u8,
+    ) -> Self {
         let mut result = Self::new();
         result.exclude_unknowns = exclude_unknowns.map(|s| s.to_string());
 
@@ -1060,12 +2274,12 @@ impl ExcludeNodes {
             return result;
         }
 
-        result.parse_line(conf_line);
+        result.parse_line(conf_line, min_ipv4_prefix, min_ipv6_prefix);
         result
     }
 
     /// Parses a single configuration line.
-    fn parse_line(&mut self, conf_line: &str) {
+    fn parse_line(&mut self, conf_line: &str, min_ipv4_prefix: u8, min_ipv6_prefix: u8) {
         for part in conf_line.split(',') {
             let mut p = part.trim().to_string();
             if p.is_empty() {
@@ -1091,17 +2305,42 @@ impl ExcludeNodes {
                     self.countries.insert(cc.to_lowercase());
                 }
             } else if p.contains(':') || p.contains('.') {
-                if let Ok(network) = p.parse::<IpNetwork>() {
-                    self.networks.push(network);
-                } else if let Ok(ip) = p.parse::<IpAddr>() {
-                    let network = match ip {
-                        IpAddr::V4(_) => format!("{}/32", ip).parse().ok(),
-                        IpAddr::V6(_) => format!("{}/128", ip).parse().ok(),
-                    };
-                    if let Some(net) = network {
-                        self.networks.push(net);
-                    }
+                // Tor accepts IPv6 in brackets, as in [2001:db8::1]/64
+                let unbracketed = match p.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                    Some((addr, rest)) => format!("{}{}", addr, rest),
+                    None => p.clone(),
+                };
+                let network = unbracketed.parse::<IpAddr>().map_or_else(
+                    |_| unbracketed.parse::<IpNetwork>().ok(),
+                    |ip| {
+                        let prefix = if ip.is_ipv4() { 32 } else { 128 };
+                        IpNetwork::new(ip, prefix).ok()
+                    },
+                );
+                let Some(network) = network else {
+                    plog(
+                        LogLevel::Warn,
+                        &format!("Ignoring unparseable ExcludeNodes entry {}", p),
+                    );
+                    continue;
+                };
+                let min_prefix = if network.is_ipv4() {
+                    min_ipv4_prefix
+                } else {
+                    min_ipv6_prefix
+                };
+                if network.prefix() < min_prefix {
+                    plog(
+                        LogLevel::Warn,
+                        &format!(
+                            "Ignoring ExcludeNodes network {}: it is broader than /{} and \
+                             would exclude too many relays to choose guards from",
+                            network, min_prefix
+                        ),
+                    );
+                    continue;
                 }
+                self.networks.push(network);
             } else {
                 self.nicks.insert(p);
             }
@@ -1159,6 +2398,45 @@ impl ExcludeNodes {
             || !self.nicks.is_empty()
             || !self.countries.is_empty()
     }
+
+    /// Rebuilds an `ExcludeNodes` value Tor would accept.
+    ///
+    /// Entries are emitted as `$FINGERPRINT`, `{cc}`, networks, then
+    /// nicknames, each group sorted. The `??` and `a1` codes added for
+    /// `GeoIPExcludeUnknown` are left out, since they come from that option
+    /// rather than from `ExcludeNodes`; parsing the result with the same
+    /// `GeoIPExcludeUnknown` value yields the same sets.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use vanguards_rs::vanguards::ExcludeNodes;
+    ///
+    /// let exclude = ExcludeNodes::parse("BadRelay,{US},10.0.0.1", Some("auto"));
+    /// assert_eq!(exclude.to_conf_string(), "{us},10.0.0.1/32,BadRelay");
+    /// ```
+    pub fn to_conf_string(&self) -> String {
+        let mut idhexes: Vec<String> = self.idhexes.iter().map(|fp| format!("${}", fp)).collect();
+        idhexes.sort();
+        let mut countries: Vec<String> = self
+            .countries
+            .iter()
+            .filter(|cc| is_valid_country_code(cc))
+            .map(|cc| format!("{{{}}}", cc))
+            .collect();
+        countries.sort();
+        let networks = self.networks.iter().map(|n| n.to_string());
+        let mut nicks: Vec<String> = self.nicks.iter().cloned().collect();
+        nicks.sort();
+
+        idhexes
+            .into_iter()
+            .chain(countries)
+            .chain(networks)
+            .chain(nicks)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
 }
 
 #[cfg(test)]
@@ -1178,6 +2456,29 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_track_first_seen() {
+        let (a, b, c) = ("A".repeat(40), "B".repeat(40), "C".repeat(40));
+        let routers = |fps: &[&String]| -> Vec<RouterStatusEntry> {
+            fps.iter()
+                .map(|fp| create_test_router(fp, "relay", "192.0.2.1"))
+                .collect()
+        };
+        let mut state = VanguardState::new("test.state");
+
+        // The first consensus sets no ages; later arrivals are dated
+        state.track_first_seen(&routers(&[&a, &b]), 1000.0);
+        state.track_first_seen(&routers(&[&a, &b, &c]), 2000.0);
+        assert_eq!(state.first_seen[&a], 0.0);
+        assert_eq!(state.first_seen[&c], 2000.0);
+
+        // A relay that leaves starts over when it comes back
+        state.track_first_seen(&routers(&[&a, &c]), 3000.0);
+        assert!(!state.first_seen.contains_key(&b));
+        state.track_first_seen(&routers(&[&a, &b, &c]), 4000.0);
+        assert_eq!(state.first_seen[&b], 4000.0);
+    }
+
     #[test]
     fn test_guard_node_creation() {
         let now = 1000000.0;
@@ -1238,6 +2539,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_lifetime_jitter() {
+        // With min == max the base distribution is a single point
+        let base = VanguardState::calculate_guard_lifetime(24, 24);
+        assert_eq!(VanguardState::apply_lifetime_jitter(base, 0.0), base);
+
+        let samples: Vec<f64> = (0..200)
+            .map(|_| VanguardState::apply_lifetime_jitter(base, 0.1))
+            .collect();
+        assert!(samples
+            .iter()
+            .all(|&l| (base * 0.9..=base * 1.1).contains(&l)));
+        let spread = samples.iter().cloned().fold(f64::MIN, f64::max)
+            - samples.iter().cloned().fold(f64::MAX, f64::min);
+        assert!(spread > base * 0.05);
+    }
+
     #[test]
     fn test_remove_expired_from_layer() {
         let now = SystemTime::now()
@@ -1275,6 +2593,482 @@ mod tests {
         assert!(!layer.iter().any(|g| g.idhex == "B".repeat(40)));
     }
 
+    fn create_test_generator(fingerprints: &[String]) -> BwWeightedGenerator {
+        use crate::node_selection::{NodeRestrictionList, Position};
+
+        let routers = fingerprints
+            .iter()
+            .map(|fp| {
+                let mut router = create_test_router(fp, "relay", "192.0.2.1");
+                router.flags = vec!["Fast".to_string(), "Stable".to_string()];
+                router.measured = Some(1000);
+                router
+            })
+            .collect();
+        BwWeightedGenerator::new(
+            routers,
+            NodeRestrictionList::new(vec![]),
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_expired_guard_not_reselected_within_cooldown() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let config = VanguardsConfig {
+            num_layer2_guards: 1,
+            num_layer3_guards: 0,
+            ..VanguardsConfig::default()
+        };
+        let generator = create_test_generator(&["A".repeat(40), "B".repeat(40)]);
+        let excluded = ExcludeNodes::new();
+
+        let mut state = VanguardState::new("test.state");
+        state
+            .layer2
+            .push(GuardNode::new("A".repeat(40), now - 1000.0, now - 1.0));
+
+        state.remove_expired_guards(&config);
+        assert!(state.layer2.is_empty());
+        assert!(state.in_rotation_cooldown(&"A".repeat(40), &config));
+
+        state
+            .replenish_layers(&generator, &excluded, &config)
+            .unwrap();
+        assert_eq!(state.layer2.len(), 1);
+        assert_eq!(state.layer2[0].idhex, "B".repeat(40));
+    }
+
+    #[test]
+    fn test_cooldown_guard_reselected_when_only_candidate() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let config = VanguardsConfig {
+            num_layer2_guards: 1,
+            num_layer3_guards: 0,
+            ..VanguardsConfig::default()
+        };
+        let generator = create_test_generator(&["A".repeat(40)]);
+
+        let mut state = VanguardState::new("test.state");
+        state
+            .layer2
+            .push(GuardNode::new("A".repeat(40), now - 1000.0, now - 1.0));
+
+        state.remove_expired_guards(&config);
+        state
+            .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+            .unwrap();
+        assert_eq!(state.layer2[0].idhex, "A".repeat(40));
+    }
+
+    #[test]
+    fn test_disabled_layer3_is_never_selected() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let config = VanguardsConfig {
+            num_layer2_guards: 1,
+            enable_layer3: false,
+            ..VanguardsConfig::default()
+        };
+        let generator = create_test_generator(&["C".repeat(40)]);
+
+        let mut state = VanguardState::new("test.state");
+        state
+            .layer2
+            .push(GuardNode::new("A".repeat(40), now, now + 1000.0));
+        state
+            .layer3
+            .push(GuardNode::new("B".repeat(40), now, now + 1000.0));
+
+        state
+            .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+            .unwrap();
+        assert_eq!(state.layer2[0].idhex, "A".repeat(40));
+        assert!(state.layer3.is_empty());
+    }
+
+    #[test]
+    fn test_lifetime_stats() {
+        let now = 1_000_000.0;
+        let guard = |i: usize, remaining: f64| {
+            GuardNode::new(format!("{:040X}", i), now - 100.0, now + remaining)
+        };
+        let layer = vec![
+            guard(1, 86_400.0 * 40.0),
+            guard(2, 1_800.0),
+            guard(3, -50.0),
+            guard(4, 86_400.0 * 3.0),
+            guard(5, 7_200.0),
+        ];
+
+        let stats = LifetimeStats::from_guards(&layer, now);
+        assert_eq!(stats.count, 5);
+        assert_eq!(stats.min_secs, Some(0));
+        assert_eq!(stats.median_secs, Some(7_200));
+        assert_eq!(stats.max_secs, Some(86_400 * 40));
+        assert_eq!(stats.histogram, vec![2, 1, 0, 1, 0, 1]);
+
+        // Even counts average the middle two
+        let stats = LifetimeStats::from_guards(&layer[..4], now);
+        assert_eq!(stats.median_secs, Some((1_800 + 86_400 * 3) / 2));
+
+        let empty = LifetimeStats::from_guards(&[], now);
+        assert_eq!(empty.count, 0);
+        assert_eq!(empty.median_secs, None);
+        assert_eq!(empty.histogram, vec![0; 6]);
+
+        let mut state = VanguardState::new("test.state");
+        state.layer3 = layer;
+        assert_eq!(state.lifetime_stats(2).count, 0);
+        assert_eq!(state.lifetime_stats(3).count, 5);
+        assert_eq!(
+            state.lifetime_stats(4),
+            LifetimeStats::from_guards(&[], now)
+        );
+    }
+
+    #[test]
+    fn test_min_layer_bw_fraction_rejects_tiny_selection() {
+        use crate::node_selection::{NodeRestrictionList, Position};
+
+        // 90 tiny relays and one carrying a quarter of the total weight
+        let big = format!("{:040X}", 1000);
+        let mut routers: Vec<_> = (1..=90)
+            .map(|i| {
+                let mut router =
+                    create_test_router(&format!("{:040X}", i), "tiny", &format!("198.51.{}.1", i));
+                router.flags = vec!["Fast".to_string(), "Stable".to_string()];
+                router.measured = Some(100);
+                router
+            })
+            .collect();
+        let mut router = create_test_router(&big, "big", "203.0.113.1");
+        router.flags = vec!["Fast".to_string(), "Stable".to_string()];
+        router.measured = Some(3000);
+        routers.push(router);
+        let generator = BwWeightedGenerator::new(
+            routers,
+            NodeRestrictionList::new(vec![]),
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap();
+        assert!((generator.weight_fraction([big.as_str()]) - 0.25).abs() < 1e-9);
+        assert_eq!(generator.weight_fraction([]), 0.0);
+
+        let config = VanguardsConfig {
+            num_layer2_guards: 1,
+            enable_layer3: false,
+            min_layer_bw_fraction: 0.2,
+            ..VanguardsConfig::default()
+        };
+        // Three in four first draws are a tiny relay, so some of these runs
+        // must reject their initial selection
+        for _ in 0..20 {
+            let mut state = VanguardState::new("test.state");
+            state
+                .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+                .unwrap();
+            assert_eq!(state.layer2.len(), 1);
+            assert_eq!(state.layer2[0].idhex, big);
+        }
+
+        // Guards kept from earlier are not reselected
+        let mut state = VanguardState::new("test.state");
+        state
+            .layer2
+            .push(GuardNode::new(format!("{:040X}", 1), 0.0, f64::MAX));
+        state
+            .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+            .unwrap();
+        assert_eq!(state.layer2.len(), 1);
+        assert_eq!(state.layer2[0].idhex, format!("{:040X}", 1));
+    }
+
+    #[test]
+    fn test_subnet_diversity_relaxation_is_reported() {
+        use crate::node_selection::{NodeRestrictionList, Position};
+
+        // Four relays in one /16 and one elsewhere
+        let addresses = [
+            "198.51.1.1",
+            "198.51.2.1",
+            "198.51.3.1",
+            "198.51.4.1",
+            "203.0.113.1",
+        ];
+        let routers = addresses
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                let mut router = create_test_router(&format!("{:040X}", i + 1), "relay", addr);
+                router.flags = vec!["Fast".to_string(), "Stable".to_string()];
+                router.measured = Some(1000);
+                router
+            })
+            .collect();
+        let generator = BwWeightedGenerator::new(
+            routers,
+            NodeRestrictionList::new(vec![]),
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap();
+        let config = VanguardsConfig {
+            num_layer2_guards: 3,
+            enable_layer3: false,
+            subnet_diversity: true,
+            ..VanguardsConfig::default()
+        };
+
+        let mut state = VanguardState::new("test.state");
+        let report = state
+            .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+            .unwrap();
+        assert_eq!(state.layer2.len(), 3);
+        // Only two /16s exist, so the third guard must break the constraint
+        assert_eq!(report.relaxed.len(), 1);
+        assert_eq!(report.relaxed[0].layer, 2);
+        assert_eq!(report.relaxed[0].constraint, DiversityConstraint::Subnet);
+        assert!(state
+            .layer2
+            .iter()
+            .any(|g| g.idhex == report.relaxed[0].fingerprint));
+
+        let mut state = VanguardState::new("test.state");
+        let config = VanguardsConfig {
+            num_layer2_guards: 2,
+            ..config
+        };
+        let report = state
+            .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+            .unwrap();
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn test_as_diversity() {
+        use crate::node_selection::{AsRestriction, NodeRestrictionList, Position};
+
+        // A and B share an AS; all three in distinct /16s
+        let fps: Vec<String> = (1..=3).map(|i| format!("{:040X}", i)).collect();
+        let routers: Vec<_> = fps
+            .iter()
+            .zip(["198.51.1.1", "203.0.113.1", "192.0.2.1"])
+            .map(|(fp, addr)| {
+                let mut router = create_test_router(fp, "relay", addr);
+                router.flags = vec!["Fast".to_string(), "Stable".to_string()];
+                router.measured = Some(1000);
+                router
+            })
+            .collect();
+        let asns = HashMap::from([
+            (fps[0].clone(), 64496),
+            (fps[1].clone(), 64496),
+            (fps[2].clone(), 64497),
+        ]);
+        let generator = BwWeightedGenerator::new(
+            routers,
+            NodeRestrictionList::new(vec![]),
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap()
+        .with_as_restriction(AsRestriction::new(asns));
+        let config = VanguardsConfig {
+            num_layer2_guards: 1,
+            num_layer3_guards: 1,
+            ..VanguardsConfig::default()
+        };
+
+        for _ in 0..20 {
+            let mut state = VanguardState::new("test.state");
+            let report = state
+                .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+                .unwrap();
+            assert!(report.is_empty());
+            let chosen: HashSet<_> = state
+                .layer2
+                .iter()
+                .chain(&state.layer3)
+                .map(|g| g.idhex.as_str())
+                .collect();
+            assert!(chosen.contains(fps[2].as_str()));
+        }
+
+        // With three guards an AS pair is unavoidable
+        let config = VanguardsConfig {
+            num_layer2_guards: 2,
+            ..config
+        };
+        let mut state = VanguardState::new("test.state");
+        let report = state
+            .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+            .unwrap();
+        assert_eq!(report.relaxed.len(), 1);
+        assert_eq!(report.relaxed[0].constraint, DiversityConstraint::Asn);
+    }
+
+    #[test]
+    fn test_family_diversity() {
+        use crate::node_selection::{NodeRestrictionList, Position, RelayFamilies};
+
+        // A and B declare each other as family; all three in distinct /16s
+        let fps: Vec<String> = (1..=3).map(|i| format!("{:040X}", i)).collect();
+        let routers: Vec<_> = fps
+            .iter()
+            .zip(["198.51.1.1", "203.0.113.1", "192.0.2.1"])
+            .map(|(fp, addr)| {
+                let mut router = create_test_router(fp, "relay", addr);
+                router.flags = vec!["Fast".to_string(), "Stable".to_string()];
+                router.measured = Some(1000);
+                router
+            })
+            .collect();
+        let declared = HashMap::from([
+            (fps[0].clone(), vec![fps[1].clone()]),
+            (fps[1].clone(), vec![fps[0].clone()]),
+        ]);
+        let generator = BwWeightedGenerator::new(
+            routers,
+            NodeRestrictionList::new(vec![]),
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap()
+        .with_families(RelayFamilies::from_declared(&declared));
+        let config = VanguardsConfig {
+            num_layer2_guards: 2,
+            enable_layer3: false,
+            ..VanguardsConfig::default()
+        };
+
+        for _ in 0..20 {
+            let mut state = VanguardState::new("test.state");
+            let report = state
+                .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+                .unwrap();
+            assert!(report.is_empty());
+            assert!(state.layer2.iter().any(|g| g.idhex == fps[2]));
+        }
+
+        // With three guards a family pair is unavoidable
+        let config = VanguardsConfig {
+            num_layer2_guards: 3,
+            ..config
+        };
+        let mut state = VanguardState::new("test.state");
+        let report = state
+            .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+            .unwrap();
+        assert_eq!(state.layer2.len(), 3);
+        assert_eq!(report.relaxed.len(), 1);
+        assert_eq!(report.relaxed[0].constraint, DiversityConstraint::Family);
+
+        // Turning enforcement off allows the pair silently
+        let config = VanguardsConfig {
+            enforce_guard_diversity: false,
+            ..config
+        };
+        let mut state = VanguardState::new("test.state");
+        let report = state
+            .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+            .unwrap();
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn test_family_diversity_leaves_subnets_alone() {
+        use crate::node_selection::{NodeRestrictionList, Position};
+
+        // Two unrelated relays in one /16
+        let fps: Vec<String> = (1..=2).map(|i| format!("{:040X}", i)).collect();
+        let routers: Vec<_> = fps
+            .iter()
+            .zip(["198.51.1.1", "198.51.2.1"])
+            .map(|(fp, addr)| {
+                let mut router = create_test_router(fp, "relay", addr);
+                router.flags = vec!["Fast".to_string(), "Stable".to_string()];
+                router.measured = Some(1000);
+                router
+            })
+            .collect();
+        let generator = BwWeightedGenerator::new(
+            routers,
+            NodeRestrictionList::new(vec![]),
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap();
+        let config = VanguardsConfig {
+            num_layer2_guards: 2,
+            enable_layer3: false,
+            enforce_guard_diversity: true,
+            subnet_diversity: false,
+            ..VanguardsConfig::default()
+        };
+
+        let mut state = VanguardState::new("test.state");
+        let report = state
+            .replenish_layers(&generator, &ExcludeNodes::new(), &config)
+            .unwrap();
+        assert_eq!(state.layer2.len(), 2);
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn test_expire_guard() {
+        let mut state = VanguardState::new("test.state");
+        for fp in ["A", "B"] {
+            state
+                .layer2
+                .push(GuardNode::new(fp.repeat(40), 0.0, f64::MAX));
+        }
+        state
+            .layer3
+            .push(GuardNode::new("C".repeat(40), 0.0, f64::MAX));
+
+        assert!(state.expire_guard(&format!("${}", "b".repeat(40))));
+        assert_eq!(state.layer2_guardset(), "A".repeat(40));
+        assert_eq!(state.layer3_guardset(), "C".repeat(40));
+        assert!(state.rotated_out.contains_key(&"B".repeat(40)));
+
+        assert!(!state.expire_guard(&"D".repeat(40)));
+        assert_eq!(state.layer2.len() + state.layer3.len(), 2);
+    }
+
+    #[test]
+    fn test_rotation_cooldown_disabled() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let config = VanguardsConfig {
+            rotation_cooldown_hours: 0,
+            ..VanguardsConfig::default()
+        };
+
+        let mut state = VanguardState::new("test.state");
+        state
+            .layer3
+            .push(GuardNode::new("A".repeat(40), now - 1000.0, now - 1.0));
+        state.remove_expired_guards(&config);
+
+        assert!(state.layer3.is_empty());
+        assert!(state.rotated_out.is_empty());
+        assert!(!state.in_rotation_cooldown(&"A".repeat(40), &config));
+    }
+
     #[test]
     fn test_exclude_nodes_parse_fingerprint() {
         let exclude = ExcludeNodes::parse("$AABBCCDD00112233445566778899AABBCCDDEEFF", None);
@@ -1325,6 +3119,31 @@ mod tests {
         assert_eq!(exclude2.networks.len(), 1);
     }
 
+    #[test]
+    fn test_exclude_nodes_rejects_broad_networks() {
+        let exclude = ExcludeNodes::parse("0.0.0.0/0,::/0,2001::/8", None);
+        assert!(exclude.networks.is_empty());
+        assert!(!exclude.has_exclusions());
+
+        let exclude = ExcludeNodes::parse("10.0.0.0/8,192.168.1.0/24,2001:db8::/16", None);
+        let networks: Vec<String> = exclude.networks.iter().map(|n| n.to_string()).collect();
+        assert_eq!(networks, ["10.0.0.0/8", "192.168.1.0/24", "2001:db8::/16"]);
+
+        let exclude = ExcludeNodes::parse_with_min_prefixes("192.168.1.0/24", None, 25, 16);
+        assert!(exclude.networks.is_empty());
+    }
+
+    #[test]
+    fn test_exclude_nodes_parse_bare_ipv6() {
+        let exclude = ExcludeNodes::parse("2001:db8::1,[2001:db8::2],[2001:db8::]/32", None);
+        let networks: Vec<String> = exclude.networks.iter().map(|n| n.to_string()).collect();
+        assert_eq!(
+            networks,
+            ["2001:db8::1/128", "2001:db8::2/128", "2001:db8::/32"]
+        );
+        assert!(exclude.nicks.is_empty());
+    }
+
     #[test]
     fn test_exclude_nodes_parse_ip_address() {
         let exclude = ExcludeNodes::parse("192.168.1.1", None);
@@ -1351,6 +3170,22 @@ mod tests {
         assert!(exclude.nicks.contains("BadRelay"));
     }
 
+    #[test]
+    fn test_exclude_nodes_conf_string_round_trip() {
+        let conf = "BadRelay,$aabbccdd00112233445566778899aabbccddeeff~named,{US},{de},\
+                    192.168.0.0/16,2001:db8::/32,10.1.2.3,OtherRelay";
+        let exclude = ExcludeNodes::parse(conf, Some("auto"));
+        let serialized = exclude.to_conf_string();
+        let reparsed = ExcludeNodes::parse(&serialized, Some("auto"));
+
+        assert_eq!(reparsed.idhexes, exclude.idhexes);
+        assert_eq!(reparsed.countries, exclude.countries);
+        assert_eq!(reparsed.networks, exclude.networks);
+        assert_eq!(reparsed.nicks, exclude.nicks);
+        assert_eq!(reparsed.to_conf_string(), serialized);
+        assert!(!serialized.contains("??"));
+    }
+
     #[test]
     fn test_exclude_nodes_geoip_exclude_unknown_1() {
         let exclude = ExcludeNodes::parse("", Some("1"));
@@ -1444,6 +3279,48 @@ mod tests {
         assert_eq!(rg.total_use_counts, 150.0);
     }
 
+    #[test]
+    fn test_rendguard_top_overused() {
+        let config = crate::config::RendguardConfig::default();
+        let mut rg = RendGuard::new();
+        // (fingerprint, used, weight): margins of 0.15, 0.05, 0.10, -4.2
+        // and one relay below use_relay_start_count
+        let counts = [
+            ("A".repeat(40), 200.0, 0.01),
+            ("B".repeat(40), 100.0, 0.01),
+            ("NOT_IN_CONSENSUS".to_string(), 100.0, 0.0),
+            ("C".repeat(40), 500.0, 0.94),
+            ("D".repeat(40), 99.0, 0.0),
+        ];
+        for (fp, used, weight) in &counts {
+            rg.use_counts.insert(
+                fp.clone(),
+                RendUseCount {
+                    idhex: fp.clone(),
+                    used: *used,
+                    weight: *weight,
+                },
+            );
+        }
+        rg.total_use_counts = 1000.0;
+
+        let top = rg.top_overused(10, &config);
+        let order: Vec<&str> = top.iter().map(|(fp, _, _)| fp.as_str()).collect();
+        assert_eq!(
+            order,
+            [
+                counts[0].0.as_str(),
+                "NOT_IN_CONSENSUS",
+                counts[1].0.as_str(),
+                counts[3].0.as_str()
+            ]
+        );
+        assert_eq!(top[0], ("A".repeat(40), 20.0, 1.0));
+
+        assert_eq!(rg.top_overused(2, &config).len(), 2);
+        assert!(RendGuard::new().top_overused(5, &config).is_empty());
+    }
+
     #[test]
     fn test_exclude_nodes_has_exclusions() {
         let empty = ExcludeNodes::new();
@@ -1478,6 +3355,351 @@ mod tests {
         assert!(exclude.countries.contains("us"));
     }
 
+    #[test]
+    fn test_rendguard_use_window() {
+        let (a, b) = ("A".repeat(40), "B".repeat(40));
+        let mut config = crate::config::RendguardConfig::default();
+
+        // Without a window, timestamps are ignored
+        let mut plain = RendGuard::new();
+        let mut timed = RendGuard::new();
+        for (i, fp) in [&a, &b, &a].into_iter().enumerate() {
+            assert_eq!(
+                plain.valid_rend_use(fp, &config),
+                timed.record_use_at(fp, i as f64 * 86400.0, &config)
+            );
+        }
+        assert_eq!(plain, timed);
+        assert!(timed.use_buckets.is_empty());
+
+        config.use_window_hours = 1;
+        let mut rg = RendGuard::new();
+        for fp in [&a, &b] {
+            rg.use_counts
+                .insert(fp.clone(), RendUseCount::new(fp.clone(), 0.5));
+        }
+        for at in [0.0, 60.0, 120.0] {
+            rg.record_use_at(&a, at, &config);
+        }
+        rg.record_use_at(&b, 1800.0, &config);
+        assert_eq!(rg.total_use_counts, 4.0);
+
+        rg.prune_old(3660.0, &config);
+        assert_eq!(rg.use_counts[&a].used, 2.0);
+        assert_eq!(rg.total_use_counts, 3.0);
+        rg.prune_old(3725.0, &config);
+        assert_eq!(rg.use_counts[&a].used, 0.0);
+        assert_eq!(rg.total_use_counts, 1.0);
+        assert!(!rg.use_buckets.contains_key(&a));
+        assert_eq!(rg.use_buckets[&b].len(), 1);
+
+        // Buckets survive a pickle round trip; state without them loads
+        let bytes = serde_pickle::to_vec(&rg, Default::default()).unwrap();
+        let loaded: RendGuard = serde_pickle::from_slice(&bytes, Default::default()).unwrap();
+        assert_eq!(loaded.use_buckets, rg.use_buckets);
+        let bytes = serde_pickle::to_vec(&RendGuard::new(), Default::default()).unwrap();
+        let value: serde_pickle::Value =
+            serde_pickle::from_slice(&bytes, Default::default()).unwrap();
+        assert!(!format!("{:?}", value).contains("use_buckets"));
+        let loaded: RendGuard = serde_pickle::from_value(value).unwrap();
+        assert!(loaded.use_buckets.is_empty());
+    }
+
+    #[test]
+    fn test_rendguard_use_window_bounded() {
+        use crate::node_selection::{NodeRestrictionList, Position};
+
+        let (a, b) = ("A".repeat(40), "B".repeat(40));
+        let config = crate::config::RendguardConfig {
+            use_window_hours: 1,
+            ..crate::config::RendguardConfig::default()
+        };
+        let mut rg = RendGuard::new();
+        for fp in [&a, &b] {
+            rg.use_counts
+                .insert(fp.clone(), RendUseCount::new(fp.clone(), 0.5));
+        }
+
+        // A busy minute takes one bucket
+        for i in 0..1000 {
+            rg.record_use_at(&a, f64::from(i) * 0.05, &config);
+        }
+        assert_eq!(rg.use_buckets[&a].len(), 1);
+
+        // Uses of a relay that left the consensus go with it; when it
+        // returns, their expiry does not come off its new count
+        rg.record_use_at(&b, 10.0, &config);
+        let mut router = create_test_router(&a, "a", "203.0.113.1");
+        router.flags = vec!["Fast".to_string(), "Stable".to_string()];
+        router.measured = Some(100);
+        let generator = BwWeightedGenerator::new(
+            vec![router],
+            NodeRestrictionList::new(vec![]),
+            HashMap::new(),
+            Position::Middle,
+        )
+        .unwrap();
+        rg.xfer_use_counts(&generator, &config);
+        assert!(!rg.use_buckets.contains_key(&b));
+        rg.use_counts
+            .insert(b.clone(), RendUseCount::new(b.clone(), 0.5));
+        rg.record_use_at(&b, 1800.0, &config);
+        rg.prune_old(3650.0, &config);
+        assert_eq!(rg.use_counts[&a].used, 0.0);
+        assert_eq!(rg.use_counts[&b].used, 1.0);
+        assert_eq!(rg.total_use_counts, 1.0);
+    }
+
+    #[test]
+    fn test_newer_pickle_revision_keeps_guards() {
+        use serde_pickle::{HashableValue, Value};
+        use std::collections::BTreeMap;
+
+        fn dict(entries: Vec<(&str, Value)>) -> Value {
+            Value::Dict(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (HashableValue::String(k.to_string()), v))
+                    .collect::<BTreeMap<_, _>>(),
+            )
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let guard = |fp: &str| {
+            dict(vec![
+                ("idhex", Value::String(fp.to_string())),
+                ("chosen_at", Value::F64(now - 60.0)),
+                ("expires_at", Value::F64(now + 3600.0)),
+                ("weight", Value::F64(0.25)),
+            ])
+        };
+        // A future revision that dropped state_file and added a layer field
+        let pickle = dict(vec![
+            ("layer1", Value::List(vec![guard(&"C".repeat(40))])),
+            ("layer2", Value::List(vec![guard(&"A".repeat(40))])),
+            (
+                "layer3",
+                Value::Tuple(vec![Value::List(vec![
+                    Value::String("B".repeat(40)),
+                    Value::I64(now as i64),
+                    Value::I64(now as i64 + 7200),
+                ])]),
+            ),
+            (
+                "rendguard",
+                dict(vec![
+                    ("use_counts", dict(vec![])),
+                    ("total_use_counts", Value::F64(0.0)),
+                    ("pickle_revision", Value::F64(2.0)),
+                ]),
+            ),
+            ("pickle_revision", Value::I64(2)),
+        ]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.state");
+        std::fs::write(
+            &path,
+            serde_pickle::value_to_vec(&pickle, Default::default()).unwrap(),
+        )
+        .unwrap();
+
+        let state = VanguardState::read_from_file(&path).unwrap();
+        assert_eq!(state.layer2_guardset(), "A".repeat(40));
+        assert_eq!(state.layer3_guardset(), "B".repeat(40));
+        assert_eq!(state.pickle_revision, 2);
+
+        std::fs::write(&path, b"not a pickle").unwrap();
+        assert!(VanguardState::read_from_file(&path).is_err());
+    }
+
+    fn sample_state(now: f64) -> VanguardState {
+        let mut state = VanguardState::new("test.state");
+        state
+            .layer2
+            .push(GuardNode::new("A".repeat(40), now - 60.0, now + 3600.0));
+        state
+            .layer3
+            .push(GuardNode::new("B".repeat(40), now - 60.0, now + 600.0));
+        state.rendguard.use_counts.insert(
+            "C".repeat(40),
+            RendUseCount {
+                idhex: "C".repeat(40),
+                used: 3.0,
+                weight: 0.25,
+            },
+        );
+        state.rendguard.total_use_counts = 3.0;
+        state.rotated_out.insert("D".repeat(40), now - 30.0);
+        state.first_seen.insert("A".repeat(40), now - 7200.0);
+        state.rendguard.not_in_consensus.record(&"F".repeat(40), 10);
+        state
+    }
+
+    #[test]
+    fn test_state_without_layer1_reads_empty() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let state = sample_state(now);
+
+        // A state file written before layer1 existed
+        let mut value = serde_pickle::to_value(&state).unwrap();
+        let serde_pickle::Value::Dict(ref mut dict) = value else {
+            panic!("state is not a dict");
+        };
+        assert!(dict
+            .remove(&serde_pickle::HashableValue::String("layer1".to_string()))
+            .is_some());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.state");
+        std::fs::write(
+            &path,
+            serde_pickle::value_to_vec(&value, Default::default()).unwrap(),
+        )
+        .unwrap();
+
+        let loaded = VanguardState::read_from_file(&path).unwrap();
+        assert!(loaded.layer1.is_empty());
+        assert_eq!(loaded.layer2, state.layer2);
+        assert_eq!(loaded.rotated_out, state.rotated_out);
+        assert_eq!(loaded.first_seen, state.first_seen);
+
+        // Once recorded, layer1 survives a write and read
+        let mut loaded = loaded;
+        loaded.update_layer1(&["E".repeat(40)], now, 86400.0);
+        loaded.write_to_file(&path).unwrap();
+        let reloaded = VanguardState::read_from_file(&path).unwrap();
+        assert_eq!(reloaded.layer1_guardset(), "E".repeat(40));
+        assert_eq!(reloaded.layer1[0].expires_at, now + 86400.0);
+    }
+
+    #[test]
+    fn test_state_backups_rotate_and_restore() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.state");
+
+        // Pretends the newest backup was taken two hours ago
+        let age_backup = || {
+            let backup = state_backup_path(&path, 1);
+            if let Ok(file) = std::fs::File::options().write(true).open(backup) {
+                let earlier = SystemTime::now() - std::time::Duration::from_secs(7200);
+                file.set_modified(earlier).unwrap();
+            }
+        };
+
+        let mut state = sample_state(now);
+        state.state_backups = 2;
+        for fp in ["1", "2", "3", "4", "5"] {
+            // The last write comes soon after the backup before it, so it
+            // replaces only the primary
+            if fp != "5" {
+                age_backup();
+            }
+            state.layer2 = vec![GuardNode::new(fp.repeat(40), now, now + 3600.0)];
+            state.write_to_file(&path).unwrap();
+        }
+
+        let layer2_of = |p: &Path| {
+            VanguardState::read_from_file(p).unwrap().layer2[0]
+                .idhex
+                .clone()
+        };
+        assert_eq!(layer2_of(&path), "5".repeat(40));
+        assert_eq!(layer2_of(&state_backup_path(&path, 1)), "3".repeat(40));
+        assert_eq!(layer2_of(&state_backup_path(&path, 2)), "2".repeat(40));
+        assert!(!state_backup_path(&path, 3).exists());
+
+        std::fs::write(&path, b"not a state file").unwrap();
+        let restored = VanguardState::load_or_create(&path);
+        assert_eq!(restored.layer2[0].idhex, "3".repeat(40));
+
+        // A missing primary with no backups still gets a fresh state
+        let fresh = VanguardState::load_or_create(&dir.path().join("other.state"));
+        assert!(fresh.layer2.is_empty());
+    }
+
+    #[test]
+    fn test_json_state_round_trip() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let mut state = sample_state(now);
+        state.state_format = StateFormat::Json;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.state");
+        state.write_to_file(&path).unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with('{'));
+        assert!(text.contains(&"A".repeat(40)));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!path.with_extension("tmp").exists());
+
+        let mut loaded = VanguardState::read_from_file(&path).unwrap();
+        // Runtime flag, not persisted
+        loaded.enable_vanguards = state.enable_vanguards;
+        assert_eq!(loaded, state);
+    }
+
+    #[test]
+    fn test_state_format_detected_on_read() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let mut state = sample_state(now);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vanguards.state");
+
+        state.write_to_file(&path).unwrap();
+        let loaded = VanguardState::read_from_file(&path).unwrap();
+        assert_eq!(loaded.state_format, StateFormat::Pickle);
+        assert_eq!(loaded.layer2, state.layer2);
+        assert_eq!(loaded.rendguard, state.rendguard);
+
+        state.state_format = StateFormat::Json;
+        state.write_to_file(&path).unwrap();
+        let loaded = VanguardState::read_from_file(&path).unwrap();
+        assert_eq!(loaded.state_format, StateFormat::Json);
+        assert_eq!(loaded.layer2, state.layer2);
+        assert_eq!(loaded.rendguard, state.rendguard);
+
+        // Both parse errors are reported
+        std::fs::write(&path, b"{\"layer2\": 42}").unwrap();
+        let err = VanguardState::read_from_file(&path)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("pickle ("), "{}", err);
+        assert!(err.contains("JSON (invalid type"), "{}", err);
+
+        // Runtime settings come from the configuration
+        let mut loaded = VanguardState::new("test.state");
+        let config = Config {
+            enable_vanguards: false,
+            state_format: StateFormat::Json,
+            ..Config::default()
+        };
+        loaded.apply_config(&config);
+        assert!(!loaded.enable_vanguards);
+        assert_eq!(loaded.state_format, StateFormat::Json);
+    }
+
     #[test]
     fn test_vanguard_state_validation_valid() {
         let now = SystemTime::now()
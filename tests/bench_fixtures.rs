@@ -0,0 +1,49 @@
+//! Checks that the benchmark fixtures are what the benchmarks assume.
+
+#[path = "../benches/fixtures/mod.rs"]
+mod fixtures;
+
+use std::collections::HashSet;
+
+use vanguards_rs::parse_network_statuses;
+
+#[test]
+fn test_bench_fixture_parses() {
+    let fixture = fixtures::consensus(fixtures::RELAY_COUNT);
+    let routers = parse_network_statuses(&fixture.ns_all).unwrap();
+    assert_eq!(routers.len(), fixtures::RELAY_COUNT);
+
+    let expected: Vec<String> = fixture
+        .ns_all
+        .lines()
+        .filter_map(|line| line.strip_prefix("r "))
+        .map(|r| fixtures::identity_hex(r.split(' ').nth(1).unwrap()).unwrap())
+        .collect();
+    let parsed: Vec<&str> = routers.iter().map(|r| r.fingerprint.as_str()).collect();
+    assert_eq!(parsed, expected);
+    assert_eq!(parsed.iter().collect::<HashSet<_>>().len(), routers.len());
+
+    for router in &routers {
+        assert_eq!(router.fingerprint.len(), 40);
+        assert!(!router.address.is_unspecified(), "{}", router.nickname);
+        assert!(router.flags.iter().any(|f| f == "Running"));
+        assert!(router.bandwidth.is_some_and(|bw| bw > 0));
+    }
+
+    // Enough Fast/Stable relays for the vanguard generator to pick from
+    let eligible = routers
+        .iter()
+        .filter(|r| r.flags.iter().any(|f| f == "Fast") && r.flags.iter().any(|f| f == "Stable"))
+        .count();
+    assert!(eligible > fixtures::RELAY_COUNT / 2);
+    assert!(routers.iter().any(|r| r.measured.is_none()));
+    assert_eq!(fixture.weights.len(), 19);
+}
+
+#[test]
+fn test_bench_fixture_is_deterministic() {
+    assert_eq!(
+        fixtures::consensus(100).ns_all,
+        fixtures::consensus(100).ns_all
+    );
+}
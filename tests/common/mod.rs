@@ -0,0 +1,149 @@
+//! Harness for running vanguards-rs against a real Tor process.
+//!
+//! The harness either launches a throwaway `tor` with its own data directory
+//! and an automatically chosen control port, or attaches to an existing node
+//! (for example one started by chutney) when `VANGUARDS_TEST_CONTROL_PORT` is
+//! set.
+//!
+//! # Environment Variables
+//!
+//! | Variable | Default | Description |
+//! |----------|---------|-------------|
+//! | `TOR_BIN` | `tor` | Tor binary to launch |
+//! | `VANGUARDS_TEST_CONTROL_PORT` | (unset) | Attach to `127.0.0.1:<port>` instead of launching Tor |
+//! | `VANGUARDS_TEST_BOOTSTRAP_SECS` | `300` | How long to wait for bootstrap to reach 100% |
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use stem_rs::controller::Controller;
+use tempfile::TempDir;
+
+/// A Tor instance the tests can connect to.
+///
+/// When the harness launched the process itself it is killed on drop, and
+/// its data directory is removed with the [`TempDir`].
+pub struct TestTor {
+    child: Option<Child>,
+    control_addr: SocketAddr,
+    dir: TempDir,
+}
+
+impl TestTor {
+    /// Launches Tor, or attaches to the node named by `VANGUARDS_TEST_CONTROL_PORT`.
+    pub fn start() -> Self {
+        let dir = tempfile::tempdir().expect("create temp dir");
+
+        if let Ok(port) = std::env::var("VANGUARDS_TEST_CONTROL_PORT") {
+            let port: u16 = port.parse().expect("VANGUARDS_TEST_CONTROL_PORT is a port");
+            return Self {
+                child: None,
+                control_addr: SocketAddr::from(([127, 0, 0, 1], port)),
+                dir,
+            };
+        }
+
+        let tor_bin = std::env::var("TOR_BIN").unwrap_or_else(|_| "tor".to_string());
+        let data_dir = dir.path().join("tor-data");
+        let port_file = dir.path().join("control-port");
+        std::fs::create_dir(&data_dir).expect("create tor data dir");
+
+        let child = Command::new(&tor_bin)
+            .arg("--DataDirectory")
+            .arg(&data_dir)
+            .args(["--SocksPort", "0"])
+            .args(["--ControlPort", "auto"])
+            .arg("--ControlPortWriteToFile")
+            .arg(&port_file)
+            .args(["--CookieAuthentication", "1"])
+            .args(["--Log", "notice stderr"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("failed to launch {}: {}", tor_bin, e));
+
+        let control_addr = wait_for_port_file(&port_file, Duration::from_secs(30));
+
+        Self {
+            child: Some(child),
+            control_addr,
+            dir,
+        }
+    }
+
+    /// Returns a fresh, authenticated controller for this Tor instance.
+    pub async fn controller(&self) -> Controller {
+        let mut controller = Controller::from_port(self.control_addr)
+            .await
+            .expect("connect to control port");
+        vanguards_rs::authenticate_any(&mut controller, None)
+            .await
+            .expect("authenticate to Tor");
+        controller
+    }
+
+    /// Blocks until Tor reports bootstrap progress of 100%.
+    pub async fn wait_for_bootstrap(&self) {
+        let timeout = std::env::var("VANGUARDS_TEST_BOOTSTRAP_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(300);
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        let mut controller = self.controller().await;
+
+        loop {
+            let phase = controller
+                .get_info("status/bootstrap-phase")
+                .await
+                .unwrap_or_default();
+            if phase.contains("PROGRESS=100") {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Tor did not bootstrap within {}s (last phase: {})",
+                timeout,
+                phase
+            );
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Returns a state file path inside the harness's temporary directory.
+    pub fn state_file(&self) -> PathBuf {
+        self.dir.path().join("vanguards.state")
+    }
+}
+
+impl Drop for TestTor {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Waits for Tor to write `PORT=127.0.0.1:NNNN` to its ControlPortWriteToFile.
+fn wait_for_port_file(path: &Path, timeout: Duration) -> SocketAddr {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Ok(contents) = std::fs::read_to_string(path) {
+            if let Some(addr) = contents
+                .lines()
+                .find_map(|l| l.strip_prefix("PORT="))
+                .and_then(|a| a.trim().parse().ok())
+            {
+                return addr;
+            }
+        }
+        assert!(
+            Instant::now() < deadline,
+            "Tor did not open a control port within {:?}",
+            timeout
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
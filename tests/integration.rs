@@ -0,0 +1,62 @@
+//! End-to-end tests against a live Tor process.
+//!
+//! These tests only compile with the `integration-tests` feature and are
+//! ignored by default, since they need a Tor binary and a network to
+//! bootstrap from.
+//! With `tor` on `PATH`:
+//!
+//! ```text
+//! cargo test --features integration-tests -- --ignored
+//! ```
+//!
+//! To use a different binary set `TOR_BIN`. To run against a chutney network
+//! instead, start it and point the tests at one of its client nodes:
+//!
+//! ```text
+//! VANGUARDS_TEST_CONTROL_PORT=8003 cargo test --features integration-tests -- --ignored
+//! ```
+
+#![cfg(feature = "integration-tests")]
+
+mod common;
+
+use std::collections::HashSet;
+
+use common::TestTor;
+use vanguards_rs::{new_consensus_event, Config, VanguardState};
+
+#[tokio::test]
+#[ignore = "requires a Tor binary and network access"]
+async fn test_applied_layer2_guards_match_getconf() {
+    let tor = TestTor::start();
+    tor.wait_for_bootstrap().await;
+    let mut controller = tor.controller().await;
+
+    let config = Config {
+        state_file: tor.state_file(),
+        ..Config::default()
+    };
+    let mut state = VanguardState::new(&config.state_file.to_string_lossy());
+    state.enable_vanguards = true;
+
+    new_consensus_event(&mut controller, &mut state, &config)
+        .await
+        .expect("apply vanguards");
+
+    let selected: HashSet<String> = state.layer2.iter().map(|g| g.idhex.clone()).collect();
+    assert_eq!(selected.len(), config.vanguards.num_layer2_guards as usize);
+
+    let applied: HashSet<String> = controller
+        .get_conf("HSLayer2Nodes")
+        .await
+        .expect("GETCONF HSLayer2Nodes")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|fp| fp.trim().trim_start_matches('$').to_uppercase())
+        .filter(|fp| !fp.is_empty())
+        .collect();
+
+    let selected: HashSet<String> = selected.iter().map(|fp| fp.to_uppercase()).collect();
+    assert_eq!(applied, selected);
+    assert!(tor.state_file().exists());
+}